@@ -43,6 +43,12 @@ pub struct Export {
     pub comments: Vec<String>,
     /// The name of the rust function/method on the rust side.
     pub rust_name: Ident,
+    /// Whether or not this function should be flagged as the wasm start
+    /// function.
+    pub start: bool,
+    /// The chunk of the wasm module this function is split into, which is
+    /// only loaded once the function is first called.
+    pub split: Option<String>,
 }
 
 /// The 3 types variations of `self`.
@@ -144,6 +150,8 @@ pub struct ImportType {
     pub doc_comment: Option<String>,
     pub instanceof_shim: String,
     pub extends: Vec<Ident>,
+    pub vendor_prefixes: Vec<Ident>,
+    pub fallbacks: Vec<Ident>,
 }
 
 #[cfg_attr(feature = "extra-traits", derive(Debug, PartialEq, Eq))]
@@ -285,10 +293,33 @@ impl Function {
     fn shared(&self) -> shared::Function {
         shared::Function {
             name: self.name.to_string(),
+            location: source_location(self.name_span),
         }
     }
 }
 
+/// Source locations of spans are only available on nightly compilers when
+/// `--cfg procmacro2_semver_exempt` is passed, as they're unstable in
+/// `proc_macro`.
+#[cfg(procmacro2_semver_exempt)]
+fn source_location(span: Span) -> Option<shared::SourceLocation> {
+    let file = span.source_file();
+    if !file.is_real() {
+        return None;
+    }
+    let start = span.start();
+    Some(shared::SourceLocation {
+        file: file.path().display().to_string(),
+        line: start.line as u32,
+        column: start.column as u32,
+    })
+}
+
+#[cfg(not(procmacro2_semver_exempt))]
+fn source_location(_span: Span) -> Option<shared::SourceLocation> {
+    None
+}
+
 impl Export {
     /// Mangles a rust -> javascript export, so that the created Ident will be unique over function
     /// name and class name, if the function belongs to a javascript class.
@@ -327,6 +358,8 @@ impl Export {
             is_constructor: self.is_constructor,
             function: self.function.shared(),
             comments: self.comments.clone(),
+            start: self.start,
+            split: self.split.clone(),
         }
     }
 }
@@ -482,6 +515,8 @@ impl ImportType {
         shared::ImportType {
             name: self.js_name.clone(),
             instanceof_shim: self.instanceof_shim.clone(),
+            vendor_prefixes: self.vendor_prefixes.iter().map(|s| s.to_string()).collect(),
+            fallbacks: self.fallbacks.iter().map(|s| s.to_string()).collect(),
         }
     }
 }
@@ -4,9 +4,9 @@ use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 use proc_macro2::{Ident, Literal, Span, TokenStream};
 use quote::ToTokens;
-use serde_json;
 use shared;
 use syn;
+use syn::spanned::Spanned;
 
 use ast;
 use Diagnostic;
@@ -87,12 +87,12 @@ impl TryToTokens for ast::Program {
         );
         let generated_static_name = Ident::new(&generated_static_name, Span::call_site());
 
-        let description = serde_json::to_string(&self.shared()?).unwrap();
+        let description = shared::binary::encode(&self.shared()?);
 
-        // Each JSON blob is prepended with the length of the JSON blob so when
-        // all these sections are concatenated in the final wasm file we know
-        // how to extract all the JSON pieces, so insert the byte length here.
-        // The value is little-endian.
+        // Each encoded program is prepended with its length so when all these
+        // sections are concatenated in the final wasm file we know how to
+        // extract all the programs, so insert the byte length here. The value
+        // is little-endian.
         let generated_static_length = description.len() + 4;
         let mut bytes = vec![
             (description.len() >> 0) as u8,
@@ -100,7 +100,7 @@ impl TryToTokens for ast::Program {
             (description.len() >> 16) as u8,
             (description.len() >> 24) as u8,
         ];
-        bytes.extend_from_slice(description.as_bytes());
+        bytes.extend_from_slice(&description);
         let generated_static_value = syn::LitByteStr::new(&bytes, Span::call_site());
 
         (quote! {
@@ -124,6 +124,11 @@ impl ToTokens for ast::Struct {
         let name_chars = name_str.chars().map(|c| c as u32);
         let new_fn = Ident::new(&shared::new_function(&name_str), Span::call_site());
         let free_fn = Ident::new(&shared::free_function(&name_str), Span::call_site());
+        let unwrap_fn = Ident::new(&shared::unwrap_function(&name_str), Span::call_site());
+        let const_name = Ident::new(
+            &format!("__wbg_generated_const_{}", name_str),
+            Span::call_site(),
+        );
         (quote! {
             impl ::wasm_bindgen::describe::WasmDescribe for #name {
                 fn describe() {
@@ -237,6 +242,85 @@ impl ToTokens for ast::Struct {
                     (*js).borrow_mut()
                 }
             }
+
+            #[allow(bad_style)]
+            const #const_name: () = {
+                use wasm_bindgen::convert::{FromWasmAbi, GlobalStack};
+                use wasm_bindgen::convert::{OptionIntoWasmAbi, OptionFromWasmAbi};
+                use wasm_bindgen::convert::{OptionRefFromWasmAbi, OptionRefMutFromWasmAbi};
+                use wasm_bindgen::__rt::{Ref, RefMut, WasmRefCell};
+                use wasm_bindgen::{JsClass, JsValue};
+
+                impl OptionIntoWasmAbi for #name {
+                    fn none() -> Self::Abi { 0 }
+                }
+
+                impl OptionFromWasmAbi for #name {
+                    fn is_none(abi: &Self::Abi) -> bool { *abi == 0 }
+                }
+
+                impl OptionRefFromWasmAbi for #name {
+                    fn is_none(abi: &Self::Abi) -> bool { *abi == 0 }
+                }
+
+                impl OptionRefMutFromWasmAbi for #name {
+                    fn is_none(abi: &Self::Abi) -> bool { *abi == 0 }
+                }
+
+                #[cfg(all(target_arch = "wasm32", not(target_os = "emscripten")))]
+                fn __wbindgen_unwrap(val: &JsValue, take: bool) -> u32 {
+                    use wasm_bindgen::convert::IntoWasmAbi;
+
+                    #[link(wasm_import_module = "__wbindgen_placeholder__")]
+                    extern {
+                        fn #unwrap_fn(idx: u32, take: u32) -> u32;
+                    }
+                    unsafe {
+                        let idx = val.into_abi(&mut GlobalStack::new());
+                        #unwrap_fn(idx, take as u32)
+                    }
+                }
+
+                #[cfg(not(all(target_arch = "wasm32", not(target_os = "emscripten"))))]
+                fn __wbindgen_unwrap(val: &JsValue, take: bool) -> u32 {
+                    drop((val, take));
+                    panic!("cannot check instanceof on non-wasm targets");
+                }
+
+                impl JsClass for #name {
+                    fn instanceof(val: &JsValue) -> bool {
+                        __wbindgen_unwrap(val, false) != 0
+                    }
+
+                    fn dyn_from(val: JsValue) -> Result<Self, JsValue> {
+                        let ptr = __wbindgen_unwrap(&val, true);
+                        if ptr == 0 {
+                            return Err(val)
+                        }
+                        unsafe {
+                            Ok(<#name as FromWasmAbi>::from_abi(ptr, &mut GlobalStack::new()))
+                        }
+                    }
+
+                    fn dyn_ref(val: &JsValue) -> Option<Ref<Self>> {
+                        let ptr = __wbindgen_unwrap(val, false);
+                        if ptr == 0 {
+                            return None
+                        }
+                        unsafe { Some((*(ptr as *mut WasmRefCell<#name>)).borrow()) }
+                    }
+
+                    fn dyn_mut(val: &JsValue) -> Option<RefMut<Self>> {
+                        let ptr = __wbindgen_unwrap(val, false);
+                        if ptr == 0 {
+                            return None
+                        }
+                        unsafe { Some((*(ptr as *mut WasmRefCell<#name>)).borrow_mut()) }
+                    }
+                }
+
+                ()
+            };
         }).to_tokens(tokens);
 
         for field in self.fields.iter() {
@@ -372,15 +456,58 @@ impl TryToTokens for ast::Export {
             },
         };
 
+        let mut assertions = vec![];
         for (i, syn::ArgCaptured { ty, .. }) in self.function.arguments.iter().enumerate() {
             let i = i + offset;
             let ident = Ident::new(&format!("arg{}", i), Span::call_site());
             match *ty {
+                _ if option_ref_elem(ty).is_some() => {
+                    let (mutable, elem) = option_ref_elem(ty).unwrap();
+                    assertions.push(assert_impl(ty, elem, if mutable {
+                        quote! { ::wasm_bindgen::convert::OptionRefMutFromWasmAbi }
+                    } else {
+                        quote! { ::wasm_bindgen::convert::OptionRefFromWasmAbi }
+                    }));
+                    if mutable {
+                        args.push(quote! {
+                            #ident: <#elem as ::wasm_bindgen::convert::RefMutFromWasmAbi>::Abi
+                        });
+                        arg_conversions.push(quote! {
+                            let mut #ident = unsafe {
+                                use wasm_bindgen::convert::{OptionRefMutFromWasmAbi, RefMutFromWasmAbi};
+                                if <#elem as OptionRefMutFromWasmAbi>::is_none(&#ident) {
+                                    None
+                                } else {
+                                    Some(<#elem as RefMutFromWasmAbi>::ref_mut_from_abi(#ident, &mut __stack))
+                                }
+                            };
+                            let #ident = #ident.as_mut().map(|#ident| &mut **#ident);
+                        });
+                    } else {
+                        args.push(quote! {
+                            #ident: <#elem as ::wasm_bindgen::convert::RefFromWasmAbi>::Abi
+                        });
+                        arg_conversions.push(quote! {
+                            let #ident = unsafe {
+                                use wasm_bindgen::convert::{OptionRefFromWasmAbi, RefFromWasmAbi};
+                                if <#elem as OptionRefFromWasmAbi>::is_none(&#ident) {
+                                    None
+                                } else {
+                                    Some(<#elem as RefFromWasmAbi>::ref_from_abi(#ident, &mut __stack))
+                                }
+                            };
+                            let #ident = #ident.as_ref().map(|#ident| &**#ident);
+                        });
+                    }
+                }
                 syn::Type::Reference(syn::TypeReference {
                     mutability: Some(_),
                     ref elem,
                     ..
                 }) => {
+                    assertions.push(assert_impl(ty, elem, quote! {
+                        ::wasm_bindgen::convert::RefMutFromWasmAbi
+                    }));
                     args.push(quote! {
                         #ident: <#elem as ::wasm_bindgen::convert::RefMutFromWasmAbi>::Abi
                     });
@@ -393,6 +520,9 @@ impl TryToTokens for ast::Export {
                     });
                 }
                 syn::Type::Reference(syn::TypeReference { ref elem, .. }) => {
+                    assertions.push(assert_impl(ty, elem, quote! {
+                        ::wasm_bindgen::convert::RefFromWasmAbi
+                    }));
                     args.push(quote! {
                         #ident: <#elem as ::wasm_bindgen::convert::RefFromWasmAbi>::Abi
                     });
@@ -405,6 +535,9 @@ impl TryToTokens for ast::Export {
                     });
                 }
                 _ => {
+                    assertions.push(assert_impl(ty, ty, quote! {
+                        ::wasm_bindgen::convert::FromWasmAbi
+                    }));
                     args.push(quote! {
                         #ident: <#ty as ::wasm_bindgen::convert::FromWasmAbi>::Abi
                     });
@@ -429,6 +562,11 @@ impl TryToTokens for ast::Export {
                 "cannot return a borrowed ref with #[wasm_bindgen]",
             )
         }
+        if let Some(ty) = &self.function.ret {
+            assertions.push(assert_impl(ty, ty, quote! {
+                ::wasm_bindgen::convert::ReturnWasmAbi
+            }));
+        }
         let ret_ty = quote! {
             -> <#syn_ret as ::wasm_bindgen::convert::ReturnWasmAbi>::Abi
         };
@@ -464,6 +602,7 @@ impl TryToTokens for ast::Export {
                 #convert_ret
             }
         }).to_tokens(into);
+        type_assertions(&generated_name.to_string(), attrs, &assertions).to_tokens(into);
 
         // In addition to generating the shim function above which is what
         // our generated JS will invoke, we *also* generate a "descriptor"
@@ -531,7 +670,7 @@ impl ToTokens for ast::ImportType {
             const #const_name: () = {
                 use wasm_bindgen::convert::{IntoWasmAbi, FromWasmAbi, Stack};
                 use wasm_bindgen::convert::{OptionIntoWasmAbi, OptionFromWasmAbi};
-                use wasm_bindgen::convert::RefFromWasmAbi;
+                use wasm_bindgen::convert::{RefFromWasmAbi, OptionRefFromWasmAbi};
                 use wasm_bindgen::describe::WasmDescribe;
                 use wasm_bindgen::{JsValue, JsCast};
                 use wasm_bindgen::__rt::core::mem::ManuallyDrop;
@@ -592,6 +731,10 @@ impl ToTokens for ast::ImportType {
                     }
                 }
 
+                impl OptionRefFromWasmAbi for #rust_name {
+                    fn is_none(abi: &Self::Abi) -> bool { *abi == 0 }
+                }
+
                 // TODO: remove this on the next major version
                 impl From<JsValue> for #rust_name {
                     fn from(obj: JsValue) -> #rust_name {
@@ -781,6 +924,7 @@ impl TryToTokens for ast::ImportFunction {
         let mut abi_arguments = Vec::new();
         let mut arg_conversions = Vec::new();
         let mut arguments = Vec::new();
+        let mut assertions = Vec::new();
         let ret_ident = Ident::new("_ret", Span::call_site());
 
         for (i, syn::ArgCaptured { pat, ty, .. }) in self.function.arguments.iter().enumerate() {
@@ -800,6 +944,9 @@ impl TryToTokens for ast::ImportFunction {
                 }
             };
 
+            assertions.push(assert_impl(ty, ty, quote! {
+                ::wasm_bindgen::convert::IntoWasmAbi
+            }));
             abi_argument_names.push(name.clone());
             abi_arguments.push(quote! {
                 #name: <#ty as ::wasm_bindgen::convert::IntoWasmAbi>::Abi
@@ -822,6 +969,9 @@ impl TryToTokens for ast::ImportFunction {
                 bail_span!(self.js_ret, "cannot return references in #[wasm_bindgen] imports yet");
             }
             Some(ref ty) => {
+                assertions.push(assert_impl(ty, ty, quote! {
+                    ::wasm_bindgen::convert::FromWasmAbi
+                }));
                 abi_ret = quote! {
                     <#ty as ::wasm_bindgen::convert::FromWasmAbi>::Abi
                 };
@@ -920,6 +1070,7 @@ impl TryToTokens for ast::ImportFunction {
         } else {
             invocation.to_tokens(tokens);
         }
+        type_assertions(&import_name.to_string(), attrs, &assertions).to_tokens(tokens);
 
         Ok(())
     }
@@ -1170,7 +1321,14 @@ impl ToTokens for ast::Dictionary {
                     fn none() -> Self::Abi { <&'a Object>::none() }
                 }
                 impl OptionFromWasmAbi for #name {
-                    fn is_none(abi: &Self::Abi) -> bool { Object::is_none(abi) }
+                    fn is_none(abi: &Self::Abi) -> bool {
+                        <Object as OptionFromWasmAbi>::is_none(abi)
+                    }
+                }
+                impl OptionRefFromWasmAbi for #name {
+                    fn is_none(abi: &Self::Abi) -> bool {
+                        <Object as OptionRefFromWasmAbi>::is_none(abi)
+                    }
                 }
 
                 impl RefFromWasmAbi for #name {
@@ -1223,6 +1381,69 @@ impl ToTokens for ast::DictionaryField {
     }
 }
 
+/// Returns the referenced type and its mutability if `ty` is of the form
+/// `Option<&T>` or `Option<&mut T>`.
+fn option_ref_elem(ty: &syn::Type) -> Option<(bool, &syn::Type)> {
+    let path = match *ty {
+        syn::Type::Path(syn::TypePath { qself: None, ref path }) => path,
+        _ => return None,
+    };
+    let seg = path.segments.last()?.into_value();
+    if seg.ident != "Option" {
+        return None;
+    }
+    let generics = match seg.arguments {
+        syn::PathArguments::AngleBracketed(ref t) => &t.args,
+        _ => return None,
+    };
+    if generics.len() != 1 {
+        return None;
+    }
+    match *generics.first()?.into_value() {
+        syn::GenericArgument::Type(syn::Type::Reference(ref r)) => {
+            Some((r.mutability.is_some(), &*r.elem))
+        }
+        _ => None,
+    }
+}
+
+/// Asserts that `ty`, or the part `target` of it, implements `trait_`.
+///
+/// Types which can't cross the wasm ABI boundary would otherwise only be
+/// reported through a missing trait implementation somewhere deep in the
+/// generated shims, so this points the error at the type in the signature.
+fn assert_impl(ty: &syn::Type, target: &syn::Type, trait_: TokenStream) -> TokenStream {
+    quote_spanned! {ty.span()=>
+        {
+            fn assert_impl<T: #trait_ + ?Sized>() {}
+            assert_impl::<#target>();
+        }
+    }
+}
+
+/// A function which is never called, holding the `assertions` made about
+/// the types in the signature of the shim `name`. Of the attributes `attrs`
+/// of the item only `cfg` and `cfg_attr` are forwarded, so the assertions
+/// are compiled whenever the item is.
+///
+/// Unlike the shims themselves this is compiled for every target, so
+/// unsupported types are also reported when checking a crate natively.
+fn type_assertions(name: &str, attrs: &[syn::Attribute], assertions: &[TokenStream]) -> TokenStream {
+    let name = Ident::new(&format!("__wbindgen_assert_types_{}", name), Span::call_site());
+    let attrs = attrs.iter().filter(|attr| {
+        attr.path.segments.len() == 1
+            && (attr.path.segments[0].ident == "cfg" || attr.path.segments[0].ident == "cfg_attr")
+    });
+    quote! {
+        #(#attrs)*
+        #[allow(non_snake_case, dead_code)]
+        #[doc(hidden)]
+        fn #name() {
+            #(#assertions)*
+        }
+    }
+}
+
 /// Emits the necessary glue tokens for "descriptor", generating an appropriate
 /// symbol name as well as attributes around the descriptor function itself.
 struct Descriptor<'a, T>(&'a Ident, T);
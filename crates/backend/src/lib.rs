@@ -9,7 +9,6 @@ extern crate log;
 extern crate proc_macro2;
 #[macro_use]
 extern crate quote;
-extern crate serde_json;
 extern crate syn;
 
 extern crate wasm_bindgen_shared as shared;
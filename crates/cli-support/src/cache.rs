@@ -0,0 +1,408 @@
+//! On-disk cache of work shared between runs of wasm-bindgen
+//!
+//! With `Bindgen::cache_dir` two things are kept in the cache directory:
+//!
+//! * The output of the last run for each combination of options and output
+//!   name, keyed by a hash of the input wasm file. Running again on an
+//!   unchanged file, as watch loops tend to do, copies the output back without
+//!   even parsing the module.
+//! * The words produced by each descriptor function, keyed by a hash of all
+//!   the code the function can run. After changing a few functions only the
+//!   descriptors whose code changed are interpreted again.
+//!
+//! Everything is looked up by hash, so an outdated or corrupt cache is never
+//! an error: it's ignored and rewritten. The hashes are FNV-1a rather than
+//! those of `DefaultHasher`, whose algorithm may change between Rust releases
+//! and so between builds of the same version of wasm-bindgen.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use failure::{Error, ResultExt};
+use parity_wasm::elements::*;
+use serde_json::{self, Map, Value};
+use shared;
+
+const DESCRIPTORS: &str = "descriptors.json";
+const MANIFEST: &str = "manifest.json";
+
+pub struct Cache {
+    dir: PathBuf,
+    /// Descriptors cached by previous runs.
+    descriptors: HashMap<String, Vec<u32>>,
+    /// Descriptors used by this run, which is all that's saved.
+    used: HashMap<String, Vec<u32>>,
+    /// Keys of the descriptors looked up so far, by name.
+    keys: HashMap<String, String>,
+    functions: Option<Functions>,
+}
+
+impl Cache {
+    /// Opens the cache in `dir`, which is created when saving.
+    pub fn new(dir: &Path) -> Cache {
+        let descriptors = fs::read(dir.join(DESCRIPTORS))
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<Value>(&contents).ok())
+            .and_then(|json| parse_descriptors(&json))
+            .unwrap_or_default();
+        Cache {
+            dir: dir.to_path_buf(),
+            descriptors,
+            used: HashMap::new(),
+            keys: HashMap::new(),
+            functions: None,
+        }
+    }
+
+    /// Returns the cached result of interpreting the descriptor export `name`
+    /// of `module`, if any.
+    pub fn descriptor(&mut self, module: &Module, name: &str) -> Option<Vec<u32>> {
+        if self.functions.is_none() {
+            self.functions = Some(Functions::new(module));
+        }
+        let key = self.functions.as_mut().unwrap().export_key(module, name)?;
+        let words = self.descriptors.get(&key).cloned();
+        if let Some(ref words) = words {
+            self.used.insert(key.clone(), words.clone());
+        }
+        self.keys.insert(name.to_string(), key);
+        words
+    }
+
+    /// Records the result of interpreting the descriptor `name`, which must
+    /// have been looked up with `descriptor` first.
+    pub fn insert_descriptor(&mut self, name: &str, words: &[u32]) {
+        if let Some(key) = self.keys.get(name) {
+            self.used.insert(key.clone(), words.to_vec());
+        }
+    }
+
+    /// Saves the descriptors used by this run, dropping those which weren't.
+    pub fn save(&self) -> Result<(), Error> {
+        let descriptors = self
+            .used
+            .iter()
+            .map(|(key, words)| (key.clone(), words.clone().into()))
+            .collect::<Map<_, _>>();
+        let mut json = Map::new();
+        json.insert("version".to_string(), shared::version().into());
+        json.insert("descriptors".to_string(), Value::Object(descriptors));
+        write_atomic(&self.dir, DESCRIPTORS, &serde_json::to_vec(&Value::Object(json))?)
+    }
+}
+
+fn parse_descriptors(json: &Value) -> Option<HashMap<String, Vec<u32>>> {
+    if json.get("version")?.as_str()? != shared::version() {
+        return None;
+    }
+    let mut ret = HashMap::new();
+    for (key, words) in json.get("descriptors")?.as_object()? {
+        let words = words
+            .as_array()?
+            .iter()
+            .map(|w| w.as_u64().map(|w| w as u32))
+            .collect::<Option<Vec<_>>>()?;
+        ret.insert(key.clone(), words);
+    }
+    Some(ret)
+}
+
+/// Writes `contents` to `name` in `dir` such that concurrent readers never
+/// see a partially written file.
+fn write_atomic(dir: &Path, name: &str, contents: &[u8]) -> Result<(), Error> {
+    fs::create_dir_all(dir)
+        .with_context(|_| format!("failed to create `{}`", dir.display()))?;
+    let tmp = dir.join(format!("{}.tmp", name));
+    let path = dir.join(name);
+    fs::write(&tmp, contents)
+        .with_context(|_| format!("failed to write `{}`", tmp.display()))?;
+    fs::rename(&tmp, &path)
+        .with_context(|_| format!("failed to write `{}`", path.display()))?;
+    Ok(())
+}
+
+/// The output of a run, which is restored when the input and options match.
+pub struct Output {
+    dir: PathBuf,
+    key: String,
+}
+
+impl Output {
+    /// `options` identifies everything besides the input which affects the
+    /// output, each combination getting a directory in the cache of its own.
+    pub fn new(cache_dir: &Path, options: &str, input: &[u8]) -> Output {
+        let options = format!("{}\0{}", shared::version(), options);
+        Output {
+            dir: cache_dir.join(format!("output-{}", hash(&options))),
+            key: hash(&(options, input)),
+        }
+    }
+
+    /// Copies the cached output to `out_dir`, returning whether there was
+    /// one to copy.
+    pub fn restore(&self, out_dir: &Path) -> Result<bool, Error> {
+        let files = match self.files() {
+            Some(files) => files,
+            None => return Ok(false),
+        };
+        if files.iter().any(|f| !self.dir.join("files").join(f).is_file()) {
+            return Ok(false);
+        }
+        for file in files {
+            let dst = out_dir.join(&file);
+            fs::copy(self.dir.join("files").join(&file), &dst)
+                .with_context(|_| format!("failed to write `{}`", dst.display()))?;
+        }
+        Ok(true)
+    }
+
+    fn files(&self) -> Option<Vec<String>> {
+        let contents = fs::read(self.dir.join(MANIFEST)).ok()?;
+        let json = serde_json::from_slice::<Value>(&contents).ok()?;
+        if json.get("key")?.as_str()? != self.key {
+            return None;
+        }
+        json.get("files")?
+            .as_array()?
+            .iter()
+            .map(|f| f.as_str().map(|f| f.to_string()))
+            .collect()
+    }
+
+    /// Replaces the cached output with `files`, relative to `out_dir`.
+    pub fn save(&self, out_dir: &Path, files: &[PathBuf]) -> Result<(), Error> {
+        // Remove the manifest first so an interrupted save is never restored.
+        drop(fs::remove_file(self.dir.join(MANIFEST)));
+        let mut names = Vec::new();
+        for file in files {
+            let name = match file.strip_prefix(out_dir) {
+                Ok(name) => name,
+                Err(_) => bail!("`{}` isn't in the output directory", file.display()),
+            };
+            let dst = self.dir.join("files").join(name);
+            fs::create_dir_all(dst.parent().unwrap())
+                .with_context(|_| format!("failed to create `{}`", dst.display()))?;
+            fs::copy(file, &dst)
+                .with_context(|_| format!("failed to write `{}`", dst.display()))?;
+            names.push(Value::from(name.to_str().unwrap()));
+        }
+        let mut json = Map::new();
+        json.insert("key".to_string(), self.key.clone().into());
+        json.insert("files".to_string(), Value::Array(names));
+        write_atomic(&self.dir, MANIFEST, &serde_json::to_vec(&Value::Object(json))?)
+    }
+}
+
+fn hash<T: Hash + ?Sized>(t: &T) -> String {
+    let mut hasher = Fnv::default();
+    t.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Hashes of the code of a module's functions.
+///
+/// A function's key covers its signature, locals and instructions along with
+/// the keys of the functions it calls, but not the indices it calls them by.
+/// Adding or removing unrelated functions shifts the indices of every function
+/// in the module, which mustn't invalidate every descriptor. The data and
+/// element segments are included for functions which read memory or make
+/// indirect calls. Globals aren't, as descriptors only use the stack pointer
+/// for scratch space, whose initial value changes along with the size of the
+/// data segments.
+struct Functions {
+    imports: Vec<String>,
+    /// Keys of each function and what it and its callees read, by index.
+    keys: HashMap<u32, (u64, Reads)>,
+    data: Option<u64>,
+    elements: Option<u64>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Reads {
+    memory: bool,
+    table: bool,
+}
+
+impl Functions {
+    fn new(module: &Module) -> Functions {
+        let imports = module
+            .import_section()
+            .map(|s| {
+                s.entries()
+                    .iter()
+                    .filter(|i| match *i.external() {
+                        External::Function(_) => true,
+                        _ => false,
+                    })
+                    .map(|i| format!("{}::{}", i.module(), i.field()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Functions {
+            imports,
+            keys: HashMap::new(),
+            data: None,
+            elements: None,
+        }
+    }
+
+    fn export_key(&mut self, module: &Module, name: &str) -> Option<String> {
+        let idx = module
+            .export_section()?
+            .entries()
+            .iter()
+            .filter(|e| e.field() == name)
+            .filter_map(|e| match *e.internal() {
+                Internal::Function(i) => Some(i),
+                _ => None,
+            })
+            .next()?;
+
+        let (key, reads) = self.key(module, idx, &mut HashSet::new())?;
+        let mut hasher = Fnv::default();
+        key.hash(&mut hasher);
+        if reads.memory {
+            self.data(module).hash(&mut hasher);
+        }
+        if reads.table {
+            self.elements(module).hash(&mut hasher);
+        }
+        Some(format!("{:016x}", hasher.finish()))
+    }
+
+    fn key(&mut self, module: &Module, idx: u32, stack: &mut HashSet<u32>) -> Option<(u64, Reads)> {
+        if let Some(name) = self.imports.get(idx as usize) {
+            return Some((hash_u64(name), Reads::default()));
+        }
+        if let Some(key) = self.keys.get(&idx) {
+            return Some(*key);
+        }
+        let local = idx as usize - self.imports.len();
+        let body = module.code_section()?.bodies().get(local)?;
+        let ty = module.function_section()?.entries().get(local)?.type_ref();
+        let ty = match *module.type_section()?.types().get(ty as usize)? {
+            Type::Function(ref f) => f.clone(),
+        };
+
+        let mut own = Vec::new();
+        let mut callees = Vec::new();
+        let mut reads = Reads::default();
+        ty.serialize(&mut own).ok()?;
+        for l in body.locals() {
+            l.clone().serialize(&mut own).ok()?;
+        }
+        for instr in body.code().elements() {
+            let instr = match *instr {
+                Instruction::Call(f) => {
+                    callees.push(f);
+                    Instruction::Call(0)
+                }
+                Instruction::CallIndirect(..) => {
+                    reads.table = true;
+                    instr.clone()
+                }
+                ref i => {
+                    reads.memory |= reads_memory(i);
+                    i.clone()
+                }
+            };
+            instr.serialize(&mut own).ok()?;
+        }
+
+        let mut hasher = Fnv::default();
+        own.hash(&mut hasher);
+        stack.insert(idx);
+        for f in callees {
+            // Recursive calls are covered by the key of the function being
+            // called, which is already being computed.
+            if stack.contains(&f) {
+                f.hash(&mut hasher);
+                continue;
+            }
+            let (key, callee_reads) = self.key(module, f, stack)?;
+            key.hash(&mut hasher);
+            reads.memory |= callee_reads.memory;
+            reads.table |= callee_reads.table;
+        }
+        stack.remove(&idx);
+        let ret = (hasher.finish(), reads);
+        self.keys.insert(idx, ret);
+        Some(ret)
+    }
+
+    fn data(&mut self, module: &Module) -> u64 {
+        if self.data.is_none() {
+            let mut bytes = Vec::new();
+            if let Some(s) = module.data_section() {
+                for d in s.entries() {
+                    drop(d.clone().serialize(&mut bytes));
+                }
+            }
+            self.data = Some(hash_u64(&bytes));
+        }
+        self.data.unwrap()
+    }
+
+    fn elements(&mut self, module: &Module) -> u64 {
+        if self.elements.is_none() {
+            let mut bytes = Vec::new();
+            if let Some(s) = module.elements_section() {
+                for e in s.entries() {
+                    drop(e.clone().serialize(&mut bytes));
+                }
+            }
+            self.elements = Some(hash_u64(&bytes));
+        }
+        self.elements.unwrap()
+    }
+}
+
+/// The 64-bit FNV-1a hash.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+fn hash_u64<T: Hash + ?Sized>(t: &T) -> u64 {
+    let mut hasher = Fnv::default();
+    t.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn reads_memory(i: &Instruction) -> bool {
+    match *i {
+        Instruction::I32Load(..)
+        | Instruction::I64Load(..)
+        | Instruction::F32Load(..)
+        | Instruction::F64Load(..)
+        | Instruction::I32Load8S(..)
+        | Instruction::I32Load8U(..)
+        | Instruction::I32Load16S(..)
+        | Instruction::I32Load16U(..)
+        | Instruction::I64Load8S(..)
+        | Instruction::I64Load8U(..)
+        | Instruction::I64Load16S(..)
+        | Instruction::I64Load16U(..)
+        | Instruction::I64Load32S(..)
+        | Instruction::I64Load32U(..) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,29 @@
+//! Decoding of the programs embedded by `#[wasm_bindgen]` in wasm files
+//!
+//! Programs used to be embedded as JSON, which is still read for older
+//! schema versions, and are now encoded in the binary format of
+//! `shared::binary`.
+
+use failure::Error;
+use serde_json;
+use shared;
+
+/// Decodes the versions of the program `data`, which are readable even if
+/// the rest of it isn't.
+pub fn versions(data: &[u8]) -> Result<shared::ProgramOnlySchema, Error> {
+    if shared::binary::is_binary(data) {
+        Ok(shared::binary::decode_versions(data)?)
+    } else {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// Decodes the program `data`, whose schema version is compatible with this
+/// binary.
+pub fn program(data: &[u8]) -> Result<shared::Program, Error> {
+    if shared::binary::is_binary(data) {
+        Ok(shared::binary::decode(data)?)
+    } else {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
@@ -1,4 +1,7 @@
 use std::char;
+use std::fmt;
+
+use failure::{Error, ResultExt};
 
 macro_rules! tys {
     ($($a:ident)*) => (tys! { @ ($($a)*) 0 });
@@ -97,14 +100,22 @@ pub enum VectorKind {
 }
 
 impl Descriptor {
-    pub fn decode(mut data: &[u32]) -> Descriptor {
-        let descriptor = Descriptor::_decode(&mut data);
-        assert!(data.is_empty(), "remaining data {:?}", data);
-        descriptor
+    pub fn decode(data: &[u32]) -> Result<Descriptor, Error> {
+        let mut rest = data;
+        let result = Descriptor::_decode(&mut rest).and_then(|descriptor| {
+            if !rest.is_empty() {
+                bail!("{} words left over after the descriptor", rest.len());
+            }
+            Ok(descriptor)
+        });
+        let decoded = &data[..data.len() - rest.len()];
+        Ok(result.with_context(|_| {
+            format!("malformed descriptor {:?}, decoded {:?} so far", data, decoded)
+        })?)
     }
 
-    fn _decode(data: &mut &[u32]) -> Descriptor {
-        match get(data) {
+    fn _decode(data: &mut &[u32]) -> Result<Descriptor, Error> {
+        Ok(match get(data)? {
             I8 => Descriptor::I8,
             I16 => Descriptor::I16,
             I32 => Descriptor::I32,
@@ -116,27 +127,33 @@ impl Descriptor {
             F32 => Descriptor::F32,
             F64 => Descriptor::F64,
             BOOLEAN => Descriptor::Boolean,
-            FUNCTION => Descriptor::Function(Box::new(Function::decode(data))),
-            CLOSURE => Descriptor::Closure(Box::new(Closure::decode(data))),
-            REF => Descriptor::Ref(Box::new(Descriptor::_decode(data))),
-            REFMUT => Descriptor::RefMut(Box::new(Descriptor::_decode(data))),
-            SLICE => Descriptor::Slice(Box::new(Descriptor::_decode(data))),
-            VECTOR => Descriptor::Vector(Box::new(Descriptor::_decode(data))),
-            OPTIONAL => Descriptor::Option(Box::new(Descriptor::_decode(data))),
+            FUNCTION => Descriptor::Function(Box::new(Function::decode(data)?)),
+            CLOSURE => Descriptor::Closure(Box::new(Closure::decode(data)?)),
+            REF => Descriptor::Ref(Box::new(Descriptor::_decode(data)?)),
+            REFMUT => Descriptor::RefMut(Box::new(Descriptor::_decode(data)?)),
+            SLICE => Descriptor::Slice(Box::new(Descriptor::_decode(data)?)),
+            VECTOR => Descriptor::Vector(Box::new(Descriptor::_decode(data)?)),
+            OPTIONAL => Descriptor::Option(Box::new(Descriptor::_decode(data)?)),
             STRING => Descriptor::String,
             ANYREF => Descriptor::Anyref,
             ENUM => Descriptor::Enum,
             RUST_STRUCT => {
-                let name = (0..get(data))
-                    .map(|_| char::from_u32(get(data)).unwrap())
-                    .collect();
+                let name = (0..get(data)?)
+                    .map(|_| {
+                        let c = get(data)?;
+                        match char::from_u32(c) {
+                            Some(c) => Ok(c),
+                            None => bail!("invalid character {} in a struct name", c),
+                        }
+                    })
+                    .collect::<Result<_, Error>>()?;
                 Descriptor::RustStruct(name)
             }
             CHAR => Descriptor::Char,
             UNIT => Descriptor::Unit,
-            CLAMPED => Descriptor::Clamped(Box::new(Descriptor::_decode(data))),
-            other => panic!("unknown descriptor: {}", other),
-        }
+            CLAMPED => Descriptor::Clamped(Box::new(Descriptor::_decode(data)?)),
+            other => bail!("unknown descriptor: {}", other),
+        })
     }
 
     pub fn unwrap_function(&self) -> &Function {
@@ -293,32 +310,92 @@ impl Descriptor {
     }
 }
 
-fn get(a: &mut &[u32]) -> u32 {
-    let ret = a[0];
-    *a = &a[1..];
-    ret
+/// Renders descriptors with Rust-like syntax, like `fn(&str) -> Option<u32>`.
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Descriptor::I8 => f.write_str("i8"),
+            Descriptor::U8 => f.write_str("u8"),
+            Descriptor::I16 => f.write_str("i16"),
+            Descriptor::U16 => f.write_str("u16"),
+            Descriptor::I32 => f.write_str("i32"),
+            Descriptor::U32 => f.write_str("u32"),
+            Descriptor::I64 => f.write_str("i64"),
+            Descriptor::U64 => f.write_str("u64"),
+            Descriptor::F32 => f.write_str("f32"),
+            Descriptor::F64 => f.write_str("f64"),
+            Descriptor::Boolean => f.write_str("bool"),
+            Descriptor::Function(ref func) => write!(f, "fn{}", func),
+            Descriptor::Closure(ref closure) => {
+                let kind = if closure.mutable { "FnMut" } else { "Fn" };
+                write!(f, "Closure<{}{}>", kind, closure.function)
+            }
+            Descriptor::Ref(ref d) => write!(f, "&{}", d),
+            Descriptor::RefMut(ref d) => write!(f, "&mut {}", d),
+            Descriptor::Slice(ref d) => write!(f, "[{}]", d),
+            Descriptor::Vector(ref d) => write!(f, "Vec<{}>", d),
+            Descriptor::String => f.write_str("String"),
+            Descriptor::Anyref => f.write_str("JsValue"),
+            Descriptor::Enum => f.write_str("enum"),
+            Descriptor::RustStruct(ref name) => f.write_str(name),
+            Descriptor::Char => f.write_str("char"),
+            Descriptor::Option(ref d) => write!(f, "Option<{}>", d),
+            Descriptor::Unit => f.write_str("()"),
+            Descriptor::Clamped(ref d) => write!(f, "Clamped<{}>", d),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("(")?;
+        for (i, arg) in self.arguments.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", arg)?;
+        }
+        f.write_str(")")?;
+        match self.ret {
+            Descriptor::Unit => Ok(()),
+            ref ret => write!(f, " -> {}", ret),
+        }
+    }
+}
+
+fn get(a: &mut &[u32]) -> Result<u32, Error> {
+    match a.split_first() {
+        Some((&ret, rest)) => {
+            *a = rest;
+            Ok(ret)
+        }
+        None => bail!("unexpected end of descriptor"),
+    }
 }
 
 impl Closure {
-    fn decode(data: &mut &[u32]) -> Closure {
-        let mutable = get(data) == REFMUT;
-        assert_eq!(get(data), FUNCTION);
-        Closure {
-            mutable,
-            function: Function::decode(data),
+    fn decode(data: &mut &[u32]) -> Result<Closure, Error> {
+        let mutable = get(data)? == REFMUT;
+        match get(data)? {
+            FUNCTION => {}
+            other => bail!("expected a closure's function, found {}", other),
         }
+        Ok(Closure {
+            mutable,
+            function: Function::decode(data)?,
+        })
     }
 }
 
 impl Function {
-    fn decode(data: &mut &[u32]) -> Function {
-        let arguments = (0..get(data))
+    fn decode(data: &mut &[u32]) -> Result<Function, Error> {
+        let arguments = (0..get(data)?)
             .map(|_| Descriptor::_decode(data))
-            .collect::<Vec<_>>();
-        Function {
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Function {
             arguments,
-            ret: Descriptor::_decode(data),
-        }
+            ret: Descriptor::_decode(data)?,
+        })
     }
 }
 
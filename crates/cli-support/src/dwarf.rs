@@ -0,0 +1,1240 @@
+//! Keeping DWARF debug info correct through bindgen's transformations
+//!
+//! DWARF in wasm refers to code by its byte offset in the payload of the code
+//! section. By the time we emit a module those offsets have all changed:
+//! `wasm-gc` removes functions, closure rewriting renumbers calls and every
+//! function body is re-encoded. To keep `--keep-debug` useful we:
+//!
+//! * record the offset of every instruction of the input module's function
+//!   bodies and temporarily name each function after its position in the
+//!   input, which the name section carries through all index remapping;
+//! * recover from the names which input function each output function came
+//!   from, restoring the original names;
+//! * rewrite the addresses in the DWARF sections of the output, mapping each
+//!   instruction of an input function to the same instruction of its output
+//!   function. Anything referring to removed code is tombstoned the same way
+//!   `wasm-ld` does it.
+//!
+//! Line tables of any DWARF version are rewritten, as are addresses in
+//! `.debug_info`, `.debug_ranges`, `.debug_loc` and `.debug_aranges` of
+//! DWARF 2 through 4. Only 32-bit DWARF is supported, which is all LLVM emits
+//! for wasm.
+
+use std::cmp::{self, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use failure::Error;
+use parity_wasm::elements::*;
+use rustc_demangle;
+
+/// Prefix of the names temporarily given to all functions.
+const TAG: &str = "__wbindgen_debug_func";
+
+/// Addresses of removed code, matching what `wasm-ld` writes for code it
+/// discards. Range and location lists use a different value as `-1` already
+/// means a base address selection there.
+const TOMBSTONE: u32 = 0xffff_ffff;
+const TOMBSTONE_RANGE: u32 = 0xffff_fffe;
+
+pub struct DebugInfo {
+    /// Layout of each function body of the input module.
+    input: Vec<Body>,
+    /// Original names of the input module's functions, by their position in
+    /// the code section.
+    names: HashMap<u32, String>,
+    /// Whether the input module had a name section at all.
+    had_names: bool,
+    /// For each function body of the output module, the input function body
+    /// it came from if any.
+    origins: Vec<Option<u32>>,
+}
+
+/// Offsets of a function body, relative to the payload of the code section.
+struct Body {
+    /// The start of the body, right after its size.
+    start: u32,
+    /// The start of each instruction.
+    instructions: Vec<u32>,
+    /// The end of the body.
+    end: u32,
+}
+
+impl DebugInfo {
+    /// Prepares `module`, parsed from `bytes`, for its DWARF to be rewritten
+    /// once bindings are generated. Returns `None` if there's no DWARF.
+    pub fn new(bytes: &[u8], module: &mut Module) -> Result<Option<DebugInfo>, Error> {
+        let has_dwarf = module.sections().iter().any(|s| match *s {
+            Section::Custom(ref s) => s.name().starts_with(".debug_"),
+            _ => false,
+        });
+        if !has_dwarf {
+            return Ok(None);
+        }
+        let input = match sections(bytes)?.into_iter().find(|s| s.id == 10) {
+            Some(code) => bodies(&bytes[code.payload.0..code.payload.1])?,
+            None => return Ok(None),
+        };
+
+        let parsed = mem::replace(module, Module::default());
+        *module = parsed.parse_names().unwrap_or_else(|p| p.1);
+        let imports = module.import_count(ImportCountType::Function) as u32;
+
+        let mut names = HashMap::new();
+        let mut had_names = false;
+        let mut tags = NameMap::with_capacity(input.len());
+        for section in module.sections_mut() {
+            let map = match *section {
+                Section::Name(NameSection::Function(ref mut f)) => f.names_mut(),
+                _ => continue,
+            };
+            had_names = true;
+            for (idx, name) in mem::replace(map, NameMap::with_capacity(0)) {
+                if idx < imports {
+                    tags.insert(idx, name);
+                } else {
+                    names.insert(idx - imports, name);
+                }
+            }
+        }
+        for i in 0..input.len() as u32 {
+            tags.insert(imports + i, format!("{}{}", TAG, i));
+        }
+        module.sections_mut().retain(|s| match *s {
+            Section::Name(NameSection::Function(_)) => false,
+            _ => true,
+        });
+        let mut section = FunctionNameSection::default();
+        *section.names_mut() = tags;
+        module.sections_mut().push(Section::Name(NameSection::Function(section)));
+
+        Ok(Some(DebugInfo {
+            input,
+            names,
+            had_names,
+            origins: Vec::new(),
+        }))
+    }
+
+    /// Recovers which input function each function of the final `module`
+    /// came from, restoring the names changed by `new`.
+    pub fn finish(&mut self, module: &mut Module, demangle: bool) {
+        let imports = module.import_count(ImportCountType::Function) as u32;
+        let bodies = module.code_section().map(|s| s.bodies().len()).unwrap_or(0);
+        self.origins = vec![None; bodies];
+
+        for section in module.sections_mut() {
+            let map = match *section {
+                Section::Name(NameSection::Function(ref mut f)) => f.names_mut(),
+                _ => continue,
+            };
+            let names = mem::replace(map, NameMap::with_capacity(0));
+            for (idx, name) in names {
+                let origin = if name.starts_with(TAG) {
+                    name[TAG.len()..].parse::<u32>().ok()
+                } else {
+                    None
+                };
+                let origin = match origin {
+                    Some(origin) => origin,
+                    None => {
+                        map.insert(idx, name);
+                        continue;
+                    }
+                };
+                if let Some(slot) = idx.checked_sub(imports).and_then(|i| self.origins.get_mut(i as usize)) {
+                    *slot = Some(origin);
+                }
+                if let Some(name) = self.names.get(&origin) {
+                    let name = if demangle {
+                        rustc_demangle::demangle(name).to_string()
+                    } else {
+                        name.clone()
+                    };
+                    map.insert(idx, name);
+                }
+            }
+        }
+
+        if !self.had_names {
+            module.sections_mut().retain(|s| match *s {
+                Section::Name(NameSection::Function(ref f)) => !f.names().is_empty(),
+                _ => true,
+            });
+        }
+    }
+
+    /// Rewrites the DWARF sections of the encoded output module `bytes` to
+    /// refer to its code.
+    pub fn rewrite(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let sections = sections(bytes)?;
+        let output = match sections.iter().find(|s| s.id == 10) {
+            Some(code) => bodies(&bytes[code.payload.0..code.payload.1])?,
+            None => bail!("wasm module with debug info has no code section"),
+        };
+        let mut outputs = vec![None; self.input.len()];
+        for (i, origin) in self.origins.iter().enumerate() {
+            if let Some(origin) = *origin {
+                let slot = match outputs.get_mut(origin as usize) {
+                    Some(slot) => slot,
+                    None => continue,
+                };
+                *slot = Some(i);
+                // Instructions are mapped one to one, which only works if
+                // bindgen kept their number the same.
+                let (before, after) = (&self.input[origin as usize], &output[i]);
+                if before.instructions.len() != after.instructions.len() {
+                    bail!(
+                        "cannot keep debug info of function {} whose body \
+                         changed from {} to {} instructions",
+                        self.names
+                            .get(&origin)
+                            .cloned()
+                            .unwrap_or_else(|| format!("{}{}", TAG, origin)),
+                        before.instructions.len(),
+                        after.instructions.len(),
+                    );
+                }
+            }
+        }
+        let map = AddressMap {
+            input: &self.input,
+            output: &output,
+            outputs,
+        };
+
+        let mut debug = HashMap::new();
+        for section in sections.iter() {
+            if let Some(ref name) = section.name {
+                if name.starts_with(".debug_") {
+                    debug.insert(&name[..], bytes[section.payload.0..section.payload.1].to_vec());
+                }
+            }
+        }
+
+        let mut line_offsets = HashMap::new();
+        if let Some(line) = debug.get_mut(".debug_line") {
+            let (rewritten, offsets) = rewrite_line(line, &map)?;
+            *line = rewritten;
+            line_offsets = offsets;
+        }
+        let mut ranges = debug.remove(".debug_ranges");
+        let mut loc = debug.remove(".debug_loc");
+        if let (Some(mut info), Some(abbrev)) = (debug.remove(".debug_info"), debug.get(".debug_abbrev")) {
+            let mut lists = Lists {
+                ranges: ranges.as_mut().map(|s| &mut s[..]),
+                loc: loc.as_mut().map(|s| &mut s[..]),
+                seen_ranges: HashSet::new(),
+                seen_loc: HashSet::new(),
+            };
+            rewrite_info(&mut info, abbrev, &line_offsets, &map, &mut lists)?;
+            debug.insert(".debug_info", info);
+        }
+        if let Some(ranges) = ranges {
+            debug.insert(".debug_ranges", ranges);
+        }
+        if let Some(loc) = loc {
+            debug.insert(".debug_loc", loc);
+        }
+        if let Some(aranges) = debug.get_mut(".debug_aranges") {
+            rewrite_aranges(aranges, &map)?;
+        }
+
+        let mut dst = bytes[..8].to_vec();
+        for section in sections.iter() {
+            let payload = &bytes[section.payload.0..section.payload.1];
+            let name = match section.name {
+                Some(ref name) => name,
+                None => {
+                    dst.extend_from_slice(&bytes[section.start..section.payload.1]);
+                    continue;
+                }
+            };
+            let payload = debug.get(&name[..]).map(|s| &s[..]).unwrap_or(payload);
+            let mut contents = Vec::new();
+            leb(&mut contents, name.len() as u64);
+            contents.extend_from_slice(name.as_bytes());
+            contents.extend_from_slice(payload);
+            dst.push(0);
+            leb(&mut dst, contents.len() as u64);
+            dst.extend_from_slice(&contents);
+        }
+        Ok(dst)
+    }
+}
+
+/// Maps addresses of the input module's code to the output's.
+struct AddressMap<'a> {
+    input: &'a [Body],
+    output: &'a [Body],
+    /// For each input function body, the output body it ended up as.
+    outputs: Vec<Option<usize>>,
+}
+
+impl<'a> AddressMap<'a> {
+    /// Maps the address `addr`, which is either the start of an instruction
+    /// or the end of a range, returning `None` if it refers to removed code.
+    fn map(&self, addr: u32) -> Option<u32> {
+        // No code lives at zero, it's instead used as the base address of
+        // units spanning many functions.
+        if addr == 0 {
+            return Some(0);
+        }
+        let i = self
+            .input
+            .binary_search_by(|b| {
+                if b.end < addr {
+                    Ordering::Less
+                } else if b.start > addr {
+                    Ordering::Greater
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .ok()?;
+        let input = &self.input[i];
+        let output = &self.output[self.outputs[i]?];
+
+        if addr >= input.end {
+            return Some(output.end);
+        }
+        if addr < input.instructions[0] {
+            let first = output.instructions[0];
+            return Some(cmp::min(output.start + (addr - input.start), first));
+        }
+        let k = match input.instructions.binary_search(&addr) {
+            Ok(k) => k,
+            Err(k) => k - 1,
+        };
+        let k = cmp::min(k, output.instructions.len() - 1);
+        let base = output.instructions[k];
+        let next = output.instructions.get(k + 1).cloned().unwrap_or(output.end);
+        Some(cmp::min(base + (addr - input.instructions[k]), cmp::max(base, next - 1)))
+    }
+}
+
+struct RawSection {
+    id: u8,
+    name: Option<String>,
+    /// Offset of the section's id.
+    start: usize,
+    /// Range of the section's payload, excluding the name of custom sections.
+    payload: (usize, usize),
+}
+
+fn sections(bytes: &[u8]) -> Result<Vec<RawSection>, Error> {
+    let mut ret = Vec::new();
+    let mut r = Reader::new(bytes, 8);
+    while !r.done() {
+        let start = r.pos;
+        let id = r.u8()?;
+        let size = r.uleb()? as usize;
+        let end = r.pos + size;
+        if end > bytes.len() {
+            bail!("malformed wasm section");
+        }
+        let name = if id == 0 {
+            let len = r.uleb()? as usize;
+            let name = String::from_utf8_lossy(r.bytes(len)?).into_owned();
+            Some(name)
+        } else {
+            None
+        };
+        ret.push(RawSection {
+            id,
+            name,
+            start,
+            payload: (r.pos, end),
+        });
+        r.pos = end;
+    }
+    Ok(ret)
+}
+
+/// Finds the offset of every instruction in the payload of a code section.
+fn bodies(code: &[u8]) -> Result<Vec<Body>, Error> {
+    let mut r = Reader::new(code, 0);
+    let count = r.uleb()?;
+    let mut ret = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let size = r.uleb()? as usize;
+        let start = r.pos;
+        let end = start + size;
+        let locals = r.uleb()?;
+        for _ in 0..locals {
+            r.uleb()?;
+            r.u8()?;
+        }
+        let mut instructions = Vec::new();
+        while r.pos < end {
+            instructions.push(r.pos as u32);
+            skip_instruction(&mut r)?;
+        }
+        if r.pos != end || instructions.is_empty() {
+            bail!("malformed function body in code section");
+        }
+        ret.push(Body {
+            start: start as u32,
+            instructions,
+            end: end as u32,
+        });
+    }
+    Ok(ret)
+}
+
+fn skip_instruction(r: &mut Reader) -> Result<(), Error> {
+    match r.u8()? {
+        // block, loop, if: a block type
+        0x02 | 0x03 | 0x04 => {
+            r.u8()?;
+        }
+        // br, br_if, call, local and global accesses, table.get/set,
+        // ref.func
+        0x0c | 0x0d | 0x10 | 0x20..=0x26 | 0xd2 => {
+            r.uleb()?;
+        }
+        // br_table
+        0x0e => {
+            let n = r.uleb()?;
+            for _ in 0..n + 1 {
+                r.uleb()?;
+            }
+        }
+        // call_indirect
+        0x11 => {
+            r.uleb()?;
+            r.uleb()?;
+        }
+        // typed select
+        0x1c => {
+            let n = r.uleb()?;
+            r.bytes(n as usize)?;
+        }
+        // loads and stores
+        0x28..=0x3e => {
+            r.uleb()?;
+            r.uleb()?;
+        }
+        // memory.size, memory.grow, ref.null
+        0x3f | 0x40 | 0xd0 => {
+            r.u8()?;
+        }
+        0x41 | 0x42 => {
+            r.sleb()?;
+        }
+        0x43 => {
+            r.bytes(4)?;
+        }
+        0x44 => {
+            r.bytes(8)?;
+        }
+        0xfc => match r.uleb()? {
+            0..=7 => {}
+            // memory.init, table.init, table.copy
+            8 | 12 | 14 => {
+                r.uleb()?;
+                r.uleb()?;
+            }
+            // data.drop, elem.drop, table.grow/size/fill
+            9 | 13 | 15 | 16 | 17 => {
+                r.uleb()?;
+            }
+            // memory.copy
+            10 => {
+                r.bytes(2)?;
+            }
+            // memory.fill
+            11 => {
+                r.u8()?;
+            }
+            op => bail!("unknown instruction 0xfc {} in code section", op),
+        },
+        0xfe => match r.uleb()? {
+            // atomic.fence
+            3 => {
+                r.u8()?;
+            }
+            _ => {
+                r.uleb()?;
+                r.uleb()?;
+            }
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+struct LineHeader {
+    min_inst_len: u8,
+    default_is_stmt: bool,
+    line_base: i8,
+    line_range: u8,
+    opcode_base: u8,
+    standard_opcode_lengths: Vec<u8>,
+}
+
+#[derive(Clone)]
+struct Row {
+    address: u32,
+    file: u64,
+    line: u64,
+    column: u64,
+    is_stmt: bool,
+    basic_block: bool,
+    end_sequence: bool,
+    prologue_end: bool,
+    epilogue_begin: bool,
+    isa: u64,
+    discriminator: u64,
+}
+
+impl Row {
+    fn new(header: &LineHeader) -> Row {
+        Row {
+            address: 0,
+            file: 1,
+            line: 1,
+            column: 0,
+            is_stmt: header.default_is_stmt,
+            basic_block: false,
+            end_sequence: false,
+            prologue_end: false,
+            epilogue_begin: false,
+            isa: 0,
+            discriminator: 0,
+        }
+    }
+
+    /// Advances the address, wrapping around like the address of sequences
+    /// `wasm-ld` tombstoned with `-1` does.
+    fn advance(&mut self, delta: u64) {
+        self.address = self.address.wrapping_add(delta as u32);
+    }
+}
+
+/// A line program, broken down into the rows it produces and opcodes which
+/// don't affect rows and are passed through as is.
+enum LineOp<'a> {
+    Row(Row),
+    Raw(&'a [u8]),
+}
+
+/// Rewrites all line programs, returning the new section along with a map
+/// from the old offset of each program to its new offset.
+fn rewrite_line(data: &[u8], map: &AddressMap) -> Result<(Vec<u8>, HashMap<u32, u32>), Error> {
+    let mut dst = Vec::new();
+    let mut offsets = HashMap::new();
+    let mut r = Reader::new(data, 0);
+    while !r.done() {
+        let start = r.pos;
+        let end = unit_end(&mut r)?;
+        let version = r.u16()?;
+        if version >= 5 {
+            let address_size = r.u8()?;
+            if address_size != 4 {
+                bail!("only 32-bit addresses are supported in DWARF line tables");
+            }
+            r.u8()?;
+        }
+        let header_length = r.u32()? as usize;
+        let program_start = r.pos + header_length;
+        let min_inst_len = r.u8()?;
+        if version >= 4 {
+            r.u8()?;
+        }
+        let default_is_stmt = r.u8()? != 0;
+        let line_base = r.u8()? as i8;
+        let line_range = r.u8()?;
+        let opcode_base = r.u8()?;
+        let mut standard_opcode_lengths = Vec::new();
+        for _ in 1..opcode_base {
+            standard_opcode_lengths.push(r.u8()?);
+        }
+        let header = LineHeader {
+            min_inst_len,
+            default_is_stmt,
+            line_base,
+            line_range,
+            opcode_base,
+            standard_opcode_lengths,
+        };
+
+        let ops = parse_line_program(&data[program_start..end], &header)?;
+        let program = encode_line_program(&ops, &header, map);
+        offsets.insert(start as u32, dst.len() as u32);
+        let unit_header = &data[start + 4..program_start];
+        push_u32(&mut dst, (unit_header.len() + program.len()) as u32);
+        dst.extend_from_slice(unit_header);
+        dst.extend_from_slice(&program);
+        r.pos = end;
+    }
+    Ok((dst, offsets))
+}
+
+fn parse_line_program<'a>(data: &'a [u8], header: &LineHeader) -> Result<Vec<LineOp<'a>>, Error> {
+    if header.line_range == 0 {
+        bail!("invalid DWARF line table header");
+    }
+    let mut ops = Vec::new();
+    let mut row = Row::new(header);
+    let mut r = Reader::new(data, 0);
+    let min_inst_len = header.min_inst_len as u64;
+    while !r.done() {
+        let start = r.pos;
+        let opcode = r.u8()?;
+        if opcode >= header.opcode_base {
+            let adjusted = (opcode - header.opcode_base) as u64;
+            row.advance(adjusted / header.line_range as u64 * min_inst_len);
+            let delta = header.line_base as i64 + (adjusted % header.line_range as u64) as i64;
+            row.line = (row.line as i64 + delta) as u64;
+            ops.push(LineOp::Row(row.clone()));
+            row.basic_block = false;
+            row.prologue_end = false;
+            row.epilogue_begin = false;
+            row.discriminator = 0;
+            continue;
+        }
+        match opcode {
+            0 => {
+                let len = r.uleb()? as usize;
+                let end = r.pos + len;
+                match r.u8()? {
+                    // DW_LNE_end_sequence
+                    1 => {
+                        row.end_sequence = true;
+                        ops.push(LineOp::Row(row.clone()));
+                        row = Row::new(header);
+                    }
+                    // DW_LNE_set_address
+                    2 => {
+                        if len != 5 {
+                            bail!("only 32-bit addresses are supported in DWARF line tables");
+                        }
+                        row.address = r.u32()?;
+                    }
+                    // DW_LNE_set_discriminator
+                    4 => row.discriminator = r.uleb()?,
+                    _ => ops.push(LineOp::Raw(r.slice(start, end)?)),
+                }
+                r.pos = end;
+            }
+            // DW_LNS_copy
+            1 => {
+                ops.push(LineOp::Row(row.clone()));
+                row.basic_block = false;
+                row.prologue_end = false;
+                row.epilogue_begin = false;
+                row.discriminator = 0;
+            }
+            // DW_LNS_advance_pc
+            2 => row.advance(r.uleb()? * min_inst_len),
+            // DW_LNS_advance_line
+            3 => row.line = (row.line as i64 + r.sleb()?) as u64,
+            // DW_LNS_set_file
+            4 => row.file = r.uleb()?,
+            // DW_LNS_set_column
+            5 => row.column = r.uleb()?,
+            // DW_LNS_negate_stmt
+            6 => row.is_stmt = !row.is_stmt,
+            // DW_LNS_set_basic_block
+            7 => row.basic_block = true,
+            // DW_LNS_const_add_pc
+            8 => {
+                let adjusted = (255 - header.opcode_base) as u64;
+                row.advance(adjusted / header.line_range as u64 * min_inst_len);
+            }
+            // DW_LNS_fixed_advance_pc
+            9 => row.advance(r.u16()? as u64),
+            // DW_LNS_set_prologue_end
+            10 => row.prologue_end = true,
+            // DW_LNS_set_epilogue_begin
+            11 => row.epilogue_begin = true,
+            // DW_LNS_set_isa
+            12 => row.isa = r.uleb()?,
+            _ => {
+                for _ in 0..header.standard_opcode_lengths[opcode as usize - 1] {
+                    r.uleb()?;
+                }
+            }
+        }
+    }
+    Ok(ops)
+}
+
+/// Encodes the rows of `ops` at their new addresses, dropping sequences of
+/// removed code.
+fn encode_line_program(ops: &[LineOp], header: &LineHeader, map: &AddressMap) -> Vec<u8> {
+    let mut dst = Vec::new();
+    let mut state = Row::new(header);
+    let mut address = None;
+    let mut last_end = None;
+    for op in ops {
+        let row = match *op {
+            LineOp::Raw(bytes) => {
+                dst.extend_from_slice(bytes);
+                continue;
+            }
+            LineOp::Row(ref row) => row,
+        };
+
+        let new_address = match map.map(row.address) {
+            Some(a) if !row.end_sequence => {
+                last_end = map.map(function_end(map, row.address));
+                a
+            }
+            // The end of a sequence may be past the last code we kept.
+            Some(a) => a,
+            None if row.end_sequence && address.is_some() => last_end.unwrap_or(0),
+            None => {
+                if row.end_sequence {
+                    state = Row::new(header);
+                    address = None;
+                }
+                continue;
+            }
+        };
+        if row.end_sequence && address.is_none() {
+            continue;
+        }
+
+        match address {
+            Some(cur) if new_address >= cur && header.min_inst_len == 1 => {
+                if new_address > cur {
+                    dst.push(2);
+                    leb(&mut dst, (new_address - cur) as u64);
+                }
+            }
+            _ => {
+                dst.extend_from_slice(&[0, 5, 2]);
+                push_u32(&mut dst, new_address);
+            }
+        }
+        address = Some(new_address);
+
+        if row.end_sequence {
+            dst.extend_from_slice(&[0, 1, 1]);
+            state = Row::new(header);
+            address = None;
+            continue;
+        }
+        if row.file != state.file {
+            dst.push(4);
+            leb(&mut dst, row.file);
+        }
+        if row.column != state.column {
+            dst.push(5);
+            leb(&mut dst, row.column);
+        }
+        if row.line != state.line {
+            dst.push(3);
+            sleb(&mut dst, row.line as i64 - state.line as i64);
+        }
+        if row.is_stmt != state.is_stmt {
+            dst.push(6);
+        }
+        if row.basic_block {
+            dst.push(7);
+        }
+        if row.prologue_end && header.opcode_base > 10 {
+            dst.push(10);
+        }
+        if row.epilogue_begin && header.opcode_base > 11 {
+            dst.push(11);
+        }
+        if row.isa != state.isa && header.opcode_base > 12 {
+            dst.push(12);
+            leb(&mut dst, row.isa);
+        }
+        if row.discriminator != 0 {
+            let mut op = vec![4];
+            leb(&mut op, row.discriminator);
+            dst.push(0);
+            leb(&mut dst, op.len() as u64);
+            dst.extend_from_slice(&op);
+        }
+        dst.push(1);
+        state = row.clone();
+    }
+    dst
+}
+
+/// Returns the end of the input function containing `addr`.
+fn function_end(map: &AddressMap, addr: u32) -> u32 {
+    map.input
+        .iter()
+        .find(|b| b.start <= addr && addr <= b.end)
+        .map(|b| b.end)
+        .unwrap_or(addr)
+}
+
+/// Range and location lists referenced from `.debug_info`, each rewritten
+/// once.
+struct Lists<'a> {
+    ranges: Option<&'a mut [u8]>,
+    loc: Option<&'a mut [u8]>,
+    seen_ranges: HashSet<u32>,
+    seen_loc: HashSet<u32>,
+}
+
+/// The attributes of an abbreviation, as `(name, form)` pairs.
+struct Abbrev {
+    attrs: Vec<(u64, u64)>,
+}
+
+const DW_AT_LOCATION: u64 = 0x02;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_AT_FRAME_BASE: u64 = 0x40;
+const DW_AT_ENTRY_PC: u64 = 0x52;
+const DW_AT_RANGES: u64 = 0x55;
+
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_INDIRECT: u64 = 0x16;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+const DW_FORM_IMPLICIT_CONST: u64 = 0x21;
+
+fn rewrite_info(
+    info: &mut [u8],
+    abbrev: &[u8],
+    line_offsets: &HashMap<u32, u32>,
+    map: &AddressMap,
+    lists: &mut Lists,
+) -> Result<(), Error> {
+    let mut patches = Vec::new();
+    {
+        let mut r = Reader::new(info, 0);
+        while !r.done() {
+            let end = unit_end(&mut r)?;
+            let version = r.u16()?;
+            if version >= 5 {
+                // DWARF 5 refers to addresses through `.debug_addr` and new
+                // kinds of lists which aren't supported yet.
+                r.pos = end;
+                continue;
+            }
+            let abbrevs = parse_abbrevs(abbrev, r.u32()? as usize)?;
+            if r.u8()? != 4 {
+                bail!("only 32-bit addresses are supported in DWARF");
+            }
+            let mut base = None;
+            while r.pos < end {
+                let code = r.uleb()?;
+                if code == 0 {
+                    continue;
+                }
+                let abbrev = match abbrevs.get(&code) {
+                    Some(a) => a,
+                    None => bail!("invalid DWARF abbreviation code {}", code),
+                };
+                let mut low_pc = None;
+                let mut high_pc = None;
+                for &(name, form) in abbrev.attrs.iter() {
+                    let mut form = form;
+                    if form == DW_FORM_INDIRECT {
+                        form = r.uleb()?;
+                    }
+                    let pos = r.pos;
+                    match (name, form) {
+                        (DW_AT_LOW_PC, DW_FORM_ADDR) | (DW_AT_ENTRY_PC, DW_FORM_ADDR) => {
+                            let old = r.u32()?;
+                            if name == DW_AT_LOW_PC {
+                                low_pc = Some(old);
+                            }
+                            patches.push((pos, map.map(old).unwrap_or(TOMBSTONE)));
+                        }
+                        (DW_AT_HIGH_PC, DW_FORM_ADDR) => {
+                            let old = r.u32()?;
+                            patches.push((pos, map.map(old).unwrap_or(TOMBSTONE)));
+                        }
+                        (DW_AT_HIGH_PC, DW_FORM_DATA1)
+                        | (DW_AT_HIGH_PC, DW_FORM_DATA2)
+                        | (DW_AT_HIGH_PC, DW_FORM_DATA4)
+                        | (DW_AT_HIGH_PC, DW_FORM_DATA8) => {
+                            high_pc = Some((pos, form));
+                            skip_form(&mut r, form, version)?;
+                        }
+                        (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET) | (DW_AT_STMT_LIST, DW_FORM_DATA4) => {
+                            let old = r.u32()?;
+                            if let Some(&new) = line_offsets.get(&old) {
+                                patches.push((pos, new));
+                            }
+                        }
+                        (DW_AT_RANGES, DW_FORM_SEC_OFFSET) | (DW_AT_RANGES, DW_FORM_DATA4) => {
+                            let offset = r.u32()?;
+                            if lists.seen_ranges.insert(offset) {
+                                if let Some(ref mut ranges) = lists.ranges {
+                                    rewrite_list(ranges, offset as usize, base.unwrap_or(0), false, map)?;
+                                }
+                            }
+                        }
+                        (DW_AT_LOCATION, DW_FORM_SEC_OFFSET)
+                        | (DW_AT_FRAME_BASE, DW_FORM_SEC_OFFSET) => {
+                            let offset = r.u32()?;
+                            if lists.seen_loc.insert(offset) {
+                                if let Some(ref mut loc) = lists.loc {
+                                    rewrite_list(loc, offset as usize, base.unwrap_or(0), true, map)?;
+                                }
+                            }
+                        }
+                        // Before DWARF 4 location lists were referenced with
+                        // plain data forms.
+                        (DW_AT_LOCATION, DW_FORM_DATA4) | (DW_AT_FRAME_BASE, DW_FORM_DATA4)
+                            if version < 4 =>
+                        {
+                            let offset = r.u32()?;
+                            if lists.seen_loc.insert(offset) {
+                                if let Some(ref mut loc) = lists.loc {
+                                    rewrite_list(loc, offset as usize, base.unwrap_or(0), true, map)?;
+                                }
+                            }
+                        }
+                        _ => skip_form(&mut r, form, version)?,
+                    }
+                }
+                // The first entry is the unit, whose `DW_AT_low_pc` is the
+                // base address of its range and location lists.
+                if base.is_none() {
+                    base = Some(low_pc.unwrap_or(0));
+                }
+                // A `DW_AT_high_pc` of a data form is the size of the code,
+                // which we can patch in place if it's at least four bytes.
+                if let (Some(low), Some((pos, form))) = (low_pc, high_pc) {
+                    let size = read_data(r.data, pos, form)?;
+                    let new_size = map.map(low).and_then(|new_low| {
+                        map.map(low.wrapping_add(size as u32)).map(|new_end| new_end - new_low)
+                    });
+                    match new_size {
+                        Some(size) if form == DW_FORM_DATA4 || form == DW_FORM_DATA8 => {
+                            patches.push((pos, size));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            r.pos = end;
+        }
+    }
+    for (pos, value) in patches {
+        info[pos..pos + 4].copy_from_slice(&u32_bytes(value));
+    }
+    Ok(())
+}
+
+fn read_data(data: &[u8], pos: usize, form: u64) -> Result<u64, Error> {
+    let mut r = Reader::new(data, pos);
+    Ok(match form {
+        DW_FORM_DATA1 => r.u8()? as u64,
+        DW_FORM_DATA2 => r.u16()? as u64,
+        DW_FORM_DATA4 => r.u32()? as u64,
+        _ => r.u64()?,
+    })
+}
+
+/// Rewrites the range list (or location list if `loc`) at `offset`.
+fn rewrite_list(data: &mut [u8], offset: usize, base: u32, loc: bool, map: &AddressMap) -> Result<(), Error> {
+    let mut base = base;
+    let mut new_base = map.map(base);
+    let mut pos = offset;
+    loop {
+        let (begin, end) = {
+            let mut r = Reader::new(data, pos);
+            (r.u32()?, r.u32()?)
+        };
+        if begin == 0 && end == 0 {
+            return Ok(());
+        }
+        if begin == 0xffff_ffff {
+            base = end;
+            new_base = map.map(base);
+            let new = new_base.unwrap_or(TOMBSTONE);
+            data[pos + 4..pos + 8].copy_from_slice(&u32_bytes(new));
+            pos += 8;
+            continue;
+        }
+        let new = new_base.and_then(|new_base| {
+            let begin = map.map(base.wrapping_add(begin))?;
+            let end = map.map(base.wrapping_add(end))?;
+            Some((begin.wrapping_sub(new_base), end.wrapping_sub(new_base)))
+        });
+        let (begin, end) = new.unwrap_or((TOMBSTONE_RANGE, TOMBSTONE_RANGE));
+        data[pos..pos + 4].copy_from_slice(&u32_bytes(begin));
+        data[pos + 4..pos + 8].copy_from_slice(&u32_bytes(end));
+        pos += 8;
+        if loc {
+            let len = Reader::new(data, pos).u16()? as usize;
+            pos += 2 + len;
+        }
+    }
+}
+
+fn rewrite_aranges(data: &mut [u8], map: &AddressMap) -> Result<(), Error> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let (end, mut tuple) = {
+            let mut r = Reader::new(data, pos);
+            let end = unit_end(&mut r)?;
+            r.u16()?;
+            r.u32()?;
+            if r.u8()? != 4 {
+                bail!("only 32-bit addresses are supported in DWARF");
+            }
+            r.u8()?;
+            // Tuples are aligned to twice the size of an address.
+            (end, pos + 16)
+        };
+        while tuple + 8 <= end {
+            let (addr, len) = {
+                let mut r = Reader::new(data, tuple);
+                (r.u32()?, r.u32()?)
+            };
+            if addr == 0 && len == 0 {
+                break;
+            }
+            let new = map.map(addr).and_then(|new_addr| {
+                map.map(addr.wrapping_add(len)).map(|new_end| (new_addr, new_end - new_addr))
+            });
+            let (addr, len) = new.unwrap_or((TOMBSTONE, 0));
+            data[tuple..tuple + 4].copy_from_slice(&u32_bytes(addr));
+            data[tuple + 4..tuple + 8].copy_from_slice(&u32_bytes(len));
+            tuple += 8;
+        }
+        pos = end;
+    }
+    Ok(())
+}
+
+fn parse_abbrevs(data: &[u8], offset: usize) -> Result<HashMap<u64, Abbrev>, Error> {
+    let mut ret = HashMap::new();
+    let mut r = Reader::new(data, offset);
+    loop {
+        let code = r.uleb()?;
+        if code == 0 {
+            return Ok(ret);
+        }
+        // The tag and whether the entry has children
+        r.uleb()?;
+        r.u8()?;
+        let mut attrs = Vec::new();
+        loop {
+            let name = r.uleb()?;
+            let form = r.uleb()?;
+            if name == 0 && form == 0 {
+                break;
+            }
+            if form == DW_FORM_IMPLICIT_CONST {
+                r.sleb()?;
+            }
+            attrs.push((name, form));
+        }
+        ret.insert(code, Abbrev { attrs });
+    }
+}
+
+fn skip_form(r: &mut Reader, form: u64, version: u16) -> Result<(), Error> {
+    match form {
+        // addr, data4, strp, ref4, sec_offset, strp_sup, line_strp,
+        // GNU_ref_alt, GNU_strp_alt
+        0x01 | 0x06 | 0x0e | 0x13 | 0x17 | 0x1d | 0x1f | 0x1f20 | 0x1f21 => {
+            r.bytes(4)?;
+        }
+        // ref_addr is the size of an address in DWARF 2 and an offset
+        // later, both of which are four bytes for us.
+        0x10 => {
+            r.bytes(4)?;
+        }
+        0x03 => {
+            let len = r.u16()? as usize;
+            r.bytes(len)?;
+        }
+        0x04 => {
+            let len = r.u32()? as usize;
+            r.bytes(len)?;
+        }
+        0x05 | 0x12 => {
+            r.bytes(2)?;
+        }
+        0x07 | 0x14 | 0x20 => {
+            r.bytes(8)?;
+        }
+        0x08 => {
+            while r.u8()? != 0 {}
+        }
+        0x09 | 0x18 => {
+            let len = r.uleb()? as usize;
+            r.bytes(len)?;
+        }
+        0x0a => {
+            let len = r.u8()? as usize;
+            r.bytes(len)?;
+        }
+        0x0b | 0x0c | 0x11 => {
+            r.u8()?;
+        }
+        0x0d => {
+            r.sleb()?;
+        }
+        0x0f | 0x15 | 0x1a | 0x1b | 0x22 | 0x23 | 0x1f01 | 0x1f02 => {
+            r.uleb()?;
+        }
+        0x16 => {
+            let form = r.uleb()?;
+            skip_form(r, form, version)?;
+        }
+        0x19 | 0x21 => {}
+        0x1c => {
+            r.bytes(4)?;
+        }
+        0x1e => {
+            r.bytes(16)?;
+        }
+        0x24 => {
+            r.bytes(8)?;
+        }
+        0x25 | 0x29 => {
+            r.bytes(1)?;
+        }
+        0x26 | 0x2a => {
+            r.bytes(2)?;
+        }
+        0x27 | 0x2b => {
+            r.bytes(3)?;
+        }
+        0x28 | 0x2c => {
+            r.bytes(4)?;
+        }
+        _ => bail!("unknown DWARF form {:#x} (DWARF version {})", form, version),
+    }
+    Ok(())
+}
+
+/// Reads the length of a unit, returning where it ends.
+fn unit_end(r: &mut Reader) -> Result<usize, Error> {
+    let len = r.u32()?;
+    if len >= 0xffff_fff0 {
+        bail!("64-bit DWARF is not supported");
+    }
+    let end = r.pos + len as usize;
+    if end > r.data.len() {
+        bail!("truncated DWARF unit");
+    }
+    Ok(end)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Reader<'a> {
+        Reader { data, pos }
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let start = self.pos;
+        self.pos += len;
+        self.slice(start, self.pos)
+    }
+
+    fn slice(&self, start: usize, end: usize) -> Result<&'a [u8], Error> {
+        match self.data.get(start..end) {
+            Some(s) => Ok(s),
+            None => bail!("unexpected end of data while reading debug info"),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.bytes(2)?;
+        Ok(b[0] as u16 | (b[1] as u16) << 8)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.bytes(4)?;
+        Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let low = self.u32()? as u64;
+        let high = self.u32()? as u64;
+        Ok(low | high << 32)
+    }
+
+    fn uleb(&mut self) -> Result<u64, Error> {
+        let mut ret = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                ret |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(ret);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, Error> {
+        let mut ret = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                ret |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    ret |= -1 << shift;
+                }
+                return Ok(ret);
+            }
+        }
+    }
+}
+
+fn u32_bytes(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+fn push_u32(dst: &mut Vec<u8>, n: u32) {
+    dst.extend_from_slice(&u32_bytes(n));
+}
+
+fn leb(dst: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            dst.push(byte);
+            return;
+        }
+        dst.push(byte | 0x80);
+    }
+}
+
+fn sleb(dst: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        let done = (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0);
+        if done {
+            dst.push(byte);
+            return;
+        }
+        dst.push(byte | 0x80);
+    }
+}
@@ -0,0 +1,303 @@
+//! Dumping what a wasm file tells wasm-bindgen about its bindings
+//!
+//! This is the implementation of `wasm-bindgen inspect`, which prints the
+//! programs embedded by `#[wasm_bindgen]` in the `__wasm_bindgen_unstable`
+//! custom sections along with the result of running each descriptor function,
+//! without generating any bindings. Problems with individual programs or
+//! descriptors are reported in the output rather than aborting, as a wasm file
+//! which fails to generate bindings is usually what's being inspected.
+
+use std::fmt::{self, Write};
+
+use failure::Error;
+use parity_wasm::elements::*;
+use serde_json::{self, Map, Value};
+use shared;
+use wasm_interpreter::Interpreter;
+
+use decode;
+use descriptor::Descriptor;
+use program_payloads;
+
+pub struct Config {
+    json: bool,
+}
+
+struct Program {
+    version: Option<String>,
+    schema_version: Option<String>,
+    program: Option<shared::Program>,
+    error: Option<String>,
+}
+
+struct DescriptorInfo {
+    name: String,
+    words: Option<Vec<u32>>,
+    descriptor: Option<String>,
+    error: Option<String>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config { json: false }
+    }
+
+    /// Print JSON for tooling instead of human readable text.
+    pub fn json(&mut self, json: bool) -> &mut Self {
+        self.json = json;
+        self
+    }
+
+    /// Inspects the wasm file `wasm`, returning what to print.
+    pub fn inspect(&self, wasm: &[u8]) -> Result<String, Error> {
+        let module = deserialize_buffer::<Module>(wasm)?;
+        let programs = programs(&module)?;
+        let descriptors = descriptors(&module);
+        Ok(if self.json {
+            json(&programs, &descriptors)
+        } else {
+            text(&programs, &descriptors)?
+        })
+    }
+}
+
+fn programs(module: &Module) -> Result<Vec<Program>, Error> {
+    let mut ret = Vec::new();
+    for s in module.sections() {
+        let custom = match *s {
+            Section::Custom(ref s) => s,
+            _ => continue,
+        };
+        if custom.name() != "__wasm_bindgen_unstable" {
+            continue;
+        }
+        for a in program_payloads(custom.payload())? {
+            let mut program = Program {
+                version: None,
+                schema_version: None,
+                program: None,
+                error: None,
+            };
+            match decode::versions(a) {
+                Ok(p) => {
+                    program.version = Some(p.version);
+                    program.schema_version = Some(p.schema_version);
+                }
+                Err(e) => program.error = Some(format!("failed to decode the schema version: {}", e)),
+            }
+            // Programs of a schema this binary can't read are only shown with
+            // their versions.
+            let compat = program.schema_version.as_ref().map(|v| shared::schema_compat(v));
+            match compat {
+                Some(shared::SchemaCompat::Compatible) => match decode::program(a) {
+                    Ok(p) => program.program = Some(p),
+                    Err(e) => program.error = Some(format!("failed to decode the program: {}", e)),
+                },
+                Some(compat) => {
+                    program.error = Some(format!(
+                        "schema version {} is {} than this binary can read (schema version {})",
+                        program.schema_version.as_ref().unwrap(),
+                        if compat == shared::SchemaCompat::Newer { "newer" } else { "older" },
+                        shared::SCHEMA_VERSION,
+                    ));
+                }
+                None => {}
+            }
+            ret.push(program);
+        }
+    }
+    Ok(ret)
+}
+
+fn descriptors(module: &Module) -> Vec<DescriptorInfo> {
+    let mut names = module
+        .export_section()
+        .map(|s| {
+            s.entries()
+                .iter()
+                .filter(|e| e.field().starts_with("__wbindgen_describe_"))
+                .map(|e| e.field().to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    names.sort();
+
+    let mut interpreter = Interpreter::new(module);
+    let mut ret = Vec::new();
+    for name in names {
+        let mut info = DescriptorInfo {
+            name: name["__wbindgen_describe_".len()..].to_string(),
+            words: None,
+            descriptor: None,
+            error: None,
+        };
+        match interpreter.interpret_descriptor(&name, module) {
+            Ok(words) => {
+                let words = words.unwrap_or(&[]).to_vec();
+                match Descriptor::decode(&words) {
+                    Ok(d) => info.descriptor = Some(d.to_string()),
+                    Err(e) => info.error = Some(error_chain(&e)),
+                }
+                info.words = Some(words);
+            }
+            Err(e) => info.error = Some(e.to_string()),
+        }
+        ret.push(info);
+    }
+    ret
+}
+
+fn error_chain(e: &Error) -> String {
+    let mut ret = e.to_string();
+    for cause in e.iter_causes() {
+        ret.push_str(": ");
+        ret.push_str(&cause.to_string());
+    }
+    ret
+}
+
+fn text(programs: &[Program], descriptors: &[DescriptorInfo]) -> Result<String, fmt::Error> {
+    let mut dst = String::new();
+    if programs.is_empty() {
+        dst.push_str("no wasm-bindgen programs found\n");
+    }
+    for (i, p) in programs.iter().enumerate() {
+        writeln!(
+            dst,
+            "program {}: wasm-bindgen {}, schema version {}",
+            i,
+            p.version.as_ref().map(|s| &s[..]).unwrap_or("unknown"),
+            p.schema_version.as_ref().map(|s| &s[..]).unwrap_or("unknown"),
+        )?;
+        if let Some(ref e) = p.error {
+            writeln!(dst, "  error: {}", e)?;
+        }
+        if let Some(ref p) = p.program {
+            program_text(&mut dst, p)?;
+        }
+        dst.push_str("\n");
+    }
+
+    dst.push_str("descriptors:\n");
+    for d in descriptors {
+        match (&d.descriptor, &d.error) {
+            (Some(ref descriptor), _) => writeln!(dst, "  {}: {}", d.name, descriptor)?,
+            (None, Some(ref e)) => writeln!(dst, "  {}: error: {}", d.name, e)?,
+            (None, None) => writeln!(dst, "  {}", d.name)?,
+        }
+        if let Some(ref words) = d.words {
+            writeln!(dst, "    {:?}", words)?;
+        }
+    }
+    Ok(dst)
+}
+
+fn program_text(dst: &mut String, p: &shared::Program) -> fmt::Result {
+    for e in p.exports.iter() {
+        let name = match e.class {
+            Some(ref class) => format!("{}::{}", class, e.function.name),
+            None => e.function.name.clone(),
+        };
+        let mut notes = Vec::new();
+        if e.is_constructor {
+            notes.push("constructor".to_string());
+        } else if e.method {
+            notes.push(if e.consumed { "method, by value" } else { "method" }.to_string());
+        }
+        if e.start {
+            notes.push("start".to_string());
+        }
+        if let Some(ref chunk) = e.split {
+            notes.push(format!("split into `{}`", chunk));
+        }
+        write!(dst, "  export fn {}", name)?;
+        if !notes.is_empty() {
+            write!(dst, " ({})", notes.join(", "))?;
+        }
+        dst.push_str("\n");
+    }
+    for i in p.imports.iter() {
+        let mut from = String::new();
+        if let Some(ref module) = i.module {
+            from.push_str(&format!(" from \"{}\"", module));
+        }
+        if let Some(ref ns) = i.js_namespace {
+            from.push_str(&format!(" in `{}`", ns));
+        }
+        match i.kind {
+            shared::ImportKind::Function(ref f) => {
+                let name = match f.method {
+                    Some(ref m) => format!("{}::{}", m.class, f.function.name),
+                    None => f.function.name.clone(),
+                };
+                writeln!(dst, "  import fn {}{} as {}", name, from, f.shim)?;
+            }
+            shared::ImportKind::Static(ref s) => {
+                writeln!(dst, "  import static {}{} as {}", s.name, from, s.shim)?;
+            }
+            shared::ImportKind::Type(ref t) => {
+                writeln!(dst, "  import type {}{}", t.name, from)?;
+            }
+            shared::ImportKind::Enum(_) => {
+                writeln!(dst, "  import enum{}", from)?;
+            }
+        }
+    }
+    for s in p.structs.iter() {
+        let fields = s
+            .fields
+            .iter()
+            .map(|f| if f.readonly { format!("readonly {}", f.name) } else { f.name.clone() })
+            .collect::<Vec<_>>();
+        writeln!(dst, "  struct {} {{ {} }}", s.name, fields.join(", "))?;
+    }
+    for e in p.enums.iter() {
+        let variants = e
+            .variants
+            .iter()
+            .map(|v| format!("{} = {}", v.name, v.value))
+            .collect::<Vec<_>>();
+        writeln!(dst, "  enum {} {{ {} }}", e.name, variants.join(", "))?;
+    }
+    Ok(())
+}
+
+fn json(programs: &[Program], descriptors: &[DescriptorInfo]) -> String {
+    let programs = programs
+        .iter()
+        .map(|p| {
+            let mut ret = Map::new();
+            ret.insert("version".to_string(), optional(p.version.clone()));
+            ret.insert("schema_version".to_string(), optional(p.schema_version.clone()));
+            let program = match p.program {
+                Some(ref p) => serde_json::to_value(p).unwrap(),
+                None => Value::Null,
+            };
+            ret.insert("program".to_string(), program);
+            ret.insert("error".to_string(), optional(p.error.clone()));
+            Value::Object(ret)
+        })
+        .collect();
+    let descriptors = descriptors
+        .iter()
+        .map(|d| {
+            let mut ret = Map::new();
+            ret.insert("name".to_string(), d.name.clone().into());
+            ret.insert("words".to_string(), optional(d.words.clone()));
+            ret.insert("descriptor".to_string(), optional(d.descriptor.clone()));
+            ret.insert("error".to_string(), optional(d.error.clone()));
+            Value::Object(ret)
+        })
+        .collect();
+
+    let mut ret = Map::new();
+    ret.insert("programs".to_string(), Value::Array(programs));
+    ret.insert("descriptors".to_string(), Value::Array(descriptors));
+    let mut ret = serde_json::to_string_pretty(&Value::Object(ret)).unwrap();
+    ret.push('\n');
+    ret
+}
+
+fn optional<T: Into<Value>>(value: Option<T>) -> Value {
+    value.map(|v| v.into()).unwrap_or(Value::Null)
+}
@@ -0,0 +1,660 @@
+//! Support for storing `JsValue`s in a wasm table of `anyref`s
+//!
+//! By default a `JsValue` is an index into a JS array managed by the JS glue,
+//! so every object crossing the boundary costs a lookup or an insertion into
+//! that array. With `--anyref` we instead emit a second wasm module which
+//! owns a table of `anyref`s (the reference types proposal) and stores the
+//! values itself:
+//!
+//! * Imported and exported functions taking or returning `JsValue` get a shim
+//!   in wasm which converts between indices into the table and `anyref`s, so
+//!   the JS glue passes objects around directly.
+//!
+//! * The `__wbindgen_object_clone_ref` and `__wbindgen_object_drop_ref`
+//!   intrinsics are implemented in wasm, allocating slots of the table with
+//!   the free list in the `wasm-bindgen` crate.
+//!
+//! * Everything else which still deals in indices, like slices of `JsValue`
+//!   or closures, reads and writes the exported table from the JS glue.
+//!
+//! The IR of parity-wasm can't represent `anyref` so the shims are
+//! assembled with placeholders which are patched in the encoded module.
+//!
+//! Engines which don't support reference types are handed the unmodified
+//! module instead, which the same JS glue works with as well.
+
+use std::collections::HashMap;
+
+use failure::Error;
+use parity_wasm;
+use parity_wasm::elements::*;
+
+use js::Context;
+
+/// Name of the exported table of `anyref`s.
+pub const TABLE: &str = "__wbindgen_anyref_table";
+
+/// Exported functions of the `wasm-bindgen` crate managing the slots of the
+/// table.
+pub const ALLOC: &str = "__wbindgen_anyref_table_alloc";
+pub const DEALLOC: &str = "__wbindgen_anyref_table_dealloc";
+
+/// Indices of the table below this are the constants `undefined`, `null`,
+/// `true` and `false`, matching `JSIDX_RESERVED` in the `wasm-bindgen` crate.
+const RESERVED: u32 = 8;
+
+/// JS expression evaluating to whether the engine supports reference types,
+/// by validating a module with a type returning an `anyref`.
+pub const DETECT_JS: &str = "\
+    WebAssembly.validate(new Uint8Array([0, 97, 115, 109, 1, 0, 0, 0, 1, 5, 1, 96, 0, 1, 111]))";
+
+/// Which arguments and return value of a function are passed as `anyref`s.
+#[derive(Default, Debug)]
+pub struct Signature {
+    /// Indices of the `JsValue` arguments, and whether ownership of each is
+    /// transferred.
+    pub args: Vec<(usize, bool)>,
+    /// Whether the return value is an owned `JsValue`.
+    pub ret: bool,
+}
+
+impl Signature {
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty() && !self.ret
+    }
+}
+
+/// Functions whose `JsValue`s are passed directly as `anyref`s, recorded
+/// while generating the JS glue.
+#[derive(Default, Debug)]
+pub struct Anyref {
+    /// Imported functions, by the name of their import.
+    pub imports: HashMap<String, Signature>,
+    /// Exported functions, by the name of their export.
+    pub exports: HashMap<String, Signature>,
+}
+
+/// Validates that `--anyref` can be used before any bindings are generated.
+pub fn prepare(cx: &mut Context) -> Result<(), Error> {
+    if !cx.config.anyref {
+        return Ok(());
+    }
+    // The free list of table slots in the `wasm-bindgen` crate is a plain
+    // static, which isn't compiled with the `atomics` target feature.
+    if cx.config.threads {
+        bail!("`--anyref` is not supported with `--threads` yet");
+    }
+    cx.require_internal_export(ALLOC)?;
+    cx.require_internal_export(DEALLOC)?;
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+    Anyref,
+}
+
+impl ValType {
+    fn encode(&self) -> u8 {
+        match *self {
+            ValType::I32 => 0x7f,
+            ValType::I64 => 0x7e,
+            ValType::F32 => 0x7d,
+            ValType::F64 => 0x7c,
+            ValType::Anyref => 0x6f,
+        }
+    }
+}
+
+impl From<ValueType> for ValType {
+    fn from(ty: ValueType) -> ValType {
+        match ty {
+            ValueType::I32 => ValType::I32,
+            ValueType::I64 => ValType::I64,
+            ValueType::F32 => ValType::F32,
+            ValueType::F64 => ValType::F64,
+        }
+    }
+}
+
+struct FuncType {
+    params: Vec<ValType>,
+    ret: Option<ValType>,
+}
+
+/// Function added to the module, whose body is encoded directly.
+struct Shim {
+    ty: u32,
+    locals: Vec<ValType>,
+    body: Vec<u8>,
+}
+
+struct Transform<'a> {
+    module: &'a mut Module,
+    /// Number of types in the type section before the transformation.
+    base_types: u32,
+    new_types: Vec<FuncType>,
+    shims: Vec<Shim>,
+    table: u32,
+    alloc: u32,
+    dealloc: u32,
+}
+
+/// Creates the encoded `anyref` flavor of `module`, whose JS glue recorded
+/// the signatures in `anyref`.
+pub fn transform(module: &Module, anyref: &Anyref) -> Result<Vec<u8>, Error> {
+    let mut module = module.clone();
+    let tables = module.import_count(ImportCountType::Table)
+        + module.table_section().map(|s| s.entries().len()).unwrap_or(0);
+    let base_types = match module.type_section() {
+        Some(s) => s.types().len() as u32,
+        None => bail!("wasm module has no type section"),
+    };
+    let alloc = find_export(&module, ALLOC)?;
+    let dealloc = find_export(&module, DEALLOC)?;
+    let (new_types, shims) = {
+        let mut t = Transform {
+            module: &mut module,
+            base_types,
+            new_types: Vec::new(),
+            shims: Vec::new(),
+            table: tables as u32,
+            alloc,
+            dealloc,
+        };
+        t.rewrite(anyref)?;
+        (t.new_types, t.shims)
+    };
+    module.export_section_mut().unwrap().entries_mut().push(ExportEntry::new(
+        TABLE.to_string(),
+        Internal::Table(tables as u32),
+    ));
+
+    let bytes = parity_wasm::serialize(module)?;
+    patch(&bytes, &new_types, &shims)
+}
+
+fn find_export(module: &Module, name: &str) -> Result<u32, Error> {
+    let export = module
+        .export_section()
+        .and_then(|s| s.entries().iter().find(|e| e.field() == name));
+    match export.map(|e| e.internal()) {
+        Some(&Internal::Function(i)) => Ok(i),
+        _ => bail!("failed to find the exported function `{}`", name),
+    }
+}
+
+impl<'a> Transform<'a> {
+    fn rewrite(&mut self, anyref: &Anyref) -> Result<(), Error> {
+        if self.module.export_section().is_none() {
+            bail!("wasm module has no export section");
+        }
+        let mut redirect = HashMap::new();
+
+        let imports = self.module
+            .import_section()
+            .map(|s| s.entries().to_vec())
+            .unwrap_or_default();
+        let mut func_idx = 0;
+        for (i, import) in imports.iter().enumerate() {
+            let ty = match *import.external() {
+                External::Function(ty) => ty,
+                _ => continue,
+            };
+            let idx = func_idx;
+            func_idx += 1;
+            let shim = match import.field() {
+                "__wbindgen_object_drop_ref" => {
+                    redirect.insert(idx, self.dealloc);
+                    continue;
+                }
+                "__wbindgen_object_clone_ref" => self.clone_ref(),
+                "__wbindgen_anyref_table_grow" => self.table_grow(),
+                "__wbindgen_anyref_table_set_null" => self.table_set_null(),
+                name => match anyref.imports.get(name) {
+                    Some(sig) if !sig.is_empty() => {
+                        let (new_ty, shim) = self.import_shim(idx, ty, sig)?;
+                        self.set_import_type(i, new_ty);
+                        shim
+                    }
+                    _ => continue,
+                },
+            };
+            redirect.insert(idx, shim);
+        }
+        self.redirect(&redirect);
+
+        let exports = self.module.export_section().unwrap().entries().to_vec();
+        for (i, export) in exports.iter().enumerate() {
+            let func = match *export.internal() {
+                Internal::Function(f) => f,
+                _ => continue,
+            };
+            let sig = match anyref.exports.get(export.field()) {
+                Some(sig) if !sig.is_empty() => sig,
+                _ => continue,
+            };
+            let shim = self.export_shim(func, sig)?;
+            let entries = self.module.export_section_mut().unwrap().entries_mut();
+            *entries[i].internal_mut() = Internal::Function(shim);
+        }
+
+        // Add placeholders for the shims, which get their real types and
+        // bodies when the module is patched after encoding.
+        let shims = self.shims.iter().map(|s| s.ty).collect::<Vec<_>>();
+        match self.module.function_section_mut() {
+            Some(s) => s.entries_mut().extend(shims.iter().map(|ty| Func::new(*ty))),
+            None => bail!("wasm module has no function section"),
+        }
+        match self.module.code_section_mut() {
+            Some(s) => {
+                for _ in shims.iter() {
+                    let body = Instructions::new(vec![Instruction::Unreachable, Instruction::End]);
+                    s.bodies_mut().push(FuncBody::new(Vec::new(), body));
+                }
+            }
+            None => bail!("wasm module has no code section"),
+        }
+        Ok(())
+    }
+
+    fn func_type(&self, ty: u32) -> Result<FuncType, Error> {
+        let types = self.module.type_section().unwrap().types();
+        let ty = match types.get(ty as usize) {
+            Some(&Type::Function(ref f)) => f,
+            None => bail!("invalid type index {}", ty),
+        };
+        Ok(FuncType {
+            params: ty.params().iter().map(|t| ValType::from(*t)).collect(),
+            ret: ty.return_type().map(ValType::from),
+        })
+    }
+
+    fn type_of(&self, func: u32) -> Result<u32, Error> {
+        let imported = self.module.import_count(ImportCountType::Function) as u32;
+        if func < imported {
+            bail!("expected a defined function, found an import");
+        }
+        match self.module
+            .function_section()
+            .and_then(|s| s.entries().get((func - imported) as usize))
+        {
+            Some(f) => Ok(f.type_ref()),
+            None => bail!("invalid function index {}", func),
+        }
+    }
+
+    fn add_type(&mut self, params: Vec<ValType>, ret: Option<ValType>) -> u32 {
+        self.new_types.push(FuncType { params, ret });
+        self.base_types + self.new_types.len() as u32 - 1
+    }
+
+    /// Adds a function with the given type and body, returning its index.
+    fn add_shim(&mut self, ty: u32, locals: Vec<ValType>, body: Vec<u8>) -> u32 {
+        let idx = self.module.functions_space() + self.shims.len();
+        self.shims.push(Shim { ty, locals, body });
+        idx as u32
+    }
+
+    fn existing_type(&mut self, params: &[ValueType], ret: Option<ValueType>) -> u32 {
+        let ty = Type::Function(FunctionType::new(params.to_vec(), ret));
+        let types = self.module.type_section_mut().unwrap().types_mut();
+        match types.iter().position(|t| *t == ty) {
+            Some(i) => i as u32,
+            None => {
+                // Appending to the parity-wasm type section would shift the
+                // indices of the types added by us, so add it alongside them.
+                let params = params.iter().map(|t| ValType::from(*t)).collect();
+                self.add_type(params, ret.map(ValType::from))
+            }
+        }
+    }
+
+    fn set_import_type(&mut self, import: usize, ty: u32) {
+        let entries = self.module.import_section_mut().unwrap().entries_mut();
+        *entries[import].external_mut() = External::Function(ty);
+    }
+
+    /// `(idx: i32) -> i32`, storing the value at `idx` in a new slot.
+    fn clone_ref(&mut self) -> u32 {
+        let ty = self.existing_type(&[ValueType::I32], Some(ValueType::I32));
+        let mut body = Vec::new();
+        call(&mut body, self.alloc);
+        local_tee(&mut body, 1);
+        local_get(&mut body, 0);
+        table_get(&mut body, self.table);
+        table_set(&mut body, self.table);
+        local_get(&mut body, 1);
+        self.add_shim(ty, vec![ValType::I32], body)
+    }
+
+    /// `(delta: i32) -> i32`, growing the table with null references.
+    fn table_grow(&mut self) -> u32 {
+        let ty = self.existing_type(&[ValueType::I32], Some(ValueType::I32));
+        let mut body = Vec::new();
+        ref_null(&mut body);
+        local_get(&mut body, 0);
+        body.push(0xfc);
+        leb(&mut body, 15);
+        leb(&mut body, self.table);
+        self.add_shim(ty, Vec::new(), body)
+    }
+
+    /// `(idx: i32)`, releasing the value at `idx`.
+    fn table_set_null(&mut self) -> u32 {
+        let ty = self.existing_type(&[ValueType::I32], None);
+        let mut body = Vec::new();
+        local_get(&mut body, 0);
+        ref_null(&mut body);
+        table_set(&mut body, self.table);
+        self.add_shim(ty, Vec::new(), body)
+    }
+
+    /// Changes the imported function `import` of type `ty` to take the
+    /// arguments in `sig` as `anyref`s. Returns the new type of the import
+    /// and a shim with the original type which Rust calls instead.
+    fn import_shim(&mut self, import: u32, ty: u32, sig: &Signature) -> Result<(u32, u32), Error> {
+        let orig = self.func_type(ty)?;
+        let mut params = orig.params.clone();
+        for &(i, _) in sig.args.iter() {
+            match params.get_mut(i) {
+                Some(p) => *p = ValType::Anyref,
+                None => bail!("invalid argument index {} for imported function", i),
+            }
+        }
+        let ret = if sig.ret { Some(ValType::Anyref) } else { orig.ret };
+        let new_ty = self.add_type(params, ret);
+
+        let nparams = orig.params.len() as u32;
+        let mut body = Vec::new();
+        for i in 0..nparams {
+            local_get(&mut body, i);
+            if sig.args.iter().any(|a| a.0 == i as usize) {
+                table_get(&mut body, self.table);
+            }
+        }
+        call(&mut body, import);
+        let mut locals = Vec::new();
+        if sig.ret {
+            // Store the returned value in a new slot of the table.
+            locals.push(ValType::Anyref);
+            locals.push(ValType::I32);
+            local_set(&mut body, nparams);
+            call(&mut body, self.alloc);
+            local_tee(&mut body, nparams + 1);
+            local_get(&mut body, nparams);
+            table_set(&mut body, self.table);
+            local_get(&mut body, nparams + 1);
+        }
+        for &(i, owned) in sig.args.iter() {
+            if owned {
+                local_get(&mut body, i as u32);
+                call(&mut body, self.dealloc);
+            }
+        }
+        Ok((new_ty, self.add_shim(ty, locals, body)))
+    }
+
+    /// Creates a shim of the exported function `func` which takes the
+    /// arguments in `sig` as `anyref`s, returning its index.
+    fn export_shim(&mut self, func: u32, sig: &Signature) -> Result<u32, Error> {
+        let orig = self.func_type(self.type_of(func)?)?;
+        let mut params = orig.params.clone();
+        for &(i, _) in sig.args.iter() {
+            match params.get_mut(i) {
+                Some(p) => *p = ValType::Anyref,
+                None => bail!("invalid argument index {} for exported function", i),
+            }
+        }
+        let ret = if sig.ret { Some(ValType::Anyref) } else { orig.ret };
+        let ty = self.add_type(params, ret);
+
+        // Each `anyref` argument is stored in a new slot of the table, which
+        // is the local following the parameters in the same order as `args`.
+        let nparams = orig.params.len() as u32;
+        let slot = |n: usize| nparams + n as u32;
+        let mut locals = sig.args.iter().map(|_| ValType::I32).collect::<Vec<_>>();
+        let mut body = Vec::new();
+        for (n, &(i, _)) in sig.args.iter().enumerate() {
+            call(&mut body, self.alloc);
+            local_tee(&mut body, slot(n));
+            local_get(&mut body, i as u32);
+            table_set(&mut body, self.table);
+        }
+        for i in 0..nparams {
+            match sig.args.iter().position(|a| a.0 == i as usize) {
+                Some(n) => local_get(&mut body, slot(n)),
+                None => local_get(&mut body, i),
+            }
+        }
+        call(&mut body, func);
+        if sig.ret {
+            let ret = slot(sig.args.len());
+            locals.push(ValType::I32);
+            local_set(&mut body, ret);
+            local_get(&mut body, ret);
+            table_get(&mut body, self.table);
+            local_get(&mut body, ret);
+            call(&mut body, self.dealloc);
+        }
+        // Borrowed values only live for the duration of the call.
+        for (n, &(_, owned)) in sig.args.iter().enumerate() {
+            if !owned {
+                local_get(&mut body, slot(n));
+                call(&mut body, self.dealloc);
+            }
+        }
+        Ok(self.add_shim(ty, locals, body))
+    }
+
+    /// Makes all references to the keys of `map` refer to its values instead.
+    fn redirect(&mut self, map: &HashMap<u32, u32>) {
+        if map.is_empty() {
+            return;
+        }
+        let get = |i: &mut u32| {
+            if let Some(new) = map.get(i) {
+                *i = *new;
+            }
+        };
+        for section in self.module.sections_mut() {
+            match *section {
+                Section::Code(ref mut s) => {
+                    for body in s.bodies_mut() {
+                        for instr in body.code_mut().elements_mut() {
+                            if let Instruction::Call(ref mut f) = *instr {
+                                get(f);
+                            }
+                        }
+                    }
+                }
+                Section::Element(ref mut s) => {
+                    for segment in s.entries_mut() {
+                        for f in segment.members_mut() {
+                            get(f);
+                        }
+                    }
+                }
+                Section::Export(ref mut s) => {
+                    for export in s.entries_mut() {
+                        if let Internal::Function(ref mut f) = *export.internal_mut() {
+                            get(f);
+                        }
+                    }
+                }
+                Section::Start(ref mut f) => get(f),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Patches the types, table and function bodies added by `Transform` into
+/// the encoded module.
+fn patch(bytes: &[u8], new_types: &[FuncType], shims: &[Shim]) -> Result<Vec<u8>, Error> {
+    let mut sections = Vec::new();
+    let mut pos = 8;
+    while pos < bytes.len() {
+        let id = bytes[pos];
+        let (size, n) = read_leb(&bytes[pos + 1..])?;
+        let start = pos + 1 + n;
+        let end = start + size as usize;
+        if end > bytes.len() {
+            bail!("malformed wasm section");
+        }
+        sections.push((id, bytes[start..end].to_vec()));
+        pos = end;
+    }
+
+    // The table section is optional and comes right before the memory
+    // section, or whichever section follows it.
+    if !sections.iter().any(|s| s.0 == 4) {
+        let at = sections
+            .iter()
+            .position(|s| s.0 != 0 && s.0 > 4)
+            .unwrap_or(sections.len());
+        let mut payload = Vec::new();
+        leb(&mut payload, 0);
+        sections.insert(at, (4, payload));
+    }
+
+    let mut dst = bytes[..8].to_vec();
+    for (id, payload) in sections {
+        let payload = match id {
+            1 => append_entries(&payload, new_types.len(), |dst| {
+                for ty in new_types {
+                    dst.push(0x60);
+                    leb(dst, ty.params.len() as u32);
+                    dst.extend(ty.params.iter().map(|t| t.encode()));
+                    match ty.ret {
+                        Some(t) => {
+                            leb(dst, 1);
+                            dst.push(t.encode());
+                        }
+                        None => leb(dst, 0),
+                    }
+                }
+            })?,
+            4 => append_entries(&payload, 1, |dst| {
+                dst.push(ValType::Anyref.encode());
+                dst.push(0);
+                leb(dst, RESERVED);
+            })?,
+            10 => replace_bodies(&payload, shims)?,
+            _ => payload,
+        };
+        dst.push(id);
+        leb(&mut dst, payload.len() as u32);
+        dst.extend_from_slice(&payload);
+    }
+    Ok(dst)
+}
+
+/// Appends `count` entries written by `f` to a vector-shaped section.
+fn append_entries<F>(payload: &[u8], count: usize, f: F) -> Result<Vec<u8>, Error>
+where
+    F: FnOnce(&mut Vec<u8>),
+{
+    let (n, len) = read_leb(payload)?;
+    let mut dst = Vec::new();
+    leb(&mut dst, n + count as u32);
+    dst.extend_from_slice(&payload[len..]);
+    f(&mut dst);
+    Ok(dst)
+}
+
+/// Replaces the trailing placeholder bodies in the code section with the
+/// bodies of `shims`.
+fn replace_bodies(payload: &[u8], shims: &[Shim]) -> Result<Vec<u8>, Error> {
+    let (count, mut pos) = read_leb(payload)?;
+    let keep = match (count as usize).checked_sub(shims.len()) {
+        Some(n) => n,
+        None => bail!("missing placeholder function bodies"),
+    };
+    for _ in 0..keep {
+        let (size, n) = read_leb(&payload[pos..])?;
+        pos += n + size as usize;
+    }
+    let mut dst = Vec::new();
+    leb(&mut dst, count);
+    dst.extend_from_slice(&payload[..pos][leb_len(payload)?..]);
+    for shim in shims {
+        let mut body = Vec::new();
+        leb(&mut body, shim.locals.len() as u32);
+        for local in shim.locals.iter() {
+            leb(&mut body, 1);
+            body.push(local.encode());
+        }
+        body.extend_from_slice(&shim.body);
+        body.push(0x0b);
+        leb(&mut dst, body.len() as u32);
+        dst.extend_from_slice(&body);
+    }
+    Ok(dst)
+}
+
+fn local_get(dst: &mut Vec<u8>, idx: u32) {
+    dst.push(0x20);
+    leb(dst, idx);
+}
+
+fn local_set(dst: &mut Vec<u8>, idx: u32) {
+    dst.push(0x21);
+    leb(dst, idx);
+}
+
+fn local_tee(dst: &mut Vec<u8>, idx: u32) {
+    dst.push(0x22);
+    leb(dst, idx);
+}
+
+fn call(dst: &mut Vec<u8>, idx: u32) {
+    dst.push(0x10);
+    leb(dst, idx);
+}
+
+fn table_get(dst: &mut Vec<u8>, table: u32) {
+    dst.push(0x25);
+    leb(dst, table);
+}
+
+fn table_set(dst: &mut Vec<u8>, table: u32) {
+    dst.push(0x26);
+    leb(dst, table);
+}
+
+fn ref_null(dst: &mut Vec<u8>) {
+    dst.push(0xd0);
+    dst.push(ValType::Anyref.encode());
+}
+
+fn leb(dst: &mut Vec<u8>, mut n: u32) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            dst.push(byte);
+            return;
+        }
+        dst.push(byte | 0x80);
+    }
+}
+
+fn read_leb(bytes: &[u8]) -> Result<(u32, usize), Error> {
+    let mut ret = 0u32;
+    for (i, byte) in bytes.iter().enumerate().take(5) {
+        ret |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((ret, i + 1));
+        }
+    }
+    bail!("malformed LEB128 integer")
+}
+
+fn leb_len(bytes: &[u8]) -> Result<usize, Error> {
+    read_leb(bytes).map(|p| p.1)
+}
@@ -12,7 +12,7 @@
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::mem;
 
-use failure::Error;
+use failure::{Error, ResultExt};
 use parity_wasm::elements::*;
 
 use descriptor::Descriptor;
@@ -20,7 +20,7 @@ use js::Context;
 use js::js2rust::Js2Rust;
 
 pub fn rewrite(input: &mut Context) -> Result<(), Error> {
-    let info = ClosureDescriptors::new(input);
+    let info = ClosureDescriptors::new(input)?;
 
     // Sanity check to make sure things look ok and skip everything below if
     // there's not calls to `Closure::new`.
@@ -85,10 +85,10 @@ impl ClosureDescriptors {
     ///
     /// All this information is then returned in the `ClosureDescriptors` return
     /// value.
-    fn new(input: &mut Context) -> ClosureDescriptors {
+    fn new(input: &mut Context) -> Result<ClosureDescriptors, Error> {
         let wbindgen_describe_closure = match input.interpreter.describe_closure_idx() {
             Some(i) => i,
-            None => return Default::default(),
+            None => return Ok(Default::default()),
         };
         let imports = input.module.import_section()
             .map(|s| s.functions())
@@ -97,7 +97,7 @@ impl ClosureDescriptors {
 
         let code = match input.module.code_section() {
             Some(code) => code,
-            None => return Default::default(),
+            None => return Ok(Default::default()),
         };
         for (i, function) in code.bodies().iter().enumerate() {
             let call_pos = function.code()
@@ -113,11 +113,13 @@ impl ClosureDescriptors {
                 Some(i) => i,
                 None => continue,
             };
-            let descriptor = input.interpreter.interpret_closure_descriptor(
-                i,
-                input.module,
-                &mut ret.element_removal_list,
-            ).unwrap();
+            let item = format!("the closure described by wasm function #{}", i + imports);
+            let descriptor = input
+                .interpreter
+                .interpret_closure_descriptor(i, input.module, &mut ret.element_removal_list)
+                .map_err(Error::from)
+                .and_then(Descriptor::decode)
+                .with_context(|_| super::describe_failed(&item))?;
             // `new_idx` is the function-space index of the function that we'll
             // be injecting. Calls to the code function `i` will instead be
             // rewritten to calls to `new_idx`, which is an import that we'll
@@ -128,11 +130,11 @@ impl ClosureDescriptors {
                 DescribeInstruction {
                     new_idx,
                     instr_idx: call_pos,
-                    descriptor: Descriptor::decode(descriptor),
+                    descriptor,
                 },
             );
         }
-        return ret
+        Ok(ret)
     }
 
     /// Here we remove elements from the function table. All our descriptor
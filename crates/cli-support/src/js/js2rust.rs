@@ -1,5 +1,6 @@
 use failure::Error;
 
+use super::anyref::Signature;
 use super::Context;
 use descriptor::{Descriptor, Function};
 
@@ -45,6 +46,21 @@ pub struct Js2Rust<'a, 'b: 'a> {
     /// The string value here is the class that this should be a constructor
     /// for.
     constructor: Option<String>,
+
+    /// Whether `JsValue`s are passed directly as `anyref`s, which requires
+    /// the wasm function to be rewritten by `--anyref`.
+    anyref: bool,
+
+    /// Rust arguments which are passed as `anyref`s, and whether ownership
+    /// of each is transferred.
+    anyref_args: Vec<(String, bool)>,
+
+    /// Whether the return value is received as an `anyref`.
+    anyref_ret: bool,
+
+    /// The chunk of split exports the function is in, which is loaded before
+    /// calling it, making the shim return a `Promise`.
+    split: Option<String>,
 }
 
 impl<'a, 'b> Js2Rust<'a, 'b> {
@@ -60,6 +76,10 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
             ret_ty: String::new(),
             ret_expr: String::new(),
             constructor: None,
+            anyref: false,
+            anyref_args: Vec::new(),
+            anyref_ret: false,
+            split: None,
         }
     }
 
@@ -73,6 +93,41 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
         Ok(self)
     }
 
+    /// Pass `JsValue`s directly as `anyref`s if `--anyref` is enabled.
+    ///
+    /// The caller must record the resulting `anyref_signature` so the wasm
+    /// function gets a matching shim.
+    pub fn anyref(&mut self, anyref: bool) -> &mut Self {
+        self.anyref = anyref && self.cx.config.anyref;
+        self
+    }
+
+    /// Returns which arguments of the wasm function, and whether its return
+    /// value, are passed as `anyref`s.
+    pub fn anyref_signature(&self) -> Signature {
+        let args = self
+            .anyref_args
+            .iter()
+            .map(|&(ref arg, owned)| {
+                let i = self.rust_arguments.iter().position(|a| a == arg).unwrap();
+                (i, owned)
+            })
+            .collect();
+        Signature {
+            args,
+            ret: self.anyref_ret,
+        }
+    }
+
+    /// Load the chunk `split` of split exports before calling the function.
+    ///
+    /// The function is then invoked with the chunk's exports bound to
+    /// `chunk`, rather than `wasm`.
+    pub fn split(&mut self, split: Option<&str>) -> &mut Self {
+        self.split = split.map(|s| s.to_string());
+        self
+    }
+
     pub fn constructor(&mut self, class: Option<&str>) -> &mut Self {
         self.constructor = class.map(|s| s.to_string());
         self
@@ -96,6 +151,8 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
                      this.ptr = 0;\n\
                      ",
                 );
+                let cleanup = self.cx.remove_cleanup("this");
+                self.prelude(&cleanup);
                 self.rust_arguments.insert(0, "ptr".to_string());
             } else {
                 self.rust_arguments.insert(0, "this.ptr".to_string());
@@ -147,7 +204,8 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
             self.js_arguments
                 .push((name.clone(), kind.js_ty().to_string()));
 
-            let func = self.cx.pass_to_wasm_function(kind)?;
+            let borrowed = arg.is_by_ref() || arg.is_clamped_by_ref();
+            let func = self.cx.pass_to_wasm_function(kind, borrowed)?;
             let val = if optional {
                 self.cx.expose_is_like_none();
                 format!("isLikeNone({}) ? [0, 0] : {}({})", name, func, name)
@@ -159,7 +217,7 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
                 i = i,
                 val = val,
             ));
-            if arg.is_by_ref() || arg.is_clamped_by_ref() {
+            if borrowed {
                 if optional {
                     bail!("optional slices aren't currently supported");
                 }
@@ -178,14 +236,11 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
                         size = kind.size()
                     ));
                 }
-                self.finally(&format!(
-                    "\
-                     wasm.__wbindgen_free(ptr{i}, len{i} * {size});\n\
-                     ",
-                    i = i,
-                    size = kind.size()
-                ));
-                self.cx.require_internal_export("__wbindgen_free")?;
+                let free = self.cx.free_borrowed(
+                    &format!("ptr{}", i),
+                    &format!("len{} * {}", i, kind.size()),
+                )?;
+                self.finally(&free);
             }
             self.rust_arguments.push(format!("ptr{}", i));
             self.rust_arguments.push(format!("len{}", i));
@@ -194,6 +249,13 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
 
         if arg.is_anyref() {
             self.js_arguments.push((name.clone(), "any".to_string()));
+            if self.anyref && !optional {
+                let f = self.cx.expose_anyref_conversion("addAnyref");
+                let arg = format!("{}({})", f, name);
+                self.anyref_args.push((arg.clone(), true));
+                self.rust_arguments.push(arg);
+                return Ok(self);
+            }
             self.cx.expose_add_heap_object();
             if optional {
                 self.cx.expose_is_like_none();
@@ -275,6 +337,70 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
                 return Ok(self);
             }
 
+            if let Some(s) = arg.rust_struct() {
+                self.cx.expose_is_like_none();
+                self.js_arguments.push((name.clone(), format!("{} | undefined", s)));
+
+                if self.cx.config.debug {
+                    self.cx.expose_assert_class();
+                    self.prelude(&format!(
+                        "
+                            if (!isLikeNone({arg})) {{
+                                _assertClass({arg}, {struct_});
+                            }}
+                        ",
+                        arg = name,
+                        struct_ = s
+                    ));
+                }
+
+                if arg.is_by_ref() {
+                    // A moved value would otherwise be passed as `None`.
+                    self.prelude(&format!(
+                        "
+                            if (!isLikeNone({arg}) && {arg}.ptr === 0) {{
+                                throw new Error('Attempt to use a moved value');
+                            }}
+                        ",
+                        arg = name,
+                    ));
+                    self.rust_arguments.push(format!("isLikeNone({0}) ? 0 : {0}.ptr", name));
+                } else {
+                    let cleanup = self.cx.remove_cleanup(&name);
+                    self.prelude(&format!(
+                        "
+                            let ptr{i} = 0;
+                            if (!isLikeNone({arg})) {{
+                                ptr{i} = {arg}.ptr;
+                                if (ptr{i} === 0) {{
+                                    throw new Error('Attempt to use a moved value');
+                                }}
+                                {arg}.ptr = 0;
+                                {cleanup}
+                            }}
+                        ",
+                        i = i,
+                        arg = name,
+                        cleanup = cleanup,
+                    ));
+                    self.rust_arguments.push(format!("ptr{}", i));
+                }
+                return Ok(self);
+            }
+
+            if arg.is_ref_anyref() {
+                self.cx.expose_is_like_none();
+                self.cx.expose_borrowed_objects();
+                self.js_arguments.push((name.clone(), "any".to_string()));
+                let pop = self.cx.pop_borrowed_object();
+                self.finally(&format!("if (!isLikeNone({})) {}", name, pop));
+                self.rust_arguments.push(format!(
+                    "isLikeNone({0}) ? 0 : addBorrowedObject({0})",
+                    name,
+                ));
+                return Ok(self);
+            }
+
             match *arg {
                 Descriptor::Boolean => {
                     self.cx.expose_is_like_none();
@@ -321,6 +447,7 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
             if arg.is_by_ref() {
                 self.rust_arguments.push(format!("{}.ptr", name));
             } else {
+                let cleanup = self.cx.remove_cleanup(&name);
                 self.prelude(&format!(
                     "\
                     const ptr{i} = {arg}.ptr;\n\
@@ -328,9 +455,11 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
                         throw new Error('Attempt to use a moved value');
                     }}
                     {arg}.ptr = 0;\n\
+                    {cleanup}\n\
                 ",
                     i = i,
-                    arg = name
+                    arg = name,
+                    cleanup = cleanup,
                 ));
                 self.rust_arguments.push(format!("ptr{}", i));
             }
@@ -375,8 +504,18 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
 
         if arg.is_ref_anyref() {
             self.js_arguments.push((name.clone(), "any".to_string()));
+            if self.anyref {
+                let f = self.cx.expose_anyref_conversion("addBorrowedAnyref");
+                let pop = self.cx.expose_anyref_conversion("popBorrowedAnyref");
+                self.finally(&format!("{}();", pop));
+                let arg = format!("{}({})", f, name);
+                self.anyref_args.push((arg.clone(), false));
+                self.rust_arguments.push(arg);
+                return Ok(self);
+            }
             self.cx.expose_borrowed_objects();
-            self.finally("stack.pop();");
+            let pop = self.cx.pop_borrowed_object();
+            self.finally(pop);
             self.rust_arguments
                 .push(format!("addBorrowedObject({})", name));
             return Ok(self);
@@ -474,6 +613,12 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
         // that `takeObject` will naturally pluck out `undefined`.
         if ty.is_anyref() {
             self.ret_ty = "any".to_string();
+            if self.anyref {
+                let f = self.cx.expose_anyref_conversion("takeAnyref");
+                self.ret_expr = format!("return {}(RET);", f);
+                self.anyref_ret = true;
+                return Ok(self);
+            }
             self.cx.expose_take_object();
             self.ret_expr = format!("return takeObject(RET);");
             return Ok(self);
@@ -551,6 +696,22 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
                 return Ok(self);
             }
 
+            if let Some(name) = ty.rust_struct() {
+                if ty.is_by_ref() {
+                    bail!("cannot return references from Rust to JS yet")
+                }
+                self.ret_ty = format!("{} | undefined", name);
+                self.cx.require_class_wrap(name);
+                self.ret_expr = format!(
+                    "
+                        const ptr = RET;
+                        return ptr === 0 ? undefined : {name}.__wrap(ptr);
+                    ",
+                    name = name,
+                );
+                return Ok(self);
+            }
+
             match *ty {
                 Descriptor::Boolean => {
                     self.ret_ty = "boolean".to_string();
@@ -642,10 +803,17 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
         let mut ret: String = self.js_arguments.iter().map(|a| {
             format!("@param {{{}}} {}\n", a.1, a.0)
         }).collect();
-        ret.push_str(&format!("@returns {{{}}}", self.ret_ty));
+        ret.push_str(&format!("@returns {{{}}}", self.ret_ty()));
         ret
     }
 
+    fn ret_ty(&self) -> String {
+        match self.split {
+            Some(_) => format!("Promise<{}>", self.ret_ty),
+            None => self.ret_ty.clone(),
+        }
+    }
+
     /// Generate the actual function.
     ///
     /// The `prefix` specified is typically the string "function" but may be
@@ -663,6 +831,9 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
             .collect::<Vec<_>>()
             .join(", ");
         let mut js = format!("{}({}) {{\n", prefix, js_args);
+        if let Some(ref chunk) = self.split {
+            js.push_str(&format!("return loadChunk('{}').then(chunk => {{\n", chunk));
+        }
         js.push_str(&self.prelude);
         let rust_args = self.rust_arguments.join(", ");
 
@@ -684,6 +855,9 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
             )
         };
         js.push_str(&invoc);
+        if self.split.is_some() {
+            js.push_str("\n});");
+        }
         js.push_str("\n}");
         let ts_args = self
             .js_arguments
@@ -693,7 +867,7 @@ impl<'a, 'b> Js2Rust<'a, 'b> {
             .join(", ");
         let ts = format!(
             "{} {}({}): {};\n",
-            prefix, self.js_name, ts_args, self.ret_ty
+            prefix, self.js_name, ts_args, self.ret_ty()
         );
         (js, ts, self.js_doc_comments())
     }
@@ -0,0 +1,486 @@
+//! Support for `--minify`, shrinking the JS glue for production
+//!
+//! External minifiers can't shorten the names of the functions the wasm
+//! module imports from the JS glue as they don't know the wasm file refers to
+//! them. We rename those imports ourselves, in both the import section and
+//! the JS, and then minify the JS:
+//!
+//! * Comments and indentation are removed, along with the newlines and
+//!   spaces which aren't needed to parse the JS the same way.
+//!
+//! * Helpers and other bindings declared at the top level of the JS, like
+//!   `getStringFromWasm` or `slab`, are renamed to the shortest names which
+//!   aren't used already, the most used ones getting the shortest.
+//!
+//! * Imports get names of their own starting with `$`, assigned in the order
+//!   of the import section rather than by how often they're used so that all
+//!   targets of multi-target output agree on them.
+//!
+//! Exported names, classes, property names and the names of JS imports are
+//! left alone, so the JS behaves the same as unminified output. This only
+//! understands as much JS as the glue we generate contains.
+
+use std::collections::{HashMap, HashSet};
+
+use failure::Error;
+use parity_wasm::elements::*;
+
+use js::Context;
+
+/// Keywords short enough to be among the generated names.
+const RESERVED: &[&str] = &[
+    "as", "do", "if", "in", "of", "for", "get", "let", "new", "set", "try", "var",
+];
+
+/// Renames the functions the wasm module imports from the JS glue, the module
+/// `module`, returning the new name of each import.
+pub fn rename_imports(cx: &mut Context, module: &str) -> HashMap<String, String> {
+    let mut renamed = HashMap::new();
+    let imports = match cx.module.import_section_mut() {
+        Some(s) => s.entries_mut(),
+        None => return renamed,
+    };
+    for import in imports.iter_mut() {
+        match *import.external() {
+            External::Function(_) => {}
+            _ => continue,
+        }
+        if import.module() != module {
+            continue;
+        }
+        // `--anyref` implements the intrinsics in wasm, finding them by name
+        // after we're done.
+        if cx.config.anyref && import.field().starts_with("__wbindgen_") {
+            continue;
+        }
+        let name = format!("${}", short_name(renamed.len()));
+        renamed.insert(import.field().to_string(), name.clone());
+        *import.field_mut() = name;
+    }
+
+    let anyref = &mut cx.anyref.imports;
+    for (from, to) in renamed.iter() {
+        if let Some(signature) = anyref.remove(from) {
+            anyref.insert(to.clone(), signature);
+        }
+    }
+    cx.size_report.rename_imports(&renamed);
+    renamed
+}
+
+/// Minifies the JS glue `js`, whose imports were renamed to `imports`.
+///
+/// Markers of source locations are kept on lines of their own. The top level
+/// of the JS is `depth` brackets deep, which is the case for the body of the
+/// function `--no-modules` output is wrapped in.
+pub fn minify(js: &str, imports: &HashMap<String, String>, depth: usize) -> Result<String, Error> {
+    let tokens = tokenize(js)?;
+    let words = tokens
+        .iter()
+        .filter(|t| t.kind == Kind::Word)
+        .map(|t| t.text)
+        .collect::<HashSet<_>>();
+    for name in imports.values() {
+        if words.contains(&name[..]) {
+            bail!("can't minify JS which already uses the name `{}`", name);
+        }
+    }
+
+    // The bindings to rename, the most used first.
+    let declared = declarations(&tokens, depth);
+    let mut counts = HashMap::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind == Kind::Word && declared.contains(token.text) {
+            counts.entry(token.text).or_insert((0, i)).0 += 1;
+        }
+    }
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|&(_, (a, a_first)), &(_, (b, b_first))| {
+        b.cmp(&a).then(a_first.cmp(&b_first))
+    });
+    let mut helpers = HashMap::new();
+    let mut next = 0;
+    for &(name, _) in counts.iter() {
+        let short = loop {
+            let short = short_name(next);
+            next += 1;
+            if !words.contains(&short[..]) && !RESERVED.contains(&&short[..]) {
+                break short;
+            }
+        };
+        helpers.insert(name, short);
+    }
+
+    let mut out = String::new();
+    let mut brackets = Brackets::default();
+    let mut prev: Option<&Token> = None;
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind == Kind::Comment {
+            if token.text.starts_with(::source_map::MARKER_PREFIX) {
+                if !out.is_empty() && !out.ends_with('\n') {
+                    out.push('\n');
+                }
+                out.push_str(token.text);
+                out.push('\n');
+                prev = None;
+            }
+            continue;
+        }
+        if let Some(prev) = prev {
+            if token.newline && !joins_lines(prev, token) {
+                out.push('\n');
+            } else if token.space && needs_space(prev, token) {
+                out.push(' ');
+            }
+        }
+
+        let text = if token.kind == Kind::Word {
+            rename(&tokens, i, &brackets, &helpers, imports)
+        } else {
+            token.text.to_string()
+        };
+        out.push_str(&text);
+        brackets.push(&tokens, i);
+        prev = Some(token);
+    }
+    out.push('\n');
+    Ok(out)
+}
+
+/// Returns the text to emit for the word `tokens[i]`.
+fn rename(
+    tokens: &[Token],
+    i: usize,
+    brackets: &Brackets,
+    helpers: &HashMap<&str, String>,
+    imports: &HashMap<String, String>,
+) -> String {
+    let text = tokens[i].text;
+    let before = |n: usize| {
+        if i >= n { tokens[i - n].text } else { "" }
+    };
+    let after = tokens.get(i + 1).map(|t| t.text).unwrap_or("");
+    // A property, rather than something spread with `...`.
+    let property = before(1) == "." && before(2) != ".";
+
+    if let Some(name) = imports.get(text) {
+        // Imports are also assigned to the exports of the glue.
+        if !property || before(2) == "__exports" || before(2) == "exports" {
+            return name.clone();
+        }
+        return text.to_string();
+    }
+
+    let name = match helpers.get(text) {
+        Some(name) => name,
+        None => return text.to_string(),
+    };
+    if property || brackets.in_class() {
+        return text.to_string();
+    }
+    if brackets.in_braces() && (before(1) == "{" || before(1) == ",") {
+        // Keys of object literals keep their names, and shorthand properties
+        // are spelled out.
+        if after == ":" {
+            return text.to_string();
+        }
+        if after == "," || after == "}" {
+            return format!("{}:{}", text, name);
+        }
+    }
+    name.clone()
+}
+
+/// Finds the names of the bindings declared at the top level of the JS, aside
+/// from exported ones and classes.
+fn declarations<'a>(tokens: &[Token<'a>], depth: usize) -> HashSet<&'a str> {
+    let mut declared = HashSet::new();
+    let mut brackets = Brackets::default();
+    for (i, token) in tokens.iter().enumerate() {
+        let keyword = match token.text {
+            "function" | "let" | "const" | "var" => token.kind == Kind::Word,
+            _ => false,
+        };
+        let exported = i > 0 && (tokens[i - 1].text == "export" || tokens[i - 1].text == "default");
+        if keyword && !exported && brackets.depth() == depth {
+            if let Some(name) = tokens.get(i + 1) {
+                if name.kind == Kind::Word {
+                    declared.insert(name.text);
+                }
+            }
+        }
+        brackets.push(tokens, i);
+    }
+    declared
+}
+
+/// The brackets enclosing a token.
+#[derive(Default)]
+struct Brackets {
+    stack: Vec<Bracket>,
+    class: bool,
+}
+
+#[derive(PartialEq)]
+enum Bracket {
+    Paren,
+    Square,
+    Brace,
+    Class,
+}
+
+impl Brackets {
+    /// Updates the brackets enclosing the token after `tokens[i]`.
+    fn push(&mut self, tokens: &[Token], i: usize) {
+        let token = &tokens[i];
+        if token.kind == Kind::Word && token.text == "class" {
+            self.class = true;
+        }
+        if token.kind != Kind::Punct {
+            return;
+        }
+        match token.text {
+            "(" => self.stack.push(Bracket::Paren),
+            "[" => self.stack.push(Bracket::Square),
+            "{" if self.class => {
+                self.class = false;
+                self.stack.push(Bracket::Class);
+            }
+            "{" => self.stack.push(Bracket::Brace),
+            ")" | "]" | "}" => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Whether the token is directly in the body of a class.
+    fn in_class(&self) -> bool {
+        self.stack.last() == Some(&Bracket::Class)
+    }
+
+    fn in_braces(&self) -> bool {
+        self.stack.last() == Some(&Bracket::Brace)
+    }
+}
+
+/// Whether the newline between `prev` and `next` can be removed without
+/// changing where semicolons are inserted.
+fn joins_lines(prev: &Token, next: &Token) -> bool {
+    match prev.text {
+        ";" | "{" | "," | "(" | "[" => return true,
+        _ => {}
+    }
+    match next.text {
+        "}" | ")" | "]" | "." => true,
+        _ => false,
+    }
+}
+
+/// Whether `prev` and `next`, originally separated by whitespace, would be
+/// read differently without it.
+fn needs_space(prev: &Token, next: &Token) -> bool {
+    let last = prev.text.chars().last().unwrap();
+    let first = next.text.chars().next().unwrap();
+    if is_word(last) && is_word(first) {
+        return true;
+    }
+    match (last, first) {
+        ('+', '+') | ('-', '-') | ('/', '/') | ('/', '*') | ('<', '!') | ('-', '>') => true,
+        _ => {
+            (prev.kind == Kind::Number && first == '.')
+                || (prev.kind == Kind::Regex && is_word(first))
+        }
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$' || !c.is_ascii()
+}
+
+/// The `n`th of the names `a` to `Z`, then `aa`, `ab` and so on.
+fn short_name(mut n: usize) -> String {
+    const FIRST: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    const REST: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    let mut name = String::new();
+    name.push(FIRST[n % FIRST.len()] as char);
+    n /= FIRST.len();
+    while n > 0 {
+        n -= 1;
+        name.push(REST[n % REST.len()] as char);
+        n /= REST.len();
+    }
+    name
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Kind {
+    Word,
+    Number,
+    String,
+    Regex,
+    Comment,
+    Punct,
+}
+
+struct Token<'a> {
+    kind: Kind,
+    text: &'a str,
+    /// Whether the token is preceded by whitespace, and whether that includes
+    /// a newline.
+    space: bool,
+    newline: bool,
+}
+
+fn tokenize<'a>(js: &'a str) -> Result<Vec<Token<'a>>, Error> {
+    let bytes = js.as_bytes();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut pos = 0;
+    let mut space = false;
+    let mut newline = false;
+    // The number of braces open in each `${...}` of template literals we're
+    // in, so the `}` ending them is found. Their contents are tokenized like
+    // the rest of the JS, and the literal around them as strings.
+    let mut substitutions: Vec<usize> = Vec::new();
+    while pos < bytes.len() {
+        let c = bytes[pos];
+        if c == b'\n' {
+            newline = true;
+            pos += 1;
+            continue;
+        }
+        if c == b' ' || c == b'\t' || c == b'\r' {
+            space = true;
+            pos += 1;
+            continue;
+        }
+        let start = pos;
+        let next = bytes.get(pos + 1).cloned();
+        let kind = if c == b'/' && next == Some(b'/') {
+            pos = js[pos..].find('\n').map(|i| pos + i).unwrap_or(bytes.len());
+            Kind::Comment
+        } else if c == b'/' && next == Some(b'*') {
+            match js[pos + 2..].find("*/") {
+                Some(i) => pos += i + 4,
+                None => bail!("unterminated comment in the JS"),
+            }
+            Kind::Comment
+        } else if c == b'`' || (c == b'}' && substitutions.last() == Some(&0)) {
+            if c == b'}' {
+                substitutions.pop();
+            }
+            let (end, substitution) = skip_template(bytes, pos + 1)?;
+            pos = end;
+            if substitution {
+                substitutions.push(0);
+            }
+            Kind::String
+        } else if c == b'\'' || c == b'"' {
+            pos = skip_quoted(bytes, pos + 1, c)?;
+            Kind::String
+        } else if c == b'/' && regex_allowed(&tokens) {
+            pos = skip_quoted(bytes, pos + 1, b'/')?;
+            while pos < bytes.len() && is_word(bytes[pos] as char) {
+                pos += 1;
+            }
+            Kind::Regex
+        } else if c.is_ascii_digit() || (c == b'.' && next.map_or(false, |n| n.is_ascii_digit())) {
+            pos += 1;
+            while pos < bytes.len() {
+                let b = bytes[pos];
+                let exponent = (b == b'+' || b == b'-')
+                    && (bytes[pos - 1] == b'e' || bytes[pos - 1] == b'E');
+                if !(is_word(b as char) || b == b'.' || exponent) {
+                    break;
+                }
+                pos += 1;
+            }
+            Kind::Number
+        } else if is_word(c as char) {
+            let len = js[pos..]
+                .find(|c: char| !is_word(c))
+                .unwrap_or(bytes.len() - pos);
+            pos += len;
+            Kind::Word
+        } else {
+            if let Some(open) = substitutions.last_mut() {
+                match c {
+                    b'{' => *open += 1,
+                    b'}' => *open -= 1,
+                    _ => {}
+                }
+            }
+            pos += 1;
+            Kind::Punct
+        };
+        tokens.push(Token {
+            kind,
+            text: &js[start..pos],
+            space: space || newline,
+            newline,
+        });
+        space = false;
+        newline = false;
+    }
+    if !substitutions.is_empty() {
+        bail!("unterminated literal in the JS")
+    }
+    Ok(tokens)
+}
+
+/// Skips past the part of a template literal starting at `pos`, right after
+/// its opening backtick or the `}` ending a substitution. Returns where it
+/// ends and whether that's the start of another substitution.
+fn skip_template(bytes: &[u8], mut pos: usize) -> Result<(usize, bool), Error> {
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'\\' => pos += 1,
+            b'`' => return Ok((pos + 1, false)),
+            b'$' if bytes.get(pos + 1) == Some(&b'{') => return Ok((pos + 2, true)),
+            _ => {}
+        }
+        pos += 1;
+    }
+    bail!("unterminated literal in the JS")
+}
+
+/// Skips past the string or regex literal starting at `pos`, right after
+/// its opening `quote`.
+fn skip_quoted(bytes: &[u8], mut pos: usize, quote: u8) -> Result<usize, Error> {
+    let mut class = false;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'\\' => pos += 1,
+            b'\n' => break,
+            b'[' if quote == b'/' => class = true,
+            b']' if quote == b'/' => class = false,
+            b if b == quote && !class => return Ok(pos + 1),
+            _ => {}
+        }
+        pos += 1;
+    }
+    bail!("unterminated literal in the JS")
+}
+
+/// Whether a `/` after `tokens` starts a regex rather than being a division.
+fn regex_allowed(tokens: &[Token]) -> bool {
+    let prev = match tokens.iter().rev().find(|t| t.kind != Kind::Comment) {
+        Some(t) => t,
+        None => return true,
+    };
+    match prev.kind {
+        // The start of a substitution in a template literal.
+        Kind::String => prev.text.ends_with("${"),
+        Kind::Number | Kind::Regex => false,
+        Kind::Word => match prev.text {
+            "return" | "typeof" | "case" | "in" | "of" | "new" | "delete" | "void"
+            | "throw" => true,
+            _ => false,
+        },
+        _ => prev.text != ")" && prev.text != "]" && prev.text != "}",
+    }
+}
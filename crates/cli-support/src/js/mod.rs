@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write;
 use std::mem;
 
@@ -9,7 +9,10 @@ use shared;
 use wasm_gc;
 
 use super::Bindgen;
+use cache::Cache;
 use descriptor::{Descriptor, VectorKind};
+use size_report::SizeReport;
+use source_map::SourceMap;
 use wasm_interpreter::Interpreter;
 
 mod js2rust;
@@ -17,6 +20,11 @@ use self::js2rust::Js2Rust;
 mod rust2js;
 use self::rust2js::Rust2Js;
 mod closures;
+pub mod minify;
+pub mod anyref;
+pub mod split;
+mod threads;
+pub use self::threads::{Tls, INIT_DATA_JS, SCRATCH_SIZE, STACK_SIZE};
 
 pub struct Context<'a> {
     pub globals: String,
@@ -46,7 +54,35 @@ pub struct Context<'a> {
     pub exported_classes: HashMap<String, ExportedClass>,
     pub function_table_needed: bool,
     pub interpreter: &'a mut Interpreter,
+
+    /// Descriptors cached by previous runs with `--cache-dir`.
+    pub cache: Option<&'a mut Cache>,
     pub memory_init: Option<ResizableLimits>,
+
+    /// The name of the export flagged with `#[wasm_bindgen(start)]`, if any,
+    /// which is invoked as soon as the wasm module is instantiated.
+    pub start: Option<String>,
+
+    /// Functions passing `JsValue`s directly as `anyref`s with `--anyref`.
+    pub anyref: anyref::Anyref,
+
+    /// Rust source locations of the generated shims with `--source-map`.
+    pub source_map: SourceMap,
+
+    /// Sizes of each binding with `--size-report`.
+    pub size_report: SizeReport,
+
+    /// Names of the exports split into each chunk of the wasm module.
+    pub split: BTreeMap<String, Vec<String>>,
+
+    /// Names to fall back on for imported types which may not exist, from
+    /// their `vendor_prefix` and `fallback` attributes, keyed by the module
+    /// they're imported from and their name.
+    pub import_fallbacks: HashMap<(Option<String>, String), Vec<String>>,
+
+    /// Layout of the thread local storage each thread allocates with
+    /// `--threads`, if the module uses any.
+    pub tls: Option<Tls>,
 }
 
 #[derive(Default)]
@@ -73,6 +109,33 @@ pub struct SubContext<'a, 'b: 'a> {
 const INITIAL_SLAB_VALUES: &[&str] = &["undefined", "null", "true", "false"];
 
 impl<'a> Context<'a> {
+    /// Records the names the types imported by `program` fall back on. This
+    /// needs to happen for all programs before generating any bindings, as
+    /// types are used by the imports of other crates as well.
+    pub fn add_import_fallbacks(&mut self, program: &shared::Program) {
+        for import in program.imports.iter() {
+            let ty = match import.kind {
+                shared::ImportKind::Type(ref ty) => ty,
+                _ => continue,
+            };
+            if import.js_namespace.is_some() {
+                continue;
+            }
+            let names = ty.vendor_prefixes
+                .iter()
+                .map(|prefix| format!("{}{}", prefix, ty.name))
+                .chain(ty.fallbacks.iter().cloned());
+            for name in names {
+                let fallbacks = self.import_fallbacks
+                    .entry((import.module.clone(), ty.name.clone()))
+                    .or_insert_with(Vec::new);
+                if !fallbacks.contains(&name) {
+                    fallbacks.push(name);
+                }
+            }
+        }
+    }
+
     fn export(&mut self, name: &str, contents: &str, comments: Option<String>) {
         let contents = contents.trim();
         if let Some(ref c) = comments {
@@ -84,24 +147,40 @@ impl<'a> Context<'a> {
             } else {
                 format!("module.exports.{} = {};\n", name, contents)
             }
-        } else if self.config.no_modules {
+        } else if self.no_modules() {
             if contents.starts_with("class") {
                 format!("{1}\n__exports.{0} = {0};\n", name, contents)
             } else {
                 format!("__exports.{} = {};\n", name, contents)
             }
         } else {
-            if contents.starts_with("function") {
+            let mut global = if contents.starts_with("function") {
                 format!("export function {}{}\n", name, &contents[8..])
             } else if contents.starts_with("class") {
                 format!("export {}\n", contents)
             } else {
                 format!("export const {} = {};\n", name, contents)
+            };
+            // With `--target web` there's no bundler to hand our exports to
+            // the wasm module as its imports, so also collect them into the
+            // object passed to `WebAssembly.instantiate`.
+            if self.config.web {
+                global.push_str(&format!("__exports.{0} = {0};\n", name));
             }
+            global
         };
         self.global(&global);
     }
 
+    /// Returns the line to emit right before the shim generated for
+    /// `function` so the source map can point it at the Rust declaration.
+    fn source_marker(&mut self, function: &shared::Function) -> String {
+        match function.location {
+            Some(ref location) if self.config.source_map => self.source_map.marker(location),
+            _ => String::new(),
+        }
+    }
+
     fn require_internal_export(&mut self, name: &'static str) -> Result<(), Error> {
         if !self.required_internal_exports.insert(name) {
             return Ok(());
@@ -121,6 +200,11 @@ impl<'a> Context<'a> {
     }
 
     pub fn finalize(&mut self, module_name: &str) -> Result<(String, String), Error> {
+        threads::prepare(self)?;
+        anyref::prepare(self)?;
+        if !self.split.is_empty() {
+            self.expose_load_chunk(module_name);
+        }
         self.write_classes()?;
 
         self.bind("__wbindgen_object_clone_ref", &|me| {
@@ -164,6 +248,20 @@ impl<'a> Context<'a> {
             ))
         })?;
 
+        // These are implemented in wasm by the `--anyref` transformation, but
+        // are still imported by the module JS falls back to.
+        for name in &["__wbindgen_anyref_table_grow", "__wbindgen_anyref_table_set_null"] {
+            self.bind(name, &|_| {
+                Ok(String::from(
+                    "
+                    function() {
+                        throw new Error('the anyref table requires reference types');
+                    }
+                    ",
+                ))
+            })?;
+        }
+
         self.bind("__wbindgen_string_new", &|me| {
             me.expose_add_heap_object();
             me.expose_get_string_from_wasm();
@@ -304,7 +402,7 @@ impl<'a> Context<'a> {
         })?;
 
         self.bind("__wbindgen_string_get", &|me| {
-            me.expose_pass_string_to_wasm()?;
+            me.expose_pass_string_to_wasm(false)?;
             me.expose_get_object();
             me.expose_uint32_memory();
             Ok(String::from(
@@ -358,7 +456,7 @@ impl<'a> Context<'a> {
 
         self.bind("__wbindgen_json_serialize", &|me| {
             me.expose_get_object();
-            me.expose_pass_string_to_wasm()?;
+            me.expose_pass_string_to_wasm(false)?;
             me.expose_uint32_memory();
             Ok(String::from(
                 "
@@ -394,11 +492,43 @@ impl<'a> Context<'a> {
             ))
         })?;
 
+        self.bind("__wbindgen_heap_snapshot", &|me| {
+            // The heap is only tracked in debug mode, and values in the
+            // `--anyref` table aren't tracked at all, so `undefined` tells
+            // Rust there's nothing to report.
+            if !me.config.debug || me.config.anyref {
+                return Ok(String::from("function() { return 0; }"));
+            }
+            me.expose_global_slab();
+            me.expose_add_heap_object();
+            Ok(String::from("function() { return addHeapObject(heapReport()); }"))
+        })?;
+
         self.bind("__wbindgen_rethrow", &|me| {
             me.expose_take_object();
             Ok(String::from("function(idx) { throw takeObject(idx); }"))
         })?;
 
+        self.bind("__wbindgen_thread_scratch", &|me| {
+            me.require_internal_export("__wbindgen_malloc")?;
+            Ok(format!(
+                "
+                function() {{
+                    if (cachedThreadScratch === 0) {{
+                        cachedThreadScratch = (wasm.__wbindgen_malloc({}) + 7) & ~7;
+                    }}
+                    return cachedThreadScratch;
+                }}
+                ",
+                threads::SCRATCH_SIZE,
+            ))
+        })?;
+
+        self.bind("__wbindgen_thread_spawn", &|me| {
+            me.expose_thread_pool(module_name)?;
+            Ok(String::from("function(task) { spawnThread(task); }"))
+        })?;
+
         self.create_memory_export();
         self.unexport_unused_internal_exports();
         closures::rewrite(self)?;
@@ -419,49 +549,141 @@ impl<'a> Context<'a> {
             ))
         })?;
 
-        self.rewrite_imports(module_name);
+        // The wasm file of multi-target output is shared by all targets, and
+        // imports the ES module glue.
+        let glue = if self.config.multi_target {
+            format!("esm/{}", module_name)
+        } else {
+            module_name.to_string()
+        };
+        self.rewrite_imports(&glue);
+        let renamed_imports = if self.config.minify {
+            minify::rename_imports(self, &format!("./{}", glue))
+        } else {
+            HashMap::new()
+        };
+
+        let start = match self.start {
+            Some(ref name) => format!("wasm.{}();\n", name),
+            None => String::new(),
+        };
+        // The constants in the table must be in place before any Rust code
+        // runs.
+        let start = if self.config.anyref {
+            self.expose_anyref_table();
+            format!("initAnyrefTable();\n{}", start)
+        } else {
+            start
+        };
+        // With threads only the main thread runs the start function, as well
+        // as initializing the shared memory.
+        let start = if self.config.threads && self.config.web {
+            format!(
+                "
+                if (maybe_memory === undefined) {{
+                    initThreadData(module, memory);
+                    {}
+                    {}
+                }} else {{
+                    wasm.__wbindgen_thread_init(maybe_thread.stack);
+                    cachedThreadScratch = maybe_thread.scratch;
+                    {}
+                }}
+                ",
+                match self.tls {
+                    Some(tls) => format!(
+                        "wasm.__wasm_init_tls({});",
+                        tls.alloc_js("wasm.__wbindgen_malloc"),
+                    ),
+                    None => String::new(),
+                },
+                start,
+                match self.tls {
+                    Some(_) => "wasm.__wasm_init_tls(maybe_thread.tls);",
+                    None => "",
+                },
+            )
+        } else if self.config.threads && !start.is_empty() {
+            format!("if (require('worker_threads').isMainThread) {{ {} }}", start)
+        } else {
+            start
+        };
 
-        let mut js = if self.config.no_modules {
+        let mut js = if self.no_modules() {
+            // Worker and worklet global scopes don't have a `window`, and
+            // audio worklets don't even have `self`, so look up the global
+            // object in a way that works in all of them.
+            let global = if self.config.worker {
+                "(typeof globalThis !== 'undefined' ? globalThis : self)"
+            } else {
+                "self"
+            };
             format!(
                     "\
                     (function() {{
                         var wasm;
                         const __exports = {{}};
                         {globals}
-                        function init(wasm_path) {{
-                            const fetchPromise = fetch(wasm_path);
-                            let resultPromise;
-                            if (typeof WebAssembly.instantiateStreaming === 'function') {{
-                                resultPromise = WebAssembly.instantiateStreaming(fetchPromise, {{ './{module}': __exports }});
-                            }} else {{
-                                resultPromise = fetchPromise
-                                    .then(response => response.arrayBuffer())
-                                    .then(buffer => WebAssembly.instantiate(buffer, {{ './{module}': __exports }}));
-                            }}
-                            return resultPromise.then(({{instance}}) => {{
-                                wasm = init.wasm = instance.exports;
-                                return;
-                            }});
-                        }};
-                        self.{global_name} = Object.assign(init, __exports);
+                        {init}
+                        {global}.{global_name} = Object.assign(init, __exports);
                     }})();",
                     globals = self.globals,
-                    module = module_name,
+                    init = self.generate_init(module_name, &start),
+                    global = global,
                     global_name = self.config.no_modules_global
                         .as_ref()
                         .map(|s| &**s)
                         .unwrap_or("wasm_bindgen"),
             )
+        } else if self.config.web {
+            if self.config.threads {
+                self.global(threads::INIT_DATA_JS);
+            }
+            self.typescript.push_str(
+                "
+                export type InitInput = RequestInfo | URL | Response | BufferSource | WebAssembly.Module;
+
+                ",
+            );
+            if self.config.threads {
+                self.typescript.push_str(
+                    "export default function init (module_or_path?: InitInput, \
+                     maybe_memory?: WebAssembly.Memory, maybe_thread?: { stack: number, scratch: number, tls?: number }): Promise<any>;\n",
+                );
+            } else {
+                self.typescript.push_str(
+                    "export default function init (module_or_path?: InitInput): Promise<any>;\n",
+                );
+            }
+            format!(
+                "\
+                /* tslint:disable */\n\
+                {imports}\n\
+                let wasm;
+                const __exports = {{}};
+
+                {globals}
+
+                {init}
+
+                export default init;
+                ",
+                imports = self.imports,
+                globals = self.globals,
+                init = self.generate_init(module_name, &start),
+            )
         } else {
-            let import_wasm = if self.globals.len() == 0 {
+            let import_wasm = if self.globals.len() == 0 && self.start.is_none() {
                 String::new()
             } else if self.use_node_require() {
                 self.footer
                     .push_str(&format!("wasm = require('./{}_bg');", module_name));
                 format!("var wasm;")
             } else {
-                format!("import * as wasm from './{}_bg';", module_name)
+                let dir = if self.config.multi_target { ".." } else { "." };
+                format!("import * as wasm from '{}/{}_bg';", dir, module_name)
             };
+            self.footer.push_str(&start);
 
             format!(
                 "\
@@ -480,14 +702,138 @@ impl<'a> Context<'a> {
 
         self.export_table();
         self.gc()?;
+        threads::rewrite(self)?;
 
         while js.contains("\n\n\n") {
             js = js.replace("\n\n\n", "\n\n");
         }
+        if self.config.minify {
+            let depth = if self.no_modules() { 2 } else { 0 };
+            js = minify::minify(&js, &renamed_imports, depth)
+                .context("failed to minify the JS glue")?;
+        }
 
         Ok((js, self.typescript.clone()))
     }
 
+    /// Generates the `init` function used by `--no-modules`, `--target web`
+    /// and `--target worker` output to instantiate the wasm module.
+    ///
+    /// The function accepts anything `fetch` does, a `Response`, the raw bytes
+    /// of the wasm file or an already compiled `WebAssembly.Module` (for
+    /// example one received from another thread via `postMessage`).
+    fn generate_init(&self, module_name: &str, start: &str) -> String {
+        // Only ES modules know where they were loaded from, so that's the
+        // only case where we can guess the location of the wasm file.
+        let default_input = if self.config.web {
+            format!(
+                "
+                if (typeof input === 'undefined') {{
+                    input = new URL('{}_bg.wasm', import.meta.url);
+                }}
+                ",
+                module_name,
+            )
+        } else {
+            String::new()
+        };
+        // Chunks of split exports are found next to the main wasm file.
+        let chunk_input = if self.split.is_empty() || !split::supported(self.config) {
+            ""
+        } else {
+            "chunkInput = input;"
+        };
+        // Engines without reference types get the unmodified module, which is
+        // expected next to the `anyref` one.
+        let anyref_fallback = if self.config.anyref {
+            format!(
+                "
+                if (!({})) {{
+                    if (typeof input === 'string') {{
+                        input = input.replace(/\\.wasm$/, '_fallback.wasm');
+                    }} else if (typeof URL === 'function' && input instanceof URL) {{
+                        input = new URL(input.href.replace(/\\.wasm$/, '_fallback.wasm'));
+                    }}
+                }}
+                ",
+                anyref::DETECT_JS,
+            )
+        } else {
+            String::new()
+        };
+        // Threads share one memory: workers are handed the one created by
+        // the main thread.
+        let (params, memory) = if self.config.threads {
+            let memory = format!(
+                "memory = __exports.memory = maybe_memory || {};",
+                self.memory_initializer().unwrap_or_default(),
+            );
+            ("input, maybe_memory, maybe_thread", memory)
+        } else {
+            ("input", String::new())
+        };
+        format!(
+            "
+            function init({params}) {{
+                {default_input}
+                {chunk_input}
+                {anyref_fallback}
+                {memory}
+                const imports = {{ './{module}': __exports }};
+
+                function instantiate(response) {{
+                    if (typeof WebAssembly.instantiateStreaming === 'function') {{
+                        return response.then(r => {{
+                            // Streaming only accepts the wasm MIME type, so
+                            // compile the bytes ourselves if the server
+                            // doesn't send it.
+                            const type = (r.headers.get('Content-Type') || '').split(';')[0].trim();
+                            if (type === 'application/wasm') {{
+                                return WebAssembly.instantiateStreaming(r, imports);
+                            }}
+                            return r.arrayBuffer()
+                                .then(bytes => WebAssembly.instantiate(bytes, imports));
+                        }});
+                    }}
+                    return response
+                        .then(r => r.arrayBuffer())
+                        .then(bytes => WebAssembly.instantiate(bytes, imports));
+                }}
+
+                let result;
+                if (typeof input === 'string' ||
+                    (typeof URL === 'function' && input instanceof URL) ||
+                    (typeof Request === 'function' && input instanceof Request)) {{
+                    result = instantiate(fetch(input));
+                }} else if (typeof Response === 'function' && input instanceof Response) {{
+                    result = instantiate(Promise.resolve(input));
+                }} else {{
+                    result = WebAssembly.instantiate(input, imports)
+                        .then(result => {{
+                            if (result instanceof WebAssembly.Instance) {{
+                                return {{ instance: result, module: input }};
+                            }}
+                            return result;
+                        }});
+                }}
+                return result.then(({{instance, module}}) => {{
+                    wasm = init.wasm = instance.exports;
+                    init.__wbindgen_wasm_module = module;
+                    {start}
+                    return wasm;
+                }});
+            }}
+            ",
+            params = params,
+            default_input = default_input,
+            chunk_input = chunk_input,
+            anyref_fallback = anyref_fallback,
+            memory = memory,
+            module = module_name,
+            start = start,
+        )
+    }
+
     fn bind(
         &mut self,
         name: &str,
@@ -514,33 +860,20 @@ impl<'a> Context<'a> {
         let mut dst = format!("class {} {{\n", name);
         let mut ts_dst = format!("export {}", dst);
 
-        let (mkweakref, freeref) = if self.config.weak_refs {
-            // When weak refs are enabled we use them to automatically free the
-            // contents of an exported rust class when it's gc'd. Note that a
-            // manual `free` function still exists for deterministic
-            // destruction.
-            //
-            // This is implemented by using a `WeakRefGroup` to run finalizers
-            // for all `WeakRef` objects that it creates. Upon construction of
-            // a new wasm object we use `makeRef` with "holdings" of a thunk to
-            // free the wasm instance.  Once the `this` (the instance we're
-            // creating) is gc'd then the finalizer will run with the
-            // `WeakRef`, and we'll pull out the `holdings`, our pointer.
+        let mkweakref = if self.config.weak_refs {
+            // When weak refs are enabled we use a `FinalizationRegistry` to
+            // automatically free the contents of an exported rust class when
+            // it's gc'd. Note that a manual `free` function still exists for
+            // deterministic destruction.
             //
-            // Note, though, that if manual finalization happens we want to
-            // cancel the `WeakRef`-generated finalization, so we retain the
-            // `WeakRef` in a global map. This global map is then used to
-            // `drop()` the `WeakRef` (cancel finalization) whenever it is
-            // finalized.
-            self.expose_cleanup_groups();
-            let mk = format!("addCleanup(this, this.ptr, free{});", name);
-            let free = "
-                CLEANUPS_MAP.get(ptr).drop();
-                CLEANUPS_MAP.delete(ptr);
-            ";
-            (mk, free)
+            // Each new instance is registered with the instance itself as the
+            // unregister token, so whenever the pointer is freed manually or
+            // ownership is moved back into Rust we unregister the instance and
+            // the finalizer won't run a second time.
+            self.expose_cleanup();
+            format!("addCleanup(this, this.ptr, free{});", name)
         } else {
-            (String::new(), "")
+            String::new()
         };
 
         if self.config.debug && !class.has_constructor {
@@ -573,6 +906,35 @@ impl<'a> Context<'a> {
             );
         }
 
+        let unwrap_name = shared::unwrap_function(&name);
+        if self.wasm_import_needed(&unwrap_name) {
+            self.expose_get_object();
+            let cleanup = self.remove_cleanup("obj");
+
+            // Hands out the pointer owned by an instance of this class (zero
+            // if it's not one), optionally taking ownership of it as well.
+            self.export(
+                &unwrap_name,
+                &format!(
+                    "
+                    function(idx, take) {{
+                        const obj = getObject(idx);
+                        if (!(obj instanceof {})) return 0;
+                        const ptr = obj.ptr;
+                        if (take && ptr !== 0) {{
+                            obj.ptr = 0;
+                            {}
+                        }}
+                        return ptr;
+                    }}
+                    ",
+                    name,
+                    cleanup,
+                ),
+                None,
+            );
+        }
+
         if wrap_needed {
             dst.push_str(&format!(
                 "
@@ -591,7 +953,7 @@ impl<'a> Context<'a> {
         for field in class.fields.iter() {
             let wasm_getter = shared::struct_field_get(name, &field.name);
             let wasm_setter = shared::struct_field_set(name, &field.name);
-            let descriptor = match self.describe(&wasm_getter) {
+            let descriptor = match self.describe(&wasm_getter, &format!("the field `{}.{}`", name, field.name))? {
                 None => continue,
                 Some(d) => d,
             };
@@ -631,12 +993,10 @@ impl<'a> Context<'a> {
         self.global(&format!(
             "
             function free{}(ptr) {{
-                {}
                 wasm.{}(ptr);
             }}
             ",
             name,
-            freeref,
             shared::free_function(&name)
         ));
         dst.push_str(&format!(
@@ -644,9 +1004,11 @@ impl<'a> Context<'a> {
             free() {{
                 const ptr = this.ptr;
                 this.ptr = 0;
+                {}
                 free{}(ptr);
             }}
             ",
+            self.remove_cleanup("this"),
             name,
         ));
         ts_dst.push_str("free(): void;\n");
@@ -661,6 +1023,18 @@ impl<'a> Context<'a> {
         Ok(())
     }
 
+    fn add_start_function(&mut self, name: &str) -> Result<(), Error> {
+        if let Some(ref prev) = self.start {
+            bail!(
+                "cannot specify two `start` functions: `{}` and `{}`",
+                prev,
+                name
+            );
+        }
+        self.start = Some(name.to_string());
+        Ok(())
+    }
+
     fn export_table(&mut self) {
         if !self.function_table_needed {
             return;
@@ -676,18 +1050,137 @@ impl<'a> Context<'a> {
         }
     }
 
-    fn create_memory_export(&mut self) {
-        let limits = match self.memory_init.clone() {
-            Some(limits) => limits,
-            None => return,
-        };
+    fn memory_initializer(&self) -> Option<String> {
+        let limits = self.memory_init.as_ref()?;
         let mut initializer = String::from("new WebAssembly.Memory({");
         initializer.push_str(&format!("initial:{}", limits.initial()));
         if let Some(max) = limits.maximum() {
             initializer.push_str(&format!(",maximum:{}", max));
         }
+        if limits.shared() {
+            initializer.push_str(",shared:true");
+        }
         initializer.push_str("})");
-        self.export("memory", &initializer, None);
+        Some(initializer)
+    }
+
+    fn create_memory_export(&mut self) {
+        let initializer = match self.memory_initializer() {
+            Some(initializer) => initializer,
+            None => return,
+        };
+        if !self.config.threads {
+            self.export("memory", &initializer, None);
+        } else if self.config.web {
+            // Assigned by `init`, see `generate_init`.
+            self.global("let memory;");
+        } else {
+            // Node workers receive the main thread's memory in their
+            // `workerData`.
+            self.global(&format!(
+                "
+                const memory = require('worker_threads').isMainThread
+                    ? {}
+                    : require('worker_threads').workerData.memory;
+                ",
+                initializer,
+            ));
+            self.export("memory", "memory", None);
+        }
+    }
+
+    fn expose_thread_pool(&mut self, module_name: &str) -> Result<(), Error> {
+        if !self.exposed_globals.insert("thread_pool") {
+            return Ok(());
+        }
+        if !self.config.threads {
+            bail!(
+                "the wasm module spawns threads but `--threads` wasn't passed \
+                 to `wasm-bindgen`"
+            );
+        }
+        self.require_internal_export("__wbindgen_malloc")?;
+        self.require_internal_export("__wbindgen_thread_entry")?;
+        let memory = self.memory();
+
+        // Each worker gets a stack and a scratch area of its own, allocated by
+        // the thread which spawns it, and is started with the module and
+        // memory it shares with the rest of the threads. The scratch area sits
+        // right above the top of the stack. Thread local storage is allocated
+        // separately as it may need a larger alignment.
+        let (alloc_tls, tls) = match self.tls {
+            Some(tls) => (
+                format!("const tls = {};", tls.alloc_js("wasm.__wbindgen_malloc")),
+                ", tls",
+            ),
+            None => (String::new(), ""),
+        };
+        let (pool_size, new_worker) = if self.config.web {
+            (
+                "navigator.hardwareConcurrency || 4",
+                format!(
+                    "
+                    const worker = new Worker(
+                        new URL('./{module}_worker.js', import.meta.url),
+                        {{ type: 'module' }},
+                    );
+                    worker.postMessage({{
+                        module: init.__wbindgen_wasm_module,
+                        memory: {memory},
+                        stack,
+                        scratch{tls},
+                    }});
+                    ",
+                    module = module_name,
+                    memory = memory,
+                    tls = tls,
+                ),
+            )
+        } else {
+            (
+                "require('os').cpus().length",
+                format!(
+                    "
+                    const worker = new (require('worker_threads').Worker)(
+                        require('path').join(__dirname, '{module}_worker.js'),
+                        {{ workerData: {{ memory: {memory}, stack, scratch{tls} }} }},
+                    );
+                    // Idle workers shouldn't keep node alive.
+                    worker.unref();
+                    ",
+                    module = module_name,
+                    memory = memory,
+                    tls = tls,
+                ),
+            )
+        };
+        let post_task = if self.config.web { "{ task }" } else { "task" };
+        self.global(&format!(
+            "
+            const THREAD_POOL = [];
+            let nextThread = 0;
+
+            function spawnThread(task) {{
+                if (THREAD_POOL.length < ({pool_size})) {{
+                    const base = wasm.__wbindgen_malloc({stack_size} + 16 + {scratch_size});
+                    const stack = (base + {stack_size} + 16) & ~15;
+                    const scratch = stack;
+                    {alloc_tls}
+                    {new_worker}
+                    THREAD_POOL.push(worker);
+                }}
+                const worker = THREAD_POOL[nextThread++ % THREAD_POOL.length];
+                worker.postMessage({post_task});
+            }}
+            ",
+            pool_size = pool_size,
+            stack_size = threads::STACK_SIZE,
+            scratch_size = threads::SCRATCH_SIZE,
+            alloc_tls = alloc_tls,
+            new_worker = new_worker,
+            post_task = post_task,
+        ));
+        Ok(())
     }
 
     fn rewrite_imports(&mut self, module_name: &str) {
@@ -779,6 +1272,12 @@ impl<'a> Context<'a> {
         }
         self.expose_global_slab();
         self.expose_global_slab_next();
+        let anyref = self.anyref_branch(
+            "
+            wasm.__wbindgen_anyref_table_dealloc(idx);
+            return;
+            ",
+        );
         let validate_owned = if self.config.debug {
             String::from(
                 "
@@ -807,6 +1306,7 @@ impl<'a> Context<'a> {
         self.global(&format!(
             "
             function dropRef(idx) {{
+                {}
                 {}
                 idx = idx >> 1;
                 if (idx < {}) return;
@@ -817,7 +1317,7 @@ impl<'a> Context<'a> {
                 slab_next = idx;
             }}
             ",
-            validate_owned, INITIAL_SLAB_VALUES.len(), dec_ref
+            anyref, validate_owned, INITIAL_SLAB_VALUES.len(), dec_ref
         ));
     }
 
@@ -868,6 +1368,31 @@ impl<'a> Context<'a> {
                 ),
                 None,
             );
+            // Each object added to the slab records where it was added from,
+            // and an id telling apart objects which reuse the same slot.
+            self.global(&format!(
+                "
+                let nextHeapId = 0;
+
+                function heapReport() {{
+                    const live = [];
+                    for (let i = {}; i < slab.length; i++) {{
+                        const val = slab[i];
+                        if (typeof(val) === 'number') continue;
+                        live.push({{
+                            index: i << 1,
+                            id: val.id,
+                            value: val.obj,
+                            refcount: val.cnt,
+                            trace: val.trace,
+                        }});
+                    }}
+                    return live;
+                }}
+                ",
+                initial_values.len()
+            ));
+            self.export("__wbindgen_heap_report", "heapReport", None);
         }
     }
 
@@ -889,6 +1414,7 @@ impl<'a> Context<'a> {
         }
         self.expose_global_stack();
         self.expose_global_slab();
+        let anyref = self.anyref_branch("return anyrefTable.get(idx);");
 
         let get_obj = if self.config.debug {
             String::from(
@@ -907,6 +1433,7 @@ impl<'a> Context<'a> {
         self.global(&format!(
             "
             function getObject(idx) {{
+                {}
                 if ((idx & 1) === 1) {{
                     return stack[idx >> 1];
                 }} else {{
@@ -915,7 +1442,7 @@ impl<'a> Context<'a> {
                 }}
             }}
             ",
-            get_obj
+            anyref, get_obj
         ));
     }
 
@@ -947,11 +1474,12 @@ impl<'a> Context<'a> {
         ));
     }
 
-    fn expose_pass_string_to_wasm(&mut self) -> Result<(), Error> {
-        if !self.exposed_globals.insert("pass_string_to_wasm") {
-            return Ok(());
+    fn expose_pass_string_to_wasm(&mut self, borrowed: bool) -> Result<&'static str, Error> {
+        let name = if borrowed { "passStringToWasmBorrowed" } else { "passStringToWasm" };
+        if !self.exposed_globals.insert(name) {
+            return Ok(name);
         }
-        self.require_internal_export("__wbindgen_malloc")?;
+        let malloc = self.malloc(borrowed)?;
         self.expose_text_encoder();
         self.expose_uint8_memory();
         let debug = if self.config.debug {
@@ -963,68 +1491,81 @@ impl<'a> Context<'a> {
         };
         self.global(&format!(
             "
-            function passStringToWasm(arg) {{
+            function {}(arg) {{
                 {}
                 const buf = cachedEncoder.encode(arg);
-                const ptr = wasm.__wbindgen_malloc(buf.length);
+                const ptr = {}(buf.length);
                 getUint8Memory().set(buf, ptr);
                 return [ptr, buf.length];
             }}
             ",
-            debug
+            name,
+            debug,
+            malloc,
         ));
-        Ok(())
+        Ok(name)
     }
 
-    fn expose_pass_array8_to_wasm(&mut self) -> Result<(), Error> {
+    fn expose_pass_array8_to_wasm(&mut self, borrowed: bool) -> Result<&'static str, Error> {
         self.expose_uint8_memory();
-        self.pass_array_to_wasm("passArray8ToWasm", "getUint8Memory", 1)
+        let name = if borrowed { "passArray8ToWasmBorrowed" } else { "passArray8ToWasm" };
+        self.pass_array_to_wasm(name, "getUint8Memory", 1, borrowed)
     }
 
-    fn expose_pass_array16_to_wasm(&mut self) -> Result<(), Error> {
+    fn expose_pass_array16_to_wasm(&mut self, borrowed: bool) -> Result<&'static str, Error> {
         self.expose_uint16_memory();
-        self.pass_array_to_wasm("passArray16ToWasm", "getUint16Memory", 2)
+        let name = if borrowed { "passArray16ToWasmBorrowed" } else { "passArray16ToWasm" };
+        self.pass_array_to_wasm(name, "getUint16Memory", 2, borrowed)
     }
 
-    fn expose_pass_array32_to_wasm(&mut self) -> Result<(), Error> {
+    fn expose_pass_array32_to_wasm(&mut self, borrowed: bool) -> Result<&'static str, Error> {
         self.expose_uint32_memory();
-        self.pass_array_to_wasm("passArray32ToWasm", "getUint32Memory", 4)
+        let name = if borrowed { "passArray32ToWasmBorrowed" } else { "passArray32ToWasm" };
+        self.pass_array_to_wasm(name, "getUint32Memory", 4, borrowed)
     }
 
-    fn expose_pass_array64_to_wasm(&mut self) -> Result<(), Error> {
+    fn expose_pass_array64_to_wasm(&mut self, borrowed: bool) -> Result<&'static str, Error> {
         self.expose_uint64_memory();
-        self.pass_array_to_wasm("passArray64ToWasm", "getUint64Memory", 8)
+        let name = if borrowed { "passArray64ToWasmBorrowed" } else { "passArray64ToWasm" };
+        self.pass_array_to_wasm(name, "getUint64Memory", 8, borrowed)
     }
 
-    fn expose_pass_array_f32_to_wasm(&mut self) -> Result<(), Error> {
+    fn expose_pass_array_f32_to_wasm(&mut self, borrowed: bool) -> Result<&'static str, Error> {
         self.expose_f32_memory();
-        self.pass_array_to_wasm("passArrayF32ToWasm", "getFloat32Memory", 4)
+        let name = if borrowed { "passArrayF32ToWasmBorrowed" } else { "passArrayF32ToWasm" };
+        self.pass_array_to_wasm(name, "getFloat32Memory", 4, borrowed)
     }
 
-    fn expose_pass_array_f64_to_wasm(&mut self) -> Result<(), Error> {
+    fn expose_pass_array_f64_to_wasm(&mut self, borrowed: bool) -> Result<&'static str, Error> {
         self.expose_f64_memory();
-        self.pass_array_to_wasm("passArrayF64ToWasm", "getFloat64Memory", 8)
+        let name = if borrowed { "passArrayF64ToWasmBorrowed" } else { "passArrayF64ToWasm" };
+        self.pass_array_to_wasm(name, "getFloat64Memory", 8, borrowed)
     }
 
-    fn expose_pass_array_jsvalue_to_wasm(&mut self) -> Result<(), Error> {
-        if !self.exposed_globals.insert("pass_array_jsvalue") {
-            return Ok(());
+    fn expose_pass_array_jsvalue_to_wasm(&mut self, borrowed: bool) -> Result<&'static str, Error> {
+        let name = if borrowed { "passArrayJsValueToWasmBorrowed" } else { "passArrayJsValueToWasm" };
+        if !self.exposed_globals.insert(name) {
+            return Ok(name);
         }
-        self.require_internal_export("__wbindgen_malloc")?;
+        let malloc = self.malloc(borrowed)?;
         self.expose_uint32_memory();
         self.expose_add_heap_object();
-        self.global("
-            function passArrayJsValueToWasm(array) {
-                const ptr = wasm.__wbindgen_malloc(array.length * 4);
+        self.global(&format!(
+            "
+            function {}(array) {{
+                const ptr = {}(array.length * 4);
                 const mem = getUint32Memory();
-                for (let i = 0; i < array.length; i++) {
+                for (let i = 0; i < array.length; i++) {{
                     mem[ptr / 4 + i] = addHeapObject(array[i]);
-                }
+                }}
                 return [ptr, array.length];
-            }
+            }}
 
-        ");
-        Ok(())
+            ",
+            name,
+            malloc,
+        ));
+        Ok(name)
     }
 
     fn pass_array_to_wasm(
@@ -1032,23 +1573,79 @@ impl<'a> Context<'a> {
         name: &'static str,
         delegate: &str,
         size: usize,
-    ) -> Result<(), Error> {
+        borrowed: bool,
+    ) -> Result<&'static str, Error> {
         if !self.exposed_globals.insert(name) {
-            return Ok(());
+            return Ok(name);
         }
-        self.require_internal_export("__wbindgen_malloc")?;
+        let malloc = self.malloc(borrowed)?;
         self.global(&format!(
             "
             function {}(arg) {{
-                const ptr = wasm.__wbindgen_malloc(arg.length * {size});
+                const ptr = {}(arg.length * {size});
                 {}().set(arg, ptr / {size});
                 return [ptr, arg.length];
             }}
             ",
             name,
+            malloc,
             delegate,
             size = size
         ));
+        Ok(name)
+    }
+
+    /// Returns the JS function allocating memory for arguments passed to
+    /// wasm, `borrowed` being whether Rust only borrows them.
+    ///
+    /// Borrowed arguments use the allocator configured with
+    /// `Bindgen::borrowed_malloc`, if any, which is how data like `&str` can be
+    /// passed in an arena. Everything else is allocated with
+    /// `__wbindgen_malloc`, as Rust frees it with the global allocator.
+    fn malloc(&mut self, borrowed: bool) -> Result<String, Error> {
+        match self.config.borrowed_malloc {
+            Some(ref malloc) if borrowed => {
+                self.require_allocator_export(malloc)?;
+                Ok(format!("wasm.{}", malloc))
+            }
+            _ => {
+                self.require_internal_export("__wbindgen_malloc")?;
+                Ok("wasm.__wbindgen_malloc".to_string())
+            }
+        }
+    }
+
+    /// Returns the JS statement freeing `len` bytes at `ptr` of an argument
+    /// which Rust only borrowed, if it needs freeing.
+    pub fn free_borrowed(&mut self, ptr: &str, len: &str) -> Result<String, Error> {
+        if self.config.borrowed_malloc.is_none() {
+            self.require_internal_export("__wbindgen_free")?;
+            return Ok(format!("wasm.__wbindgen_free({}, {});\n", ptr, len));
+        }
+        match self.config.borrowed_free {
+            Some(ref free) => {
+                self.require_allocator_export(free)?;
+                Ok(format!("wasm.{}({}, {});\n", free, ptr, len))
+            }
+            None => Ok(String::new()),
+        }
+    }
+
+    fn require_allocator_export(&self, name: &str) -> Result<(), Error> {
+        let exported = self
+            .module
+            .export_section()
+            .map(|s| s.entries().iter().any(|e| e.field() == name))
+            .unwrap_or(false);
+        if !exported {
+            bail!(
+                "the custom allocator function `{}` was not found in the exports \
+                 of the wasm file, it needs to be exported with \
+                 `#[no_mangle] pub extern \"C\" fn {}`",
+                name,
+                name,
+            );
+        }
         Ok(())
     }
 
@@ -1065,7 +1662,19 @@ impl<'a> Context<'a> {
                 const TextEncoder = require('util').TextEncoder;
                 ",
             );
-        } else if !(self.config.browser || self.config.no_modules) {
+        } else if self.config.inline_utf8 {
+            // Some scopes, like audio worklets, lack `TextEncoder`, in which case
+            // we fall back to a small UTF-8 codec shipped with the bindings.
+            self.expose_utf8_codec();
+            self.global(
+                "
+                let cachedEncoder = typeof TextEncoder === 'function'
+                    ? new TextEncoder('utf-8')
+                    : { encode: utf8Encode };
+                ",
+            );
+            return;
+        } else if !(self.config.browser || self.no_modules() || self.config.web) {
             self.global(
                 "
                 const TextEncoder = typeof self === 'object' && self.TextEncoder
@@ -1094,7 +1703,19 @@ impl<'a> Context<'a> {
                 const TextDecoder = require('util').TextDecoder;
                 ",
             );
-        } else if !(self.config.browser || self.config.no_modules) {
+        } else if self.config.inline_utf8 {
+            // Some scopes, like audio worklets, lack `TextDecoder`, in which case
+            // we fall back to a small UTF-8 codec shipped with the bindings.
+            self.expose_utf8_codec();
+            self.global(
+                "
+                let cachedDecoder = typeof TextDecoder === 'function'
+                    ? new TextDecoder('utf-8')
+                    : { decode: utf8Decode };
+                ",
+            );
+            return;
+        } else if !(self.config.browser || self.no_modules() || self.config.web) {
             self.global(
                 "
                 const TextDecoder = typeof self === 'object' && self.TextDecoder
@@ -1110,6 +1731,84 @@ impl<'a> Context<'a> {
         );
     }
 
+    fn expose_utf8_codec(&mut self) {
+        if !self.exposed_globals.insert("utf8_codec") {
+            return;
+        }
+        // Lone surrogates in JS strings are encoded as U+FFFD, just like
+        // `TextEncoder` does. Strings coming out of Rust are valid UTF-8, but
+        // invalid input is still decoded following the WHATWG decoder behind
+        // `TextDecoder`, with a U+FFFD for each invalid sequence, rather than
+        // reading past the end of the input or throwing.
+        self.global(
+            "
+            function utf8Encode(arg) {
+                const buf = [];
+                for (const c of arg) {
+                    let cp = c.codePointAt(0);
+                    if (cp >= 0xd800 && cp <= 0xdfff) {
+                        cp = 0xfffd;
+                    }
+                    if (cp < 0x80) {
+                        buf.push(cp);
+                    } else if (cp < 0x800) {
+                        buf.push(0xc0 | (cp >> 6), 0x80 | (cp & 0x3f));
+                    } else if (cp < 0x10000) {
+                        buf.push(0xe0 | (cp >> 12), 0x80 | ((cp >> 6) & 0x3f), 0x80 | (cp & 0x3f));
+                    } else {
+                        buf.push(0xf0 | (cp >> 18), 0x80 | ((cp >> 12) & 0x3f),
+                                 0x80 | ((cp >> 6) & 0x3f), 0x80 | (cp & 0x3f));
+                    }
+                }
+                return new Uint8Array(buf);
+            }
+
+            function utf8Decode(bytes) {
+                let out = '';
+                for (let i = 0; i < bytes.length;) {
+                    const b = bytes[i++];
+                    let cp = 0xfffd;
+                    let n = 0;
+                    // Range of the next continuation byte, narrowed after
+                    // some lead bytes to reject overlong encodings,
+                    // surrogates and code points above U+10FFFF.
+                    let lo = 0x80;
+                    let hi = 0xbf;
+                    if (b < 0x80) {
+                        cp = b;
+                    } else if (b >= 0xc2 && b < 0xe0) {
+                        cp = b & 0x1f;
+                        n = 1;
+                    } else if (b >= 0xe0 && b < 0xf0) {
+                        if (b === 0xe0) lo = 0xa0;
+                        if (b === 0xed) hi = 0x9f;
+                        cp = b & 0x0f;
+                        n = 2;
+                    } else if (b >= 0xf0 && b < 0xf5) {
+                        if (b === 0xf0) lo = 0x90;
+                        if (b === 0xf4) hi = 0x8f;
+                        cp = b & 0x07;
+                        n = 3;
+                    }
+                    for (; n > 0; n--) {
+                        // A byte ending an invalid sequence starts the
+                        // next one.
+                        if (i === bytes.length || bytes[i] < lo || bytes[i] > hi) {
+                            cp = 0xfffd;
+                            break;
+                        }
+                        cp = (cp << 6) | (bytes[i++] & 0x3f);
+                        lo = 0x80;
+                        hi = 0xbf;
+                    }
+                    out += String.fromCodePoint(cp);
+                }
+                return out;
+            }
+            ",
+        );
+    }
+
     fn expose_get_string_from_wasm(&mut self) {
         if !self.exposed_globals.insert("get_string_from_wasm") {
             return;
@@ -1377,14 +2076,54 @@ impl<'a> Context<'a> {
             return;
         }
         self.expose_global_stack();
+        if !self.config.anyref {
+            self.global(
+                "
+                function addBorrowedObject(obj) {
+                    stack.push(obj);
+                    return ((stack.length - 1) << 1) | 1;
+                }
+                ",
+            );
+            return;
+        }
+
+        // Indices into the table can't be told apart from indices into the
+        // stack, so borrowed objects get a slot in the table as well which
+        // is released when they're popped.
+        self.expose_add_heap_object();
+        self.expose_drop_ref();
         self.global(
             "
             function addBorrowedObject(obj) {
+                if (anyrefTable !== undefined) {
+                    const idx = addHeapObject(obj);
+                    stack.push(idx);
+                    return idx;
+                }
                 stack.push(obj);
                 return ((stack.length - 1) << 1) | 1;
             }
             ",
         );
+        self.global(
+            "
+            function popBorrowedObject() {
+                const val = stack.pop();
+                if (anyrefTable !== undefined) dropRef(val);
+            }
+            ",
+        );
+    }
+
+    /// Returns the statement releasing the most recent object passed to
+    /// `addBorrowedObject`.
+    fn pop_borrowed_object(&self) -> &'static str {
+        if self.config.anyref {
+            "popBorrowedObject();"
+        } else {
+            "stack.pop();"
+        }
     }
 
     fn expose_take_object(&mut self) {
@@ -1404,12 +2143,76 @@ impl<'a> Context<'a> {
         );
     }
 
+    /// Exposes `loadChunk`, which instantiates a chunk of split exports the
+    /// first time it's called, returning a promise of the chunk's exports.
+    fn expose_load_chunk(&mut self, module_name: &str) {
+        if !self.exposed_globals.insert("load_chunk") {
+            return;
+        }
+        let load = if !split::supported(self.config) {
+            // Split exports are in the main module after all.
+            self.global("function loadChunk() { return Promise.resolve(wasm); }");
+            return;
+        } else if self.config.nodejs {
+            format!(
+                "
+                const path = require('path').join(__dirname, '{}_bg_' + name + '.wasm');
+                const bytes = Promise.resolve(require('fs').readFileSync(path));
+                ",
+                module_name,
+            )
+        } else {
+            self.global("let chunkInput;");
+            String::from(
+                "
+                if (typeof chunkInput !== 'string' &&
+                    !(typeof URL === 'function' && chunkInput instanceof URL)) {
+                    return Promise.reject(new Error(
+                        'split exports can only be loaded if `init` was ' +
+                        'passed the location of the wasm file'));
+                }
+                const input = String(chunkInput).replace(/\\.wasm$/, '_' + name + '.wasm');
+                const bytes = fetch(input).then(response => response.arrayBuffer());
+                ",
+            )
+        };
+        self.global(&format!(
+            "
+            const chunks = {{}};
+            function loadChunk(name) {{
+                if (chunks[name] === undefined) {{
+                    {load}
+                    const imports = {{ '{main}': wasm }};
+                    chunks[name] = bytes
+                        .then(bytes => WebAssembly.instantiate(bytes, imports))
+                        .then(result => result.instance.exports)
+                        .catch(e => {{
+                            // Let the next call try loading the chunk again.
+                            chunks[name] = undefined;
+                            throw e;
+                        }});
+                }}
+                return chunks[name];
+            }}
+            ",
+            load = load,
+            main = split::MAIN,
+        ));
+    }
+
     fn expose_add_heap_object(&mut self) {
         if !self.exposed_globals.insert("add_heap_object") {
             return;
         }
         self.expose_global_slab();
         self.expose_global_slab_next();
+        let anyref = self.anyref_branch(
+            "
+            const idx = wasm.__wbindgen_anyref_table_alloc();
+            anyrefTable.set(idx, obj);
+            return idx;
+            ",
+        );
         let set_slab_next = if self.config.debug {
             String::from(
                 "
@@ -1424,19 +2227,122 @@ impl<'a> Context<'a> {
                 ",
             )
         };
+        let entry = if self.config.debug {
+            "{ obj, cnt: 1, id: nextHeapId++, trace: new Error().stack }"
+        } else {
+            "{ obj, cnt: 1 }"
+        };
         self.global(&format!(
             "
             function addHeapObject(obj) {{
+                {}
                 if (slab_next === slab.length) slab.push(slab.length + 1);
                 const idx = slab_next;
                 const next = slab[idx];
                 {}
-                slab[idx] = {{ obj, cnt: 1 }};
+                slab[idx] = {};
                 return idx << 1;
             }}
             ",
-            set_slab_next
+            anyref, set_slab_next, entry
+        ));
+    }
+
+    /// With `--anyref` objects are stored in a wasm table when the engine
+    /// supports reference types, which `initAnyrefTable` detects once the
+    /// module is instantiated.
+    fn expose_anyref_table(&mut self) {
+        if !self.exposed_globals.insert("anyref_table") {
+            return;
+        }
+        let reserved = INITIAL_SLAB_VALUES
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("anyrefTable.set({}, {});", i << 1, v))
+            .collect::<Vec<_>>();
+        self.global(&format!(
+            "
+            let anyrefTable;
+            function initAnyrefTable() {{
+                anyrefTable = wasm.{};
+                if (anyrefTable === undefined) return;
+                {}
+            }}
+            ",
+            anyref::TABLE,
+            reserved.join("\n"),
+        ));
+    }
+
+    fn record_anyref_import(&mut self, name: &str, signature: anyref::Signature) {
+        if !signature.is_empty() {
+            self.anyref.imports.insert(name.to_string(), signature);
+        }
+    }
+
+    fn record_anyref_export(&mut self, name: &str, signature: anyref::Signature) {
+        if !signature.is_empty() {
+            self.anyref.exports.insert(name.to_string(), signature);
+        }
+    }
+
+    /// Returns `body` wrapped to only run when objects are stored in the
+    /// wasm table, or nothing without `--anyref`.
+    fn anyref_branch(&mut self, body: &str) -> String {
+        if !self.config.anyref {
+            return String::new();
+        }
+        self.expose_anyref_table();
+        format!("if (anyrefTable !== undefined) {{\n{}\n}}", body.trim())
+    }
+
+    /// Exposes one of the functions converting a `JsValue` passed directly
+    /// as an `anyref`. These are the identity when the wasm table is used,
+    /// and otherwise fall back to the slab.
+    fn expose_anyref_conversion(&mut self, name: &'static str) -> &'static str {
+        if !self.exposed_globals.insert(name) {
+            return name;
+        }
+        self.expose_anyref_table();
+        if name == "popBorrowedAnyref" {
+            self.expose_borrowed_objects();
+            self.global(
+                "
+                function popBorrowedAnyref() {
+                    if (anyrefTable === undefined) popBorrowedObject();
+                }
+                ",
+            );
+            return name;
+        }
+        let fallback = match name {
+            "takeAnyref" => {
+                self.expose_take_object();
+                "takeObject"
+            }
+            "getAnyref" => {
+                self.expose_get_object();
+                "getObject"
+            }
+            "addAnyref" => {
+                self.expose_add_heap_object();
+                "addHeapObject"
+            }
+            "addBorrowedAnyref" => {
+                self.expose_borrowed_objects();
+                "addBorrowedObject"
+            }
+            _ => panic!("unknown anyref conversion `{}`", name),
+        };
+        self.global(&format!(
+            "
+            function {}(val) {{
+                return anyrefTable === undefined ? {}(val) : val;
+            }}
+            ",
+            name, fallback,
         ));
+        name
     }
 
     fn wasm_import_needed(&self, name: &str) -> bool {
@@ -1451,42 +2357,26 @@ impl<'a> Context<'a> {
             .any(|i| i.module() == "__wbindgen_placeholder__" && i.field() == name)
     }
 
-    fn pass_to_wasm_function(&mut self, t: VectorKind) -> Result<&'static str, Error> {
-        let s = match t {
-            VectorKind::String => {
-                self.expose_pass_string_to_wasm()?;
-                "passStringToWasm"
-            }
+    /// Returns the function passing vectors of type `t` to wasm, `borrowed`
+    /// being whether Rust only borrows them.
+    fn pass_to_wasm_function(
+        &mut self,
+        t: VectorKind,
+        borrowed: bool,
+    ) -> Result<&'static str, Error> {
+        let borrowed = borrowed && self.config.borrowed_malloc.is_some();
+        match t {
+            VectorKind::String => self.expose_pass_string_to_wasm(borrowed),
             VectorKind::I8 | VectorKind::U8 | VectorKind::ClampedU8 => {
-                self.expose_pass_array8_to_wasm()?;
-                "passArray8ToWasm"
-            }
-            VectorKind::U16 | VectorKind::I16 => {
-                self.expose_pass_array16_to_wasm()?;
-                "passArray16ToWasm"
-            }
-            VectorKind::I32 | VectorKind::U32 => {
-                self.expose_pass_array32_to_wasm()?;
-                "passArray32ToWasm"
+                self.expose_pass_array8_to_wasm(borrowed)
             }
-            VectorKind::I64 | VectorKind::U64 => {
-                self.expose_pass_array64_to_wasm()?;
-                "passArray64ToWasm"
-            }
-            VectorKind::F32 => {
-                self.expose_pass_array_f32_to_wasm()?;
-                "passArrayF32ToWasm"
-            }
-            VectorKind::F64 => {
-                self.expose_pass_array_f64_to_wasm()?;
-                "passArrayF64ToWasm"
-            }
-            VectorKind::Anyref => {
-                self.expose_pass_array_jsvalue_to_wasm()?;
-                "passArrayJsValueToWasm"
-            }
-        };
-        Ok(s)
+            VectorKind::U16 | VectorKind::I16 => self.expose_pass_array16_to_wasm(borrowed),
+            VectorKind::I32 | VectorKind::U32 => self.expose_pass_array32_to_wasm(borrowed),
+            VectorKind::I64 | VectorKind::U64 => self.expose_pass_array64_to_wasm(borrowed),
+            VectorKind::F32 => self.expose_pass_array_f32_to_wasm(borrowed),
+            VectorKind::F64 => self.expose_pass_array_f64_to_wasm(borrowed),
+            VectorKind::Anyref => self.expose_pass_array_jsvalue_to_wasm(borrowed),
+        }
     }
 
     fn expose_get_vector_from_wasm(&mut self, ty: VectorKind) -> &'static str {
@@ -1655,26 +2545,47 @@ impl<'a> Context<'a> {
         ");
     }
 
-    fn expose_cleanup_groups(&mut self) {
-        if !self.exposed_globals.insert("cleanup_groups") {
+    fn expose_cleanup(&mut self) {
+        if !self.exposed_globals.insert("cleanup") {
             return
         }
+        // Engines without `FinalizationRegistry` get a no-op registry, in
+        // which case objects must be freed manually with `free()` as usual.
         self.global(
             "
-                const CLEANUPS = new WeakRefGroup(x => x.holdings());
-                const CLEANUPS_MAP = new Map();
+                const CLEANUPS = (typeof FinalizationRegistry === 'undefined')
+                    ? { register: () => {}, unregister: () => {} }
+                    : new FinalizationRegistry(free => free());
 
                 function addCleanup(obj, ptr, free) {
-                    const ref = CLEANUPS.makeRef(obj, () => free(ptr));
-                    CLEANUPS_MAP.set(ptr, ref);
+                    CLEANUPS.register(obj, () => free(ptr), obj);
+                }
+
+                function removeCleanup(obj) {
+                    CLEANUPS.unregister(obj);
                 }
             "
         );
     }
 
+    /// Returns the JS statement cancelling automatic cleanup of `obj`, needed
+    /// whenever its pointer is freed manually or handed back to Rust.
+    fn remove_cleanup(&mut self, obj: &str) -> String {
+        if !self.config.weak_refs {
+            return String::new();
+        }
+        self.expose_cleanup();
+        format!("removeCleanup({});", obj)
+    }
+
     fn gc(&mut self) -> Result<(), Error> {
         self.parse_wasm_names();
         let module = mem::replace(self.module, Module::default());
+        let before = if self.config.size_report.is_some() {
+            Some(parity_wasm::serialize(module.clone())?.len())
+        } else {
+            None
+        };
         let result = wasm_gc::Config::new()
             .demangle(self.config.demangle)
             .keep_debug(self.config.keep_debug || self.config.debug)
@@ -1683,6 +2594,10 @@ impl<'a> Context<'a> {
             Ok(m) => m,
             Err(result) => deserialize_buffer(&result.into_bytes()?)?,
         };
+        if let Some(before) = before {
+            let after = parity_wasm::serialize(self.module.clone())?.len();
+            self.size_report.gc(before, after);
+        }
         Ok(())
     }
 
@@ -1692,10 +2607,28 @@ impl<'a> Context<'a> {
         *self.module = module;
     }
 
-    fn describe(&mut self, name: &str) -> Option<Descriptor> {
+    /// Interprets and decodes the descriptor of the export or import `name`,
+    /// with `item` naming it in errors.
+    fn describe(&mut self, name: &str, item: &str) -> Result<Option<Descriptor>, Error> {
         let name = format!("__wbindgen_describe_{}", name);
-        let descriptor = self.interpreter.interpret_descriptor(&name, self.module)?;
-        Some(Descriptor::decode(descriptor))
+        let cached = match self.cache {
+            Some(ref mut cache) => cache.descriptor(self.module, &name),
+            None => None,
+        };
+        if let Some(words) = cached {
+            return Ok(Descriptor::decode(&words).map(Some).with_context(|_| describe_failed(item))?);
+        }
+        let result = match self.interpreter.interpret_descriptor(&name, self.module) {
+            Ok(Some(d)) => {
+                if let Some(ref mut cache) = self.cache {
+                    cache.insert_descriptor(&name, d);
+                }
+                Descriptor::decode(d).map(Some)
+            }
+            Ok(None) => return Ok(None),
+            Err(e) => Err(e.into()),
+        };
+        Ok(result.with_context(|_| describe_failed(item))?)
     }
 
     fn global(&mut self, s: &str) {
@@ -1710,6 +2643,12 @@ impl<'a> Context<'a> {
         self.globals.push_str("\n");
     }
 
+    /// Whether exports are attached to a global rather than using a module
+    /// system, which is the case for `--no-modules` and `--target worker`.
+    fn no_modules(&self) -> bool {
+        self.config.no_modules || self.config.worker
+    }
+
     fn use_node_require(&self) -> bool {
         self.config.nodejs && !self.config.nodejs_experimental_modules
     }
@@ -1782,20 +2721,49 @@ impl<'a, 'b> SubContext<'a, 'b> {
         if let Some(ref class) = export.class {
             return self.generate_export_for_class(class, export);
         }
+        if export.start {
+            return self.cx.add_start_function(&export.function.name);
+        }
 
-        let descriptor = match self.cx.describe(&export.function.name) {
+        let descriptor = match self.cx.describe(&export.function.name, &format!("the export `{}`", export.function.name))? {
             None => return Ok(()),
             Some(d) => d,
         };
 
-        let (js, ts, js_doc) = Js2Rust::new(&export.function.name, self.cx)
-            .process(descriptor.unwrap_function())?
-            .finish("function", &format!("wasm.{}", export.function.name));
-        self.cx.export(
-            &export.function.name,
-            &js,
-            Some(format_doc_comments(&export.comments, Some(js_doc))),
-        );
+        // Split exports are called through the exports of their chunk.
+        let invoc = match export.split {
+            Some(ref chunk) => {
+                self.cx
+                    .split
+                    .entry(chunk.clone())
+                    .or_insert_with(Vec::new)
+                    .push(export.function.name.clone());
+                format!("chunk.{}", export.function.name)
+            }
+            None => format!("wasm.{}", export.function.name),
+        };
+        let (js, ts, js_doc, anyref) = {
+            let mut builder = Js2Rust::new(&export.function.name, self.cx);
+            builder
+                .anyref(true)
+                .split(export.split.as_ref().map(|s| &s[..]))
+                .process(descriptor.unwrap_function())?;
+            let (js, ts, js_doc) = builder.finish("function", &invoc);
+            (js, ts, js_doc, builder.anyref_signature())
+        };
+        self.cx.record_anyref_export(&export.function.name, anyref);
+        let mut comments = format_doc_comments(&export.comments, Some(js_doc));
+        if self.cx.config.size_report.is_some() {
+            self.cx.size_report.export(
+                self.cx.module,
+                &export.function.name,
+                None,
+                &export.function.name,
+                &format!("{}{}", comments, js),
+            );
+        }
+        comments.push_str(&self.cx.source_marker(&export.function));
+        self.cx.export(&export.function.name, &js, Some(comments));
         self.cx.globals.push_str("\n");
         self.cx.typescript.push_str("export ");
         self.cx.typescript.push_str(&ts);
@@ -1810,7 +2778,7 @@ impl<'a, 'b> SubContext<'a, 'b> {
     ) -> Result<(), Error> {
         let wasm_name = shared::struct_function_export_name(class_name, &export.function.name);
 
-        let descriptor = match self.cx.describe(&wasm_name) {
+        let descriptor = match self.cx.describe(&wasm_name, &format!("the export `{}.{}`", class_name, export.function.name))? {
             None => return Ok(()),
             Some(d) => d,
         };
@@ -1820,20 +2788,36 @@ impl<'a, 'b> SubContext<'a, 'b> {
         } else {
             &export.function.name
         };
-        let (js, ts, js_doc) = Js2Rust::new(function_name, self.cx)
-            .method(export.method, export.consumed)
-            .constructor(if export.is_constructor { Some(class_name) } else { None })
-            .process(descriptor.unwrap_function())?
-            .finish("", &format!("wasm.{}", wasm_name));
+        let (js, ts, js_doc, anyref) = {
+            let mut builder = Js2Rust::new(function_name, self.cx);
+            builder
+                .anyref(true)
+                .method(export.method, export.consumed)
+                .constructor(if export.is_constructor { Some(class_name) } else { None })
+                .process(descriptor.unwrap_function())?;
+            let (js, ts, js_doc) = builder.finish("", &format!("wasm.{}", wasm_name));
+            (js, ts, js_doc, builder.anyref_signature())
+        };
+        self.cx.record_anyref_export(&wasm_name, anyref);
+        let marker = self.cx.source_marker(&export.function);
+        let doc = format_doc_comments(&export.comments, Some(js_doc));
+        if self.cx.config.size_report.is_some() {
+            self.cx.size_report.export(
+                self.cx.module,
+                &export.function.name,
+                Some(class_name),
+                &wasm_name,
+                &format!("{}{}{}", doc, function_name, js),
+            );
+        }
 
         let class = self
             .cx
             .exported_classes
             .entry(class_name.to_string())
             .or_insert(ExportedClass::default());
-        class
-            .contents
-            .push_str(&format_doc_comments(&export.comments, Some(js_doc)));
+        class.contents.push_str(&doc);
+        class.contents.push_str(&marker);
 
         if export.is_constructor {
             if class.has_constructor {
@@ -1896,18 +2880,24 @@ impl<'a, 'b> SubContext<'a, 'b> {
         // TODO: should support more types to import here
         let obj = self.import_name(info, &import.name)?;
         self.cx.expose_add_heap_object();
-        self.cx.export(
-            &import.shim,
-            &format!(
-                "
-                function() {{
-                    return addHeapObject({});
-                }}
-                ",
-                obj
-            ),
-            None,
+        let js = format!(
+            "
+            function() {{
+                return addHeapObject({});
+            }}
+            ",
+            obj
         );
+        if self.cx.config.size_report.is_some() {
+            self.cx.size_report.import(
+                self.cx.module,
+                "static",
+                &import.name,
+                &import.shim,
+                &js,
+            );
+        }
+        self.cx.export(&import.shim, &js, None);
         Ok(())
     }
 
@@ -1926,19 +2916,34 @@ impl<'a, 'b> SubContext<'a, 'b> {
             return Ok(());
         }
 
-        let descriptor = match self.cx.describe(&import.shim) {
+        let descriptor = match self.cx.describe(&import.shim, &format!("the import `{}`", import.function.name))? {
             None => return Ok(()),
             Some(d) => d,
         };
 
         let target = self.generated_import_target(info, import, &descriptor)?;
 
-        let js = Rust2Js::new(self.cx)
-            .catch(import.catch)
-            .variadic(import.variadic)
-            .process(descriptor.unwrap_function())?
-            .finish(&target)?;
-        self.cx.export(&import.shim, &js, None);
+        let (js, anyref) = {
+            let mut builder = Rust2Js::new(self.cx);
+            builder
+                .anyref(true)
+                .catch(import.catch)
+                .variadic(import.variadic)
+                .process(descriptor.unwrap_function())?;
+            (builder.finish(&target)?, builder.anyref_signature())
+        };
+        self.cx.record_anyref_import(&import.shim, anyref);
+        if self.cx.config.size_report.is_some() {
+            self.cx.size_report.import(
+                self.cx.module,
+                "import",
+                &import.function.name,
+                &import.shim,
+                &js,
+            );
+        }
+        let marker = self.cx.source_marker(&import.function);
+        self.cx.export(&import.shim, &js, Some(marker));
         Ok(())
     }
 
@@ -1967,8 +2972,22 @@ impl<'a, 'b> SubContext<'a, 'b> {
         };
 
         let class = self.import_name(info, &method_data.class)?;
+        let has_fallbacks = self.import_fallbacks(info, &method_data.class).is_some();
         let op = match &method_data.kind {
-            shared::MethodKind::Constructor => return Ok(format!("new {}", class)),
+            shared::MethodKind::Constructor => {
+                if !has_fallbacks {
+                    return Ok(format!("new {}", class));
+                }
+                // Neither the type nor any of its fallbacks may exist, in
+                // which case constructing it throws like its methods do.
+                self.cx.global(&format!(
+                    "const {}_target = {} !== undefined ? {} : function() {{
+                        throw new Error(`wasm-bindgen: {} does not exist`);
+                    }};",
+                    import.shim, class, class, method_data.class,
+                ));
+                return Ok(format!("new {}_target", import.shim));
+            }
             shared::MethodKind::Operation(op) => op,
         };
         let target = if import.structural {
@@ -2058,14 +3077,30 @@ impl<'a, 'b> SubContext<'a, 'b> {
             }
         };
 
+        // Types with fallbacks may not exist at all in the current
+        // environment, so avoid touching their prototype until they're known
+        // to exist. Calling such an import then throws instead of failing
+        // when this module is evaluated.
+        let target = if !import.structural && has_fallbacks {
+            format!("({} === undefined ? undefined : {})", class, target)
+        } else {
+            target
+        };
+
         let fallback = if import.structural {
             "".to_string()
         } else {
+            let member = match &op.kind {
+                shared::OperationKind::Regular => &import.function.name,
+                shared::OperationKind::Getter(g) => g,
+                shared::OperationKind::Setter(s) => s,
+                _ => unreachable!(),
+            };
             format!(
                 " || function() {{
-                    throw new Error(`wasm-bindgen: {} does not exist`);
+                    throw new Error(`wasm-bindgen: {}.{} does not exist`);
                 }}",
-                target
+                method_data.class, member,
             )
         };
 
@@ -2090,12 +3125,17 @@ impl<'a, 'b> SubContext<'a, 'b> {
         }
         let name = self.import_name(info, &import.name)?;
         self.cx.expose_get_object();
+        let check = if self.import_fallbacks(info, &import.name).is_some() {
+            format!("{0} !== undefined && getObject(idx) instanceof {0}", name)
+        } else {
+            format!("getObject(idx) instanceof {}", name)
+        };
         let body = format!("
                 function(idx) {{
-                    return getObject(idx) instanceof {} ? 1 : 0;
+                    return {} ? 1 : 0;
                 }}
             ",
-            name,
+            check,
         );
         self.cx.export(&import.instanceof_shim, &body, None);
         Ok(())
@@ -2127,11 +3167,12 @@ impl<'a, 'b> SubContext<'a, 'b> {
     fn import_name(&mut self, import: &shared::Import, item: &str) -> Result<String, Error> {
         // First up, imports don't work at all in `--no-modules` mode as we're
         // not sure how to import them.
-        if self.cx.config.no_modules {
+        if self.cx.no_modules() {
             if let Some(module) = &import.module {
                 bail!(
-                    "import from `{}` module not allowed with `--no-modules`; \
-                     use `--nodejs` or `--browser` instead",
+                    "import from `{}` module not allowed with `--no-modules` or \
+                     `--target worker`; use `--nodejs`, `--browser` or \
+                     `--target web` instead",
                     module
                 );
             }
@@ -2156,12 +3197,44 @@ impl<'a, 'b> SubContext<'a, 'b> {
         // generate a new identifier and are sure to generate the appropriate JS
         // import for our new identifier.
         let use_node_require = self.cx.use_node_require();
+        let fallbacks = self.import_fallbacks(import, name_to_import).cloned();
+        let namespace = match (&fallbacks, &import.module) {
+            (Some(_), Some(module)) => Some(self.import_namespace(module)),
+            _ => None,
+        };
         let imported_identifiers = &mut self.cx.imported_identifiers;
         let imports = &mut self.cx.imports;
+        let globals = &mut self.cx.globals;
         let identifier = self.cx.imported_names.entry(import.module.clone())
             .or_insert_with(Default::default)
             .entry(name_to_import.to_string())
             .or_insert_with(|| {
+                // Types with fallbacks resolve to the first of their own name
+                // and the names they fall back on which exists when this
+                // module is loaded, or `undefined` if none of them do.
+                if let Some(fallbacks) = fallbacks {
+                    let name = generate_identifier(
+                        &format!("__wbg_{}", name_to_import),
+                        imported_identifiers,
+                    );
+                    let mut candidates = vec![name_to_import.to_string()];
+                    candidates.extend(fallbacks);
+                    let mut switch = String::from("undefined");
+                    for candidate in candidates.iter().rev() {
+                        switch = match namespace {
+                            Some(ref namespace) => format!(
+                                "{0}.{1} !== undefined ? {0}.{1} : {2}",
+                                namespace, candidate, switch,
+                            ),
+                            None => format!(
+                                "typeof {0} !== 'undefined' ? {0} : {1}",
+                                candidate, switch,
+                            ),
+                        };
+                    }
+                    globals.push_str(&format!("const {} = {};\n", name, switch));
+                    return name;
+                }
                 let name = generate_identifier(name_to_import, imported_identifiers);
                 if let Some(module) = &import.module {
                     if use_node_require {
@@ -2192,6 +3265,41 @@ impl<'a, 'b> SubContext<'a, 'b> {
             Ok(identifier.to_string())
         }
     }
+
+    /// Returns the names `item`, imported via `import`, falls back on if it
+    /// doesn't exist, if it was declared with any.
+    fn import_fallbacks(&self, import: &shared::Import, item: &str) -> Option<&Vec<String>> {
+        if import.js_namespace.is_some() {
+            return None;
+        }
+        self.cx.import_fallbacks.get(&(import.module.clone(), item.to_string()))
+    }
+
+    /// Imports all of `module` as a namespace, returning its identifier.
+    fn import_namespace(&mut self, module: &str) -> String {
+        let use_node_require = self.cx.use_node_require();
+        let imported_identifiers = &mut self.cx.imported_identifiers;
+        let imports = &mut self.cx.imports;
+        self.cx.imported_names.entry(Some(module.to_string()))
+            .or_insert_with(Default::default)
+            .entry("*".to_string())
+            .or_insert_with(|| {
+                let name = generate_identifier("__wbg_module", imported_identifiers);
+                if use_node_require {
+                    imports.push_str(&format!(
+                        "const {} = require(String.raw`{}`);\n",
+                        name, module
+                    ));
+                } else {
+                    imports.push_str(&format!(
+                        "import * as {} from '{}';\n",
+                        name, module
+                    ));
+                }
+                name
+            })
+            .clone()
+    }
 }
 
 fn generate_identifier(name: &str, used_names: &mut HashMap<String, usize>) -> String {
@@ -2216,3 +3324,20 @@ fn format_doc_comments(comments: &Vec<String>, js_doc_comments: Option<String>)
     };
     format!("/**\n{}{}*/\n", body, doc)
 }
+
+/// Error message for a descriptor of `item` which couldn't be interpreted or
+/// decoded, which is most often due to mismatched versions of wasm-bindgen.
+fn describe_failed(item: &str) -> String {
+    format!(
+        "failed to determine the signature of {}
+
+this usually means that the wasm file was built against a version of the
+`wasm-bindgen` crate this binary ({}) can't read, for example because two
+crates in the dependency graph depend on different versions of it. This binary
+reads wasm files built with `wasm-bindgen` {} up to its own version, so
+make sure `cargo tree` lists a single version of `wasm-bindgen` in that range",
+        item,
+        shared::version(),
+        shared::oldest_release(),
+    )
+}
@@ -1,5 +1,6 @@
 use failure::{self, Error};
 
+use super::anyref::Signature;
 use super::{Context, Js2Rust};
 use descriptor::{Descriptor, Function};
 
@@ -39,6 +40,17 @@ pub struct Rust2Js<'a, 'b: 'a> {
 
     /// Whether or not the last argument is a slice representing variadic arguments.
     variadic: bool,
+
+    /// Whether `JsValue`s are passed directly as `anyref`s, which requires
+    /// the wasm import to be rewritten by `--anyref`.
+    anyref: bool,
+
+    /// Shim arguments which are passed as `anyref`s, and whether ownership of
+    /// each is transferred.
+    anyref_args: Vec<(String, bool)>,
+
+    /// Whether the return value is passed as an `anyref`.
+    anyref_ret: bool,
 }
 
 impl<'a, 'b> Rust2Js<'a, 'b> {
@@ -54,6 +66,9 @@ impl<'a, 'b> Rust2Js<'a, 'b> {
             ret_expr: String::new(),
             catch: false,
             variadic: false,
+            anyref: false,
+            anyref_args: Vec::new(),
+            anyref_ret: false,
         }
     }
 
@@ -66,6 +81,32 @@ impl<'a, 'b> Rust2Js<'a, 'b> {
         self
     }
 
+    /// Pass `JsValue`s directly as `anyref`s if `--anyref` is enabled.
+    ///
+    /// The caller must record the resulting `anyref_signature` so the wasm
+    /// import gets a matching shim.
+    pub fn anyref(&mut self, anyref: bool) -> &mut Self {
+        self.anyref = anyref && self.cx.config.anyref;
+        self
+    }
+
+    /// Returns which arguments of the wasm import, and whether its return
+    /// value, are passed as `anyref`s.
+    pub fn anyref_signature(&self) -> Signature {
+        let args = self
+            .anyref_args
+            .iter()
+            .map(|&(ref arg, owned)| {
+                let i = self.shim_arguments.iter().position(|a| a == arg).unwrap();
+                (i, owned)
+            })
+            .collect();
+        Signature {
+            args,
+            ret: self.anyref_ret,
+        }
+    }
+
     pub fn variadic(&mut self, variadic: bool) -> &mut Self {
         if variadic {
             self.cx.expose_uint32_memory();
@@ -136,16 +177,50 @@ impl<'a, 'b> Rust2Js<'a, 'b> {
         // No need to special case `optional` here because `takeObject` will
         // naturally work.
         if arg.is_anyref() {
-            self.cx.expose_take_object();
-            self.js_arguments.push(format!("takeObject({})", abi));
+            let f = if self.anyref && self.catch {
+                // The wasm shim can't release the value if the import throws,
+                // so take it by index and release it in a `finally` instead.
+                self.cx.expose_get_object();
+                self.cx.expose_drop_ref();
+                self.finally(&format!("dropRef({});", abi));
+                "getObject"
+            } else if self.anyref {
+                self.anyref_args.push((abi.clone(), true));
+                self.cx.expose_anyref_conversion("takeAnyref")
+            } else {
+                self.cx.expose_take_object();
+                "takeObject"
+            };
+            self.js_arguments.push(format!("{}({})", f, abi));
             return Ok(())
         } else if arg.is_ref_anyref() {
-            self.cx.expose_get_object();
-            self.js_arguments.push(format!("getObject({})", abi));
+            let f = if self.anyref {
+                self.anyref_args.push((abi.clone(), false));
+                self.cx.expose_anyref_conversion("getAnyref")
+            } else {
+                self.cx.expose_get_object();
+                "getObject"
+            };
+            self.js_arguments.push(format!("{}({})", f, abi));
             return Ok(())
         }
 
         if optional {
+            if let Some(class) = arg.rust_struct() {
+                if arg.is_by_ref() {
+                    bail!("cannot invoke JS functions with custom ref types yet")
+                }
+                self.cx.require_class_wrap(class);
+                let assign = format!(
+                    "let c{0} = {0} === 0 ? undefined : {1}.__wrap({0});",
+                    abi,
+                    class,
+                );
+                self.prelude(&assign);
+                self.js_arguments.push(format!("c{}", abi));
+                return Ok(());
+            }
+
             if arg.is_wasm_native() {
                 let value = self.shim_argument();
                 self.js_arguments.push(format!(
@@ -299,7 +374,7 @@ impl<'a, 'b> Rust2Js<'a, 'b> {
             bail!("cannot return a reference from JS to Rust")
         }
         if let Some(ty) = ty.vector_kind() {
-            let f = self.cx.pass_to_wasm_function(ty)?;
+            let f = self.cx.pass_to_wasm_function(ty, false)?;
             self.cx.expose_uint32_memory();
             self.shim_arguments.insert(0, "ret".to_string());
             let mut prelude = String::new();
@@ -324,6 +399,12 @@ impl<'a, 'b> Rust2Js<'a, 'b> {
             return Ok(());
         }
         if ty.is_anyref() {
+            if self.anyref && !optional {
+                let f = self.cx.expose_anyref_conversion("addAnyref");
+                self.ret_expr = format!("return {}(JS);", f);
+                self.anyref_ret = true;
+                return Ok(())
+            }
             self.cx.expose_add_heap_object();
             if optional {
                 self.cx.expose_is_like_none();
@@ -337,6 +418,32 @@ impl<'a, 'b> Rust2Js<'a, 'b> {
             return Ok(())
         }
         if optional {
+            if let Some(class) = ty.rust_struct() {
+                self.cx.expose_is_like_none();
+                // A moved value would otherwise silently turn into `None`.
+                self.ret_expr = format!(
+                    "
+                    const val = JS;
+                    if (isLikeNone(val)) {{
+                        return 0;
+                    }}
+                    if (!(val instanceof {0})) {{
+                        throw new Error('expected value of type {0}');
+                    }}
+                    const ret = val.ptr;
+                    if (ret === 0) {{
+                        throw new Error('Attempt to use a moved value');
+                    }}
+                    val.ptr = 0;
+                    {1}
+                    return ret;
+                    ",
+                    class,
+                    self.cx.remove_cleanup("val"),
+                );
+                return Ok(());
+            }
+
             if ty.is_wasm_native() {
                 self.cx.expose_is_like_none();
                 self.cx.expose_uint32_memory();
@@ -464,9 +571,11 @@ impl<'a, 'b> Rust2Js<'a, 'b> {
                 }}
                 const ret = val.ptr;
                 val.ptr = 0;
+                {1}
                 return ret;\
             ",
-                class
+                class,
+                self.cx.remove_cleanup("val"),
             );
             return Ok(());
         }
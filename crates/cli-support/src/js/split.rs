@@ -0,0 +1,500 @@
+//! Support for splitting exports into separately loaded wasm modules
+//!
+//! Exports marked with `#[wasm_bindgen(split = "name")]` are moved, along with
+//! every function only they use, into a chunk `*_bg_name.wasm` which the JS
+//! glue fetches and instantiates the first time one of them is called. Each
+//! chunk imports everything else it needs from the main module, which exports
+//! it for that purpose:
+//!
+//! * the memory and table, so both modules share them,
+//! * immutable globals used by the chunk, while mutable ones like the stack
+//!   pointer are accessed through functions getting and setting them, as not
+//!   all engines support importing and exporting mutable globals,
+//! * functions the chunk calls but which stay in the main module, including
+//!   the main module's own imports.
+//!
+//! Output which can't be split keeps everything in the main module, with the
+//! JS glue of split exports still returning a `Promise`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem;
+
+use failure::Error;
+use parity_wasm;
+use parity_wasm::elements::*;
+use wasm_gc;
+
+use Bindgen;
+
+/// Module name chunks import the exports of the main module from.
+pub const MAIN: &str = "__wbindgen_main";
+
+/// Whether exports are split into chunks with the current output, which
+/// requires JS glue that loads the wasm file itself. The wasm file of
+/// multi-target output is shared with bundlers, so it's never split.
+pub fn supported(config: &Bindgen) -> bool {
+    if config.anyref || config.threads || config.nodejs_experimental_modules || config.multi_target {
+        return false;
+    }
+    config.nodejs || config.web || config.no_modules || config.worker
+}
+
+/// Moves the exports of each chunk in `chunks` out of `module`, returning the
+/// module for each chunk.
+pub fn split(
+    module: &mut Module,
+    chunks: &BTreeMap<String, Vec<String>>,
+    config: &Bindgen,
+) -> Result<Vec<(String, Module)>, Error> {
+    if chunks.is_empty() || !supported(config) {
+        return Ok(Vec::new());
+    }
+    // Chunks take the names of the functions moved into them.
+    let parsed = mem::replace(module, Module::default()).parse_names();
+    *module = parsed.unwrap_or_else(|p| p.1);
+    let imported = module.import_count(ImportCountType::Function) as u32;
+    let calls = calls(module, imported);
+
+    // The split exports are removed from the main module, the chunks export
+    // them instead.
+    let chunks = chunks
+        .iter()
+        .map(|(name, exports)| {
+            let exports = exports
+                .iter()
+                .map(|e| Ok((e.clone(), find_export(module, e)?)))
+                .collect::<Result<Vec<_>, Error>>()?;
+            Ok((name, exports))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    if let Some(s) = module.export_section_mut() {
+        s.entries_mut().retain(|e| {
+            !chunks
+                .iter()
+                .any(|&(_, ref exports)| exports.iter().any(|&(ref name, _)| name == e.field()))
+        });
+    }
+
+    // Everything reachable from what remains in the main module stays there.
+    let mut roots = Vec::new();
+    if let Some(s) = module.export_section() {
+        for e in s.entries() {
+            if let Internal::Function(i) = *e.internal() {
+                roots.push(i);
+            }
+        }
+    }
+    if let Some(s) = module.elements_section() {
+        for segment in s.entries() {
+            roots.extend(segment.members());
+        }
+    }
+    if let Some(start) = module.start_section() {
+        roots.push(start);
+    }
+    let main = reachable(&calls, roots);
+
+    // Functions used by several chunks stay in the main module as well.
+    let mut owner = HashMap::new();
+    for &(name, ref exports) in chunks.iter() {
+        let roots = exports.iter().map(|&(_, f)| f).collect();
+        for f in reachable(&calls, roots) {
+            if f < imported || main.contains(&f) {
+                continue;
+            }
+            owner
+                .entry(f)
+                .and_modify(|o| *o = None)
+                .or_insert(Some(&name[..]));
+        }
+    }
+
+    let mut ret = Vec::new();
+    for &(name, ref exports) in chunks.iter() {
+        let moved = owner
+            .iter()
+            .filter(|&(_, o)| *o == Some(&name[..]))
+            .map(|(&f, _)| f)
+            .collect::<BTreeSet<_>>();
+        let chunk = create_chunk(module, imported, &moved, exports)?;
+        ret.push((name.clone(), gc(chunk, config)?));
+    }
+
+    // What the chunks took over is no longer used by the main module.
+    *module = gc(mem::replace(module, Module::default()), config)?;
+    Ok(ret)
+}
+
+fn create_chunk(
+    module: &mut Module,
+    imported: u32,
+    moved: &BTreeSet<u32>,
+    exports: &[(String, u32)],
+) -> Result<Module, Error> {
+    let mut imports = Vec::new();
+
+    // The memory is shared with the main module, as is the table if the
+    // chunk makes indirect calls.
+    let memory = match memory_type(module) {
+        Some(ty) => ty,
+        None => bail!("wasm module has no memory to share with split exports"),
+    };
+    let field = export_internal(module, Internal::Memory(0), "__wbindgen_split_memory");
+    imports.push(ImportEntry::new(
+        MAIN.to_string(),
+        field,
+        External::Memory(memory),
+    ));
+    let bodies = module
+        .code_section()
+        .map(|s| s.bodies().to_vec())
+        .unwrap_or_default();
+    let call_indirect = moved.iter().any(|&f| {
+        bodies[(f - imported) as usize]
+            .code()
+            .elements()
+            .iter()
+            .any(|instr| match *instr {
+                Instruction::CallIndirect(..) => true,
+                _ => false,
+            })
+    });
+    let table = if call_indirect { table_type(module) } else { None };
+    if let Some(table) = table {
+        let field = export_internal(module, Internal::Table(0), "__wbindgen_split_table");
+        imports.push(ImportEntry::new(
+            MAIN.to_string(),
+            field,
+            External::Table(table),
+        ));
+    }
+
+    // Then the globals and functions the chunk uses, which are imported in
+    // the order of their index in the main module.
+    let global_types = global_types(module);
+    let func_types = func_types(module);
+    let mut globals = BTreeMap::new();
+    let mut mutable_globals = BTreeMap::new();
+    let mut funcs = BTreeMap::new();
+    let mut used_funcs = BTreeSet::new();
+    for &f in moved {
+        for instr in bodies[(f - imported) as usize].code().elements() {
+            match *instr {
+                Instruction::GetGlobal(g) | Instruction::SetGlobal(g) => {
+                    if global_types[g as usize].is_mutable() {
+                        mutable_globals.insert(g, (0, 0));
+                    } else {
+                        globals.insert(g, 0);
+                    }
+                }
+                Instruction::Call(f) if !moved.contains(&f) => {
+                    used_funcs.insert(f);
+                }
+                _ => {}
+            }
+        }
+    }
+    for &(_, f) in exports {
+        if !moved.contains(&f) {
+            used_funcs.insert(f);
+        }
+    }
+    for (i, (&g, new)) in globals.iter_mut().enumerate() {
+        *new = i as u32;
+        let field = export_internal(
+            module,
+            Internal::Global(g),
+            &format!("__wbindgen_split_global{}", g),
+        );
+        imports.push(ImportEntry::new(
+            MAIN.to_string(),
+            field,
+            External::Global(global_types[g as usize]),
+        ));
+    }
+    for (i, &f) in used_funcs.iter().enumerate() {
+        funcs.insert(f, i as u32);
+        let field = export_internal(
+            module,
+            Internal::Function(f),
+            &format!("__wbindgen_split_func{}", f),
+        );
+        imports.push(ImportEntry::new(
+            MAIN.to_string(),
+            field,
+            External::Function(func_types[f as usize]),
+        ));
+    }
+    let mut next = used_funcs.len() as u32;
+    for (&g, accessors) in mutable_globals.iter_mut() {
+        let ty = global_types[g as usize].content_type();
+        let get = add_function(
+            module,
+            FunctionType::new(Vec::new(), Some(ty)),
+            vec![Instruction::GetGlobal(g), Instruction::End],
+            &format!("__wbindgen_split_global{}_get", g),
+        );
+        let set = add_function(
+            module,
+            FunctionType::new(vec![ty], None),
+            vec![Instruction::GetLocal(0), Instruction::SetGlobal(g), Instruction::End],
+            &format!("__wbindgen_split_global{}_set", g),
+        );
+        for &(ref field, ty) in [get, set].iter() {
+            imports.push(ImportEntry::new(
+                MAIN.to_string(),
+                field.clone(),
+                External::Function(ty),
+            ));
+        }
+        *accessors = (next, next + 1);
+        next += 2;
+    }
+    for (i, &f) in moved.iter().enumerate() {
+        funcs.insert(f, next + i as u32);
+    }
+
+    let mut functions = Vec::new();
+    let mut code = Vec::new();
+    for &f in moved {
+        functions.push(Func::new(func_types[f as usize]));
+        let mut body = bodies[(f - imported) as usize].clone();
+        for instr in body.code_mut().elements_mut() {
+            let accessed = match *instr {
+                Instruction::GetGlobal(g) => mutable_globals.get(&g).map(|a| a.0),
+                Instruction::SetGlobal(g) => mutable_globals.get(&g).map(|a| a.1),
+                _ => None,
+            };
+            if let Some(accessor) = accessed {
+                *instr = Instruction::Call(accessor);
+                continue;
+            }
+            match *instr {
+                Instruction::GetGlobal(ref mut g) | Instruction::SetGlobal(ref mut g) => {
+                    *g = globals[&*g];
+                }
+                Instruction::Call(ref mut f) => *f = funcs[&*f],
+                _ => {}
+            }
+        }
+        code.push(body);
+    }
+    let exports = exports
+        .iter()
+        .map(|&(ref name, f)| ExportEntry::new(name.clone(), Internal::Function(funcs[&f])))
+        .collect();
+
+    let mut names = FunctionNameSection::default();
+    for section in module.sections() {
+        if let Section::Name(NameSection::Function(ref main)) = *section {
+            for &f in moved {
+                if let Some(name) = main.names().get(f) {
+                    names.names_mut().insert(funcs[&f], name.clone());
+                }
+            }
+        }
+    }
+
+    // Types are copied over wholesale, gc will remove those which are unused.
+    let types = module
+        .type_section()
+        .map(|s| s.types().to_vec())
+        .unwrap_or_default();
+    let mut sections = vec![
+        Section::Type(TypeSection::with_types(types)),
+        Section::Import(ImportSection::with_entries(imports)),
+        Section::Function(FunctionSection::with_entries(functions)),
+        Section::Export(ExportSection::with_entries(exports)),
+    ];
+    // `wasm-gc` only keeps imported tables which an element segment uses, so
+    // the chunk gets an empty one if it imports the table.
+    if table.is_some() {
+        let offset = InitExpr::new(vec![Instruction::I32Const(0), Instruction::End]);
+        let segment = ElementSegment::new(0, offset, Vec::new());
+        sections.push(Section::Element(ElementSection::with_entries(vec![segment])));
+    }
+    sections.push(Section::Code(CodeSection::with_bodies(code)));
+    if !names.names().is_empty() {
+        sections.push(Section::Name(NameSection::Function(names)));
+    }
+    Ok(Module::new(sections))
+}
+
+/// Returns the functions called by each function defined in `module`.
+fn calls(module: &Module, imported: u32) -> HashMap<u32, Vec<u32>> {
+    let mut ret = HashMap::new();
+    let bodies = match module.code_section() {
+        Some(s) => s.bodies(),
+        None => return ret,
+    };
+    for (i, body) in bodies.iter().enumerate() {
+        let callees = body
+            .code()
+            .elements()
+            .iter()
+            .filter_map(|instr| match *instr {
+                Instruction::Call(f) => Some(f),
+                _ => None,
+            })
+            .collect();
+        ret.insert(imported + i as u32, callees);
+    }
+    ret
+}
+
+fn reachable(calls: &HashMap<u32, Vec<u32>>, roots: Vec<u32>) -> BTreeSet<u32> {
+    let mut ret = BTreeSet::new();
+    let mut stack = roots;
+    while let Some(f) = stack.pop() {
+        if !ret.insert(f) {
+            continue;
+        }
+        if let Some(callees) = calls.get(&f) {
+            stack.extend(callees);
+        }
+    }
+    ret
+}
+
+fn find_export(module: &Module, name: &str) -> Result<u32, Error> {
+    let export = module
+        .export_section()
+        .and_then(|s| s.entries().iter().find(|e| e.field() == name));
+    match export.map(|e| e.internal()) {
+        Some(&Internal::Function(i)) => Ok(i),
+        _ => bail!("failed to find the exported function `{}`", name),
+    }
+}
+
+/// Returns the name `internal` is exported as from `module`, exporting it as
+/// `name` if it isn't yet.
+fn export_internal(module: &mut Module, internal: Internal, name: &str) -> String {
+    if module.export_section().is_none() {
+        module
+            .sections_mut()
+            .push(Section::Export(ExportSection::default()));
+    }
+    let exports = module.export_section_mut().unwrap().entries_mut();
+    if let Some(e) = exports.iter().find(|e| *e.internal() == internal) {
+        return e.field().to_string();
+    }
+    exports.push(ExportEntry::new(name.to_string(), internal));
+    name.to_string()
+}
+
+/// Adds a function of type `ty` running `code` to `module`, exporting it as
+/// `name`, unless an earlier chunk added it already. Returns the name of the
+/// export and the index of the type.
+fn add_function(
+    module: &mut Module,
+    ty: FunctionType,
+    code: Vec<Instruction>,
+    name: &str,
+) -> (String, u32) {
+    if let Ok(idx) = find_export(module, name) {
+        return (name.to_string(), func_types(module)[idx as usize]);
+    }
+    if module.type_section().is_none() {
+        module.sections_mut().push(Section::Type(TypeSection::default()));
+    }
+    let types = module.type_section_mut().unwrap().types_mut();
+    let ty = Type::Function(ty);
+    let type_idx = match types.iter().position(|t| *t == ty) {
+        Some(i) => i,
+        None => {
+            types.push(ty);
+            types.len() - 1
+        }
+    } as u32;
+
+    let idx = func_types(module).len() as u32;
+    module
+        .function_section_mut()
+        .expect("module with split exports has functions")
+        .entries_mut()
+        .push(Func::new(type_idx));
+    module
+        .code_section_mut()
+        .expect("module with split exports has code")
+        .bodies_mut()
+        .push(FuncBody::new(Vec::new(), Instructions::new(code)));
+    (export_internal(module, Internal::Function(idx), name), type_idx)
+}
+
+fn memory_type(module: &Module) -> Option<MemoryType> {
+    let imported = module.import_section().and_then(|s| {
+        s.entries()
+            .iter()
+            .filter_map(|i| match *i.external() {
+                External::Memory(ref ty) => Some(ty.clone()),
+                _ => None,
+            })
+            .next()
+    });
+    imported.or_else(|| {
+        module
+            .memory_section()
+            .and_then(|s| s.entries().get(0).cloned())
+    })
+}
+
+fn table_type(module: &Module) -> Option<TableType> {
+    let imported = module.import_section().and_then(|s| {
+        s.entries()
+            .iter()
+            .filter_map(|i| match *i.external() {
+                External::Table(ref ty) => Some(ty.clone()),
+                _ => None,
+            })
+            .next()
+    });
+    imported.or_else(|| {
+        module
+            .table_section()
+            .and_then(|s| s.entries().get(0).cloned())
+    })
+}
+
+/// Returns the type of every global in `module`, imported ones first.
+fn global_types(module: &Module) -> Vec<GlobalType> {
+    let mut ret = Vec::new();
+    if let Some(s) = module.import_section() {
+        for i in s.entries() {
+            if let External::Global(ty) = *i.external() {
+                ret.push(ty);
+            }
+        }
+    }
+    if let Some(s) = module.global_section() {
+        ret.extend(s.entries().iter().map(|g| *g.global_type()));
+    }
+    ret
+}
+
+/// Returns the type index of every function in `module`, imported ones
+/// first.
+fn func_types(module: &Module) -> Vec<u32> {
+    let mut ret = Vec::new();
+    if let Some(s) = module.import_section() {
+        for i in s.entries() {
+            if let External::Function(ty) = *i.external() {
+                ret.push(ty);
+            }
+        }
+    }
+    if let Some(s) = module.function_section() {
+        ret.extend(s.entries().iter().map(|f| f.type_ref()));
+    }
+    ret
+}
+
+fn gc(module: Module, config: &Bindgen) -> Result<Module, Error> {
+    let result = wasm_gc::Config::new()
+        .demangle(config.demangle)
+        .keep_debug(config.keep_debug || config.debug)
+        .run(module, |m| parity_wasm::serialize(m).unwrap())?;
+    Ok(match result.into_module() {
+        Ok(m) => m,
+        Err(result) => parity_wasm::deserialize_buffer(&result.into_bytes()?)?,
+    })
+}
@@ -0,0 +1,312 @@
+//! Support for multi-threaded wasm modules in wasm-bindgen
+//!
+//! With `--threads` the same wasm module is instantiated once per thread: on
+//! the main thread and in each worker of a thread pool. All instances share
+//! one imported `WebAssembly.Memory`, which needs a bit of preparation:
+//!
+//! * Active data segments are applied every time a module is instantiated,
+//!   so a worker starting up would clobber the statics of every other thread.
+//!   We instead move the segments to a custom section which the JS glue copies
+//!   into memory once, on the main thread.
+//!
+//! * Each instance has its own globals, but they're all initialized to the
+//!   same stack pointer. We inject an exported `__wbindgen_thread_init`
+//!   function which workers call to switch to a stack of their own.
+//!
+//! * Arguments are passed through a scratch area which each thread's JS glue
+//!   hands out with `__wbindgen_thread_scratch`. The thread spawning a worker
+//!   allocates it along with the worker's stack, before the worker runs any
+//!   code.
+//!
+//! * Thread local storage lives at `__tls_base`, another global. Every
+//!   thread allocates a block of `__tls_size` bytes of its own and calls
+//!   `__wasm_init_tls` to copy the initial values into it: the main thread
+//!   when it initializes the shared memory, and workers when they start up,
+//!   with a block allocated by the spawning thread along with their stack.
+//!
+//! The JS object heap needs no special treatment: each thread loads its own
+//! copy of the JS glue and with it its own heap.
+
+use failure::Error;
+use parity_wasm::elements::*;
+
+use js::Context;
+
+/// Name of the custom section which the data segments are moved to.
+pub const DATA_SECTION: &str = "__wbindgen_thread_data";
+
+/// Name of the injected function which initializes the stack of a worker.
+pub const THREAD_INIT: &str = "__wbindgen_thread_init";
+
+/// Size of the stack allocated for each worker, matching the default size of
+/// the main thread's stack.
+pub const STACK_SIZE: u32 = 1 << 20;
+
+/// Size of the argument scratch area of each thread, room for the
+/// `GLOBAL_STACK_CAP` words of `wasm-bindgen` aligned to 8 bytes.
+pub const SCRATCH_SIZE: u32 = 72;
+
+/// Symbols LLVM defines for modules using thread local storage.
+const TLS_SYMBOLS: &[&str] = &["__tls_base", "__tls_size", "__tls_align", "__wasm_init_tls"];
+
+/// Layout of the thread local storage of a module, read from the
+/// `__tls_size` and `__tls_align` globals which `rustc` exports along with
+/// `__wasm_init_tls` when compiling with atomics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tls {
+    pub size: u32,
+    pub align: u32,
+}
+
+impl Tls {
+    /// Returns the thread local storage layout of `module`, or `None` if it
+    /// doesn't use any.
+    pub fn find(module: &Module) -> Result<Option<Tls>, Error> {
+        let exports = module.export_section().map(|s| s.entries()).unwrap_or(&[]);
+        let export = |name: &str| exports.iter().find(|e| e.field() == name).map(|e| e.internal());
+        match export("__wasm_init_tls") {
+            Some(Internal::Function(_)) => {}
+            Some(_) => bail!("`__wasm_init_tls` isn't exported as a function"),
+            None if uses_tls(module) => bail!(
+                "the wasm module uses thread local storage but doesn't export \
+                 `__wasm_init_tls`, `__tls_size` and `__tls_align`"
+            ),
+            None => return Ok(None),
+        }
+        let constant = |name: &str| -> Result<u32, Error> {
+            let global = match export(name) {
+                Some(&Internal::Global(i)) => i,
+                _ => bail!("the wasm module uses thread local storage but doesn't export `{}`", name),
+            };
+            let imported = module.import_count(ImportCountType::Global) as u32;
+            let entry = global
+                .checked_sub(imported)
+                .and_then(|i| module.global_section()?.entries().get(i as usize));
+            match entry.map(|g| g.init_expr().code()) {
+                Some(&[Instruction::I32Const(n), Instruction::End]) => Ok(n as u32),
+                _ => bail!("`{}` isn't a constant defined by the wasm module", name),
+            }
+        };
+        let tls = Tls { size: constant("__tls_size")?, align: constant("__tls_align")? };
+        if !tls.align.is_power_of_two() {
+            bail!("`__tls_align` is {}, which isn't a power of two", tls.align);
+        }
+        Ok(Some(tls))
+    }
+
+    /// JS expression allocating a block of thread local storage with the
+    /// `malloc` function, evaluating to its address.
+    pub fn alloc_js(&self, malloc: &str) -> String {
+        format!(
+            "({}({}) + {}) & ~{}",
+            malloc,
+            self.size + self.align - 1,
+            self.align - 1,
+            self.align - 1,
+        )
+    }
+}
+
+/// JS function copying the data segments moved by `rewrite` into memory, run
+/// once on the main thread after instantiation.
+pub const INIT_DATA_JS: &str = "
+    function initThreadData(module, memory) {
+        const mem = new Uint8Array(memory.buffer);
+        for (const section of WebAssembly.Module.customSections(module, '__wbindgen_thread_data')) {
+            const view = new DataView(section);
+            for (let i = 0; i < view.byteLength;) {
+                const offset = view.getUint32(i, true);
+                const len = view.getUint32(i + 4, true);
+                mem.set(new Uint8Array(section, i + 8, len), offset);
+                i += 8 + len;
+            }
+        }
+    }
+";
+
+/// Validates that the module can be used with threads before any bindings
+/// are generated.
+pub fn prepare(cx: &mut Context) -> Result<(), Error> {
+    if !cx.config.threads {
+        return Ok(());
+    }
+    let config = cx.config;
+    if !((config.nodejs && !config.nodejs_experimental_modules) || config.web) {
+        bail!("`--threads` is currently only supported with `--nodejs` or `--target web`");
+    }
+
+    let shared = cx.module
+        .import_section()
+        .and_then(|s| {
+            s.entries()
+                .iter()
+                .filter_map(|i| match i.external() {
+                    External::Memory(m) => Some(m.limits().shared()),
+                    _ => None,
+                })
+                .next()
+        });
+    match shared {
+        Some(true) => {}
+        Some(false) => bail!(
+            "`--threads` requires the wasm module to import a shared memory; \
+             compile with `-C target-feature=+atomics` and link with \
+             `--shared-memory`"
+        ),
+        None => bail!(
+            "`--threads` requires the wasm module to import its memory; \
+             link with `--import-memory` and `--shared-memory`"
+        ),
+    }
+    cx.tls = Tls::find(cx.module)?;
+
+    // Workers are handed their scratch area when they're started, only the
+    // main thread allocates one when it's first needed.
+    let scratch = if config.web {
+        "0"
+    } else {
+        "require('worker_threads').isMainThread
+            ? 0
+            : require('worker_threads').workerData.scratch"
+    };
+    cx.global(&format!("let cachedThreadScratch = {};", scratch));
+
+    // Make sure the JS glue creates the shared memory so it can be handed to
+    // workers.
+    cx.memory();
+    Ok(())
+}
+
+/// Returns whether `module` uses thread local storage, judging from the
+/// symbols LLVM defines for it which show up in its imports, exports or
+/// function names.
+fn uses_tls(module: &Module) -> bool {
+    let imports = module
+        .import_section()
+        .map(|s| s.entries())
+        .unwrap_or(&[])
+        .iter()
+        .map(|i| i.field());
+    let exports = module
+        .export_section()
+        .map(|s| s.entries())
+        .unwrap_or(&[])
+        .iter()
+        .map(|e| e.field());
+    if imports.chain(exports).any(|name| TLS_SYMBOLS.contains(&name)) {
+        return true;
+    }
+    // The name section is usually still a custom section at this point.
+    let module = module.clone().parse_names().unwrap_or_else(|p| p.1);
+    module.sections().iter().any(|section| match *section {
+        Section::Name(NameSection::Function(ref f)) => {
+            f.names().iter().any(|(_, name)| TLS_SYMBOLS.contains(&&name[..]))
+        }
+        _ => false,
+    })
+}
+
+/// Rewrites the final wasm module so it can be instantiated on many threads.
+pub fn rewrite(cx: &mut Context) -> Result<(), Error> {
+    if !cx.config.threads {
+        return Ok(());
+    }
+    move_data_segments(cx.module)?;
+    inject_thread_init(cx.module)?;
+    Ok(())
+}
+
+/// Moves all data segments to a custom section, encoded as a sequence of
+/// little-endian `u32` offset and length pairs each followed by the data.
+fn move_data_segments(module: &mut Module) -> Result<(), Error> {
+    let mut payload = Vec::new();
+    let mut data_section = None;
+    for (i, section) in module.sections().iter().enumerate() {
+        let data = match *section {
+            Section::Data(ref s) => s,
+            _ => continue,
+        };
+        for segment in data.entries() {
+            let offset = match segment.offset().code() {
+                &[Instruction::I32Const(offset), Instruction::End] => offset as u32,
+                _ => bail!("data segments with non-constant offsets are not supported with threads"),
+            };
+            push_u32(&mut payload, offset);
+            push_u32(&mut payload, segment.value().len() as u32);
+            payload.extend_from_slice(segment.value());
+        }
+        data_section = Some(i);
+    }
+
+    if let Some(i) = data_section {
+        module.sections_mut().remove(i);
+    }
+    let mut custom = CustomSection::default();
+    custom.name_mut().push_str(DATA_SECTION);
+    *custom.payload_mut() = payload;
+    module.sections_mut().push(Section::Custom(custom));
+    Ok(())
+}
+
+fn push_u32(dst: &mut Vec<u8>, n: u32) {
+    dst.extend_from_slice(&[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]);
+}
+
+/// Injects the exported `__wbindgen_thread_init(stack_top)` function which
+/// sets the stack pointer of the current instance.
+fn inject_thread_init(module: &mut Module) -> Result<(), Error> {
+    // LLD always defines the stack pointer as the first global, and it's the
+    // only mutable `i32` global in Rust modules.
+    let imported_globals = module.import_count(ImportCountType::Global) as u32;
+    let stack_pointer = module
+        .global_section()
+        .and_then(|s| {
+            s.entries().iter().position(|g| {
+                g.global_type().is_mutable() && g.global_type().content_type() == ValueType::I32
+            })
+        })
+        .map(|i| imported_globals + i as u32);
+    let stack_pointer = match stack_pointer {
+        Some(i) => i,
+        None => bail!("failed to find the stack pointer of the wasm module"),
+    };
+
+    let ty = FunctionType::new(vec![ValueType::I32], None);
+    let types = match module.type_section_mut() {
+        Some(s) => s.types_mut(),
+        None => bail!("wasm module has no type section"),
+    };
+    let type_idx = match types.iter().position(|t| *t == Type::Function(ty.clone())) {
+        Some(i) => i as u32,
+        None => {
+            types.push(Type::Function(ty));
+            (types.len() - 1) as u32
+        }
+    };
+
+    let func_idx = module.functions_space() as u32;
+    match module.function_section_mut() {
+        Some(s) => s.entries_mut().push(Func::new(type_idx)),
+        None => bail!("wasm module has no function section"),
+    }
+    let body = FuncBody::new(
+        Vec::new(),
+        Instructions::new(vec![
+            Instruction::GetLocal(0),
+            Instruction::SetGlobal(stack_pointer),
+            Instruction::End,
+        ]),
+    );
+    match module.code_section_mut() {
+        Some(s) => s.bodies_mut().push(body),
+        None => bail!("wasm module has no code section"),
+    }
+    match module.export_section_mut() {
+        Some(s) => s.entries_mut().push(ExportEntry::new(
+            THREAD_INIT.to_string(),
+            Internal::Function(func_idx),
+        )),
+        None => bail!("wasm module has no export section"),
+    }
+    Ok(())
+}
@@ -1,6 +1,7 @@
 #![doc(html_root_url = "https://docs.rs/wasm-bindgen-cli-support/0.2")]
 
 extern crate parity_wasm;
+extern crate rustc_demangle;
 extern crate wasm_bindgen_shared as shared;
 extern crate serde_json;
 extern crate wasm_gc;
@@ -9,8 +10,7 @@ extern crate failure;
 extern crate wasm_bindgen_wasm_interpreter as wasm_interpreter;
 
 use std::any::Any;
-use std::collections::BTreeSet;
-use std::env;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::mem;
 use std::path::{Path, PathBuf};
@@ -18,10 +18,25 @@ use std::path::{Path, PathBuf};
 use failure::{Error, ResultExt};
 use parity_wasm::elements::*;
 
+mod cache;
+mod decode;
 mod descriptor;
+mod dwarf;
+pub mod inspect;
 mod js;
+mod size_report;
+mod source_map;
 pub mod wasm2es6js;
 
+/// The minifier behind `Bindgen::minify`, exposed for its tests.
+#[doc(hidden)]
+pub use js::minify::minify as minify_js;
+
+/// The sizes of the stack and scratch area allocated for each worker with
+/// `Bindgen::threads`, exposed for its tests.
+#[doc(hidden)]
+pub use js::{SCRATCH_SIZE as THREAD_SCRATCH_SIZE, STACK_SIZE as THREAD_STACK_SIZE};
+
 pub struct Bindgen {
     input: Input,
     nodejs: bool,
@@ -29,13 +44,36 @@ pub struct Bindgen {
     browser: bool,
     no_modules: bool,
     no_modules_global: Option<String>,
+    web: bool,
+    worker: bool,
+    inline_utf8: bool,
+    threads: bool,
     debug: bool,
     typescript: bool,
     demangle: bool,
     keep_debug: bool,
-    // Experimental support for `WeakRefGroup`, an upcoming ECMAScript feature.
-    // Currently only enable-able through an env var.
+    // Automatically free exported objects through `FinalizationRegistry`
+    // when the engine supports it.
     weak_refs: bool,
+    // Store `JsValue`s in a wasm table of `anyref`s when the engine supports
+    // reference types.
+    anyref: bool,
+    // Emit a source map pointing the generated JS at the Rust declarations.
+    source_map: bool,
+    // Where to write a report of the sizes of each binding.
+    size_report: Option<PathBuf>,
+    // Emit CommonJS and ES module glue sharing one wasm file, along with a
+    // `package.json` pointing at both.
+    multi_target: bool,
+    // Where to cache descriptors and output between runs.
+    cache_dir: Option<PathBuf>,
+    // Exports allocating and freeing arguments which Rust only borrows.
+    borrowed_malloc: Option<String>,
+    borrowed_free: Option<String>,
+    // Shorten names in the JS glue and the wasm imports of it.
+    minify: bool,
+    // Problems with the output of the last `generate` which didn't stop it.
+    warnings: Vec<String>,
 }
 
 enum Input {
@@ -45,6 +83,17 @@ enum Input {
     None,
 }
 
+/// The JS glue generated for one target along with what's needed to finish
+/// the wasm module for it.
+struct Glue {
+    js: String,
+    ts: String,
+    anyref: js::anyref::Anyref,
+    source_map: source_map::SourceMap,
+    size_report: size_report::SizeReport,
+    split: BTreeMap<String, Vec<String>>,
+}
+
 impl Bindgen {
     pub fn new() -> Bindgen {
         Bindgen {
@@ -54,11 +103,24 @@ impl Bindgen {
             browser: false,
             no_modules: false,
             no_modules_global: None,
+            web: false,
+            worker: false,
+            inline_utf8: false,
+            threads: false,
             debug: false,
             typescript: false,
             demangle: true,
             keep_debug: false,
-            weak_refs: env::var("WASM_BINDGEN_WEAKREF").is_ok(),
+            weak_refs: false,
+            anyref: false,
+            source_map: false,
+            size_report: None,
+            multi_target: false,
+            cache_dir: None,
+            borrowed_malloc: None,
+            borrowed_free: None,
+            minify: false,
+            warnings: Vec::new(),
         }
     }
 
@@ -122,6 +184,54 @@ impl Bindgen {
         self
     }
 
+    /// Generate a browser-native ES module which exports an async `init`
+    /// function used to load and instantiate the wasm, no bundler required.
+    pub fn web(&mut self, web: bool) -> &mut Bindgen {
+        self.web = web;
+        self
+    }
+
+    /// Generate output for web workers and worklets loaded with
+    /// `importScripts` or evaluated as classic scripts.
+    ///
+    /// This is like `no_modules`, except that the global used to expose the
+    /// bindings is looked up so it works in any global scope, including audio
+    /// worklets which lack `self`. Module workers should use `web` instead.
+    pub fn worker(&mut self, worker: bool) -> &mut Bindgen {
+        self.worker = worker;
+        self
+    }
+
+    /// Generate output for both node.js and bundlers in one go.
+    ///
+    /// CommonJS glue for node.js is written to the `node` directory and an ES
+    /// module for bundlers to the `esm` directory. Both use the same wasm
+    /// file and TypeScript definitions in the output directory, next to a
+    /// generated `package.json` whose `main`, `module`, `browser` and `types`
+    /// fields point at the matching files.
+    pub fn multi_target(&mut self, multi_target: bool) -> &mut Bindgen {
+        self.multi_target = multi_target;
+        self
+    }
+
+    /// Bundle a minimal UTF-8 encoder and decoder into the generated JS which
+    /// is used if `TextEncoder` or `TextDecoder` are not available.
+    pub fn inline_utf8(&mut self, inline_utf8: bool) -> &mut Bindgen {
+        self.inline_utf8 = inline_utf8;
+        self
+    }
+
+    /// Prepare a module compiled with a shared memory to be run on multiple
+    /// threads, with `wasm_bindgen::thread::spawn` running closures on a pool
+    /// of workers.
+    ///
+    /// This is currently supported with `nodejs` (using `worker_threads`)
+    /// and `web` output.
+    pub fn threads(&mut self, threads: bool) -> &mut Bindgen {
+        self.threads = threads;
+        self
+    }
+
     pub fn debug(&mut self, debug: bool) -> &mut Bindgen {
         self.debug = debug;
         self
@@ -137,38 +247,236 @@ impl Bindgen {
         self
     }
 
+    /// Register instances of exported classes with a `FinalizationRegistry`
+    /// so they're freed automatically once garbage collected.
+    ///
+    /// The generated JS feature-detects `FinalizationRegistry` and otherwise
+    /// falls back to requiring a manual call to `free()`.
+    pub fn weak_refs(&mut self, weak_refs: bool) -> &mut Bindgen {
+        self.weak_refs = weak_refs;
+        self
+    }
+
+    /// Store `JsValue`s in a wasm table of `anyref`s rather than in a JS
+    /// array, passing objects between JS and wasm directly.
+    ///
+    /// This emits a second wasm file using reference types. The unmodified
+    /// module is also emitted as `*_bg_fallback.wasm` for output which loads
+    /// the wasm file itself, and is used on engines without reference types.
+    pub fn anyref(&mut self, anyref: bool) -> &mut Bindgen {
+        self.anyref = anyref;
+        self
+    }
+
+    /// Emits a `*.js.map` source map next to the JS glue which maps each
+    /// generated shim to the `#[wasm_bindgen]` declaration it came from.
+    ///
+    /// Source locations are only recorded by the macro on nightly compilers
+    /// with `--cfg procmacro2_semver_exempt`. If none of the functions has
+    /// one the source map doesn't map anything, and `warnings` says so.
+    pub fn source_map(&mut self, source_map: bool) -> &mut Bindgen {
+        self.source_map = source_map;
+        self
+    }
+
+    /// Writes a JSON report to `path` attributing the size of the output to
+    /// each binding.
+    ///
+    /// For every export and import the report lists the size of its JS glue,
+    /// its function in the output wasm and its descriptor function, along
+    /// with how much each gc pass removed from the module.
+    pub fn size_report<P: AsRef<Path>>(&mut self, path: P) -> &mut Bindgen {
+        self.size_report = Some(path.as_ref().to_path_buf());
+        self
+    }
+
     pub fn keep_debug(&mut self, keep_debug: bool) -> &mut Bindgen {
         self.keep_debug = keep_debug;
         self
     }
 
+    /// Caches work in the directory `path` to speed up later runs.
+    ///
+    /// The result of interpreting each descriptor function is cached, keyed
+    /// by a hash of the code it runs, so after a rebuild only descriptors
+    /// whose code changed are interpreted again. The output is cached too,
+    /// and is copied back without parsing the module if the input file and
+    /// options are unchanged. Output isn't cached with `size_report`, which
+    /// measures the module.
+    pub fn cache_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut Bindgen {
+        self.cache_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Allocates strings and slices which Rust only borrows, like `&str` and
+    /// `&[u8]` arguments, with the exported function `name` rather than
+    /// `__wbindgen_malloc`.
+    ///
+    /// The function takes the number of bytes to allocate and returns a
+    /// pointer aligned to 8 bytes, like
+    /// `#[no_mangle] pub extern "C" fn name(size: usize) -> *mut u8`. Without
+    /// `borrowed_free` the memory is never freed by the JS glue, which suits
+    /// arenas reset by Rust, like a scratch buffer for each frame. Values
+    /// Rust takes ownership of are always allocated with `__wbindgen_malloc`
+    /// as Rust frees them with its global allocator.
+    pub fn borrowed_malloc(&mut self, name: &str) -> &mut Bindgen {
+        self.borrowed_malloc = Some(name.to_string());
+        self
+    }
+
+    /// Frees memory allocated with `borrowed_malloc` with the exported
+    /// function `name` once the call it was allocated for returns, like
+    /// `#[no_mangle] pub extern "C" fn name(ptr: *mut u8, size: usize)`.
+    pub fn borrowed_free(&mut self, name: &str) -> &mut Bindgen {
+        self.borrowed_free = Some(name.to_string());
+        self
+    }
+
+    /// Minifies the JS glue, including the names of the functions the wasm
+    /// module imports from it, which other minifiers can't rename.
+    ///
+    /// Comments and whitespace are removed and helpers are given short names,
+    /// while exported names stay the same. This can't be combined with
+    /// `debug` as its checks are left out of production output.
+    pub fn minify(&mut self, minify: bool) -> &mut Bindgen {
+        self.minify = minify;
+        self
+    }
+
+    /// Problems found by the last call to `generate` which didn't stop it
+    /// from generating bindings, for the caller to show to the user.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
     pub fn generate<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.warnings.clear();
+        if self.borrowed_free.is_some() && self.borrowed_malloc.is_none() {
+            bail!("a custom `free` for borrowed arguments requires a custom `malloc` too");
+        }
+        if self.minify && self.debug {
+            bail!("minified output can't include the checks of debug output");
+        }
+        let targets = [
+            self.nodejs,
+            self.browser,
+            self.no_modules,
+            self.web,
+            self.worker,
+            self.multi_target,
+        ];
+        if targets.iter().filter(|t| **t).count() > 1 {
+            bail!(
+                "only one of the nodejs, browser, no-modules, web, worker and \
+                 multi targets can be generated at once"
+            );
+        }
+        if self.multi_target {
+            return self.generate_multi_target(path.as_ref());
+        }
         self._generate(path.as_ref())
     }
 
+    fn generate_multi_target(&mut self, out_dir: &Path) -> Result<(), Error> {
+        if self.nodejs_experimental_modules || self.threads {
+            bail!("multi-target output can't be combined with experimental node modules or `--threads`");
+        }
+
+        let stem = match self.input {
+            Input::None => bail!("must have an input by now"),
+            Input::Module(_, ref name) | Input::Bytes(_, ref name) => name.clone(),
+            Input::Path(ref path) => path.file_stem().unwrap().to_str().unwrap().to_string(),
+        };
+
+        for dir in ["node", "esm"].iter() {
+            let dir = out_dir.join(dir);
+            fs::create_dir_all(&dir)
+                .with_context(|_| format!("failed to create `{}`", dir.display()))?;
+        }
+        self._generate(out_dir)?;
+
+        let mut fields = vec![
+            ("main", format!("./node/{}.js", stem)),
+            ("module", format!("./esm/{}.js", stem)),
+            ("browser", format!("./esm/{}.js", stem)),
+        ];
+        if self.typescript {
+            fields.push(("types", format!("./{}.d.ts", stem)));
+        }
+        let fields = fields
+            .iter()
+            .map(|&(key, ref value)| {
+                format!("  \"{}\": {}", key, serde_json::to_string(value).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let package_path = out_dir.join("package.json");
+        fs::write(&package_path, format!("{{\n{}\n}}\n", fields.join(",\n")))
+            .with_context(|_| format!("failed to write `{}`", package_path.display()))?;
+        Ok(())
+    }
+
     fn _generate(&mut self, out_dir: &Path) -> Result<(), Error> {
-        let (mut module, stem) = match self.input {
+        // Debug info refers to the encoded input module, so hang on to it if
+        // we're keeping debug info.
+        let keep_debug = self.keep_debug || self.debug;
+        let (mut module, stem, input_bytes, input_len, output) = match self.input {
             Input::None => bail!("must have an input by now"),
             Input::Module(ref mut m, ref name) => {
                 let blank_module = Module::new(Vec::new());
-                (mem::replace(m, blank_module), &name[..])
+                let module = mem::replace(m, blank_module);
+                let bytes = if keep_debug || self.size_report.is_some() {
+                    Some(parity_wasm::serialize(module.clone())?)
+                } else {
+                    None
+                };
+                let len = bytes.as_ref().map(|b| b.len()).unwrap_or(0);
+                (module, name.clone(), if keep_debug { bytes } else { None }, len, None)
             }
             Input::Bytes(ref b, ref name) => {
+                let output = self.cached_output(name, b);
+                if let Some(ref output) = output {
+                    if output.restore(out_dir)? {
+                        return Ok(());
+                    }
+                }
                 let module = parity_wasm::deserialize_buffer::<Module>(&b)
                     .context("failed to parse input file as wasm")?;
-                (module, &name[..])
+                let bytes = if keep_debug { Some(b.clone()) } else { None };
+                (module, name.clone(), bytes, b.len(), output)
             }
             Input::Path(ref path) => {
                 let contents = fs::read(&path)
                     .with_context(|_| format!("failed to read `{}`", path.display()))?;
+                let stem = path.file_stem().unwrap().to_str().unwrap().to_string();
+                let output = self.cached_output(&stem, &contents);
+                if let Some(ref output) = output {
+                    if output.restore(out_dir)? {
+                        return Ok(());
+                    }
+                }
                 let module = parity_wasm::deserialize_buffer::<Module>(&contents)
                     .context("failed to parse input file as wasm")?;
-                let stem = path.file_stem().unwrap().to_str().unwrap();
-                (module, stem)
+                let len = contents.len();
+                (module, stem, if keep_debug { Some(contents) } else { None }, len, output)
             }
         };
+        let mut debug_info = match input_bytes {
+            Some(ref bytes) => dwarf::DebugInfo::new(bytes, &mut module)
+                .context("failed to read debug info of the wasm file")?,
+            None => None,
+        };
         let programs = extract_programs(&mut module)
             .with_context(|_| "failed to extract wasm-bindgen custom sections")?;
+        if self.source_map && !has_source_locations(&programs) {
+            self.warnings.push(
+                "`--source-map` needs the source locations of \
+                 `#[wasm_bindgen]` functions, which are only recorded when \
+                 compiling with a nightly compiler and \
+                 `RUSTFLAGS=\"--cfg procmacro2_semver_exempt\"`, so the source \
+                 map won't map anything back to Rust code"
+                    .to_string(),
+            );
+        }
 
         // Here we're actually instantiating the module we've parsed above for
         // execution. Why, you might be asking, are we executing wasm code? A
@@ -184,62 +492,270 @@ impl Bindgen {
         // execute a shim function which informs us about its type so we can
         // then generate the appropriate bindings.
         let mut instance = wasm_interpreter::Interpreter::new(&module);
+        let mut cache = self.cache_dir.as_ref().map(|dir| cache::Cache::new(dir));
 
-        let (js, ts) = {
-            let mut cx = js::Context {
-                globals: String::new(),
-                imports: String::new(),
-                footer: String::new(),
-                typescript: format!("/* tslint:disable */\n"),
-                exposed_globals: Default::default(),
-                required_internal_exports: Default::default(),
-                imported_names: Default::default(),
-                imported_identifiers: Default::default(),
-                exported_classes: Default::default(),
-                config: &self,
-                module: &mut module,
-                function_table_needed: false,
-                interpreter: &mut instance,
-                memory_init: None,
-                imported_functions: Default::default(),
-                imported_statics: Default::default(),
-            };
-            for program in programs.iter() {
-                js::SubContext {
-                    program,
-                    cx: &mut cx,
-                }.generate()?;
-            }
-            cx.finalize(stem)?
-        };
+        // Multi-target output has glue for both node.js and bundlers around a
+        // single wasm module. Generating glue rewrites the module the same way
+        // for either, so the node.js glue is generated from a copy of it which
+        // is thrown away afterwards.
+        let mut glues = Vec::new();
+        if self.multi_target {
+            self.nodejs = true;
+            let node = self.generate_glue(&mut module.clone(), &programs, &mut instance, &mut cache, &stem);
+            self.nodejs = false;
+            glues.push((out_dir.join("node"), true, node?));
+            let esm = self.generate_glue(&mut module, &programs, &mut instance, &mut cache, &stem)?;
+            glues.push((out_dir.join("esm"), false, esm));
+        } else {
+            let glue = self.generate_glue(&mut module, &programs, &mut instance, &mut cache, &stem)?;
+            glues.push((out_dir.to_path_buf(), self.nodejs, glue));
+        }
+        if let Some(ref cache) = cache {
+            cache.save().context("failed to save the cache")?;
+        }
 
+        // Files written to the output directory, which are cached.
+        let mut written = Vec::new();
         let extension = if self.nodejs_experimental_modules { "mjs" } else { "js" };
-        let js_path = out_dir.join(stem).with_extension(extension);
-        fs::write(&js_path, reset_indentation(&js))
-            .with_context(|_| format!("failed to write `{}`", js_path.display()))?;
+        let wasm_path = out_dir.join(format!("{}_bg", stem)).with_extension("wasm");
+        let mut js_len = 0;
+        for &(ref js_dir, nodejs, ref glue) in glues.iter() {
+            let js_path = js_dir.join(&stem).with_extension(extension);
+            let js = if self.minify { glue.js.clone() } else { reset_indentation(&glue.js) };
+            let js = if self.source_map {
+                let js_name = format!("{}.{}", stem, extension);
+                let (js, map) = glue.source_map.finish(&js, &js_name);
+                let map_path = js_dir.join(format!("{}.map", js_name));
+                write_output(&map_path, map, &mut written)?;
+                js
+            } else {
+                js
+            };
+            js_len = js.len();
+            write_output(&js_path, js, &mut written)?;
+
+            if nodejs {
+                let js_path = js_dir.join(format!("{}_bg", stem)).with_extension(extension);
+                let shim = self.generate_node_wasm_import(&module, &wasm_path, &stem)?;
+                write_output(&js_path, shim, &mut written)?;
+            }
+        }
+        // Everything about the wasm module is the same for all glue, so the
+        // bundler's is used for multi-target output.
+        let Glue { ts, anyref, mut size_report, split, .. } = glues.pop().unwrap().2;
+        let chunks = js::split::split(&mut module, &split, self)
+            .context("failed to split exports into chunks")?;
 
         if self.typescript {
-            let ts_path = out_dir.join(stem).with_extension("d.ts");
-            fs::write(&ts_path, ts)
-                .with_context(|_| format!("failed to write `{}`", ts_path.display()))?;
+            let ts_path = out_dir.join(&stem).with_extension("d.ts");
+            write_output(&ts_path, ts, &mut written)?;
         }
 
-        let wasm_path = out_dir.join(format!("{}_bg", stem)).with_extension("wasm");
+        if self.threads {
+            let worker_path = out_dir.join(format!("{}_worker", stem)).with_extension(extension);
+            write_output(&worker_path, self.generate_thread_worker(&stem), &mut written)?;
+        }
 
-        if self.nodejs {
-            let js_path = wasm_path.with_extension(extension);
-            let shim = self.generate_node_wasm_import(&module, &wasm_path);
-            fs::write(&js_path, shim)
-                .with_context(|_| format!("failed to write `{}`", js_path.display()))?;
+        if let Some(ref mut debug_info) = debug_info {
+            debug_info.finish(&mut module, self.demangle);
         }
+        if self.size_report.is_some() {
+            size_report.measure(&module);
+        }
+        let rewrite_debug_info = |bytes: Vec<u8>| -> Result<Vec<u8>, Error> {
+            match debug_info {
+                Some(ref debug_info) => Ok(debug_info
+                    .rewrite(&bytes)
+                    .context("failed to rewrite debug info of the wasm file")?),
+                None => Ok(bytes),
+            }
+        };
 
-        let wasm_bytes = parity_wasm::serialize(module)?;
-        fs::write(&wasm_path, wasm_bytes)
-            .with_context(|_| format!("failed to write `{}`", wasm_path.display()))?;
+        let wasm_bytes = if self.anyref {
+            let anyref_bytes = js::anyref::transform(&module, &anyref)
+                .context("failed to create the anyref wasm module")?;
+            if self.anyref_fallback() {
+                let fallback_path = out_dir.join(format!("{}_bg_fallback", stem)).with_extension("wasm");
+                let fallback = rewrite_debug_info(parity_wasm::serialize(module)?)?;
+                write_output(&fallback_path, fallback, &mut written)?;
+            }
+            rewrite_debug_info(anyref_bytes)?
+        } else {
+            rewrite_debug_info(parity_wasm::serialize(module)?)?
+        };
+        let wasm_len = wasm_bytes.len();
+        write_output(&wasm_path, wasm_bytes, &mut written)?;
+        for (name, chunk) in chunks {
+            let chunk_path = out_dir.join(format!("{}_bg_{}", stem, name)).with_extension("wasm");
+            write_output(&chunk_path, parity_wasm::serialize(chunk)?, &mut written)?;
+        }
+
+        if let Some(ref path) = self.size_report {
+            fs::write(path, size_report.finish(input_len, wasm_len, js_len))
+                .with_context(|_| format!("failed to write `{}`", path.display()))?;
+        }
+        if let Some(output) = output {
+            output.save(out_dir, &written).context("failed to save the cache")?;
+        }
         Ok(())
     }
 
-    fn generate_node_wasm_import(&self, m: &Module, path: &Path) -> String {
+    /// Generates the JS glue and TypeScript definitions for `programs` with
+    /// the current configuration, rewriting `module` to go along with them.
+    fn generate_glue(
+        &self,
+        module: &mut Module,
+        programs: &[shared::Program],
+        instance: &mut wasm_interpreter::Interpreter,
+        cache: &mut Option<cache::Cache>,
+        stem: &str,
+    ) -> Result<Glue, Error> {
+        let mut cx = js::Context {
+            globals: String::new(),
+            imports: String::new(),
+            footer: String::new(),
+            typescript: format!("/* tslint:disable */\n"),
+            exposed_globals: Default::default(),
+            required_internal_exports: Default::default(),
+            imported_names: Default::default(),
+            imported_identifiers: Default::default(),
+            exported_classes: Default::default(),
+            config: self,
+            module,
+            function_table_needed: false,
+            interpreter: instance,
+            cache: cache.as_mut(),
+            memory_init: None,
+            start: None,
+            imported_functions: Default::default(),
+            imported_statics: Default::default(),
+            anyref: Default::default(),
+            source_map: Default::default(),
+            size_report: Default::default(),
+            split: Default::default(),
+            import_fallbacks: Default::default(),
+            tls: None,
+        };
+        for program in programs.iter() {
+            cx.add_import_fallbacks(program);
+        }
+        for program in programs.iter() {
+            js::SubContext {
+                program,
+                cx: &mut cx,
+            }.generate()?;
+        }
+        let (js, ts) = cx.finalize(stem)?;
+        Ok(Glue {
+            js,
+            ts,
+            anyref: mem::replace(&mut cx.anyref, Default::default()),
+            source_map: mem::replace(&mut cx.source_map, Default::default()),
+            size_report: mem::replace(&mut cx.size_report, Default::default()),
+            split: mem::replace(&mut cx.split, Default::default()),
+        })
+    }
+
+    /// The cache of the output generated from `input`, if caching it.
+    fn cached_output(&self, stem: &str, input: &[u8]) -> Option<cache::Output> {
+        let dir = match self.cache_dir {
+            Some(ref dir) if self.size_report.is_none() => dir,
+            _ => return None,
+        };
+        let options = format!(
+            "{} {:?} {:?}",
+            stem,
+            (
+                self.nodejs,
+                self.nodejs_experimental_modules,
+                self.browser,
+                self.no_modules,
+                &self.no_modules_global,
+                self.web,
+                self.worker,
+                self.inline_utf8,
+            ),
+            (
+                self.threads,
+                self.debug,
+                self.typescript,
+                self.demangle,
+                self.keep_debug,
+                self.weak_refs,
+                self.anyref,
+                self.source_map,
+                self.multi_target,
+                &self.borrowed_malloc,
+                &self.borrowed_free,
+                self.minify,
+            ),
+        );
+        Some(cache::Output::new(dir, &options, input))
+    }
+
+    /// Whether the generated JS loads the wasm file itself, in which case it
+    /// can pick the fallback of `--anyref` on engines without reference types.
+    /// Bundlers always load the `anyref` module, but the node.js glue of
+    /// multi-target output doesn't.
+    fn anyref_fallback(&self) -> bool {
+        self.nodejs || self.web || self.no_modules || self.worker || self.multi_target
+    }
+
+    /// Generates the script run by each worker of the thread pool, which
+    /// runs the tasks the pool hands it.
+    fn generate_thread_worker(&self, stem: &str) -> String {
+        let worker = if self.web {
+            format!(
+                "
+                import init from './{}.js';
+
+                let ready;
+                self.onmessage = event => {{
+                    const data = event.data;
+                    if (data.module !== undefined) {{
+                        // `init` switches to the stack, scratch area and
+                        // thread local storage of this thread before running
+                        // any other code.
+                        ready = init(data.module, data.memory, data);
+                    }} else {{
+                        ready.then(wasm => wasm.__wbindgen_thread_entry(data.task));
+                    }}
+                }};
+                ",
+                stem,
+            )
+        } else {
+            format!(
+                "
+                const {{ parentPort }} = require('worker_threads');
+                // Load the JS glue first as it's what instantiates the wasm.
+                require('./{0}');
+                const wasm = require('./{0}_bg');
+
+                parentPort.on('message', task => wasm.__wbindgen_thread_entry(task));
+                ",
+                stem,
+            )
+        };
+        reset_indentation(&worker)
+    }
+
+    /// JS expression for the name of the wasm file loaded in node, which is
+    /// the fallback of `--anyref` if reference types aren't supported.
+    fn node_wasm_file(&self, path: &Path) -> String {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        if !self.anyref {
+            return format!("'{}'", name);
+        }
+        format!(
+            "{} ? '{}' : '{}'",
+            js::anyref::DETECT_JS,
+            name,
+            name.replace(".wasm", "_fallback.wasm"),
+        )
+    }
+
+    fn generate_node_wasm_import(&self, m: &Module, path: &Path, stem: &str) -> Result<String, Error> {
         let mut imports = BTreeSet::new();
         if let Some(i) = m.import_section() {
             for i in i.entries() {
@@ -266,20 +782,28 @@ impl Bindgen {
                 if (process.platform === 'win32') {{
                     file = file.substring(1);
                 }}
-                const bytes = fs.readFileSync(path.join(file, '{}'));
-            ", path.file_name().unwrap().to_str().unwrap()));
+                const bytes = fs.readFileSync(path.join(file, {}));
+            ", self.node_wasm_file(path)));
         } else {
+            // The wasm file shared by multi-target output sits one directory up.
+            let dir = if self.multi_target { "__dirname, '..'" } else { "__dirname" };
             shim.push_str(&format!("
-                const path = require('path').join(__dirname, '{}');
+                const path = require('path').join({}, {});
                 const bytes = require('fs').readFileSync(path);
-            ", path.file_name().unwrap().to_str().unwrap()));
+            ", dir, self.node_wasm_file(path)));
         }
         shim.push_str("let imports = {};\n");
         for (i, module) in imports.iter().enumerate() {
             if self.nodejs_experimental_modules {
                 shim.push_str(&format!("imports['{}'] = import{};\n", module, i));
             } else {
-                shim.push_str(&format!("imports['{0}'] = require('{0}');\n", module));
+                // Multi-target wasm imports the ES module glue, which node
+                // swaps for the CommonJS glue next to this file.
+                if self.multi_target && *module == format!("./esm/{}", stem) {
+                    shim.push_str(&format!("imports['{}'] = require('./{}');\n", module, stem));
+                } else {
+                    shim.push_str(&format!("imports['{0}'] = require('{0}');\n", module));
+                }
             }
         }
 
@@ -290,24 +814,86 @@ impl Bindgen {
             ",
         ));
 
+        // Only the main thread initializes the shared memory, every other
+        // thread just needs a stack and thread local storage of its own.
+        if self.threads {
+            let memory_module = m.import_section()
+                .and_then(|s| {
+                    s.entries()
+                        .iter()
+                        .find(|i| match i.external() {
+                            External::Memory(_) => true,
+                            _ => false,
+                        })
+                })
+                .map(|i| i.module())
+                .unwrap_or("env");
+            let (main_tls, worker_tls) = match js::Tls::find(m)? {
+                Some(tls) => (
+                    format!(
+                        "wasmInstance.exports.__wasm_init_tls({});",
+                        tls.alloc_js("wasmInstance.exports.__wbindgen_malloc"),
+                    ),
+                    "wasmInstance.exports.__wasm_init_tls(workerData.tls);",
+                ),
+                None => (String::new(), ""),
+            };
+            shim.push_str(&format!(
+                "
+                    const {{ isMainThread, workerData }} = require('worker_threads');
+                    if (isMainThread) {{
+                        {}
+                        initThreadData(wasmModule, imports['{}'].memory);
+                        {}
+                    }} else {{
+                        wasmInstance.exports.__wbindgen_thread_init(workerData.stack);
+                        {}
+                    }}
+                ",
+                js::INIT_DATA_JS,
+                memory_module,
+                main_tls,
+                worker_tls,
+            ));
+        }
+
         if self.nodejs_experimental_modules {
-            if let Some(e) = m.export_section() {
-                for name in e.entries().iter().map(|e| e.field()) {
-                    shim.push_str("export const ");
-                    shim.push_str(name);
-                    shim.push_str(" = wasmInstance.exports.");
-                    shim.push_str(name);
-                    shim.push_str(";\n");
-                }
+            let mut exports = m
+                .export_section()
+                .map(|e| e.entries().iter().map(|e| e.field()).collect::<Vec<_>>())
+                .unwrap_or_default();
+            if self.anyref {
+                exports.push(js::anyref::TABLE);
+            }
+            for name in exports {
+                shim.push_str("export const ");
+                shim.push_str(name);
+                shim.push_str(" = wasmInstance.exports.");
+                shim.push_str(name);
+                shim.push_str(";\n");
             }
         } else {
             shim.push_str("module.exports = wasmInstance.exports;\n");
         }
 
-        reset_indentation(&shim)
+        Ok(reset_indentation(&shim))
     }
 }
 
+/// Whether any function of `programs` knows where it was declared, or there
+/// are no functions to map at all.
+fn has_source_locations(programs: &[shared::Program]) -> bool {
+    let mut functions = programs.iter().flat_map(|program| {
+        let exports = program.exports.iter().map(|e| &e.function);
+        let imports = program.imports.iter().filter_map(|i| match i.kind {
+            shared::ImportKind::Function(ref f) => Some(&f.function),
+            _ => None,
+        });
+        exports.chain(imports)
+    }).peekable();
+    functions.peek().is_none() || functions.any(|f| f.location.is_some())
+}
+
 fn extract_programs(module: &mut Module) -> Result<Vec<shared::Program>, Error> {
     let version = shared::version();
     let mut ret = Vec::new();
@@ -323,37 +909,53 @@ fn extract_programs(module: &mut Module) -> Result<Vec<shared::Program>, Error>
         }
         to_remove.push(i);
 
-        let mut payload = custom.payload();
-        while payload.len() > 0 {
-            let len = ((payload[0] as usize) << 0)
-                | ((payload[1] as usize) << 8)
-                | ((payload[2] as usize) << 16)
-                | ((payload[3] as usize) << 24);
-            let (a, b) = payload[4..].split_at(len as usize);
-            payload = b;
-
-            let p: shared::ProgramOnlySchema = match serde_json::from_slice(&a) {
+        for a in program_payloads(custom.payload())? {
+            let p = match decode::versions(a) {
                 Ok(f) => f,
                 Err(e) => bail!("failed to decode what looked like wasm-bindgen data: {}", e),
             };
-            if p.schema_version != shared::SCHEMA_VERSION {
-                bail!(
+            match shared::schema_compat(&p.schema_version) {
+                shared::SchemaCompat::Compatible => {}
+                shared::SchemaCompat::Older => bail!(
                     "
 
 it looks like the Rust project used to create this wasm file was linked against
-a different version of wasm-bindgen than this binary:
+a version of wasm-bindgen which is too old for this binary:
 
-  rust wasm file: {}
-     this binary: {}
+  rust wasm file: {} (schema version {})
+     this binary: {} (schema version {})
 
-Currently the bindgen format is unstable enough that these two version must
-exactly match, so it's required that these two version are kept in sync by
-either updating the wasm-bindgen dependency or this binary. You should be able
-to update the wasm-bindgen dependency with:
+This binary can process wasm files built with older versions of wasm-bindgen
+only down to {}, so you'll need to update the wasm-bindgen dependency
+with:
 
     cargo update -p wasm-bindgen
 
-or you can update the binary with
+or install a binary matching the version of the dependency with
+
+    cargo install -f wasm-bindgen-cli --version {}
+
+if this warning fails to go away though and you're not sure what to do feel free
+to open an issue at https://github.com/rustwasm/wasm-bindgen/issues!
+",
+                    p.version,
+                    p.schema_version,
+                    version,
+                    shared::SCHEMA_VERSION,
+                    shared::oldest_release(),
+                    p.version.split_whitespace().next().unwrap_or(""),
+                ),
+                shared::SchemaCompat::Newer => bail!(
+                    "
+
+it looks like the Rust project used to create this wasm file was linked against
+a newer version of wasm-bindgen than this binary:
+
+  rust wasm file: {} (schema version {})
+     this binary: {} (schema version {})
+
+This binary can only process wasm files built with the same or older versions
+of wasm-bindgen, so it needs to be updated with:
 
     cargo install -f wasm-bindgen-cli
 
@@ -361,10 +963,12 @@ if this warning fails to go away though and you're not sure what to do feel free
 to open an issue at https://github.com/rustwasm/wasm-bindgen/issues!
 ",
                     p.version,
-                    version
-                );
+                    p.schema_version,
+                    version,
+                    shared::SCHEMA_VERSION,
+                ),
             }
-            let p: shared::Program = match serde_json::from_slice(&a) {
+            let p = match decode::program(a) {
                 Ok(f) => f,
                 Err(e) => bail!("failed to decode what looked like wasm-bindgen data: {}", e),
             };
@@ -378,6 +982,30 @@ to open an issue at https://github.com/rustwasm/wasm-bindgen/issues!
     Ok(ret)
 }
 
+/// Splits the payload of a `__wasm_bindgen_unstable` custom section into the
+/// encoded programs in it, each of which is prefixed by its length. Programs
+/// are in the binary format of `shared::binary`, starting with its `MARKER`,
+/// or JSON if written by a release of the macro from before schema version 2.
+fn program_payloads(mut payload: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut ret = Vec::new();
+    while payload.len() > 0 {
+        if payload.len() < 4 {
+            bail!("truncated wasm-bindgen custom section");
+        }
+        let len = ((payload[0] as usize) << 0)
+            | ((payload[1] as usize) << 8)
+            | ((payload[2] as usize) << 16)
+            | ((payload[3] as usize) << 24);
+        if payload.len() - 4 < len {
+            bail!("truncated wasm-bindgen custom section");
+        }
+        let (a, b) = payload[4..].split_at(len as usize);
+        ret.push(a);
+        payload = b;
+    }
+    Ok(ret)
+}
+
 fn reset_indentation(s: &str) -> String {
     let mut indent: u32 = 0;
     let mut dst = String::new();
@@ -401,3 +1029,14 @@ fn reset_indentation(s: &str) -> String {
     }
     return dst
 }
+
+fn write_output<C: AsRef<[u8]>>(
+    path: &Path,
+    contents: C,
+    written: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    fs::write(path, contents)
+        .with_context(|_| format!("failed to write `{}`", path.display()))?;
+    written.push(path.to_path_buf());
+    Ok(())
+}
@@ -0,0 +1,296 @@
+//! A report of which bindings the bytes of the output come from
+//!
+//! While generating bindings we note down the size of the JS glue generated
+//! for each export and import along with the size of its descriptor function
+//! (which is removed from the output). Once the final wasm module is known
+//! the size of the wasm code of each binding is added and the whole report is
+//! written out as JSON. Exports are attributed the code reachable from their
+//! wasm function, both all of it and just the part no other root of the
+//! module reaches, which is what removing the export would save.
+
+use std::collections::HashMap;
+
+use parity_wasm;
+use parity_wasm::elements::*;
+use serde_json::{self, Map, Value};
+
+use reset_indentation;
+
+#[derive(Default)]
+pub struct SizeReport {
+    bindings: Vec<Binding>,
+    gc: Vec<(usize, usize)>,
+}
+
+struct Binding {
+    kind: &'static str,
+    name: String,
+    class: Option<String>,
+    /// Name of the export or import of the binding in the wasm module.
+    wasm_name: String,
+    js: usize,
+    wasm: Option<usize>,
+    /// Size of all the code reachable from an export, including code shared
+    /// with other roots.
+    wasm_reachable: Option<usize>,
+    descriptor: Option<usize>,
+}
+
+impl SizeReport {
+    /// Records an exported function whose wasm export is `wasm_name` and
+    /// whose JS glue is `js`.
+    pub fn export(
+        &mut self,
+        module: &Module,
+        name: &str,
+        class: Option<&str>,
+        wasm_name: &str,
+        js: &str,
+    ) {
+        self.bindings.push(Binding {
+            kind: "export",
+            name: name.to_string(),
+            class: class.map(|s| s.to_string()),
+            wasm_name: wasm_name.to_string(),
+            js: reset_indentation(js).len(),
+            wasm: None,
+            wasm_reachable: None,
+            descriptor: function_size(module, &format!("__wbindgen_describe_{}", wasm_name)),
+        });
+    }
+
+    /// Records an imported function or static whose wasm import is `shim`.
+    pub fn import(
+        &mut self,
+        module: &Module,
+        kind: &'static str,
+        name: &str,
+        shim: &str,
+        js: &str,
+    ) {
+        self.bindings.push(Binding {
+            kind,
+            name: name.to_string(),
+            class: None,
+            wasm_name: shim.to_string(),
+            js: reset_indentation(js).len(),
+            wasm: None,
+            wasm_reachable: None,
+            descriptor: function_size(module, &format!("__wbindgen_describe_{}", shim)),
+        });
+    }
+
+    /// Follows the imports renamed by `--minify`.
+    pub fn rename_imports(&mut self, renamed: &HashMap<String, String>) {
+        for binding in self.bindings.iter_mut() {
+            if binding.kind == "export" {
+                continue;
+            }
+            if let Some(name) = renamed.get(&binding.wasm_name) {
+                binding.wasm_name = name.clone();
+            }
+        }
+    }
+
+    /// Records the size of the module before and after a gc pass.
+    pub fn gc(&mut self, before: usize, after: usize) {
+        self.gc.push((before, after));
+    }
+
+    /// Records the size of each binding in the final wasm module.
+    pub fn measure(&mut self, module: &Module) {
+        let calls = CallGraph::new(module);
+        let exported = |name: &str| exported_function(module, name);
+        // Everything the module keeps alive other than the exports of
+        // bindings: exports of its own, the table and the start function.
+        let mut roots = Vec::new();
+        if let Some(s) = module.export_section() {
+            for entry in s.entries() {
+                let binding = self.bindings
+                    .iter()
+                    .any(|b| b.kind == "export" && b.wasm_name == entry.field());
+                if let Internal::Function(f) = *entry.internal() {
+                    if !binding {
+                        roots.push(f);
+                    }
+                }
+            }
+        }
+        if let Some(s) = module.elements_section() {
+            for segment in s.entries() {
+                roots.extend(segment.members());
+            }
+        }
+        roots.extend(module.start_section());
+
+        let exports = self.bindings
+            .iter()
+            .map(|b| if b.kind == "export" { exported(&b.wasm_name) } else { None })
+            .collect::<Vec<_>>();
+        for (i, binding) in self.bindings.iter_mut().enumerate() {
+            let f = match exports[i] {
+                Some(f) => f,
+                None => {
+                    if binding.kind != "export" {
+                        binding.wasm = import_size(module, &binding.wasm_name);
+                    }
+                    continue;
+                }
+            };
+            let others = roots
+                .iter()
+                .cloned()
+                .chain(exports.iter().enumerate().filter(|&(j, _)| j != i).filter_map(|(_, f)| *f));
+            let shared = calls.reachable(others);
+            let own = calls.reachable(Some(f));
+            binding.wasm = Some(calls.size(&own, |f| !shared[f]));
+            binding.wasm_reachable = Some(calls.size(&own, |_| true));
+        }
+    }
+
+    /// Produces the JSON report, given the total sizes of the input and
+    /// output.
+    pub fn finish(&self, input_wasm: usize, output_wasm: usize, js: usize) -> String {
+        let mut gc = Vec::new();
+        for &(before, after) in self.gc.iter() {
+            let mut run = Map::new();
+            run.insert("before".to_string(), before.into());
+            run.insert("after".to_string(), after.into());
+            run.insert("removed".to_string(), before.saturating_sub(after).into());
+            gc.push(Value::Object(run));
+        }
+        let mut wasm = Map::new();
+        wasm.insert("input".to_string(), input_wasm.into());
+        wasm.insert("output".to_string(), output_wasm.into());
+        wasm.insert("gc".to_string(), Value::Array(gc));
+
+        let bindings_js = self.bindings.iter().map(|b| b.js).sum::<usize>();
+        let mut js_sizes = Map::new();
+        js_sizes.insert("total".to_string(), js.into());
+        js_sizes.insert("bindings".to_string(), bindings_js.into());
+        js_sizes.insert("other".to_string(), js.saturating_sub(bindings_js).into());
+
+        let mut bindings = Vec::new();
+        for binding in self.bindings.iter() {
+            let mut b = Map::new();
+            b.insert("kind".to_string(), binding.kind.into());
+            b.insert("name".to_string(), binding.name.clone().into());
+            b.insert("class".to_string(), optional(binding.class.clone()));
+            b.insert("wasm_name".to_string(), binding.wasm_name.clone().into());
+            b.insert("js".to_string(), binding.js.into());
+            b.insert("wasm".to_string(), optional(binding.wasm));
+            b.insert("wasm_reachable".to_string(), optional(binding.wasm_reachable));
+            b.insert("descriptor".to_string(), optional(binding.descriptor));
+            bindings.push(Value::Object(b));
+        }
+
+        let mut report = Map::new();
+        report.insert("wasm".to_string(), Value::Object(wasm));
+        report.insert("js".to_string(), Value::Object(js_sizes));
+        report.insert("bindings".to_string(), Value::Array(bindings));
+        let mut ret = serde_json::to_string_pretty(&Value::Object(report)).unwrap();
+        ret.push('\n');
+        ret
+    }
+}
+
+/// Returns the index of the function exported as `name`.
+fn exported_function(module: &Module, name: &str) -> Option<u32> {
+    module
+        .export_section()?
+        .entries()
+        .iter()
+        .filter(|e| e.field() == name)
+        .filter_map(|e| match *e.internal() {
+            Internal::Function(i) => Some(i),
+            _ => None,
+        })
+        .next()
+}
+
+/// Returns the encoded size of the body of the function exported as `name`.
+fn function_size(module: &Module, name: &str) -> Option<usize> {
+    let idx = exported_function(module, name)?;
+    let imports = module.import_count(ImportCountType::Function) as u32;
+    let body = module.code_section()?.bodies().get(idx.checked_sub(imports)? as usize)?;
+    parity_wasm::serialize(body.clone()).ok().map(|b| b.len())
+}
+
+/// The functions each defined function of a module may call, along with the
+/// encoded size of its body. Functions using `call_indirect` may call any
+/// function in the table.
+struct CallGraph {
+    imports: u32,
+    sizes: Vec<usize>,
+    callees: Vec<Vec<u32>>,
+}
+
+impl CallGraph {
+    fn new(module: &Module) -> CallGraph {
+        let bodies = module.code_section().map(|s| s.bodies()).unwrap_or(&[]);
+        let table = module
+            .elements_section()
+            .map(|s| s.entries().iter().flat_map(|e| e.members().iter().cloned()).collect())
+            .unwrap_or(Vec::new());
+        CallGraph {
+            imports: module.import_count(ImportCountType::Function) as u32,
+            sizes: bodies
+                .iter()
+                .map(|b| parity_wasm::serialize(b.clone()).map(|b| b.len()).unwrap_or(0))
+                .collect(),
+            callees: bodies
+                .iter()
+                .map(|b| {
+                    let mut callees = Vec::new();
+                    for instr in b.code().elements() {
+                        match *instr {
+                            Instruction::Call(f) => callees.push(f),
+                            Instruction::CallIndirect(..) => callees.extend(table.iter().cloned()),
+                            _ => {}
+                        }
+                    }
+                    callees
+                })
+                .collect(),
+        }
+    }
+
+    /// Marks the defined functions reachable from `roots`.
+    fn reachable(&self, roots: impl IntoIterator<Item = u32>) -> Vec<bool> {
+        let mut live = vec![false; self.sizes.len()];
+        let mut stack = roots.into_iter().collect::<Vec<_>>();
+        while let Some(f) = stack.pop() {
+            let f = match f.checked_sub(self.imports) {
+                Some(f) if (f as usize) < live.len() => f as usize,
+                _ => continue,
+            };
+            if !live[f] {
+                live[f] = true;
+                stack.extend(self.callees[f].iter().cloned());
+            }
+        }
+        live
+    }
+
+    /// The total size of the functions marked in `live` passing `filter`.
+    fn size(&self, live: &[bool], filter: impl Fn(usize) -> bool) -> usize {
+        (0..live.len())
+            .filter(|&f| live[f] && filter(f))
+            .map(|f| self.sizes[f])
+            .sum()
+    }
+}
+
+/// Returns the encoded size of the import of `name`.
+fn import_size(module: &Module, name: &str) -> Option<usize> {
+    let entry = module
+        .import_section()?
+        .entries()
+        .iter()
+        .find(|e| e.field() == name)?;
+    parity_wasm::serialize(entry.clone()).ok().map(|b| b.len())
+}
+
+fn optional<T: Into<Value>>(value: Option<T>) -> Value {
+    value.map(|v| v.into()).unwrap_or(Value::Null)
+}
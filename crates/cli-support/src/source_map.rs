@@ -0,0 +1,115 @@
+//! Generation of source maps for the JS glue
+//!
+//! While generating bindings each shim with a known Rust source location is
+//! preceded by a marker line (see `SourceMap::marker`). Once the JS is
+//! complete `SourceMap::finish` strips the markers and maps every line of the
+//! shim following a marker back to the Rust declaration it was generated for.
+
+use serde_json;
+use shared;
+
+pub const MARKER_PREFIX: &str = "/*__wbindgen_source_location:";
+const MARKER_SUFFIX: &str = "*/";
+
+#[derive(Default)]
+pub struct SourceMap {
+    locations: Vec<(usize, u32, u32)>,
+    sources: Vec<String>,
+}
+
+impl SourceMap {
+    /// Records `location` and returns the line to emit right before the shim
+    /// generated for it.
+    pub fn marker(&mut self, location: &shared::SourceLocation) -> String {
+        let source = match self.sources.iter().position(|s| *s == location.file) {
+            Some(i) => i,
+            None => {
+                self.sources.push(location.file.clone());
+                self.sources.len() - 1
+            }
+        };
+        // Source maps use 0-based lines.
+        let line = location.line.saturating_sub(1);
+        self.locations.push((source, line, location.column));
+        format!("{}{}{}\n", MARKER_PREFIX, self.locations.len() - 1, MARKER_SUFFIX)
+    }
+
+    /// Removes all markers from `js`, returning the JS to write out along with
+    /// the JSON source map for it.
+    pub fn finish(&self, js: &str, file: &str) -> (String, String) {
+        let mut out = String::new();
+        let mut mappings = String::new();
+        let mut first_line = true;
+        let mut current = None;
+        let mut depth = 0u32;
+        let mut prev = (0i64, 0i64, 0i64);
+
+        for line in js.lines() {
+            let trimmed = line.trim();
+            if trimmed.starts_with(MARKER_PREFIX) && trimmed.ends_with(MARKER_SUFFIX) {
+                let idx = &trimmed[MARKER_PREFIX.len()..trimmed.len() - MARKER_SUFFIX.len()];
+                current = idx.parse::<usize>().ok().and_then(|i| self.locations.get(i));
+                depth = 0;
+                continue;
+            }
+
+            if !first_line {
+                mappings.push(';');
+            }
+            first_line = false;
+            out.push_str(line);
+            out.push('\n');
+
+            let (source, src_line, src_column) = match current {
+                Some(&location) => location,
+                None => continue,
+            };
+            if !trimmed.is_empty() {
+                let segment = (source as i64, src_line as i64, src_column as i64);
+                vlq(&mut mappings, 0);
+                vlq(&mut mappings, segment.0 - prev.0);
+                vlq(&mut mappings, segment.1 - prev.1);
+                vlq(&mut mappings, segment.2 - prev.2);
+                prev = segment;
+            }
+
+            // Follow the shim's braces the same way `reset_indentation` does
+            // to find where it ends.
+            if trimmed.starts_with('}') || (trimmed.ends_with('}') && !trimmed.starts_with('*')) {
+                depth = depth.saturating_sub(1);
+            }
+            if trimmed.ends_with('{') {
+                depth += 1;
+            }
+            if depth == 0 && !trimmed.is_empty() {
+                current = None;
+            }
+        }
+
+        let map = format!(
+            "{{\"version\":3,\"file\":{},\"sources\":{},\"names\":[],\"mappings\":\"{}\"}}\n",
+            serde_json::to_string(file).unwrap(),
+            serde_json::to_string(&self.sources).unwrap(),
+            mappings,
+        );
+        out.push_str(&format!("//# sourceMappingURL={}.map\n", file));
+        (out, map)
+    }
+}
+
+/// Appends `n` in the base64 VLQ encoding used by source maps.
+fn vlq(dst: &mut String, n: i64) {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut n = if n < 0 { ((-n) << 1) | 1 } else { n << 1 };
+    loop {
+        let mut digit = n & 0x1f;
+        n >>= 5;
+        if n > 0 {
+            digit |= 0x20;
+        }
+        dst.push(BASE64[digit as usize] as char);
+        if n == 0 {
+            break;
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Tests of allocating borrowed arguments with custom exported functions,
+//! running the generated JS in node.js.
+
+extern crate parity_wasm;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::fs;
+use std::process::Command;
+
+use parity_wasm::elements::*;
+use wasm_bindgen_cli_support::Bindgen;
+
+use support::*;
+
+/// A bump allocator counting its calls at address `counter` and handing out
+/// 64 bytes after `base` for each call.
+fn alloc(counter: i32, base: i32) -> FuncBody {
+    body(vec![
+        Instruction::I32Const(counter),
+        Instruction::I32Const(counter),
+        Instruction::I32Load(2, 0),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::I32Store(2, 0),
+        Instruction::I32Const(counter),
+        Instruction::I32Load(2, 0),
+        Instruction::I32Const(6),
+        Instruction::I32Shl,
+        Instruction::I32Const(base),
+        Instruction::I32Add,
+        Instruction::End,
+    ])
+}
+
+/// A free function counting its calls at address `counter` and storing the
+/// pointer it was last called with right after.
+fn free(counter: i32) -> FuncBody {
+    body(vec![
+        Instruction::I32Const(counter),
+        Instruction::I32Const(counter),
+        Instruction::I32Load(2, 0),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::I32Store(2, 0),
+        Instruction::I32Const(counter + 4),
+        Instruction::GetLocal(0),
+        Instruction::I32Store(2, 0),
+        Instruction::End,
+    ])
+}
+
+/// A wasm module exporting `fn greet(arg0: &str, arg1: String)`, which
+/// stores its arguments at address 24, along with `__wbindgen_malloc` and
+/// `frame_alloc`, counting their calls at addresses 0 and 4, and
+/// `__wbindgen_free` and `frame_free`, counting theirs at 8 and 16.
+fn module() -> Module {
+    let i32s = |n| vec![ValueType::I32; n];
+    let mut m = ModuleBuilder::new();
+    m.intrinsic("__wbindgen_describe", &i32s(1), None);
+    let mut store_args = Vec::new();
+    for i in 0..4 {
+        store_args.push(Instruction::I32Const(24 + 4 * i as i32));
+        store_args.push(Instruction::GetLocal(i));
+        store_args.push(Instruction::I32Store(2, 0));
+    }
+    store_args.push(Instruction::End);
+    m.export("greet", &i32s(4), None, body(store_args));
+    m.describe("greet", &[FUNCTION, 2, REF, STRING, STRING, UNIT]);
+    m.export("__wbindgen_malloc", &i32s(1), Some(ValueType::I32), alloc(0, 256));
+    m.export("frame_alloc", &i32s(1), Some(ValueType::I32), alloc(4, 1024));
+    m.export("__wbindgen_free", &i32s(2), None, free(8));
+    m.export("frame_free", &i32s(2), None, free(16));
+    m.program(&program(&["greet"], &[]));
+    m.build()
+}
+
+/// Calls `greet` and prints the calls of each allocator along with where
+/// the arguments ended up.
+const RUN_JS: &str = "
+    const { greet } = require('./test');
+    const wasm = require('./test_bg');
+    greet('hi', 'there');
+
+    const mem = new Int32Array(wasm.memory.buffer);
+    const str = (ptr, len) => Buffer.from(wasm.memory.buffer, ptr, len).toString();
+    console.log(`malloc ${mem[0]} frame_alloc ${mem[1]}`);
+    console.log(`free ${mem[2]} ${mem[3]} frame_free ${mem[4]} ${mem[5]}`);
+    console.log(`arg0 ${mem[6]} ${str(mem[6], mem[7])}`);
+    console.log(`arg1 ${mem[8]} ${str(mem[8], mem[9])}`);
+";
+
+/// Generates node.js bindings for `module()` configured by `configure` and
+/// runs `RUN_JS` with them, returning its output.
+fn run(configure: impl FnOnce(&mut Bindgen)) -> String {
+    let dir = generate(module(), |b| {
+        b.nodejs(true);
+        configure(b);
+    }).unwrap();
+    fs::write(dir.path().join("run.js"), RUN_JS).unwrap();
+    let output = Command::new("node")
+        .arg("run.js")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    stdout.trim().to_string()
+}
+
+#[test]
+fn default_allocator() {
+    assert_eq!(
+        run(|_| {}),
+        "\
+malloc 2 frame_alloc 0
+free 1 320 frame_free 0 0
+arg0 320 hi
+arg1 384 there",
+    );
+}
+
+#[test]
+fn borrowed_malloc_and_free() {
+    // `String`s are freed by Rust, so still use its allocator.
+    assert_eq!(
+        run(|b| {
+            b.borrowed_malloc("frame_alloc").borrowed_free("frame_free");
+        }),
+        "\
+malloc 1 frame_alloc 1
+free 0 0 frame_free 1 1088
+arg0 1088 hi
+arg1 320 there",
+    );
+}
+
+#[test]
+fn borrowed_malloc_without_free() {
+    // Without a custom `free` the borrowed argument is left to the allocator,
+    // like memory of an arena which is reset all at once.
+    assert_eq!(
+        run(|b| {
+            b.borrowed_malloc("frame_alloc");
+        }),
+        "\
+malloc 1 frame_alloc 1
+free 0 0 frame_free 0 0
+arg0 1088 hi
+arg1 320 there",
+    );
+}
+
+#[test]
+fn missing_allocator() {
+    let err = generate(module(), |b| {
+        b.nodejs(true).borrowed_malloc("missing_alloc");
+    }).unwrap_err();
+    assert!(err.contains("`missing_alloc` was not found"), "{}", err);
+
+    let err = generate(module(), |b| {
+        b.nodejs(true).borrowed_free("frame_free");
+    }).unwrap_err();
+    assert!(err.contains("requires a custom `malloc`"), "{}", err);
+}
@@ -0,0 +1,170 @@
+//! Tests of `--anyref`, validating and running the transformed module in
+//! node.js, whose engine supports reference types.
+
+extern crate parity_wasm;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::fs;
+use std::process::Command;
+
+use parity_wasm::elements::*;
+
+use support::*;
+
+/// A program exporting `identity(JsValue) -> JsValue` and importing
+/// `take(JsValue)`, `try_take(JsValue)` with `catch` and `make() -> JsValue`
+/// from `./imports`.
+fn anyref_program() -> Vec<u8> {
+    let import = |shim: &str, name: &str, catch: bool| {
+        format!(
+            r#"{{
+                "module": "./imports",
+                "js_namespace": null,
+                "kind": {{
+                    "kind": "function",
+                    "shim": "{}",
+                    "catch": {},
+                    "variadic": false,
+                    "method": null,
+                    "structural": false,
+                    "function": {{ "name": "{}", "location": null }}
+                }}
+            }}"#,
+            shim, catch, name,
+        )
+    };
+    let imports = [
+        import("__wbg_take", "take", false),
+        import("__wbg_try_take", "try_take", true),
+        import("__wbg_make", "make", false),
+    ];
+    let program = String::from_utf8(program(&["identity"], &[])).unwrap();
+    program
+        .replace(r#""imports": []"#, &format!(r#""imports": [{}]"#, imports.join(", ")))
+        .into_bytes()
+}
+
+/// A wasm module for `anyref_program()` whose table slots are allocated by
+/// growing the table and freed by nulling them, counting the slots freed at
+/// address 0. Its `run` export passes a value from `make` to `take` and
+/// another one to `try_take`, which stores an exception at address 16.
+fn module() -> Module {
+    let i32 = ValueType::I32;
+    let mut m = ModuleBuilder::new();
+    m.intrinsic("__wbindgen_describe", &[i32], None);
+    let take = m.intrinsic("__wbg_take", &[i32], None);
+    let try_take = m.intrinsic("__wbg_try_take", &[i32, i32], None);
+    let make = m.intrinsic("__wbg_make", &[], Some(i32));
+    let grow = m.intrinsic("__wbindgen_anyref_table_grow", &[i32], Some(i32));
+    let set_null = m.intrinsic("__wbindgen_anyref_table_set_null", &[i32], None);
+    m.export("__wbindgen_anyref_table_alloc", &[], Some(i32), body(vec![
+        Instruction::I32Const(1),
+        Instruction::Call(grow),
+        Instruction::End,
+    ]));
+    m.export("__wbindgen_anyref_table_dealloc", &[i32], None, body(vec![
+        Instruction::GetLocal(0),
+        Instruction::Call(set_null),
+        Instruction::I32Const(0),
+        Instruction::I32Const(0),
+        Instruction::I32Load(2, 0),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::I32Store(2, 0),
+        Instruction::End,
+    ]));
+    m.export("run", &[], None, body(vec![
+        Instruction::Call(make),
+        Instruction::Call(take),
+        Instruction::Call(make),
+        Instruction::I32Const(16),
+        Instruction::Call(try_take),
+        Instruction::End,
+    ]));
+    m.export("identity", &[i32], Some(i32), body(vec![Instruction::GetLocal(0), Instruction::End]));
+    m.describe("__wbg_take", &[FUNCTION, 1, ANYREF, UNIT]);
+    m.describe("__wbg_try_take", &[FUNCTION, 1, ANYREF, UNIT]);
+    m.describe("__wbg_make", &[FUNCTION, 0, ANYREF]);
+    m.describe("identity", &[FUNCTION, 1, ANYREF, ANYREF]);
+    m.program(&anyref_program());
+    m.build()
+}
+
+const IMPORTS_JS: &str = "
+    let made = 0;
+    exports.taken = [];
+    exports.make = () => ({ id: ++made });
+    exports.take = x => { exports.taken.push(x.id); };
+    exports.try_take = x => {
+        exports.taken.push(x.id);
+        throw new Error('not taken');
+    };
+";
+
+/// Validates both modules, then runs `identity` and `run` and prints what
+/// happened to the values passed around.
+const RUN_JS: &str = "
+    const fs = require('fs');
+    for (const file of ['test_bg.wasm', 'test_bg_fallback.wasm']) {
+        try {
+            new WebAssembly.Module(fs.readFileSync(file));
+        } catch (e) {
+            console.log(`${file}: ${e.message}`);
+        }
+    }
+
+    const imports = require('./imports');
+    const { identity } = require('./test');
+    const wasm = require('./test_bg');
+    const table = wasm.__wbindgen_anyref_table;
+    const obj = {};
+    console.log(`identity ${identity(obj) === obj}`);
+    wasm.run();
+
+    const mem = new Int32Array(wasm.memory.buffer);
+    console.log(`taken ${imports.taken}`);
+    console.log(`freed ${mem[0]} of ${table.length - 8}`);
+    console.log(`cleared ${[8, 9, 10].map(i => table.get(i) === null)}`);
+    console.log(`exception ${mem[4]} ${table.get(mem[5]).message}`);
+";
+
+#[test]
+fn transformed_module_runs() {
+    let dir = generate(module(), |b| {
+        b.nodejs(true).anyref(true);
+    }).unwrap();
+    fs::write(dir.path().join("imports.js"), IMPORTS_JS).unwrap();
+    fs::write(dir.path().join("run.js"), RUN_JS).unwrap();
+    let output = Command::new("node")
+        .arg("run.js")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+
+    // Slots 8 to 10 are the argument of `identity` and the values passed to
+    // `take` and `try_take`, all freed, while the exception stays in 11.
+    assert_eq!(
+        stdout.trim(),
+        "\
+identity true
+taken 1,2
+freed 3 of 4
+cleared true,true,true
+exception 1 not taken",
+    );
+}
+
+#[test]
+fn fallback_imports_glue() {
+    let dir = generate(module(), |b| {
+        b.nodejs(true).anyref(true);
+    }).unwrap();
+    let imports = imports(&wasm(dir.path(), "test_bg_fallback.wasm"));
+    assert!(imports.contains(&"./test::__wbg_take".to_string()), "{:?}", imports);
+}
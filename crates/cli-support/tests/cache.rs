@@ -0,0 +1,159 @@
+//! Tests of caching output between runs with `Bindgen::cache_dir`.
+
+extern crate parity_wasm;
+extern crate serde_json;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use parity_wasm::elements::*;
+use wasm_bindgen_cli_support::Bindgen;
+
+use support::*;
+
+/// A wasm file with just a memory and `program` in its custom section.
+fn wasm_file(program: &[u8]) -> Vec<u8> {
+    parity_wasm::serialize(memory_module(program)).unwrap()
+}
+
+/// Generates bindings for the wasm file `input` into a new directory in
+/// `root`, returning the directory.
+fn generate_into(root: &Path, input: &Path, cache: &Path, name: &str) -> PathBuf {
+    let out = root.join(name);
+    fs::create_dir(&out).unwrap();
+    Bindgen::new()
+        .input_path(input)
+        .nodejs(true)
+        .cache_dir(cache)
+        .generate(&out)
+        .unwrap();
+    out
+}
+
+fn cached_js(cache: &Path) -> PathBuf {
+    let output = fs::read_dir(cache)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.file_name().unwrap().to_str().unwrap().starts_with("output-"))
+        .expect("output wasn't cached");
+    output.join("files/test.js")
+}
+
+#[test]
+fn output_is_restored() {
+    let root = tempfile::tempdir().unwrap();
+    let input = root.path().join("test.wasm");
+    let cache = root.path().join("cache");
+    fs::write(&input, wasm_file(&full_program())).unwrap();
+
+    let first = generate_into(root.path(), &input, &cache, "first");
+    let js = fs::read_to_string(first.join("test.js")).unwrap();
+    assert!(js.contains("class Counter"));
+    assert_eq!(fs::read_to_string(cached_js(&cache)).unwrap(), js);
+
+    // The second run copies the cached output rather than generating it.
+    fs::write(cached_js(&cache), "// cached").unwrap();
+    let second = generate_into(root.path(), &input, &cache, "second");
+    assert_eq!(fs::read_to_string(second.join("test.js")).unwrap(), "// cached");
+    assert_eq!(
+        fs::read(first.join("test_bg.wasm")).unwrap(),
+        fs::read(second.join("test_bg.wasm")).unwrap(),
+    );
+}
+
+#[test]
+fn changed_input_is_generated() {
+    let root = tempfile::tempdir().unwrap();
+    let input = root.path().join("test.wasm");
+    let cache = root.path().join("cache");
+    fs::write(&input, wasm_file(&full_program())).unwrap();
+    generate_into(root.path(), &input, &cache, "first");
+    fs::write(cached_js(&cache), "// cached").unwrap();
+
+    let program = String::from_utf8(full_program()).unwrap().replace("Counter", "Tally");
+    fs::write(&input, wasm_file(program.as_bytes())).unwrap();
+    let second = generate_into(root.path(), &input, &cache, "second");
+    let js = fs::read_to_string(second.join("test.js")).unwrap();
+    assert!(js.contains("class Tally"), "{}", js);
+}
+
+/// A wasm module exporting `a` and `b`. The descriptor of `b` calls a helper
+/// which describes its return type as `ret`, and `unrelated` functions are
+/// defined before everything else, shifting the indices of the rest.
+fn descriptor_module(a: &[Instruction], ret: i32, unrelated: usize) -> Module {
+    let mut m = ModuleBuilder::new();
+    let describe_intrinsic = m.intrinsic("__wbindgen_describe", &[ValueType::I32], None);
+    for _ in 0..unrelated {
+        m.function(&[], None, body(vec![Instruction::End]));
+    }
+    m.export("a", &[], None, body(a.to_vec()));
+    m.export("b", &[], None, body(vec![Instruction::End]));
+    let helper = m.describe("a", &[FUNCTION, 0, UNIT]) + 2;
+    m.export("__wbindgen_describe_b", &[], None, body(vec![
+        Instruction::I32Const(FUNCTION),
+        Instruction::Call(describe_intrinsic),
+        Instruction::I32Const(0),
+        Instruction::Call(describe_intrinsic),
+        Instruction::Call(helper),
+        Instruction::End,
+    ]));
+    m.function(&[], None, describe(describe_intrinsic, &[ret]));
+    m.program(&program(&["a", "b"], &[]));
+    m.build()
+}
+
+/// Generates bindings for `module` with the cache in `cache`, returning the
+/// JS and the cached descriptors by key.
+fn generate_cached(module: Module, cache: &Path) -> (String, BTreeMap<String, Vec<u64>>) {
+    let dir = generate(module, |b| {
+        b.nodejs(true).cache_dir(cache);
+    }).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_str(&read(cache, "descriptors.json")).unwrap();
+    let descriptors = json["descriptors"]
+        .as_object()
+        .unwrap()
+        .iter()
+        .map(|(key, words)| {
+            let words = words.as_array().unwrap().iter().map(|w| w.as_u64().unwrap());
+            (key.clone(), words.collect())
+        })
+        .collect();
+    (js(&dir), descriptors)
+}
+
+#[test]
+fn unrelated_changes_keep_descriptors() {
+    let root = tempfile::tempdir().unwrap();
+    let cache = root.path().join("cache");
+    let (_, first) = generate_cached(descriptor_module(&[Instruction::End], UNIT, 0), &cache);
+    assert_eq!(first.len(), 2, "{:?}", first);
+
+    // Changing `a` itself and adding functions doesn't change the code of
+    // either descriptor.
+    let a = [Instruction::Nop, Instruction::End];
+    let (_, second) = generate_cached(descriptor_module(&a, UNIT, 3), &cache);
+    assert_eq!(first, second);
+}
+
+#[test]
+fn changed_callee_invalidates_descriptor() {
+    let root = tempfile::tempdir().unwrap();
+    let cache = root.path().join("cache");
+    let (js, first) = generate_cached(descriptor_module(&[Instruction::End], UNIT, 0), &cache);
+    assert!(!js.contains("@returns {number}"), "{}", js);
+
+    let (js, second) = generate_cached(descriptor_module(&[Instruction::End], I32, 0), &cache);
+    assert!(js.contains("@returns {number}"), "{}", js);
+    let kept = first.keys().filter(|k| second.contains_key(*k)).collect::<Vec<_>>();
+    assert_eq!(kept.len(), 1, "{:?} {:?}", first, second);
+    assert_eq!(first[kept[0]], [FUNCTION as u64, 0, UNIT as u64]);
+    let changed = second.iter().find(|&(k, _)| !first.contains_key(k)).unwrap();
+    assert_eq!(*changed.1, [FUNCTION as u64, 0, I32 as u64]);
+}
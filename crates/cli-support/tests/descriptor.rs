@@ -0,0 +1,62 @@
+//! Tests of the errors for descriptors which can't be decoded.
+
+extern crate parity_wasm;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use parity_wasm::elements::*;
+
+use support::*;
+
+/// A wasm module exporting `run`, whose descriptor is `words`.
+fn module(words: &[i32]) -> Module {
+    let mut m = ModuleBuilder::new();
+    m.intrinsic("__wbindgen_describe", &[ValueType::I32], None);
+    m.export("run", &[], None, body(vec![Instruction::End]));
+    m.describe("run", words);
+    m.program(&program(&["run"], &[]));
+    m.build()
+}
+
+/// Generates bindings for a module whose descriptor is `words`, checking
+/// the error names the export and lists the words decoded before `error`.
+fn assert_fails(words: &[i32], decoded: &[i32], error: &str) {
+    let err = generate(module(words), |b| {
+        b.nodejs(true);
+    }).err().unwrap();
+    assert!(err.contains("failed to determine the signature of the export `run`"), "{}", err);
+    let words = format!("malformed descriptor {:?}, decoded {:?} so far", words, decoded);
+    assert!(err.contains(&words), "{}", err);
+    assert!(err.contains(error), "{}", err);
+}
+
+#[test]
+fn truncated() {
+    assert_fails(&[FUNCTION, 2, I32], &[FUNCTION, 2, I32], "unexpected end of descriptor");
+}
+
+#[test]
+fn unknown_tag() {
+    assert_fails(&[FUNCTION, 1, 99, UNIT], &[FUNCTION, 1, 99], "unknown descriptor: 99");
+}
+
+#[test]
+fn leftover_words() {
+    assert_fails(
+        &[FUNCTION, 0, UNIT, I32, I32],
+        &[FUNCTION, 0, UNIT],
+        "2 words left over after the descriptor",
+    );
+}
+
+#[test]
+fn invalid_struct_name() {
+    assert_fails(
+        &[FUNCTION, 0, RUST_STRUCT, 2, 'A' as i32, 0xd800],
+        &[FUNCTION, 0, RUST_STRUCT, 2, 'A' as i32, 0xd800],
+        "invalid character 55296 in a struct name",
+    );
+}
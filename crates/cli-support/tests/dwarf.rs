@@ -0,0 +1,242 @@
+//! Tests of the rewriting of DWARF debug info done with `--keep-debug`.
+
+extern crate parity_wasm;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use parity_wasm::elements::*;
+
+use support::*;
+
+const CLOSURE: i32 = 12;
+
+// Offsets of the instructions of the input module below in the payload of its
+// code section, which starts with the number of bodies.
+//
+// `dead`: its body starts at 2, with instructions at 3, 4, 5 and 6.
+const DEAD_START: u32 = 2;
+const DEAD_END: u32 = 7;
+// `make`: its body starts at 8, with instructions at 9, 11, 13, 15 and 17.
+const MAKE_START: u32 = 8;
+const MAKE_FIRST: u32 = 9;
+const MAKE_CALL: u32 = 15;
+const MAKE_END: u32 = 18;
+
+// Offsets in the output module, where `make` is the only function left and
+// its body starts at 2, with instructions at 3, 5, 7, 9 and 11.
+const NEW_MAKE_START: u32 = 2;
+const NEW_MAKE_FIRST: u32 = 3;
+const NEW_MAKE_CALL: u32 = 9;
+const NEW_MAKE_END: u32 = 12;
+
+/// A wasm module with a function `dead` which is removed, a closure factory
+/// `make` whose call to `__wbindgen_describe_closure` is rewritten, and the
+/// descriptor of that closure. Its DWARF has a line program with a sequence
+/// for each of the first two functions and one for code `wasm-ld`
+/// tombstoned, and a unit with a subprogram for each of them.
+fn module(names: bool) -> Module {
+    let i32 = ValueType::I32;
+    let mut m = ModuleBuilder::new();
+    let describe_intrinsic = m.intrinsic("__wbindgen_describe", &[i32], None);
+    let describe_closure = m.intrinsic("__wbindgen_describe_closure", &[i32; 3], Some(i32));
+    let dead = m.function(&[], None, body(vec![
+        Instruction::Nop,
+        Instruction::Nop,
+        Instruction::Nop,
+        Instruction::End,
+    ]));
+    let make = m.export("run", &[], Some(i32), body(vec![
+        Instruction::I32Const(0),
+        Instruction::I32Const(0),
+        Instruction::I32Const(0),
+        Instruction::Call(describe_closure),
+        Instruction::End,
+    ]));
+    let descriptor = describe(describe_intrinsic, &[CLOSURE, 0, FUNCTION, 0, UNIT]);
+    let descriptor = m.function(&[], None, descriptor);
+    m.table(&[descriptor]);
+    m.program(&program(&[], &[]));
+    m.section(debug_section(".debug_abbrev", abbrev()));
+    m.section(debug_section(
+        ".debug_info",
+        info(&[(MAKE_START, MAKE_END - MAKE_START), (DEAD_START, DEAD_END - DEAD_START)]),
+    ));
+    m.section(debug_section(".debug_line", line(&input_line_program())));
+    if names {
+        let mut map = NameMap::default();
+        map.insert(dead, "dead".to_string());
+        map.insert(make, "make".to_string());
+        map.insert(descriptor, "descriptor".to_string());
+        let mut names = FunctionNameSection::default();
+        *names.names_mut() = map;
+        m.section(Section::Name(NameSection::Function(names)));
+    }
+    m.build()
+}
+
+fn debug_section(name: &str, payload: Vec<u8>) -> Section {
+    let mut custom = CustomSection::default();
+    *custom.name_mut() = name.to_string();
+    *custom.payload_mut() = payload;
+    Section::Custom(custom)
+}
+
+fn push_u32(dst: &mut Vec<u8>, val: u32) {
+    dst.extend_from_slice(&[val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8]);
+}
+
+/// A compile unit abbreviation with a line program and a base address, and a
+/// subprogram one with a code range.
+fn abbrev() -> Vec<u8> {
+    vec![
+        // DW_TAG_compile_unit, with children
+        1, 0x11, 1,
+        // DW_AT_stmt_list, DW_FORM_sec_offset
+        0x10, 0x17,
+        // DW_AT_low_pc, DW_FORM_addr
+        0x11, 0x01,
+        0, 0,
+        // DW_TAG_subprogram, without children
+        2, 0x2e, 0,
+        // DW_AT_low_pc, DW_FORM_addr
+        0x11, 0x01,
+        // DW_AT_high_pc, DW_FORM_data4
+        0x12, 0x06,
+        0, 0,
+        0,
+    ]
+}
+
+/// A DWARF 4 unit with a subprogram for each `(low_pc, size)` of
+/// `subprograms`.
+fn info(subprograms: &[(u32, u32)]) -> Vec<u8> {
+    let mut unit = vec![4, 0];
+    push_u32(&mut unit, 0);
+    unit.push(4);
+    unit.push(1);
+    push_u32(&mut unit, 0);
+    push_u32(&mut unit, 0);
+    for &(low_pc, size) in subprograms {
+        unit.push(2);
+        push_u32(&mut unit, low_pc);
+        push_u32(&mut unit, size);
+    }
+    unit.push(0);
+    let mut ret = Vec::new();
+    push_u32(&mut ret, unit.len() as u32);
+    ret.extend_from_slice(&unit);
+    ret
+}
+
+/// A DWARF 4 line program unit running `program`.
+fn line(program: &[u8]) -> Vec<u8> {
+    let mut header = vec![
+        // minimum_instruction_length, maximum_operations_per_instruction,
+        // default_is_stmt, line_base, line_range, opcode_base
+        1, 1, 1, -5i8 as u8, 14, 13,
+        // standard_opcode_lengths
+        0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1,
+        // no include_directories nor file_names
+        0, 0,
+    ];
+    let mut unit = vec![4, 0];
+    push_u32(&mut unit, header.len() as u32);
+    unit.append(&mut header);
+    unit.extend_from_slice(program);
+    let mut ret = Vec::new();
+    push_u32(&mut ret, unit.len() as u32);
+    ret.extend_from_slice(&unit);
+    ret
+}
+
+fn set_address(dst: &mut Vec<u8>, address: u32) {
+    dst.extend_from_slice(&[0, 5, 2]);
+    push_u32(dst, address);
+}
+
+fn input_line_program() -> Vec<u8> {
+    let mut ret = Vec::new();
+    // `dead`: a row at line 1 and the end of the sequence
+    set_address(&mut ret, DEAD_START + 1);
+    ret.push(1);
+    ret.extend_from_slice(&[2, (DEAD_END - DEAD_START - 1) as u8]);
+    ret.extend_from_slice(&[0, 1, 1]);
+    // code tombstoned by `wasm-ld`, whose address wraps around
+    set_address(&mut ret, 0xffff_ffff);
+    ret.push(1);
+    ret.extend_from_slice(&[2, 4]);
+    ret.extend_from_slice(&[0, 1, 1]);
+    // `make`: rows at line 10 and 11, then the end of the sequence
+    set_address(&mut ret, MAKE_FIRST);
+    ret.extend_from_slice(&[3, 9, 1]);
+    ret.extend_from_slice(&[2, (MAKE_CALL - MAKE_FIRST) as u8, 3, 1, 1]);
+    ret.extend_from_slice(&[2, (MAKE_END - MAKE_CALL) as u8]);
+    ret.extend_from_slice(&[0, 1, 1]);
+    ret
+}
+
+fn custom<'a>(module: &'a Module, name: &str) -> &'a [u8] {
+    module
+        .sections()
+        .iter()
+        .filter_map(|s| match *s {
+            Section::Custom(ref s) if s.name() == name => Some(s.payload()),
+            _ => None,
+        })
+        .next()
+        .unwrap()
+}
+
+#[test]
+fn rewrites_addresses() {
+    let output = generate_wasm(module(true), |b| {
+        b.keep_debug(true);
+    }).unwrap();
+    assert_eq!(imports(&output), ["./test::__wbindgen_closure_wrapper1"]);
+    let code = output.code_section().unwrap().bodies();
+    assert_eq!(code.len(), 1);
+    assert_eq!(code[0].code().elements()[3], Instruction::Call(0));
+
+    let mut expected = Vec::new();
+    set_address(&mut expected, NEW_MAKE_FIRST);
+    expected.extend_from_slice(&[3, 9, 1]);
+    expected.extend_from_slice(&[2, (NEW_MAKE_CALL - NEW_MAKE_FIRST) as u8, 3, 1, 1]);
+    expected.extend_from_slice(&[2, (NEW_MAKE_END - NEW_MAKE_CALL) as u8]);
+    expected.extend_from_slice(&[0, 1, 1]);
+    assert_eq!(custom(&output, ".debug_line"), &line(&expected)[..]);
+
+    let expected = info(&[
+        (NEW_MAKE_START, NEW_MAKE_END - NEW_MAKE_START),
+        (0xffff_ffff, DEAD_END - DEAD_START),
+    ]);
+    assert_eq!(custom(&output, ".debug_info"), &expected[..]);
+    assert_eq!(custom(&output, ".debug_abbrev"), &abbrev()[..]);
+}
+
+#[test]
+fn restores_names() {
+    let output = generate_wasm(module(true), |b| {
+        b.keep_debug(true);
+    }).unwrap().parse_names().unwrap();
+    let names = output
+        .names_section()
+        .map(|s| match *s {
+            NameSection::Function(ref f) => f
+                .names()
+                .iter()
+                .map(|(i, name)| (i, name.clone()))
+                .collect::<Vec<_>>(),
+            _ => Vec::new(),
+        })
+        .unwrap();
+    assert_eq!(names, [(1, "make".to_string())]);
+
+    // The names used to track functions never make it to the output.
+    let output = generate_wasm(module(false), |b| {
+        b.keep_debug(true);
+    }).unwrap().parse_names().unwrap();
+    assert!(output.names_section().is_none());
+}
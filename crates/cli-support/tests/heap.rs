@@ -0,0 +1,118 @@
+//! Tests of the heap tracking done by the JS glue in debug mode, running it
+//! in node.js.
+
+extern crate parity_wasm;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::fs;
+use std::process::Command;
+
+use parity_wasm::elements::*;
+use wasm_bindgen_cli_support::Bindgen;
+
+use support::*;
+
+/// A wasm module whose export `leak` creates the string `hello` at address
+/// 0 with `__wbindgen_string_new`, storing its handle at address 8, `drop`
+/// drops it again, and `snapshot` takes a heap report with the
+/// `__wbindgen_heap_snapshot` intrinsic, storing its handle at address 12.
+fn module() -> Module {
+    let i32 = ValueType::I32;
+    let mut m = ModuleBuilder::new();
+    let string_new = m.intrinsic("__wbindgen_string_new", &[i32; 2], Some(i32));
+    let snapshot = m.intrinsic("__wbindgen_heap_snapshot", &[], Some(i32));
+    let drop_ref = m.intrinsic("__wbindgen_object_drop_ref", &[i32], None);
+    m.export("leak", &[], None, body(vec![
+        Instruction::I32Const(8),
+        Instruction::I32Const(0),
+        Instruction::I32Const(5),
+        Instruction::Call(string_new),
+        Instruction::I32Store(2, 0),
+        Instruction::End,
+    ]));
+    m.export("drop", &[], None, body(vec![
+        Instruction::I32Const(8),
+        Instruction::I32Load(2, 0),
+        Instruction::Call(drop_ref),
+        Instruction::End,
+    ]));
+    m.export("snapshot", &[], None, body(vec![
+        Instruction::I32Const(12),
+        Instruction::Call(snapshot),
+        Instruction::I32Store(2, 0),
+        Instruction::End,
+    ]));
+    m.data(0, b"hello");
+    m.program(&program(&[], &[]));
+    m.build()
+}
+
+/// Leaks a string and takes a snapshot of the heap from wasm, printing what
+/// the heap reports say before and after dropping the string.
+const RUN_JS: &str = "
+    const glue = require('./test');
+    const wasm = require('./test_bg');
+    const mem = new Int32Array(wasm.memory.buffer);
+    const report = glue.__wbindgen_heap_report;
+    console.log(`report ${typeof report}`);
+
+    wasm.leak();
+    wasm.snapshot();
+    if (report) {
+        const live = report();
+        const leaked = live.find(v => v.index === mem[2]);
+        console.log(`live ${live.length} ${leaked.value} ${leaked.refcount}`);
+        console.log(`trace ${leaked.trace.includes('__wbindgen_string_new')}`);
+        // The snapshot from wasm is a report itself, taken before it was
+        // added to the heap.
+        const snapshot = live.find(v => v.index === mem[3]).value;
+        console.log(`snapshot ${snapshot.map(v => v.value)} ${snapshot[0].id === leaked.id}`);
+        wasm.drop();
+        console.log(`dropped ${report().map(v => v.index === mem[2])}`);
+    } else {
+        console.log(`snapshot ${mem[3]}`);
+    }
+";
+
+/// Generates node.js bindings for `module()` configured by `configure` and
+/// runs `RUN_JS` with them, returning its output.
+fn run(configure: impl FnOnce(&mut Bindgen)) -> String {
+    let dir = generate(module(), |b| {
+        b.nodejs(true);
+        configure(b);
+    }).unwrap();
+    fs::write(dir.path().join("run.js"), RUN_JS).unwrap();
+    let output = Command::new("node")
+        .arg("run.js")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    stdout.trim().to_string()
+}
+
+#[test]
+fn debug_tracks_heap() {
+    assert_eq!(
+        run(|b| {
+            b.debug(true);
+        }),
+        "\
+report function
+live 2 hello 1
+trace true
+snapshot hello true
+dropped false",
+    );
+}
+
+#[test]
+fn heap_is_untracked_without_debug() {
+    // Rust gets no report to tell it the heap isn't tracked.
+    assert_eq!(run(|_| {}), "report undefined\nsnapshot 0");
+}
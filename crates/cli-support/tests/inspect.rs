@@ -0,0 +1,83 @@
+//! Tests of `wasm-bindgen inspect` on a program using the whole schema.
+
+extern crate parity_wasm;
+#[macro_use]
+extern crate serde_json;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use parity_wasm::elements::*;
+use wasm_bindgen_cli_support::inspect::Config;
+
+use support::*;
+
+/// A wasm module embedding `full_program`, along with the descriptor of its
+/// `greet` export and a malformed one for `main`.
+fn module() -> Vec<u8> {
+    let mut m = ModuleBuilder::new();
+    m.without_memory();
+    m.intrinsic("__wbindgen_describe", &[ValueType::I32], None);
+    m.describe("greet", &[FUNCTION, 1, REF, STRING, UNIT]);
+    m.describe("main", &[FUNCTION, 0]);
+    m.program(&full_program());
+    parity_wasm::serialize(m.build()).unwrap()
+}
+
+#[test]
+fn text() {
+    let text = Config::new().inspect(&module()).unwrap();
+    let header = format!(
+        "program 0: wasm-bindgen 0.2.22 (ae0d8f9c2), schema version {}",
+        wasm_bindgen_shared::SCHEMA_VERSION,
+    );
+    for line in [
+        &*header,
+        "  export fn greet (split into `greetings`)",
+        "  export fn main (start)",
+        "  export fn Counter::increment (method)",
+        "  import fn log in `console` as __wbg_log_1f6bbde4e7fd6d05",
+        "  import type Element",
+        "  struct Counter { readonly count }",
+        "  enum Color { Red = 0, Green = 1 }",
+        "descriptors:",
+        "    [11, 1, 14, 13, 23]",
+        "  main: error: malformed descriptor [11, 0], decoded [11, 0] so far: \
+         unexpected end of descriptor",
+    ].iter() {
+        assert!(text.lines().any(|l| l == *line), "missing {:?} in\n{}", line, text);
+    }
+}
+
+#[test]
+fn json() {
+    let json = Config::new().json(true).inspect(&module()).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+    let programs = json["programs"].as_array().unwrap();
+    assert_eq!(programs.len(), 1);
+    assert_eq!(programs[0]["version"], "0.2.22 (ae0d8f9c2)");
+    assert_eq!(
+        programs[0]["schema_version"],
+        wasm_bindgen_shared::SCHEMA_VERSION.to_string(),
+    );
+    assert_eq!(programs[0]["error"], serde_json::Value::Null);
+    let program = &programs[0]["program"];
+    assert_eq!(program["exports"][0]["function"]["name"], "greet");
+    assert_eq!(program["exports"][0]["split"], "greetings");
+    assert_eq!(program["imports"][1]["kind"]["fallbacks"][0], "HTMLElement");
+    assert_eq!(program["structs"][0]["name"], "Counter");
+
+    let descriptors = json["descriptors"].as_array().unwrap();
+    assert_eq!(descriptors.len(), 2);
+    assert_eq!(descriptors[0]["name"], "greet");
+    assert_eq!(descriptors[0]["words"], json!([11, 1, 14, 13, 23]));
+    assert_eq!(descriptors[0]["error"], serde_json::Value::Null);
+    assert!(descriptors[0]["descriptor"].is_string(), "{}", descriptors[0]);
+    assert_eq!(descriptors[1]["name"], "main");
+    assert_eq!(descriptors[1]["descriptor"], serde_json::Value::Null);
+    let error = descriptors[1]["error"].as_str().unwrap();
+    assert!(error.contains("unexpected end of descriptor"), "{}", error);
+}
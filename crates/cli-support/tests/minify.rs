@@ -0,0 +1,137 @@
+//! Tests of minified output with `Bindgen::minify`.
+
+extern crate parity_wasm;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::collections::HashMap;
+use std::fs;
+use std::process::Command;
+
+use parity_wasm::elements::*;
+use wasm_bindgen_cli_support::Bindgen;
+
+use support::*;
+
+/// A wasm module whose export `run` calls the `__wbindgen_string_new` and
+/// `__wbindgen_object_drop_ref` intrinsics, and whose `greet` export returns
+/// the string `hello` at address 0.
+fn module() -> Module {
+    let mut m = ModuleBuilder::new();
+    let i32 = ValueType::I32;
+    m.intrinsic("__wbindgen_describe", &[i32], None);
+    let string_new = m.intrinsic("__wbindgen_string_new", &[i32; 2], Some(i32));
+    let drop_ref = m.intrinsic("__wbindgen_object_drop_ref", &[i32], None);
+    m.export("run", &[], None, body(vec![
+        Instruction::I32Const(0),
+        Instruction::I32Const(5),
+        Instruction::Call(string_new),
+        Instruction::Call(drop_ref),
+        Instruction::End,
+    ]));
+    m.export("greet", &[], Some(i32), body(vec![
+        Instruction::I32Const(0),
+        Instruction::I32Const(5),
+        Instruction::Call(string_new),
+        Instruction::End,
+    ]));
+    m.describe("greet", &[FUNCTION, 0, ANYREF]);
+    m.data(0, b"hello");
+    m.program(&program(&["greet"], &[]));
+    m.build()
+}
+
+/// The generated JS for `module()` configured by `configure`, along with the
+/// fields of the output wasm module's imports.
+fn glue(configure: impl FnOnce(&mut Bindgen)) -> Result<(String, Vec<String>), String> {
+    let dir = generate(module(), configure)?;
+    Ok((js(&dir), imports(&wasm(dir.path(), "test_bg.wasm"))))
+}
+
+#[test]
+fn names_are_shortened() {
+    let (js, imports) = glue(|b| {
+        b.minify(true);
+    }).unwrap();
+    assert_eq!(imports, ["./test::$a", "./test::$b"]);
+    assert!(js.contains("export function $a(p,l){return "), "{}", js);
+    assert!(js.contains("export function $b(i){"), "{}", js);
+    for name in ["__wbindgen", "addHeapObject", "getStringFromWasm", "dropRef"].iter() {
+        assert!(!js.contains(name), "{}", js);
+    }
+}
+
+#[test]
+fn glue_is_stripped() {
+    let (full, _) = glue(|_| {}).unwrap();
+    let (js, _) = glue(|b| {
+        b.minify(true);
+    }).unwrap();
+    assert!(full.contains("/*") && full.contains("//"), "{}", full);
+    assert!(!js.contains("/*") && !js.contains("//"), "{}", js);
+    assert!(!js.contains("\n\n") && !js.contains("  "), "{}", js);
+    // The values `slab` starts with are still objects with an `obj` key.
+    assert!(js.contains("[{obj:undefined},{obj:null},"), "{}", js);
+    assert!(js.len() < full.len() * 2 / 3, "{} vs {}", js.len(), full.len());
+}
+
+/// Calls the exports of the module through the glue, printing what `greet`
+/// returned.
+const RUN_JS: &str = "
+    const { greet } = require('./test');
+    const wasm = require('./test_bg');
+    wasm.run();
+    const greetings = [greet(), greet()];
+    console.log(`${greetings.join(' ')} ${typeof greetings[0]}`);
+";
+
+#[test]
+fn nodejs_glue_runs() {
+    for &minify in [false, true].iter() {
+        let dir = generate(module(), |b| {
+            b.nodejs(true).minify(minify);
+        }).unwrap();
+        let imports = imports(&wasm(dir.path(), "test_bg.wasm"));
+        if minify {
+            assert_eq!(imports, ["./test::$a", "./test::$b"]);
+        }
+        fs::write(dir.path().join("run.js"), RUN_JS).unwrap();
+        let output = Command::new("node")
+            .arg("run.js")
+            .current_dir(dir.path())
+            .output()
+            .expect("failed to run node");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+        assert_eq!(stdout.trim(), "hello hello string", "minify: {}", minify);
+    }
+}
+
+#[test]
+fn debug_is_rejected() {
+    let err = glue(|b| {
+        b.minify(true).debug(true);
+    }).unwrap_err();
+    assert!(err.contains("debug"), "{}", err);
+}
+
+#[test]
+fn template_substitutions_are_renamed() {
+    let js = "
+        function describe(value) {
+            return `<${value}>`;
+        }
+        export function run(x) {
+            return `${describe(x)} and ${`nested ${describe({ x })}`} ${/}/.source}`;
+        }
+    ";
+    let js = wasm_bindgen_cli_support::minify_js(js, &HashMap::new(), 0).unwrap();
+    assert_eq!(
+        js,
+        "function a(value){return`<${value}>`;}\n\
+         export function run(x){return`${a(x)} and ${`nested ${a({x})}`} ${/}/.source}`;}\n",
+    );
+}
@@ -0,0 +1,110 @@
+//! Tests that wasm files built with older versions of the `#[wasm_bindgen]`
+//! macro can still be processed, using the programs released versions of the
+//! schema embedded in `schema/*`, along with the current version.
+
+extern crate parity_wasm;
+extern crate serde_json;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::fs;
+use std::process::Command;
+
+use parity_wasm::elements::*;
+
+use support::*;
+
+/// A wasm module embedding `program`, which exports the `greet` function
+/// and `Counter::increment` method of the fixtures and the `main` start
+/// function of `full_program` as functions taking nothing, along with their
+/// descriptors.
+fn module(program: &[u8]) -> Module {
+    let mut m = ModuleBuilder::new();
+    m.intrinsic("__wbindgen_describe", &[ValueType::I32], None);
+    m.export("greet", &[], None, body(vec![Instruction::End]));
+    m.export("main", &[], None, body(vec![Instruction::End]));
+    m.export("counter_increment", &[ValueType::I32], None, body(vec![Instruction::End]));
+    m.describe("greet", &[FUNCTION, 0, UNIT]);
+    m.describe("main", &[FUNCTION, 0, UNIT]);
+    m.describe("counter_increment", &[FUNCTION, 0, UNIT]);
+    m.program(program);
+    m.build()
+}
+
+/// Generates node.js bindings for `program`, returning the generated JS.
+fn nodejs_js(program: &[u8]) -> Result<String, String> {
+    generate_js(module(program), |b| {
+        b.nodejs(true);
+    })
+}
+
+/// Uses the exports of the program in the schema fixtures.
+const RUN_JS: &str = "
+    const { greet, Color, Counter } = require('./test');
+    greet();
+    console.log(`${Color.Red} ${Color.Green} ${typeof Counter.prototype.increment}`);
+";
+
+/// Generates node.js bindings for `program` and runs `RUN_JS` with them,
+/// returning its output.
+fn run(program: &[u8]) -> String {
+    let dir = generate(module(program), |b| {
+        b.nodejs(true);
+    }).unwrap();
+    fs::write(dir.path().join("run.js"), RUN_JS).unwrap();
+    let output = Command::new("node")
+        .arg("run.js")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    stdout.trim().to_string()
+}
+
+#[test]
+fn schema_0_2_22() {
+    assert_eq!(run(include_bytes!("schema/0.2.22.json")), "0 1 function");
+}
+
+/// `full_program` encoded in the binary format the macro embeds.
+fn binary_program() -> Vec<u8> {
+    let program: wasm_bindgen_shared::Program =
+        serde_json::from_slice(&full_program()).unwrap();
+    wasm_bindgen_shared::binary::encode(&program)
+}
+
+#[test]
+fn current_schema() {
+    assert_eq!(run(&binary_program()), "0 1 function");
+}
+
+#[test]
+fn truncated_binary() {
+    let program = binary_program();
+    let err = nodejs_js(&program[..program.len() - 1]).unwrap_err();
+    assert!(err.contains("unexpected end of program"), "{}", err);
+}
+
+#[test]
+fn newer_schema() {
+    let newer = wasm_bindgen_shared::SCHEMA_VERSION + 1;
+    let program = String::from_utf8(full_program()).unwrap().replace(
+        &format!(r#""schema_version": "{}""#, wasm_bindgen_shared::SCHEMA_VERSION),
+        &format!(r#""schema_version": "{}""#, newer),
+    );
+    let err = nodejs_js(program.as_bytes()).unwrap_err();
+    assert!(err.contains("newer version of wasm-bindgen than this binary"), "{}", err);
+}
+
+#[test]
+fn older_schema() {
+    let program = include_str!("schema/0.2.22.json")
+        .replace(r#""schema_version": "0.2.22""#, r#""schema_version": "0.2.21""#);
+    let err = nodejs_js(program.as_bytes()).unwrap_err();
+    assert!(err.contains("too old for this binary"), "{}", err);
+    assert!(err.contains("only down to 0.2.22,"), "{}", err);
+}
@@ -0,0 +1,77 @@
+//! Tests of the report written with `Bindgen::size_report`.
+
+extern crate parity_wasm;
+extern crate serde_json;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::fs;
+
+use parity_wasm::elements::*;
+
+use support::*;
+
+/// The descriptor of both exports of `module()`.
+const DESCRIPTOR: &[i32] = &[FUNCTION, 0, UNIT];
+
+/// The bodies of the functions of `module()` besides the descriptors, after
+/// the import of `__wbindgen_describe`. Export `a` calls `only_a` and
+/// `shared`, and export `b` calls `shared`.
+fn bodies() -> Vec<FuncBody> {
+    vec![
+        // a
+        body(vec![Instruction::Call(3), Instruction::Call(4), Instruction::End]),
+        // b
+        body(vec![Instruction::Call(4), Instruction::End]),
+        // only_a
+        body(vec![Instruction::Nop, Instruction::Nop, Instruction::End]),
+        // shared
+        body(vec![Instruction::Nop, Instruction::Nop, Instruction::Nop, Instruction::End]),
+    ]
+}
+
+/// A wasm module exporting `a` and `b` as described by `bodies()`.
+fn module() -> Module {
+    let bodies = bodies();
+    let mut m = ModuleBuilder::new();
+    m.intrinsic("__wbindgen_describe", &[ValueType::I32], None);
+    m.export("a", &[], None, bodies[0].clone());
+    m.export("b", &[], None, bodies[1].clone());
+    m.function(&[], None, bodies[2].clone());
+    m.function(&[], None, bodies[3].clone());
+    m.describe("a", DESCRIPTOR);
+    m.describe("b", DESCRIPTOR);
+    m.program(&program(&["a", "b"], &[]));
+    m.build()
+}
+
+#[test]
+fn exports_are_attributed_reachable_code() {
+    let report_dir = tempfile::tempdir().unwrap();
+    let dir = generate(module(), |b| {
+        b.nodejs(true).size_report(report_dir.path().join("report.json"));
+    }).unwrap();
+    let report: serde_json::Value =
+        serde_json::from_str(&read(report_dir.path(), "report.json")).unwrap();
+
+    let size = |b| parity_wasm::serialize(b).unwrap().len() as u64;
+    let sizes = bodies().into_iter().map(size).collect::<Vec<_>>();
+    let (a, b, only_a, shared) = (sizes[0], sizes[1], sizes[2], sizes[3]);
+    let descriptor = size(describe(0, DESCRIPTOR));
+
+    let bindings = report["bindings"].as_array().unwrap();
+    assert_eq!(bindings.len(), 2, "{}", report);
+    assert_eq!(bindings[0]["name"], "a");
+    assert_eq!(bindings[0]["wasm"], a + only_a);
+    assert_eq!(bindings[0]["wasm_reachable"], a + only_a + shared);
+    assert_eq!(bindings[0]["descriptor"], descriptor);
+    assert_eq!(bindings[1]["name"], "b");
+    assert_eq!(bindings[1]["wasm"], b);
+    assert_eq!(bindings[1]["wasm_reachable"], b + shared);
+
+    let output = fs::metadata(dir.path().join("test_bg.wasm")).unwrap().len();
+    assert_eq!(report["wasm"]["output"], output);
+}
@@ -0,0 +1,165 @@
+//! Tests of the source maps emitted with `Bindgen::source_map`.
+
+extern crate parity_wasm;
+#[macro_use]
+extern crate serde_json;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use parity_wasm::elements::*;
+use wasm_bindgen_cli_support::Bindgen;
+
+use support::*;
+
+/// The exports of `module()` with the file, line and column the macro
+/// recorded for each of them. `third` comes before `first` in its file so
+/// both the source and the line go backwards between them.
+const EXPORTS: &[(&str, &str, u32, u32)] = &[
+    ("first", "src/lib.rs", 40, 4),
+    ("second", "src/other.rs", 3, 0),
+    ("third", "src/lib.rs", 2, 8),
+];
+
+/// A program exporting the functions in `EXPORTS`, with their locations if
+/// `locations` is set.
+fn located_program(locations: bool) -> Vec<u8> {
+    let names = EXPORTS.iter().map(|e| e.0).collect::<Vec<_>>();
+    let mut program: serde_json::Value = serde_json::from_slice(&program(&names, &[])).unwrap();
+    if locations {
+        let exports = program["exports"].as_array_mut().unwrap();
+        for (export, &(_, file, line, column)) in exports.iter_mut().zip(EXPORTS) {
+            export["function"]["location"] =
+                json!({ "file": file, "line": line, "column": column });
+        }
+    }
+    serde_json::to_vec(&program).unwrap()
+}
+
+/// A wasm module for `located_program`, where `first` takes and returns an
+/// `i32` and the other exports take nothing.
+fn module(locations: bool) -> Module {
+    let mut m = ModuleBuilder::new();
+    m.intrinsic("__wbindgen_describe", &[ValueType::I32], None);
+    let first = body(vec![Instruction::GetLocal(0), Instruction::End]);
+    m.export("first", &[ValueType::I32], Some(ValueType::I32), first);
+    m.export("second", &[], None, body(vec![Instruction::End]));
+    m.export("third", &[], None, body(vec![Instruction::End]));
+    m.describe("first", &[FUNCTION, 1, I32, I32]);
+    m.describe("second", &[FUNCTION, 0, UNIT]);
+    m.describe("third", &[FUNCTION, 0, UNIT]);
+    m.program(&located_program(locations));
+    m.build()
+}
+
+/// Decodes a base64 VLQ encoded segment of a source map.
+fn decode_segment(segment: &str) -> Vec<i64> {
+    const BASE64: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut values = Vec::new();
+    let mut value = 0;
+    let mut shift = 0;
+    for c in segment.chars() {
+        let digit = BASE64.find(c).unwrap() as i64;
+        value |= (digit & 0x1f) << shift;
+        shift += 5;
+        if digit & 0x20 == 0 {
+            let negative = value & 1 == 1;
+            value >>= 1;
+            values.push(if negative { -value } else { value });
+            value = 0;
+            shift = 0;
+        }
+    }
+    assert_eq!(shift, 0, "unterminated segment {:?}", segment);
+    values
+}
+
+/// Decodes `mappings` into the source, line and column of each generated
+/// line, if it's mapped.
+fn decode_mappings(mappings: &str) -> Vec<Option<(i64, i64, i64)>> {
+    let mut prev = (0, 0, 0);
+    mappings
+        .split(';')
+        .map(|line| {
+            if line.is_empty() {
+                return None;
+            }
+            assert!(!line.contains(','), "more than one segment on a line: {:?}", line);
+            let values = decode_segment(line);
+            assert_eq!(values.len(), 4, "{:?}", line);
+            assert_eq!(values[0], 0, "segments start at the first column");
+            prev = (prev.0 + values[1], prev.1 + values[2], prev.2 + values[3]);
+            Some(prev)
+        })
+        .collect()
+}
+
+#[test]
+fn markers_are_stripped() {
+    let dir = generate(module(true), |b| {
+        b.nodejs(true).source_map(true);
+    }).unwrap();
+    let js = js(&dir);
+    assert!(!js.contains("__wbindgen_source_location"), "{}", js);
+    assert!(js.ends_with("\n//# sourceMappingURL=test.js.map\n"), "{}", js);
+}
+
+#[test]
+fn shims_map_to_declarations() {
+    let dir = generate(module(true), |b| {
+        b.nodejs(true).source_map(true);
+    }).unwrap();
+    let js = js(&dir);
+    let map: serde_json::Value = serde_json::from_str(&read(dir.path(), "test.js.map")).unwrap();
+    assert_eq!(map["version"], 3);
+    assert_eq!(map["file"], "test.js");
+    assert_eq!(map["sources"], json!(["src/lib.rs", "src/other.rs"]));
+
+    // The `sourceMappingURL` comment is the only line without a mapping
+    // entry.
+    let lines = js.lines().collect::<Vec<_>>();
+    let mappings = map["mappings"].as_str().unwrap();
+    // Line 40 is 39 when 0-based, which takes two digits, and moving to
+    // `second` makes the line and column deltas negative.
+    assert!(mappings.contains(";AAuCI;"), "{}", mappings);
+    assert!(mappings.contains(";ACrCJ;"), "{}", mappings);
+    let mappings = decode_mappings(mappings);
+    assert_eq!(mappings.len() + 1, lines.len());
+
+    for &(name, file, line, column) in EXPORTS {
+        let source = if file == "src/lib.rs" { 0 } else { 1 };
+        let expected = Some((source, line as i64 - 1, column as i64));
+        let start = lines
+            .iter()
+            .position(|l| l.contains(&format!("exports.{} = function(", name)))
+            .unwrap();
+        let end = start + lines[start..].iter().position(|l| *l == "};").unwrap();
+        for i in start..end + 1 {
+            assert_eq!(mappings[i], expected, "{}: {}", name, lines[i]);
+        }
+        // The glue after the shim isn't mapped.
+        assert_eq!(mappings[end + 1], None, "{}", lines[end + 1]);
+    }
+}
+
+#[test]
+fn missing_locations() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut b = Bindgen::new();
+    b.input_module("test", module(false), |m| parity_wasm::serialize(m).unwrap())
+        .nodejs(true)
+        .source_map(true)
+        .generate(dir.path())
+        .unwrap();
+    assert_eq!(b.warnings().len(), 1);
+    assert!(b.warnings()[0].contains("procmacro2_semver_exempt"), "{:?}", b.warnings());
+    let js = js(&dir);
+    assert!(js.ends_with("\n//# sourceMappingURL=test.js.map\n"), "{}", js);
+    let map: serde_json::Value = serde_json::from_str(&read(dir.path(), "test.js.map")).unwrap();
+    assert_eq!(map["sources"], json!([]));
+    let mappings = decode_mappings(map["mappings"].as_str().unwrap());
+    assert_eq!(mappings.len() + 1, js.lines().count());
+    assert!(mappings.iter().all(|m| m.is_none()), "{:?}", mappings);
+}
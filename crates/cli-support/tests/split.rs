@@ -0,0 +1,213 @@
+//! Tests of exports split into chunks with `#[wasm_bindgen(split)]`.
+
+extern crate parity_wasm;
+extern crate serde_json;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::fs;
+use std::process::Command;
+
+use parity_wasm::elements::*;
+
+use support::*;
+
+/// A program exporting `add` and `split_add`, the latter split into the
+/// `split_test` chunk.
+fn split_program() -> Vec<u8> {
+    let mut program: serde_json::Value =
+        serde_json::from_slice(&program(&["add", "split_add"], &[])).unwrap();
+    program["exports"][1]["split"] = "split_test".into();
+    serde_json::to_vec(&program).unwrap()
+}
+
+/// A wasm module for `split_program()`. Besides the memory, `split_add` uses
+/// a function `add` uses too, one only it uses, the table through
+/// `call_indirect`, an immutable global and a mutable one, which it
+/// increments on each call. It returns the sum of all of them and of the
+/// word at address 0.
+fn module() -> Module {
+    let i32 = ValueType::I32;
+    let mut m = ModuleBuilder::new();
+    m.intrinsic("__wbindgen_describe", &[i32], None);
+    let shared = m.function(&[i32, i32], Some(i32), body(vec![
+        Instruction::GetLocal(0),
+        Instruction::GetLocal(1),
+        Instruction::I32Add,
+        Instruction::End,
+    ]));
+    let own = m.function(&[], Some(i32), body(vec![Instruction::I32Const(10), Instruction::End]));
+    let indirect = m.function(&[], Some(i32), body(vec![
+        Instruction::I32Const(100),
+        Instruction::End,
+    ]));
+    let counter = m.global(true, 5);
+    let constant = m.global(false, 7);
+    m.table(&[indirect]).data(0, &[0xe8, 0x03, 0, 0]);
+    m.export("add", &[i32, i32], Some(i32), body(vec![
+        Instruction::GetLocal(0),
+        Instruction::GetLocal(1),
+        Instruction::Call(shared),
+        Instruction::End,
+    ]));
+    let indirect_ty = m.ty(&[], Some(i32));
+    m.export("split_add", &[i32, i32], Some(i32), body(vec![
+        Instruction::GetGlobal(counter),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::SetGlobal(counter),
+        Instruction::GetLocal(0),
+        Instruction::GetLocal(1),
+        Instruction::Call(shared),
+        Instruction::Call(own),
+        Instruction::I32Add,
+        Instruction::I32Const(0),
+        Instruction::CallIndirect(indirect_ty, 0),
+        Instruction::I32Add,
+        Instruction::GetGlobal(counter),
+        Instruction::I32Add,
+        Instruction::GetGlobal(constant),
+        Instruction::I32Add,
+        Instruction::I32Const(0),
+        Instruction::I32Load(2, 0),
+        Instruction::I32Add,
+        Instruction::End,
+    ]));
+    m.describe("add", &[FUNCTION, 2, I32, I32, I32]);
+    m.describe("split_add", &[FUNCTION, 2, I32, I32, I32]);
+    m.program(&split_program());
+    m.build()
+}
+
+/// The signature of function `idx` of `module`.
+fn signature(module: &Module, idx: u32) -> FunctionType {
+    let imported = module
+        .import_section()
+        .map(|s| s.entries())
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|i| match *i.external() {
+            External::Function(ty) => Some(ty),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let ty = match imported.get(idx as usize) {
+        Some(&ty) => ty,
+        None => {
+            let defined = module.function_section().unwrap().entries();
+            defined[idx as usize - imported.len()].type_ref()
+        }
+    };
+    match module.type_section().unwrap().types()[ty as usize] {
+        Type::Function(ref ty) => ty.clone(),
+    }
+}
+
+#[test]
+fn chunk_imports_resolve_against_main() {
+    let dir = generate(module(), |b| {
+        b.nodejs(true);
+    }).unwrap();
+    let main = wasm(dir.path(), "test_bg.wasm");
+    let chunk = wasm(dir.path(), "test_bg_split_test.wasm");
+    assert!(!exports(&main).contains(&"split_add".to_string()), "{:?}", exports(&main));
+    assert_eq!(exports(&chunk), ["split_add"]);
+
+    // Each import of the chunk is an export of the main module of the same
+    // kind and type, and no mutable global crosses between them.
+    let main_exports = main.export_section().unwrap().entries();
+    let mut kinds = Vec::new();
+    for import in chunk.import_section().unwrap().entries() {
+        assert_eq!(import.module(), "__wbindgen_main");
+        let export = main_exports
+            .iter()
+            .find(|e| e.field() == import.field())
+            .unwrap_or_else(|| panic!("`{}` isn't exported", import.field()));
+        match (import.external(), export.internal()) {
+            (&External::Memory(_), &Internal::Memory(0)) => kinds.push("memory"),
+            (&External::Table(_), &Internal::Table(0)) => kinds.push("table"),
+            (&External::Global(ty), &Internal::Global(g)) => {
+                let defined = main.global_section().unwrap().entries();
+                assert!(!ty.is_mutable(), "{}", import.field());
+                assert_eq!(*defined[g as usize].global_type(), ty);
+                kinds.push("global");
+            }
+            (&External::Function(ty), &Internal::Function(f)) => {
+                let ty = match chunk.type_section().unwrap().types()[ty as usize] {
+                    Type::Function(ref ty) => ty.clone(),
+                };
+                assert_eq!(signature(&main, f), ty, "{}", import.field());
+                kinds.push("function");
+            }
+            (import, export) => panic!("`{:?}` imported as {:?}", export, import),
+        }
+    }
+    // Besides `shared`, the mutable global is accessed through a getter and
+    // a setter.
+    assert_eq!(kinds, ["memory", "table", "global", "function", "function", "function"]);
+}
+
+/// The function names in the name section of `module`.
+fn function_names(module: Module) -> Vec<String> {
+    let module = module.parse_names().unwrap();
+    let names = module.sections().iter().filter_map(|s| match *s {
+        Section::Name(NameSection::Function(ref f)) => Some(f.names()),
+        _ => None,
+    });
+    names.flat_map(|n| n.iter().map(|(_, name)| name.clone())).collect()
+}
+
+#[test]
+fn chunks_keep_function_names() {
+    let mut names = FunctionNameSection::default();
+    let functions = ["describe", "shared", "own", "indirect", "add", "split_add"];
+    for (i, name) in functions.iter().enumerate() {
+        names.names_mut().insert(i as u32, name.to_string());
+    }
+    let mut module = module();
+    module.sections_mut().push(Section::Name(NameSection::Function(names)));
+    // Round trip the module so the name section is left unparsed.
+    let module = parity_wasm::deserialize_buffer(&parity_wasm::serialize(module).unwrap()).unwrap();
+
+    let dir = generate(module, |b| {
+        b.nodejs(true);
+    }).unwrap();
+    let main = wasm(dir.path(), "test_bg.wasm");
+    let chunk = wasm(dir.path(), "test_bg_split_test.wasm");
+    assert_eq!(function_names(main), ["shared", "indirect", "add"]);
+    assert_eq!(function_names(chunk), ["own", "split_add"]);
+}
+
+/// Validates the chunk and calls `split_add` twice.
+const RUN_JS: &str = "
+    const fs = require('fs');
+    new WebAssembly.Module(fs.readFileSync('test_bg_split_test.wasm'));
+    const { add, split_add } = require('./test');
+    console.log(add(1, 2));
+    split_add(1, 2)
+        .then(n => console.log(n))
+        .then(() => split_add(1, 2))
+        .then(n => console.log(n));
+";
+
+#[test]
+fn split_exports_run() {
+    let dir = generate(module(), |b| {
+        b.nodejs(true);
+    }).unwrap();
+    fs::write(dir.path().join("run.js"), RUN_JS).unwrap();
+    let output = Command::new("node")
+        .arg("run.js")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+
+    // 1 + 2 + 10 + 100 + 7 + 1000 plus the mutable global, which starts at 5
+    // and is incremented in the main module's copy on each call.
+    assert_eq!(stdout.trim(), "3\n1126\n1127");
+}
@@ -0,0 +1,30 @@
+//! Tests of `#[wasm_bindgen(start)]` functions.
+
+extern crate parity_wasm;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use support::*;
+
+#[test]
+fn two_start_functions() {
+    let err = generate(memory_module(&program(&["a", "b"], &["a", "b"])), |b| {
+        b.nodejs(true);
+    }).err().unwrap();
+    assert!(err.contains("cannot specify two `start` functions: `a` and `b`"), "{}", err);
+}
+
+#[test]
+fn two_start_functions_across_crates() {
+    // Each crate embeds its own program, so the two are only seen together
+    // when generating bindings.
+    let mut module = ModuleBuilder::new();
+    module.program(&program(&["a"], &["a"])).program(&program(&["b"], &["b"]));
+    let err = generate(module.build(), |b| {
+        b.nodejs(true);
+    }).err().unwrap();
+    assert!(err.contains("cannot specify two `start` functions: `a` and `b`"), "{}", err);
+}
@@ -0,0 +1,404 @@
+//! Helpers for the tests building wasm modules by hand and generating
+//! bindings for them.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::Path;
+
+use parity_wasm;
+use parity_wasm::elements::*;
+use tempfile::{self, TempDir};
+use wasm_bindgen_cli_support::Bindgen;
+use wasm_bindgen_shared;
+
+// Words of descriptors, see `descriptor.rs`.
+pub const I32: i32 = 4;
+pub const FUNCTION: i32 = 11;
+pub const STRING: i32 = 13;
+pub const REF: i32 = 14;
+pub const ANYREF: i32 = 18;
+pub const RUST_STRUCT: i32 = 20;
+pub const UNIT: i32 = 23;
+
+/// A JSON program with the given exported functions, each in the form the
+/// macro emits for a free function, flagged as `start` if it's in `starts`.
+pub fn program(exports: &[&str], starts: &[&str]) -> Vec<u8> {
+    let exports = exports
+        .iter()
+        .map(|name| {
+            format!(
+                r#"{{
+                    "class": null,
+                    "method": false,
+                    "consumed": false,
+                    "is_constructor": false,
+                    "function": {{ "name": "{}", "location": null }},
+                    "comments": [],
+                    "start": {},
+                    "split": null
+                }}"#,
+                name,
+                starts.contains(name),
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(
+        r#"{{
+            "exports": [{}],
+            "enums": [],
+            "imports": [],
+            "structs": [],
+            "version": "{}",
+            "schema_version": "{}"
+        }}"#,
+        exports.join(", "),
+        wasm_bindgen_shared::version(),
+        wasm_bindgen_shared::SCHEMA_VERSION,
+    ).into_bytes()
+}
+
+/// A JSON program of the current schema version which uses every part of the
+/// schema, see `program.json`.
+pub fn full_program() -> Vec<u8> {
+    include_str!("program.json")
+        .replace(
+            r#""SCHEMA_VERSION""#,
+            &format!(r#""{}""#, wasm_bindgen_shared::SCHEMA_VERSION),
+        )
+        .into_bytes()
+}
+
+/// The `__wasm_bindgen_unstable` custom section embedding `program`.
+pub fn custom_section(program: &[u8]) -> Section {
+    let mut payload = Vec::new();
+    let len = program.len() as u32;
+    payload.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+    payload.extend_from_slice(program);
+    let mut custom = CustomSection::default();
+    *custom.name_mut() = "__wasm_bindgen_unstable".to_string();
+    *custom.payload_mut() = payload;
+    Section::Custom(custom)
+}
+
+/// A wasm module with just an exported memory and `program`.
+pub fn memory_module(program: &[u8]) -> Module {
+    let mut m = ModuleBuilder::new();
+    m.program(program);
+    m.build()
+}
+
+/// How the module built by a `ModuleBuilder` gets its memory.
+enum Memory {
+    Exported,
+    Imported(MemoryType),
+    None,
+}
+
+/// Builds a wasm module, declaring a type for each distinct signature. The
+/// module exports a memory of one page unless told otherwise.
+///
+/// Functions are numbered in the order they're added after all imports,
+/// which therefore have to be added first, and each method adding one
+/// returns its index for the bodies calling it.
+pub struct ModuleBuilder {
+    memory: Memory,
+    types: Vec<FunctionType>,
+    imports: Vec<ImportEntry>,
+    functions: Vec<Func>,
+    bodies: Vec<FuncBody>,
+    table: Option<Vec<u32>>,
+    globals: Vec<GlobalEntry>,
+    exports: Vec<ExportEntry>,
+    data: Vec<DataSegment>,
+    custom: Vec<Section>,
+}
+
+impl ModuleBuilder {
+    pub fn new() -> ModuleBuilder {
+        ModuleBuilder {
+            memory: Memory::Exported,
+            types: Vec::new(),
+            imports: Vec::new(),
+            functions: Vec::new(),
+            bodies: Vec::new(),
+            table: None,
+            globals: Vec::new(),
+            exports: Vec::new(),
+            data: Vec::new(),
+            custom: Vec::new(),
+        }
+    }
+
+    /// Imports `memory` as `env::memory` instead of exporting one.
+    pub fn import_memory(&mut self, memory: MemoryType) -> &mut Self {
+        self.memory = Memory::Imported(memory);
+        self
+    }
+
+    /// Leaves the module without a memory.
+    pub fn without_memory(&mut self) -> &mut Self {
+        self.memory = Memory::None;
+        self
+    }
+
+    /// The index of the type of the signature `params -> ret`.
+    pub fn ty(&mut self, params: &[ValueType], ret: Option<ValueType>) -> u32 {
+        let ty = FunctionType::new(params.to_vec(), ret);
+        match self.types.iter().position(|t| *t == ty) {
+            Some(i) => i as u32,
+            None => {
+                self.types.push(ty);
+                self.types.len() as u32 - 1
+            }
+        }
+    }
+
+    /// Imports the function `module::name` of signature `params -> ret`.
+    pub fn import(
+        &mut self,
+        module: &str,
+        name: &str,
+        params: &[ValueType],
+        ret: Option<ValueType>,
+    ) -> u32 {
+        assert!(self.functions.is_empty(), "imports must be added before functions");
+        let ty = self.ty(params, ret);
+        self.imports.push(ImportEntry::new(
+            module.to_string(),
+            name.to_string(),
+            External::Function(ty),
+        ));
+        self.imports.len() as u32 - 1
+    }
+
+    /// Imports the intrinsic `name` of signature `params -> ret`.
+    pub fn intrinsic(&mut self, name: &str, params: &[ValueType], ret: Option<ValueType>) -> u32 {
+        self.import("__wbindgen_placeholder__", name, params, ret)
+    }
+
+    /// Adds a function of signature `params -> ret`.
+    pub fn function(
+        &mut self,
+        params: &[ValueType],
+        ret: Option<ValueType>,
+        body: FuncBody,
+    ) -> u32 {
+        let ty = self.ty(params, ret);
+        self.functions.push(Func::new(ty));
+        self.bodies.push(body);
+        (self.imports.len() + self.functions.len()) as u32 - 1
+    }
+
+    /// Adds a function of signature `params -> ret` exported as `name`.
+    pub fn export(
+        &mut self,
+        name: &str,
+        params: &[ValueType],
+        ret: Option<ValueType>,
+        body: FuncBody,
+    ) -> u32 {
+        let idx = self.function(params, ret, body);
+        self.export_item(name, Internal::Function(idx));
+        idx
+    }
+
+    /// Exports `internal` as `name`.
+    pub fn export_item(&mut self, name: &str, internal: Internal) -> &mut Self {
+        self.exports.push(ExportEntry::new(name.to_string(), internal));
+        self
+    }
+
+    /// Adds the descriptor of the export or import shim `name`, passing each
+    /// of `words` to the `__wbindgen_describe` intrinsic, which must have
+    /// been imported.
+    pub fn describe(&mut self, name: &str, words: &[i32]) -> u32 {
+        let intrinsic = self
+            .imports
+            .iter()
+            .position(|i| i.field() == "__wbindgen_describe")
+            .expect("`__wbindgen_describe` isn't imported") as u32;
+        let body = describe(intrinsic, words);
+        self.export(&format!("__wbindgen_describe_{}", name), &[], None, body)
+    }
+
+    /// Adds an `i32` global initialized to `value`.
+    pub fn global(&mut self, mutable: bool, value: i32) -> u32 {
+        self.globals.push(GlobalEntry::new(
+            GlobalType::new(ValueType::I32, mutable),
+            InitExpr::new(vec![Instruction::I32Const(value), Instruction::End]),
+        ));
+        self.globals.len() as u32 - 1
+    }
+
+    /// Adds a table holding `functions`.
+    pub fn table(&mut self, functions: &[u32]) -> &mut Self {
+        self.table = Some(functions.to_vec());
+        self
+    }
+
+    /// Adds a data segment initializing the memory at `offset` to `data`.
+    pub fn data(&mut self, offset: i32, data: &[u8]) -> &mut Self {
+        self.data.push(DataSegment::new(
+            0,
+            InitExpr::new(vec![Instruction::I32Const(offset), Instruction::End]),
+            data.to_vec(),
+        ));
+        self
+    }
+
+    /// Embeds `program` in a `__wasm_bindgen_unstable` custom section.
+    pub fn program(&mut self, program: &[u8]) -> &mut Self {
+        self.section(custom_section(program))
+    }
+
+    /// Adds `section` after all the others.
+    pub fn section(&mut self, section: Section) -> &mut Self {
+        self.custom.push(section);
+        self
+    }
+
+    pub fn build(self) -> Module {
+        let mut imports = self.imports;
+        let mut exports = self.exports;
+        let mut memories = Vec::new();
+        match self.memory {
+            Memory::Exported => {
+                memories.push(MemoryType::new(1, None));
+                exports.insert(0, ExportEntry::new("memory".to_string(), Internal::Memory(0)));
+            }
+            Memory::Imported(memory) => {
+                let memory = ImportEntry::new(
+                    "env".to_string(),
+                    "memory".to_string(),
+                    External::Memory(memory),
+                );
+                imports.insert(0, memory);
+            }
+            Memory::None => {}
+        }
+
+        let mut sections = Vec::new();
+        if !self.types.is_empty() {
+            let types = self.types.into_iter().map(Type::Function).collect();
+            sections.push(Section::Type(TypeSection::with_types(types)));
+        }
+        if !imports.is_empty() {
+            sections.push(Section::Import(ImportSection::with_entries(imports)));
+        }
+        if !self.functions.is_empty() {
+            sections.push(Section::Function(FunctionSection::with_entries(self.functions)));
+        }
+        if let Some(ref table) = self.table {
+            let len = table.len() as u32;
+            let table = TableType::new(len, None);
+            sections.push(Section::Table(TableSection::with_entries(vec![table])));
+        }
+        if !memories.is_empty() {
+            sections.push(Section::Memory(MemorySection::with_entries(memories)));
+        }
+        if !self.globals.is_empty() {
+            sections.push(Section::Global(GlobalSection::with_entries(self.globals)));
+        }
+        if !exports.is_empty() {
+            sections.push(Section::Export(ExportSection::with_entries(exports)));
+        }
+        if let Some(table) = self.table {
+            sections.push(Section::Element(ElementSection::with_entries(vec![ElementSegment::new(
+                0,
+                InitExpr::new(vec![Instruction::I32Const(0), Instruction::End]),
+                table,
+            )])));
+        }
+        if !self.bodies.is_empty() {
+            sections.push(Section::Code(CodeSection::with_bodies(self.bodies)));
+        }
+        if !self.data.is_empty() {
+            sections.push(Section::Data(DataSection::with_entries(self.data)));
+        }
+        sections.extend(self.custom);
+        Module::new(sections)
+    }
+}
+
+/// A function body of `code` without locals.
+pub fn body(code: Vec<Instruction>) -> FuncBody {
+    FuncBody::new(Vec::new(), Instructions::new(code))
+}
+
+/// The body of a descriptor function passing each of `words` to the
+/// `__wbindgen_describe` import, which is function `describe`.
+pub fn describe(describe: u32, words: &[i32]) -> FuncBody {
+    let mut code = Vec::new();
+    for word in words {
+        code.push(Instruction::I32Const(*word));
+        code.push(Instruction::Call(describe));
+    }
+    code.push(Instruction::End);
+    body(code)
+}
+
+/// Generates bindings named `test` for `module` configured by `configure`,
+/// returning the directory of the output. Errors include their causes.
+pub fn generate(module: Module, configure: impl FnOnce(&mut Bindgen)) -> Result<TempDir, String> {
+    let dir = tempfile::tempdir().unwrap();
+    let mut b = Bindgen::new();
+    b.input_module("test", module, |m| parity_wasm::serialize(m).unwrap());
+    configure(&mut b);
+    b.generate(dir.path()).map_err(|e| {
+        e.iter_chain().map(|c| c.to_string()).collect::<Vec<_>>().join("\n")
+    })?;
+    Ok(dir)
+}
+
+/// Generates bindings named `test` for `module` configured by `configure`,
+/// returning the generated JS.
+pub fn generate_js(module: Module, configure: impl FnOnce(&mut Bindgen)) -> Result<String, String> {
+    generate(module, configure).map(|dir| js(&dir))
+}
+
+/// Generates bindings named `test` for `module` configured by `configure`,
+/// returning the output wasm module.
+pub fn generate_wasm(
+    module: Module,
+    configure: impl FnOnce(&mut Bindgen),
+) -> Result<Module, String> {
+    generate(module, configure).map(|dir| wasm(dir.path(), "test_bg.wasm"))
+}
+
+/// Reads the generated `test.js` in `dir`.
+pub fn js(dir: &TempDir) -> String {
+    read(dir.path(), "test.js")
+}
+
+/// Reads the file `name` in `dir`.
+pub fn read(dir: &Path, name: &str) -> String {
+    fs::read_to_string(dir.join(name)).unwrap()
+}
+
+/// Parses the wasm file `name` in `dir`.
+pub fn wasm(dir: &Path, name: &str) -> Module {
+    parity_wasm::deserialize_file(dir.join(name)).unwrap()
+}
+
+/// The `module::field` of each import of `module`.
+pub fn imports(module: &Module) -> Vec<String> {
+    module
+        .import_section()
+        .map(|s| s.entries())
+        .unwrap_or(&[])
+        .iter()
+        .map(|i| format!("{}::{}", i.module(), i.field()))
+        .collect()
+}
+
+/// The names of each export of `module`.
+pub fn exports(module: &Module) -> Vec<String> {
+    module
+        .export_section()
+        .map(|s| s.entries())
+        .unwrap_or(&[])
+        .iter()
+        .map(|e| e.field().to_string())
+        .collect()
+}
@@ -0,0 +1,226 @@
+//! Tests of the output of each `--target`.
+
+extern crate parity_wasm;
+extern crate serde_json;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use parity_wasm::elements::*;
+
+use support::*;
+
+/// A wasm module exporting `add(i32, i32) -> i32`, along with a `run` export
+/// which calls the `__wbindgen_string_new` and `__wbindgen_object_drop_ref`
+/// intrinsics.
+fn module() -> Module {
+    let i32s = |n| vec![ValueType::I32; n];
+    let mut m = ModuleBuilder::new();
+    m.intrinsic("__wbindgen_describe", &i32s(1), None);
+    let string_new = m.intrinsic("__wbindgen_string_new", &i32s(2), Some(ValueType::I32));
+    let drop_ref = m.intrinsic("__wbindgen_object_drop_ref", &i32s(1), None);
+    m.export("add", &i32s(2), Some(ValueType::I32), body(vec![
+        Instruction::GetLocal(0),
+        Instruction::GetLocal(1),
+        Instruction::I32Add,
+        Instruction::End,
+    ]));
+    m.export("run", &[], None, body(vec![
+        Instruction::I32Const(0),
+        Instruction::I32Const(5),
+        Instruction::Call(string_new),
+        Instruction::Call(drop_ref),
+        Instruction::End,
+    ]));
+    m.describe("add", &[FUNCTION, 2, I32, I32, I32]);
+    m.program(&program(&["add"], &[]));
+    m.build()
+}
+
+/// Collects the paths of all files in `dir` into `dst`, each starting with
+/// `prefix`.
+fn files(dir: &Path, prefix: &str, dst: &mut Vec<String>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let name = format!("{}{}", prefix, entry.file_name().to_str().unwrap());
+        if entry.file_type().unwrap().is_dir() {
+            files(&entry.path(), &format!("{}/", name), dst);
+        } else {
+            dst.push(name);
+        }
+    }
+}
+
+#[test]
+fn web() {
+    let dir = generate(module(), |b| {
+        b.web(true).typescript(true);
+    }).unwrap();
+    let ts = read(dir.path(), "test.d.ts");
+    assert!(
+        ts.contains("export default function init (module_or_path?: InitInput): Promise<any>;"),
+        "{}",
+        ts,
+    );
+
+    // Without an argument `init` fetches the wasm file next to the glue,
+    // which node.js can't fetch from a `file:` URL itself.
+    fs::write(dir.path().join("package.json"), r#"{ "type": "module" }"#).unwrap();
+    let script = r#"
+        import fs from 'fs';
+        import init, { add } from './test.js';
+        globalThis.fetch = url => {
+            console.log(url.href === new URL('test_bg.wasm', import.meta.url).href);
+            const headers = { 'Content-Type': 'application/wasm' };
+            return Promise.resolve(new Response(fs.readFileSync(url), { headers }));
+        };
+        const wasm = await init();
+        wasm.run();
+        console.log(add(1, 2));
+    "#;
+    fs::write(dir.path().join("run.mjs"), script).unwrap();
+    let output = Command::new("node")
+        .arg("run.mjs")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout.trim(), "true\n3");
+}
+
+#[test]
+fn web_link_error() {
+    let dir = generate(memory_module(&program(&[], &[])), |b| {
+        b.web(true);
+    }).unwrap();
+
+    // A wasm file importing a function the glue doesn't provide fails to link,
+    // whether it's streamed or compiled from its bytes.
+    let mut module = ModuleBuilder::new();
+    module.without_memory().import("./test", "missing", &[], None);
+    let module = parity_wasm::serialize(module.build()).unwrap();
+    fs::write(dir.path().join("missing.wasm"), module).unwrap();
+    fs::write(dir.path().join("package.json"), r#"{ "type": "module" }"#).unwrap();
+    let script = r#"
+        import fs from 'fs';
+        import init from './test.js';
+        const bytes = fs.readFileSync('missing.wasm');
+        for (const type of ['application/wasm', 'application/octet-stream']) {
+            const response = new Response(bytes, { headers: { 'Content-Type': type } });
+            await init(response).then(
+                () => console.log('instantiated'),
+                e => console.log(e.constructor.name, e.message.includes('missing')),
+            );
+        }
+    "#;
+    fs::write(dir.path().join("run.mjs"), script).unwrap();
+    let output = Command::new("node")
+        .arg("run.mjs")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout.trim(), "LinkError true\nLinkError true");
+}
+
+#[test]
+fn worker() {
+    let dir = generate(module(), |b| {
+        b.worker(true);
+    }).unwrap();
+    // The glue runs as a classic script in a scope without `self` or
+    // `window`, like an audio worklet's, and is handed a compiled module.
+    let script = r#"
+        const fs = require('fs');
+        const vm = require('vm');
+        const scope = vm.createContext({ TextEncoder, TextDecoder, console });
+        vm.runInContext(fs.readFileSync('test.js', 'utf8'), scope);
+        const compiled = new WebAssembly.Module(fs.readFileSync('test_bg.wasm'));
+        scope.wasm_bindgen(compiled).then(() => {
+            const { wasm_bindgen } = scope;
+            wasm_bindgen.wasm.run();
+            console.log(wasm_bindgen.add(1, 2), wasm_bindgen.__wbindgen_wasm_module === compiled);
+        });
+    "#;
+    fs::write(dir.path().join("run.js"), script).unwrap();
+    let output = Command::new("node")
+        .arg("run.js")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout.trim(), "3 true");
+}
+
+#[test]
+fn one_target_at_a_time() {
+    let err = generate(memory_module(&program(&[], &[])), |b| {
+        b.web(true).nodejs(true);
+    }).err().unwrap();
+    assert!(err.contains("only one of the nodejs, browser"), "{}", err);
+
+    let err = generate(memory_module(&program(&[], &[])), |b| {
+        b.multi_target(true).no_modules(true);
+    }).err().unwrap();
+    assert!(err.contains("only one of the nodejs, browser"), "{}", err);
+}
+
+#[test]
+fn multi() {
+    let dir = generate(module(), |b| {
+        b.multi_target(true).typescript(true);
+    }).unwrap();
+    let mut layout = Vec::new();
+    files(dir.path(), "", &mut layout);
+    layout.sort();
+    assert_eq!(
+        layout,
+        [
+            "esm/test.js",
+            "node/test.js",
+            "node/test_bg.js",
+            "package.json",
+            "test.d.ts",
+            "test_bg.wasm",
+        ],
+    );
+
+    let package: serde_json::Value =
+        serde_json::from_str(&read(dir.path(), "package.json")).unwrap();
+    assert_eq!(package["main"], "./node/test.js");
+    assert_eq!(package["module"], "./esm/test.js");
+    assert_eq!(package["browser"], "./esm/test.js");
+    assert_eq!(package["types"], "./test.d.ts");
+    assert_eq!(package.as_object().unwrap().len(), 4, "{}", package);
+
+    // The wasm file imports the ES module glue, which is next to the wasm
+    // file for bundlers.
+    let esm = read(&dir.path().join("esm"), "test.js");
+    assert!(esm.contains("import * as wasm from '../test_bg';"), "{}", esm);
+    assert!(esm.contains("export function add("), "{}", esm);
+    let imports = imports(&wasm(dir.path(), "test_bg.wasm"));
+    assert_eq!(
+        imports,
+        ["./esm/test::__wbindgen_string_new", "./esm/test::__wbindgen_object_drop_ref"],
+    );
+
+    // The node.js glue works with the same wasm file.
+    let output = Command::new("node")
+        .arg("-e")
+        .arg("const { add } = require('.'); require('./node/test_bg').run(); console.log(add(1, 2));")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout.trim(), "3");
+}
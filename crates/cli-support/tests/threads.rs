@@ -0,0 +1,294 @@
+//! Tests of `--threads`, running the generated glue on `worker_threads`.
+
+extern crate parity_wasm;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::collections::HashMap;
+use std::fs;
+use std::process::Command;
+
+use parity_wasm::elements::*;
+use wasm_bindgen_cli_support::{THREAD_SCRATCH_SIZE, THREAD_STACK_SIZE};
+
+use support::*;
+
+/// Where the bump allocator of `module()` starts handing out memory, which is
+/// also the top of the main thread's stack.
+const HEAP_BASE: u32 = 4096;
+
+/// Size and alignment of the thread local storage of `module(true)`.
+const TLS_SIZE: u32 = 9;
+const TLS_ALIGN: u32 = 8;
+
+/// A wasm module importing a shared memory, whose `run(task)` export spawns
+/// a thread running `task`. Tasks record the stack pointer and the scratch
+/// area of the thread running them in the first two words at `task`. With
+/// `tls` the module has thread local storage whose initial value is 42, and
+/// tasks also record its address and value in the next two words.
+fn module(tls: bool) -> Module {
+    let i32 = ValueType::I32;
+    let mut m = ModuleBuilder::new();
+    // Pages of 64KiB, enough for the stack of a worker.
+    m.import_memory(MemoryType::deserialize(&mut &[0x03, 32, 32][..]).unwrap());
+    let spawn = m.intrinsic("__wbindgen_thread_spawn", &[i32], None);
+    let scratch = m.intrinsic("__wbindgen_thread_scratch", &[], Some(i32));
+    // A bump allocator whose next address is stored at address 0.
+    m.export("__wbindgen_malloc", &[i32], Some(i32), FuncBody::new(
+        vec![Local::new(1, i32)],
+        Instructions::new(vec![
+            Instruction::I32Const(0),
+            Instruction::I32Const(0),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(1),
+            Instruction::GetLocal(0),
+            Instruction::I32Add,
+            Instruction::I32Store(2, 0),
+            Instruction::GetLocal(1),
+            Instruction::End,
+        ]),
+    ));
+    let stack_pointer = m.global(true, HEAP_BASE as i32);
+    let tls_base = if tls { Some(m.global(true, 0)) } else { None };
+    let mut entry = Vec::new();
+    if let Some(tls_base) = tls_base {
+        entry.extend(vec![
+            Instruction::GetLocal(0),
+            Instruction::GetGlobal(tls_base),
+            Instruction::I32Store(2, 8),
+            Instruction::GetLocal(0),
+            Instruction::GetGlobal(tls_base),
+            Instruction::I32Load(2, 0),
+            Instruction::I32Store(2, 12),
+        ]);
+    }
+    entry.extend(vec![
+        Instruction::GetLocal(0),
+        Instruction::Call(scratch),
+        Instruction::I32Store(2, 4),
+        Instruction::GetLocal(0),
+        Instruction::GetGlobal(stack_pointer),
+        Instruction::I32Store(2, 0),
+        Instruction::End,
+    ]);
+    m.export("__wbindgen_thread_entry", &[i32], None, body(entry));
+    m.export("run", &[i32], None, body(vec![
+        Instruction::GetLocal(0),
+        Instruction::Call(spawn),
+        Instruction::End,
+    ]));
+    if let Some(tls_base) = tls_base {
+        // `__wasm_init_tls(ptr)` points `__tls_base` at `ptr` and stores the
+        // initial value there.
+        m.export("__wasm_init_tls", &[i32], None, body(vec![
+            Instruction::GetLocal(0),
+            Instruction::SetGlobal(tls_base),
+            Instruction::GetLocal(0),
+            Instruction::I32Const(42),
+            Instruction::I32Store(2, 0),
+            Instruction::End,
+        ]));
+        let size = m.global(false, TLS_SIZE as i32);
+        let align = m.global(false, TLS_ALIGN as i32);
+        m.export_item("__tls_base", Internal::Global(tls_base));
+        m.export_item("__tls_size", Internal::Global(size));
+        m.export_item("__tls_align", Internal::Global(align));
+    }
+    m.data(0, &[HEAP_BASE as u8, (HEAP_BASE >> 8) as u8, 0, 0]);
+    m.program(&program(&[], &[]));
+    m.build()
+}
+
+/// Spawns a thread and prints what it recorded once it's done, along with
+/// the size of memory and the thread local storage of the main thread, one
+/// `name value` pair per line.
+const RUN_JS: &str = "
+    const { memory } = require('./test');
+    const wasm = require('./test_bg');
+    const heap = new Int32Array(memory.buffer);
+    const task = wasm.__wbindgen_malloc(16);
+    wasm.run(task);
+    const start = Date.now();
+    const timer = setInterval(() => {
+        if (Atomics.load(heap, task / 4) !== 0) {
+            clearInterval(timer);
+            const [stack, scratch, tls, value] = heap.subarray(task / 4, task / 4 + 4);
+            const layout = { memory: memory.buffer.byteLength, task, stack, scratch, tls, value };
+            if (wasm.__tls_base !== undefined) {
+                layout.main_tls = wasm.__tls_base.value;
+                layout.main_value = heap[layout.main_tls / 4];
+            }
+            for (const key in layout) console.log(`${key} ${layout[key]}`);
+        } else if (Date.now() - start > 10000) {
+            clearInterval(timer);
+            console.log('timed out');
+        }
+    }, 10);
+";
+
+/// Generates node.js bindings for `module`, spawns a thread with them and
+/// returns what `RUN_JS` printed.
+fn run(module: Module) -> String {
+    let dir = generate(module, |b| {
+        b.nodejs(true).threads(true);
+    }).unwrap();
+    fs::write(dir.path().join("run.js"), RUN_JS).unwrap();
+    let output = Command::new("node")
+        .arg("run.js")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    stdout
+}
+
+/// Checks the layout printed by `RUN_JS`: the worker's stack, scratch area
+/// and thread local storage lie in the heap, are aligned, and don't overlap
+/// each other, the task or the main thread's thread local storage, which
+/// holds its initial value on both threads.
+fn check_layout(stdout: &str, tls: bool) {
+    let layout = stdout
+        .lines()
+        .map(|line| {
+            let mut parts = line.split(' ');
+            let name = parts.next().unwrap();
+            let value = parts.next().and_then(|v| v.parse::<u32>().ok());
+            (name, value.unwrap_or_else(|| panic!("unexpected output: {}", stdout)))
+        })
+        .collect::<HashMap<_, _>>();
+    let get = |name: &str| layout[name];
+
+    let stack = get("stack");
+    assert!(stack >= THREAD_STACK_SIZE, "stack top {} is too low", stack);
+    assert_eq!(stack % 16, 0, "stack top {} isn't aligned", stack);
+    assert_eq!(get("scratch") % 8, 0, "scratch area {} isn't aligned", get("scratch"));
+    let mut regions = vec![
+        ("task", get("task"), 16),
+        ("stack", stack - THREAD_STACK_SIZE, THREAD_STACK_SIZE),
+        ("scratch area", get("scratch"), THREAD_SCRATCH_SIZE),
+    ];
+    if tls {
+        for &(name, key) in &[("thread local storage", "tls"), ("main thread local storage", "main_tls")] {
+            assert_eq!(get(key) % TLS_ALIGN, 0, "{} {} isn't aligned", name, get(key));
+            regions.push((name, get(key), TLS_SIZE));
+        }
+        assert_eq!(get("value"), 42);
+        assert_eq!(get("main_value"), 42);
+    }
+
+    for &(name, start, len) in &regions {
+        assert!(
+            start >= HEAP_BASE && start + len <= get("memory"),
+            "{} at {}..{} isn't in the heap",
+            name,
+            start,
+            start + len,
+        );
+    }
+    for (i, &(a, a_start, a_len)) in regions.iter().enumerate() {
+        for &(b, b_start, b_len) in &regions[i + 1..] {
+            assert!(
+                a_start + a_len <= b_start || b_start + b_len <= a_start,
+                "{} at {}..{} overlaps {} at {}..{}",
+                a,
+                a_start,
+                a_start + a_len,
+                b,
+                b_start,
+                b_start + b_len,
+            );
+        }
+    }
+}
+
+#[test]
+fn spawns_on_worker_threads() {
+    check_layout(&run(module(false)), false);
+}
+
+#[test]
+fn thread_local_storage_per_thread() {
+    check_layout(&run(module(true)), true);
+}
+
+/// `RUN_JS` for the `--target web` glue, whose workers are web workers
+/// emulated on `worker_threads`. The shared memory is taken from the first
+/// message to a worker, as the ES module doesn't export it.
+const RUN_WEB_JS: &str = "
+    import fs from 'fs';
+    import { Worker as NodeWorker } from 'worker_threads';
+    import init from './test.js';
+
+    const BOOTSTRAP = `
+        const { parentPort, workerData } = require('worker_threads');
+        const queue = [];
+        globalThis.self = globalThis;
+        parentPort.on('message', data => {
+            if (self.onmessage) self.onmessage({ data }); else queue.push(data);
+        });
+        import(workerData).then(() => queue.forEach(data => self.onmessage({ data })));
+    `;
+    let memory;
+    Object.defineProperty(globalThis, 'navigator', { value: { hardwareConcurrency: 1 } });
+    globalThis.Worker = class {
+        constructor(url) {
+            this.worker = new NodeWorker(BOOTSTRAP, { eval: true, workerData: url.href });
+            this.worker.unref();
+        }
+        postMessage(data) {
+            memory = memory || data.memory;
+            this.worker.postMessage(data);
+        }
+    };
+
+    const wasm = await init(fs.readFileSync('test_bg.wasm'));
+    const task = wasm.__wbindgen_malloc(16);
+    wasm.run(task);
+    const heap = new Int32Array(memory.buffer);
+    const start = Date.now();
+    const timer = setInterval(() => {
+        if (Atomics.load(heap, task / 4) !== 0) {
+            clearInterval(timer);
+            const [stack, scratch, tls, value] = heap.subarray(task / 4, task / 4 + 4);
+            const layout = { memory: memory.buffer.byteLength, task, stack, scratch, tls, value };
+            layout.main_tls = wasm.__tls_base.value;
+            layout.main_value = heap[layout.main_tls / 4];
+            for (const key in layout) console.log(`${key} ${layout[key]}`);
+        } else if (Date.now() - start > 10000) {
+            clearInterval(timer);
+            console.log('timed out');
+        }
+    }, 10);
+";
+
+#[test]
+fn web_workers_init_thread_local_storage() {
+    let dir = generate(module(true), |b| {
+        b.web(true).threads(true);
+    }).unwrap();
+    fs::write(dir.path().join("package.json"), r#"{ "type": "module" }"#).unwrap();
+    fs::write(dir.path().join("run.mjs"), RUN_WEB_JS).unwrap();
+    let output = Command::new("node")
+        .arg("run.mjs")
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+
+    check_layout(&stdout, true);
+}
+
+#[test]
+fn thread_local_storage_needs_its_layout() {
+    let mut module = module(true);
+    module.export_section_mut().unwrap().entries_mut().retain(|e| e.field() != "__tls_size");
+    let err = generate(module, |b| {
+        b.nodejs(true).threads(true);
+    }).err().unwrap();
+    assert!(err.contains("doesn't export `__tls_size`"), "{}", err);
+}
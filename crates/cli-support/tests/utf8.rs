@@ -0,0 +1,251 @@
+//! Tests of the UTF-8 codec bundled with `--inline-utf8`, running the
+//! generated `--target worker` glue with and without the native
+//! `TextEncoder` and `TextDecoder`.
+
+extern crate parity_wasm;
+extern crate tempfile;
+extern crate wasm_bindgen_cli_support;
+extern crate wasm_bindgen_shared;
+
+mod support;
+
+use std::fs;
+use std::process::Command;
+
+use parity_wasm::elements::*;
+
+use support::*;
+
+/// Where the bump allocator of `module()` starts handing out memory.
+const HEAP_BASE: i32 = 1024;
+
+/// Where the exports of `module()` return strings, which is
+/// `__wbindgen_global_argument_ptr()`.
+const RET: i32 = 8;
+
+/// Where `module()` stores the bytes of `MALFORMED`, one after the other.
+const MALFORMED_BASE: i32 = 16;
+
+/// Byte sequences which aren't valid UTF-8, along with the code points the
+/// WHATWG decoder turns them into.
+const MALFORMED: &[(&[u8], &str)] = &[
+    // Overlong encodings.
+    (&[0xc0, 0x80], "fffd fffd"),
+    (&[0xc1, 0xbf], "fffd fffd"),
+    (&[0xe0, 0x80, 0x80], "fffd fffd fffd"),
+    (&[0xe0, 0x9f, 0xbf], "fffd fffd fffd"),
+    (&[0xf0, 0x8f, 0xbf, 0xbf], "fffd fffd fffd fffd"),
+    // Surrogates.
+    (&[0xed, 0xa0, 0x80], "fffd fffd fffd"),
+    (&[0xed, 0xbf, 0xbf], "fffd fffd fffd"),
+    // Above U+10FFFF.
+    (&[0xf4, 0x90, 0x80, 0x80], "fffd fffd fffd fffd"),
+    (&[0xf5, 0x80, 0x80, 0x80], "fffd fffd fffd fffd"),
+    (&[0xff], "fffd"),
+    // Truncated sequences, whose last byte starts the next one if it can.
+    (&[0xe2, 0x82, 0x41], "fffd 41"),
+    (&[0xf0, 0x9f, 0x98, 0xe2, 0x9c, 0x93], "fffd 2713"),
+    (&[0x80, 0x41], "fffd 41"),
+    // The bounds of the narrowed ranges are still valid.
+    (&[0xe0, 0xa0, 0x80], "800"),
+    (&[0xed, 0x9f, 0xbf], "d7ff"),
+    (&[0xee, 0x80, 0x80], "e000"),
+    (&[0xf0, 0x90, 0x80, 0x80], "10000"),
+    (&[0xf4, 0x8f, 0xbf, 0xbf], "10ffff"),
+];
+
+/// A wasm module exporting `echo(&str) -> String`, which returns its argument,
+/// `chop(&str) -> String`, which returns all but its last byte, and
+/// `decode(ptr: i32, len: i32) -> String`, which returns the bytes at `ptr`
+/// as they are, along with a bump allocator and a `__wbindgen_free` which
+/// doesn't free anything.
+fn module() -> Module {
+    let i32 = ValueType::I32;
+    let mut m = ModuleBuilder::new();
+    m.intrinsic("__wbindgen_describe", &[i32], None);
+    // Both store the pointer and the length of the returned string at `ret`.
+    let string = |chopped| {
+        body(vec![
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(1),
+            Instruction::I32Store(2, 0),
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(2),
+            Instruction::I32Const(chopped),
+            Instruction::I32Sub,
+            Instruction::I32Store(2, 4),
+            Instruction::End,
+        ])
+    };
+    m.export("echo", &[i32; 3], None, string(0));
+    m.export("chop", &[i32; 3], None, string(1));
+    m.export("decode", &[i32; 3], None, string(0));
+    m.describe("echo", &[FUNCTION, 1, REF, STRING, STRING]);
+    m.describe("chop", &[FUNCTION, 1, REF, STRING, STRING]);
+    m.describe("decode", &[FUNCTION, 2, I32, I32, STRING]);
+    // The next address of the bump allocator is stored at address 0.
+    m.export("__wbindgen_malloc", &[i32], Some(i32), FuncBody::new(
+        vec![Local::new(1, i32)],
+        Instructions::new(vec![
+            Instruction::I32Const(0),
+            Instruction::I32Const(0),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(1),
+            Instruction::GetLocal(0),
+            Instruction::I32Add,
+            Instruction::I32Store(2, 0),
+            Instruction::GetLocal(1),
+            Instruction::End,
+        ]),
+    ));
+    m.export("__wbindgen_free", &[i32; 2], None, body(vec![Instruction::End]));
+    m.export("__wbindgen_global_argument_ptr", &[], Some(i32), body(vec![
+        Instruction::I32Const(RET),
+        Instruction::End,
+    ]));
+    m.data(0, &[HEAP_BASE as u8, (HEAP_BASE >> 8) as u8, 0, 0]);
+    m.data(MALFORMED_BASE, &MALFORMED.iter().flat_map(|m| m.0.iter().cloned()).collect::<Vec<_>>());
+    m.program(&program(&["echo", "chop", "decode"], &[]));
+    m.build()
+}
+
+/// Passes each of `cases` through `echo` and `chop`, printing the code points
+/// of what came back. The glue runs in a global scope of its own which, like
+/// an audio worklet's, lacks `TextEncoder` and `TextDecoder` unless `native`
+/// is passed.
+const RUN_JS: &str = r"
+    const fs = require('fs');
+    const vm = require('vm');
+    const scope = process.argv[2] === 'native' ? { TextEncoder, TextDecoder } : {};
+    vm.createContext(scope);
+    vm.runInContext(fs.readFileSync('test.js', 'utf8'), scope);
+    const { wasm_bindgen } = scope;
+    const points = s => Array.from(s, c => c.codePointAt(0).toString(16)).join(' ');
+    const cases = [
+        'héllo ✓',
+        '😀',
+        'x\u{10ffff}',
+        'a\ud800b',
+        '\udc00',
+    ];
+    wasm_bindgen(fs.readFileSync('test_bg.wasm')).then(() => {
+        for (const s of cases) {
+            console.log(`${points(wasm_bindgen.echo(s))} / ${points(wasm_bindgen.chop(s))}`);
+        }
+    });
+";
+
+/// Generates `--target worker` bindings with `--inline-utf8` for `module()`
+/// and runs `js` in node with the given codec.
+fn run(js: &str, codec: &str) -> String {
+    let dir = generate(module(), |b| {
+        b.worker(true).inline_utf8(true);
+    }).unwrap();
+    fs::write(dir.path().join("run.js"), js).unwrap();
+    let output = Command::new("node")
+        .arg("run.js")
+        .arg(codec)
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn inline_codec_round_trips() {
+    let expected = "\
+        68 e9 6c 6c 6f 20 2713 / 68 e9 6c 6c 6f 20 fffd\n\
+        1f600 / fffd\n\
+        78 10ffff / 78 fffd\n\
+        61 fffd 62 / 61 fffd\n\
+        fffd / fffd\n";
+    assert_eq!(run(RUN_JS, "inline"), expected);
+    assert_eq!(run(RUN_JS, "native"), expected);
+}
+
+/// Decodes the `[ptr, len]` pairs of `CASES` with `decode`, printing the code
+/// points of each string, in the same global scope as `RUN_JS`.
+const DECODE_JS: &str = r"
+    const fs = require('fs');
+    const vm = require('vm');
+    const scope = process.argv[2] === 'native' ? { TextEncoder, TextDecoder } : {};
+    vm.createContext(scope);
+    vm.runInContext(fs.readFileSync('test.js', 'utf8'), scope);
+    const { wasm_bindgen } = scope;
+    const points = s => Array.from(s, c => c.codePointAt(0).toString(16)).join(' ');
+    wasm_bindgen(fs.readFileSync('test_bg.wasm')).then(() => {
+        for (const [ptr, len] of CASES) {
+            console.log(points(wasm_bindgen.decode(ptr, len)));
+        }
+    });
+";
+
+#[test]
+fn inline_codec_decodes_malformed_input() {
+    let mut cases = Vec::new();
+    let mut ptr = MALFORMED_BASE as usize;
+    for &(bytes, _) in MALFORMED {
+        cases.push(format!("[{}, {}]", ptr, bytes.len()));
+        ptr += bytes.len();
+    }
+    assert!(ptr <= HEAP_BASE as usize);
+    let js = DECODE_JS.replace("CASES", &format!("[{}]", cases.join(", ")));
+    let expected = MALFORMED.iter().map(|m| format!("{}\n", m.1)).collect::<String>();
+    assert_eq!(run(&js, "inline"), expected);
+    assert_eq!(run(&js, "native"), expected);
+}
+
+/// Echoes a string through the glue, printing it along with how often the
+/// native codec was used. The glue gets the native codec when `native` is
+/// passed.
+const FALLBACK_JS: &str = r"
+    const fs = require('fs');
+    const vm = require('vm');
+    let used = 0;
+    class Encoder extends TextEncoder {
+        encode(s) { used++; return super.encode(s); }
+    }
+    class Decoder extends TextDecoder {
+        decode(b) { used++; return super.decode(b); }
+    }
+    const native = { TextEncoder: Encoder, TextDecoder: Decoder };
+    const scope = vm.createContext(process.argv[2] === 'native' ? native : {});
+    try {
+        vm.runInContext(fs.readFileSync('test.js', 'utf8'), scope);
+    } catch (e) {
+        console.log(e.message);
+        return;
+    }
+    const { wasm_bindgen } = scope;
+    wasm_bindgen(fs.readFileSync('test_bg.wasm')).then(() => {
+        console.log(`${wasm_bindgen.echo('héllo')} ${used}`);
+    });
+";
+
+/// Generates `--target worker` bindings for `module()`, with `--inline-utf8`
+/// if `inline` is set, and runs `FALLBACK_JS` in node with the given codec.
+fn run_fallback(inline: bool, codec: &str) -> String {
+    let dir = generate(module(), |b| {
+        b.worker(true).inline_utf8(inline);
+    }).unwrap();
+    fs::write(dir.path().join("run.js"), FALLBACK_JS).unwrap();
+    let output = Command::new("node")
+        .arg("run.js")
+        .arg(codec)
+        .current_dir(dir.path())
+        .output()
+        .expect("failed to run node");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+#[test]
+fn inline_codec_is_a_fallback() {
+    // The native codec is used when it's there, encoding the argument and
+    // decoding the return value.
+    assert_eq!(run_fallback(true, "native"), "héllo 2");
+    assert_eq!(run_fallback(true, "inline"), "héllo 0");
+    assert_eq!(run_fallback(false, "native"), "héllo 2");
+    assert_eq!(run_fallback(false, "inline"), "TextEncoder is not defined");
+}
@@ -33,7 +33,7 @@ use std::process;
 use std::thread;
 
 use failure::{ResultExt, Error};
-use parity_wasm::elements::{Module, Deserialize, Section};
+use parity_wasm::elements::{Module, Deserialize, External, Section};
 use wasm_bindgen_cli_support::Bindgen;
 
 mod headless;
@@ -122,13 +122,33 @@ fn rmain() -> Result<(), Error> {
         }
         node = !custom.payload().contains(&0x01);
     }
+
+    // Test binaries compiled with a shared memory are run on threads, which
+    // are only supported in node.js for now.
+    let threads = wasm.import_section()
+        .map(|s| {
+            s.entries().iter().any(|i| match i.external() {
+                External::Memory(m) => m.limits().shared(),
+                _ => false,
+            })
+        })
+        .unwrap_or(false);
+    if threads && !node {
+        bail!("tests using threads can currently only be run in node.js");
+    }
+
     let headless = env::var("NO_HEADLESS").is_err();
     let debug = env::var("WASM_BINDGEN_NO_DEBUG").is_err();
+    let weak_refs = env::var("WASM_BINDGEN_WEAKREF").is_ok();
+    let anyref = env::var("WASM_BINDGEN_ANYREF").is_ok();
 
     // Make the generated bindings available for the tests to execute against.
     shell.status("Executing bindgen...");
     let mut b = Bindgen::new();
     b.debug(debug)
+        .weak_refs(weak_refs)
+        .threads(threads)
+        .anyref(anyref)
         .nodejs(node)
         .input_module(module, wasm, |w| parity_wasm::serialize(w).unwrap())
         .keep_debug(false)
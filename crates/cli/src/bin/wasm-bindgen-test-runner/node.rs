@@ -90,6 +90,8 @@ pub fn execute(module: &str, tmpdir: &Path, args: &[OsString], tests: &[String])
     exec(
         Command::new("node")
             .env("NODE_PATH", env::join_paths(&path).unwrap())
+            // Lets tests force collection to exercise `--weak-refs` cleanup.
+            .arg("--expose-gc")
             .arg(&js_path)
             .args(args)
     )
@@ -6,11 +6,12 @@ extern crate wasm_bindgen_shared;
 #[macro_use]
 extern crate failure;
 
+use std::fs;
 use std::path::PathBuf;
 use std::process;
 
 use docopt::Docopt;
-use failure::Error;
+use failure::{Error, ResultExt};
 use wasm_bindgen_cli_support::Bindgen;
 
 const USAGE: &'static str = "
@@ -18,12 +19,16 @@ Generating JS bindings for a wasm file
 
 Usage:
     wasm-bindgen [options] <input>
+    wasm-bindgen inspect [--json] <input>
     wasm-bindgen -h | --help
     wasm-bindgen -V | --version
 
 Options:
     -h --help                Show this screen.
     --out-dir DIR            Output directory
+    --target TARGET          What type of output to generate, valid values are
+                             [bundler, nodejs, browser, no-modules, web,
+                             worker, multi]
     --nodejs                 Generate output that only works in node.js
     --browser                Generate output that only works in a browser
     --no-modules             Generate output that only works in a browser (without modules)
@@ -33,6 +38,17 @@ Options:
     --debug                  Include otherwise-extraneous debug checks in output
     --no-demangle            Don't demangle Rust symbol names
     --keep-debug             Keep debug sections in wasm files
+    --weak-refs              Free exported objects automatically when gc'd
+    --inline-utf8            Include a UTF-8 codec for scopes lacking TextDecoder
+    --threads                Prepare a shared memory module to run on threads
+    --anyref                 Store JS values in a wasm table of anyrefs
+    --source-map             Emit a source map pointing the JS at Rust declarations
+    --size-report FILE       Write a JSON report of the output size of each binding
+    --cache-dir DIR          Reuse work from previous runs cached in DIR
+    --borrowed-malloc NAME   Allocate borrowed strings and slices with export NAME
+    --borrowed-free NAME     Free borrowed strings and slices with export NAME
+    --minify                 Shorten names and strip comments in the JS glue
+    --json                   With `inspect`, print its output as JSON
     -V --version             Print the version number of wasm-bindgen
 ";
 
@@ -48,7 +64,20 @@ struct Args {
     flag_version: bool,
     flag_no_demangle: bool,
     flag_no_modules_global: Option<String>,
+    flag_target: Option<String>,
     flag_keep_debug: bool,
+    flag_weak_refs: bool,
+    flag_inline_utf8: bool,
+    flag_threads: bool,
+    flag_anyref: bool,
+    flag_source_map: bool,
+    flag_size_report: Option<PathBuf>,
+    flag_cache_dir: Option<PathBuf>,
+    flag_borrowed_malloc: Option<String>,
+    flag_borrowed_free: Option<String>,
+    flag_minify: bool,
+    flag_json: bool,
+    cmd_inspect: bool,
     arg_input: Option<PathBuf>,
 }
 
@@ -78,6 +107,25 @@ fn rmain(args: &Args) -> Result<(), Error> {
         None => bail!("input file expected"),
     };
 
+    if args.cmd_inspect {
+        let wasm = fs::read(input)
+            .with_context(|_| format!("failed to read `{}`", input.display()))?;
+        let output = wasm_bindgen_cli_support::inspect::Config::new()
+            .json(args.flag_json)
+            .inspect(&wasm)?;
+        print!("{}", output);
+        return Ok(());
+    }
+    if args.flag_json {
+        bail!("`--json` can only be used with `wasm-bindgen inspect`");
+    }
+
+    if args.flag_target.is_some()
+        && (args.flag_nodejs || args.flag_browser || args.flag_no_modules)
+    {
+        bail!("`--target` can't be combined with `--nodejs`, `--browser` or `--no-modules`");
+    }
+
     let typescript = args.flag_typescript || !args.flag_no_typescript;
 
     let mut b = Bindgen::new();
@@ -88,15 +136,59 @@ fn rmain(args: &Args) -> Result<(), Error> {
         .debug(args.flag_debug)
         .demangle(!args.flag_no_demangle)
         .keep_debug(args.flag_keep_debug)
+        .weak_refs(args.flag_weak_refs)
+        .inline_utf8(args.flag_inline_utf8)
+        .threads(args.flag_threads)
+        .anyref(args.flag_anyref)
+        .source_map(args.flag_source_map)
+        .minify(args.flag_minify)
         .typescript(typescript);
     if let Some(ref name) = args.flag_no_modules_global {
         b.no_modules_global(name);
     }
+    if let Some(ref path) = args.flag_size_report {
+        b.size_report(path);
+    }
+    if let Some(ref path) = args.flag_cache_dir {
+        b.cache_dir(path);
+    }
+    if let Some(ref name) = args.flag_borrowed_malloc {
+        b.borrowed_malloc(name);
+    }
+    if let Some(ref name) = args.flag_borrowed_free {
+        b.borrowed_free(name);
+    }
+    match args.flag_target.as_ref().map(|s| &s[..]) {
+        None | Some("bundler") => {}
+        Some("nodejs") => {
+            b.nodejs(true);
+        }
+        Some("browser") => {
+            b.browser(true);
+        }
+        Some("no-modules") => {
+            b.no_modules(true);
+        }
+        Some("web") => {
+            b.web(true);
+        }
+        Some("worker") => {
+            b.worker(true);
+        }
+        Some("multi") => {
+            b.multi_target(true);
+        }
+        Some(s) => bail!("invalid target: `{}`", s),
+    }
 
     let out_dir = match args.flag_out_dir {
         Some(ref p) => p,
         None => bail!("the `--out-dir` argument is now required"),
     };
 
-    b.generate(out_dir)
+    b.generate(out_dir)?;
+    for warning in b.warnings() {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(())
 }
@@ -192,6 +192,40 @@ impl BindgenAttrs {
             _ => false,
         })
     }
+
+    /// Return the list of vendor prefixes to try for an imported type
+    fn vendor_prefixes(&self) -> impl Iterator<Item = &Ident> {
+        self.attrs.iter().filter_map(|a| match a {
+            BindgenAttr::VendorPrefix(s) => Some(s),
+            _ => None,
+        })
+    }
+
+    /// Return the list of other names to try for an imported type
+    fn fallbacks(&self) -> impl Iterator<Item = &Ident> {
+        self.attrs.iter().filter_map(|a| match a {
+            BindgenAttr::Fallback(s) => Some(s),
+            _ => None,
+        })
+    }
+
+    /// Whether the start attribute is present
+    fn start(&self) -> bool {
+        self.attrs.iter().any(|a| match *a {
+            BindgenAttr::Start => true,
+            _ => false,
+        })
+    }
+
+    /// Get the first split attribute
+    fn split(&self) -> Option<&str> {
+        self.attrs
+            .iter()
+            .filter_map(|a| match a {
+                BindgenAttr::Split(s) => Some(&s[..]),
+                _ => None,
+            }).next()
+    }
 }
 
 impl Parse for BindgenAttrs {
@@ -225,6 +259,10 @@ pub enum BindgenAttr {
     JsClass(String),
     Extends(Ident),
     Variadic,
+    Start,
+    Split(String),
+    VendorPrefix(Ident),
+    Fallback(Ident),
 }
 
 impl Parse for BindgenAttr {
@@ -258,6 +296,21 @@ impl Parse for BindgenAttr {
         if attr == "variadic" {
             return Ok(BindgenAttr::Variadic)
         }
+        if attr == "start" {
+            return Ok(BindgenAttr::Start)
+        }
+        if attr == "split" {
+            input.parse::<Token![=]>()?;
+            return Ok(BindgenAttr::Split(input.parse::<syn::LitStr>()?.value()))
+        }
+        if attr == "vendor_prefix" {
+            input.parse::<Token![=]>()?;
+            return Ok(BindgenAttr::VendorPrefix(input.parse::<AnyIdent>()?.0))
+        }
+        if attr == "fallback" {
+            input.parse::<Token![=]>()?;
+            return Ok(BindgenAttr::Fallback(input.parse::<AnyIdent>()?.0))
+        }
         if attr == "static_method_of" {
             input.parse::<Token![=]>()?;
             return Ok(BindgenAttr::StaticMethodOf(input.parse::<AnyIdent>()?.0))
@@ -556,6 +609,8 @@ impl ConvertToAst<BindgenAttrs> for syn::ForeignItemType {
             rust_name: self.ident,
             js_name,
             extends: attrs.extends().cloned().collect(),
+            vendor_prefixes: attrs.vendor_prefixes().cloned().collect(),
+            fallbacks: attrs.fallbacks().cloned().collect(),
         }))
     }
 }
@@ -631,6 +686,7 @@ fn function_from_decl(
     }
 
     assert_no_lifetimes(&decl)?;
+    assert_supported_types(&decl)?;
 
     let syn::FnDecl { inputs, output, .. } = { *decl };
 
@@ -730,12 +786,36 @@ impl<'a> MacroParse<(Option<BindgenAttrs>, &'a mut TokenStream)> for syn::Item {
                 let comments = extract_doc_comments(&f.attrs);
                 f.to_tokens(tokens);
                 let opts = opts.unwrap_or_default();
+                let start = opts.start();
+                let split = opts.split().map(|s| s.to_string());
+                if start && split.is_some() {
+                    bail_span!(
+                        f.ident,
+                        "the start function cannot be split into another module",
+                    );
+                }
+                if start {
+                    if f.decl.inputs.len() > 0 {
+                        bail_span!(
+                            f.decl.inputs,
+                            "the start function cannot have arguments",
+                        );
+                    }
+                    if let syn::ReturnType::Type(..) = f.decl.output {
+                        bail_span!(
+                            f.decl.output,
+                            "the start function cannot have a return value",
+                        );
+                    }
+                }
                 program.exports.push(ast::Export {
                     class: None,
                     method_self: None,
                     is_constructor: false,
                     comments,
                     rust_name: f.ident.clone(),
+                    start,
+                    split,
                     function: f.convert(opts)?,
                 });
             }
@@ -854,6 +934,18 @@ impl<'a, 'b> MacroParse<()> for (&'a Ident, &'b mut syn::ImplItem) {
         }
 
         let opts = BindgenAttrs::find(&mut method.attrs)?;
+        if opts.start() {
+            bail_span!(
+                method.sig.ident,
+                "the `start` attribute can only be applied to free functions",
+            );
+        }
+        if opts.split().is_some() {
+            bail_span!(
+                method.sig.ident,
+                "the `split` attribute can only be applied to free functions",
+            );
+        }
         let comments = extract_doc_comments(&method.attrs);
         let is_constructor = opts.constructor();
         let (function, method_self) = function_from_decl(
@@ -873,6 +965,8 @@ impl<'a, 'b> MacroParse<()> for (&'a Ident, &'b mut syn::ImplItem) {
             function,
             comments,
             rust_name: method.sig.ident.clone(),
+            start: false,
+            split: None,
         });
         Ok(())
     }
@@ -1077,6 +1171,117 @@ fn assert_no_lifetimes(decl: &syn::FnDecl) -> Result<(), Diagnostic> {
     Diagnostic::from_vec(walk.diagnostics)
 }
 
+/// Check that the function signature doesn't mention types which can never
+/// cross the wasm ABI boundary.
+///
+/// Other unsupported types are caught by the trait bounds the generated code
+/// asserts on each type in the signature, but the forms that can be
+/// recognized syntactically are rejected here with a suggestion of what to
+/// use instead.
+fn assert_supported_types(decl: &syn::FnDecl) -> Result<(), Diagnostic> {
+    struct Walk {
+        diagnostics: Vec<Diagnostic>,
+    }
+
+    impl<'ast> syn::visit::Visit<'ast> for Walk {
+        fn visit_type_tuple(&mut self, i: &'ast syn::TypeTuple) {
+            if i.elems.len() == 0 {
+                return;
+            }
+            self.diagnostics.push(err_span!(
+                i,
+                "tuples are not supported in #[wasm_bindgen] signatures, \
+                 consider using a #[wasm_bindgen] struct, `js_sys::Array`, or \
+                 `JsValue` instead"
+            ));
+        }
+
+        fn visit_type_array(&mut self, i: &'ast syn::TypeArray) {
+            self.diagnostics.push(err_span!(
+                i,
+                "fixed-size arrays are not supported in #[wasm_bindgen] \
+                 signatures, consider using `Vec<T>`, `Box<[T]>`, or `&[T]` \
+                 instead"
+            ));
+        }
+
+        fn visit_type_impl_trait(&mut self, i: &'ast syn::TypeImplTrait) {
+            self.diagnostics.push(err_span!(
+                i,
+                "`impl Trait` is not supported in #[wasm_bindgen] signatures, \
+                 consider using a concrete type, or `&Fn`, `&mut FnMut`, or \
+                 `Closure` for callbacks"
+            ));
+        }
+
+        fn visit_type_bare_fn(&mut self, i: &'ast syn::TypeBareFn) {
+            self.diagnostics.push(err_span!(
+                i,
+                "function pointers are not supported in #[wasm_bindgen] \
+                 signatures, consider using `&Fn`, `&mut FnMut`, `Closure`, or \
+                 `js_sys::Function` instead"
+            ));
+        }
+
+        fn visit_type_reference(&mut self, i: &'ast syn::TypeReference) {
+            if let syn::Type::Reference(_) = *i.elem {
+                self.diagnostics.push(err_span!(
+                    i,
+                    "nested references are not supported in #[wasm_bindgen] \
+                     signatures, consider passing `&T` instead"
+                ));
+                return;
+            }
+            syn::visit::visit_type_reference(self, i);
+        }
+
+        fn visit_type_path(&mut self, i: &'ast syn::TypePath) {
+            if i.qself.is_none() {
+                if let Ok(ident) = extract_path_ident(&i.path) {
+                    if ident == "i128" || ident == "u128" {
+                        self.diagnostics.push(err_span!(
+                            i,
+                            "128-bit integers are not supported in \
+                             #[wasm_bindgen] signatures, consider using `i64` \
+                             or `u64` (a JS `BigInt`), or `f64`, instead"
+                        ));
+                        return;
+                    }
+                }
+                let last = i.path.segments.iter().last().map(|s| s.ident.to_string());
+                match last.as_ref().map(|s| &s[..]) {
+                    Some("HashMap") | Some("BTreeMap") => {
+                        self.diagnostics.push(err_span!(
+                            i,
+                            "maps are not supported in #[wasm_bindgen] \
+                             signatures, consider using `js_sys::Map`, or a \
+                             `JsValue` created with `JsValue::from_serde`, \
+                             instead"
+                        ));
+                        return;
+                    }
+                    Some("HashSet") | Some("BTreeSet") => {
+                        self.diagnostics.push(err_span!(
+                            i,
+                            "sets are not supported in #[wasm_bindgen] \
+                             signatures, consider using `js_sys::Set`, or a \
+                             `Vec<T>` of its elements, instead"
+                        ));
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+            syn::visit::visit_type_path(self, i);
+        }
+    }
+    let mut walk = Walk {
+        diagnostics: Vec::new(),
+    };
+    syn::visit::Visit::visit_fn_decl(&mut walk, decl);
+    Diagnostic::from_vec(walk.diagnostics)
+}
+
 /// This method always fails if the BindgenAttrs contain variadic
 fn assert_not_variadic(attrs: &BindgenAttrs, span: &dyn ToTokens) -> Result<(), Diagnostic> {
     if attrs.variadic() {
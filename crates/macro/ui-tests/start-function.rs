@@ -0,0 +1,20 @@
+extern crate wasm_bindgen;
+
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(start)]
+pub fn foo(a: u32) {}
+
+#[wasm_bindgen(start)]
+pub fn foo2() -> u32 {}
+
+#[wasm_bindgen]
+pub struct A;
+
+#[wasm_bindgen]
+impl A {
+    #[wasm_bindgen(start)]
+    pub fn foo3() {}
+}
+
+fn main() {}
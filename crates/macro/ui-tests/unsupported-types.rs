@@ -0,0 +1,44 @@
+extern crate wasm_bindgen;
+
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub fn tuple(a: (u32, u32)) {}
+
+#[wasm_bindgen]
+pub fn array() -> [u8; 4] {}
+
+#[wasm_bindgen]
+pub fn impl_trait(a: impl Fn()) {}
+
+#[wasm_bindgen]
+pub fn fn_pointer(a: fn(u32)) {}
+
+#[wasm_bindgen]
+pub fn nested_ref(a: &&u32) {}
+
+#[wasm_bindgen]
+pub fn wide(a: u128) -> Option<i128> {}
+
+#[wasm_bindgen]
+extern "C" {
+    fn import_tuple() -> (u32, String);
+}
+
+pub struct NotExported;
+
+#[wasm_bindgen]
+pub fn hash_map(a: std::collections::HashMap<u32, u32>) {}
+
+#[wasm_bindgen]
+pub fn btree_set() -> std::collections::BTreeSet<u32> {}
+
+#[wasm_bindgen]
+pub fn not_exported() -> NotExported {
+    NotExported
+}
+
+#[wasm_bindgen]
+pub fn not_exported_ref(a: &NotExported) {}
+
+fn main() {}
@@ -0,0 +1,431 @@
+//! The compact binary format `#[wasm_bindgen]` embeds programs in
+//!
+//! Programs used to be embedded as JSON, which the CLI still reads for older
+//! schema versions, and are now encoded in a binary format laid out as:
+//!
+//! * `MARKER`, a NUL byte, which JSON can't start with,
+//! * the version of the binary format as an unsigned LEB128 `u32`,
+//! * the schema version and the version of wasm-bindgen, as strings,
+//! * the number of strings in the program and the strings themselves,
+//! * the fields of the `Program` in declaration order, skipping the versions.
+//!
+//! Strings in the header and the string table are an unsigned LEB128 length
+//! followed by UTF-8. Everywhere else a string is the LEB128 index of the
+//! string in the table, which deduplicates the many repeated module names,
+//! classes and doc comments. Other values are encoded as:
+//!
+//! * `u32`s as unsigned LEB128,
+//! * `bool`s as a byte of 0 or 1,
+//! * `Option`s as a byte of 0 for `None`, or 1 followed by the value,
+//! * `Vec`s as their length followed by each element,
+//! * enums as a byte with the index of the variant followed by its fields.
+//!
+//! Fields added in later schema versions are appended to their struct, and
+//! programs of older versions are decoded with a default for them.
+//!
+//! Both the encoder used by the macro and the decoder used by the CLI live
+//! here so they can't disagree on the order of fields.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::str;
+
+use super::*;
+
+/// Marks a program as binary, as JSON programs can't start with a NUL.
+pub const MARKER: u8 = 0;
+
+/// Version of the binary format, bumped whenever it changes in a way which
+/// isn't covered by the schema version.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// An error decoding a binary program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    msg: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.msg.fmt(f)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+macro_rules! bail {
+    ($($t:tt)*) => (return Err(Error { msg: format!($($t)*) }))
+}
+
+/// Returns whether `data` is a program in the binary format rather than JSON.
+pub fn is_binary(data: &[u8]) -> bool {
+    data.first() == Some(&MARKER)
+}
+
+/// Encodes `program` in the binary format.
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut encoder = Encoder {
+        strings: HashMap::new(),
+        table: Vec::new(),
+        body: Vec::new(),
+    };
+    program.exports.encode(&mut encoder);
+    program.enums.encode(&mut encoder);
+    program.imports.encode(&mut encoder);
+    program.structs.encode(&mut encoder);
+
+    let mut ret = vec![MARKER];
+    leb(&mut ret, FORMAT_VERSION);
+    raw_str(&mut ret, &program.schema_version);
+    raw_str(&mut ret, &program.version);
+    leb(&mut ret, encoder.table.len() as u32);
+    for s in encoder.table.iter() {
+        raw_str(&mut ret, s);
+    }
+    ret.extend_from_slice(&encoder.body);
+    ret
+}
+
+/// Decodes the versions of the binary program `data`, which are readable
+/// even if the rest of it isn't.
+pub fn decode_versions(data: &[u8]) -> Result<ProgramOnlySchema, Error> {
+    Decoder::new(data)?.header()
+}
+
+/// Decodes the binary program `data`, whose schema version is compatible with
+/// this crate.
+pub fn decode(data: &[u8]) -> Result<Program, Error> {
+    let mut decoder = Decoder::new(data)?;
+    let versions = decoder.header()?;
+    decoder.schema = match versions.schema_version.parse() {
+        Ok(schema) => schema,
+        Err(_) => bail!("invalid schema version `{}`", versions.schema_version),
+    };
+    let count = decoder.u32()?;
+    for _ in 0..count {
+        let s = decoder.raw_str()?;
+        decoder.strings.push(s);
+    }
+    let exports = Decode::decode(&mut decoder)?;
+    let enums = Decode::decode(&mut decoder)?;
+    let imports = Decode::decode(&mut decoder)?;
+    let structs = Decode::decode(&mut decoder)?;
+    if !decoder.data.is_empty() {
+        bail!("{} bytes left over after the program", decoder.data.len());
+    }
+    Ok(Program {
+        exports,
+        enums,
+        imports,
+        structs,
+        version: versions.version,
+        schema_version: versions.schema_version,
+    })
+}
+
+struct Encoder<'a> {
+    strings: HashMap<&'a str, u32>,
+    table: Vec<&'a str>,
+    body: Vec<u8>,
+}
+
+impl<'a> Encoder<'a> {
+    fn u32(&mut self, n: u32) {
+        leb(&mut self.body, n);
+    }
+
+    fn byte(&mut self, b: u8) {
+        self.body.push(b);
+    }
+
+    fn str(&mut self, s: &'a str) {
+        let next = self.table.len() as u32;
+        let idx = *self.strings.entry(s).or_insert(next);
+        if idx == next {
+            self.table.push(s);
+        }
+        self.u32(idx);
+    }
+}
+
+fn leb(dst: &mut Vec<u8>, mut n: u32) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            dst.push(byte);
+            return;
+        }
+        dst.push(byte | 0x80);
+    }
+}
+
+fn raw_str(dst: &mut Vec<u8>, s: &str) {
+    leb(dst, s.len() as u32);
+    dst.extend_from_slice(s.as_bytes());
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    strings: Vec<&'a str>,
+    schema: u32,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Result<Decoder<'a>, Error> {
+        if !is_binary(data) {
+            bail!("not a binary program");
+        }
+        Ok(Decoder {
+            data: &data[1..],
+            strings: Vec::new(),
+            schema: 0,
+        })
+    }
+
+    fn header(&mut self) -> Result<ProgramOnlySchema, Error> {
+        let format = self.u32()?;
+        if format != FORMAT_VERSION {
+            bail!(
+                "unknown version {} of the binary program format, this binary \
+                 only reads version {}",
+                format,
+                FORMAT_VERSION,
+            );
+        }
+        let schema_version = self.raw_str()?.to_string();
+        let version = self.raw_str()?.to_string();
+        Ok(ProgramOnlySchema { schema_version, version })
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        match self.data.split_first() {
+            Some((&b, rest)) => {
+                self.data = rest;
+                Ok(b)
+            }
+            None => bail!("unexpected end of program"),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut ret = 0u32;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift >= 32 {
+                bail!("integer too large in program");
+            }
+            ret |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(ret);
+            }
+            shift += 7;
+        }
+    }
+
+    fn raw_str(&mut self) -> Result<&'a str, Error> {
+        let len = self.u32()? as usize;
+        if self.data.len() < len {
+            bail!("unexpected end of program");
+        }
+        let (s, rest) = self.data.split_at(len);
+        self.data = rest;
+        match str::from_utf8(s) {
+            Ok(s) => Ok(s),
+            Err(e) => bail!("invalid string in program: {}", e),
+        }
+    }
+}
+
+trait Encode {
+    fn encode<'a>(&'a self, dst: &mut Encoder<'a>);
+}
+
+trait Decode: Sized {
+    fn decode(src: &mut Decoder) -> Result<Self, Error>;
+}
+
+impl Encode for String {
+    fn encode<'a>(&'a self, dst: &mut Encoder<'a>) {
+        dst.str(self);
+    }
+}
+
+impl Decode for String {
+    fn decode(src: &mut Decoder) -> Result<String, Error> {
+        let idx = src.u32()? as usize;
+        match src.strings.get(idx) {
+            Some(s) => Ok(s.to_string()),
+            None => bail!("string {} out of bounds in program", idx),
+        }
+    }
+}
+
+impl Encode for bool {
+    fn encode<'a>(&'a self, dst: &mut Encoder<'a>) {
+        dst.byte(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(src: &mut Decoder) -> Result<bool, Error> {
+        match src.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => bail!("invalid boolean {} in program", b),
+        }
+    }
+}
+
+impl Encode for u32 {
+    fn encode<'a>(&'a self, dst: &mut Encoder<'a>) {
+        dst.u32(*self);
+    }
+}
+
+impl Decode for u32 {
+    fn decode(src: &mut Decoder) -> Result<u32, Error> {
+        src.u32()
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode<'a>(&'a self, dst: &mut Encoder<'a>) {
+        match *self {
+            Some(ref t) => {
+                dst.byte(1);
+                t.encode(dst);
+            }
+            None => dst.byte(0),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(src: &mut Decoder) -> Result<Option<T>, Error> {
+        match src.byte()? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(src)?)),
+            b => bail!("invalid option {} in program", b),
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode<'a>(&'a self, dst: &mut Encoder<'a>) {
+        dst.u32(self.len() as u32);
+        for t in self.iter() {
+            t.encode(dst);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(src: &mut Decoder) -> Result<Vec<T>, Error> {
+        (0..src.u32()?).map(|_| T::decode(src)).collect()
+    }
+}
+
+macro_rules! decode_field {
+    ($src:ident) => (Decode::decode($src)?);
+    ($src:ident $since:expr) => (
+        if $src.schema >= $since {
+            Decode::decode($src)?
+        } else {
+            Default::default()
+        }
+    );
+}
+
+/// Implements `Encode` and `Decode` for structs from their fields in order,
+/// where `field @ n` is a field added in schema version `n`.
+macro_rules! structs {
+    ($($name:ident { $($field:ident $(@ $since:expr)*,)* })*) => ($(
+        impl Encode for $name {
+            #[allow(unused_variables)]
+            fn encode<'a>(&'a self, dst: &mut Encoder<'a>) {
+                $(self.$field.encode(dst);)*
+            }
+        }
+
+        impl Decode for $name {
+            #[allow(unused_variables)]
+            fn decode(src: &mut Decoder) -> Result<$name, Error> {
+                Ok($name {
+                    $($field: decode_field!(src $($since)*),)*
+                })
+            }
+        }
+    )*)
+}
+
+structs! {
+    Import { module, js_namespace, kind, }
+    ImportFunction { shim, catch, variadic, method, structural, function, }
+    MethodData { class, kind, }
+    Operation { is_static, kind, }
+    ImportStatic { name, shim, }
+    ImportType { name, instanceof_shim, vendor_prefixes, fallbacks, }
+    ImportEnum {}
+    Export { class, method, consumed, is_constructor, function, comments, start, split, }
+    Enum { name, variants, comments, }
+    EnumVariant { name, value, }
+    Function { name, location, }
+    SourceLocation { file, line, column, }
+    Struct { name, fields, comments, }
+    StructField { name, readonly, comments, }
+}
+
+macro_rules! enums {
+    ($($name:ident { $($variant:ident $(($field:ident))* = $tag:expr,)* })*) => ($(
+        impl Encode for $name {
+            fn encode<'a>(&'a self, dst: &mut Encoder<'a>) {
+                match *self {
+                    $($name::$variant $((ref $field))* => {
+                        dst.byte($tag);
+                        $($field.encode(dst);)*
+                    })*
+                }
+            }
+        }
+
+        impl Decode for $name {
+            fn decode(src: &mut Decoder) -> Result<$name, Error> {
+                Ok(match src.byte()? {
+                    $($tag => $name::$variant $(({
+                        let $field = Decode::decode(src)?;
+                        $field
+                    }))*,)*
+                    b => bail!("invalid {} {} in program", stringify!($name), b),
+                })
+            }
+        }
+    )*)
+}
+
+enums! {
+    ImportKind {
+        Function(f) = 0,
+        Static(s) = 1,
+        Type(t) = 2,
+        Enum(e) = 3,
+    }
+    MethodKind {
+        Constructor = 0,
+        Operation(op) = 1,
+    }
+    OperationKind {
+        Regular = 0,
+        Getter(s) = 1,
+        Setter(s) = 2,
+        IndexingGetter = 3,
+        IndexingSetter = 4,
+        IndexingDeleter = 5,
+    }
+}
@@ -3,9 +3,76 @@
 #[macro_use]
 extern crate serde_derive;
 
-// The schema is so unstable right now we just force it to change whenever this
-// package's version changes, which happens on all publishes.
-pub const SCHEMA_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub mod binary;
+
+/// Version of the format of the `Program`s which `#[wasm_bindgen]` embeds in
+/// wasm files.
+///
+/// This must be bumped whenever the format changes, though only once between
+/// two releases as nothing reads unreleased versions. Fields added to the
+/// format need a default when decoding programs of older versions, and if
+/// older programs can't be read anymore `OLDEST_SCHEMA_VERSION` must be
+/// bumped to the new version as well. See the "Schema versions" page of the
+/// guide for the history of the format.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// The oldest schema version which can still be read by this crate.
+const OLDEST_SCHEMA_VERSION: u32 = 1;
+
+/// The first schema version whose programs are encoded in the format of
+/// `binary` and record their schema version as a number. Programs of older
+/// versions are JSON and record the release which wrote them instead.
+const FIRST_BINARY_SCHEMA_VERSION: u32 = 2;
+
+/// The first release of wasm-bindgen writing each released schema version.
+///
+/// Programs of the versions below `FIRST_BINARY_SCHEMA_VERSION` recorded this
+/// release as their schema version, so it's how they're recognized.
+const SCHEMA_RELEASES: &[(u32, &str)] = &[(1, "0.2.22")];
+
+/// How programs of some schema version relate to this version of the schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaCompat {
+    /// Programs of the version can be read.
+    Compatible,
+    /// The version is newer than this crate knows about.
+    Newer,
+    /// The version is too old to be read.
+    Older,
+}
+
+/// Determines whether programs of `schema_version` can be read.
+pub fn schema_compat(schema_version: &str) -> SchemaCompat {
+    let unnumbered = SCHEMA_RELEASES
+        .iter()
+        .find(|&&(version, release)| {
+            version < FIRST_BINARY_SCHEMA_VERSION && release == schema_version
+        });
+    let version = match unnumbered {
+        Some(&(version, _)) => version,
+        None => match schema_version.parse::<u32>() {
+            Ok(v) => v,
+            Err(_) => return SchemaCompat::Older,
+        },
+    };
+    if version > SCHEMA_VERSION {
+        SchemaCompat::Newer
+    } else if version < OLDEST_SCHEMA_VERSION {
+        SchemaCompat::Older
+    } else {
+        SchemaCompat::Compatible
+    }
+}
+
+/// The oldest release of wasm-bindgen whose programs can still be read, which
+/// is this version if no release wrote a schema version that can be read.
+pub fn oldest_release() -> String {
+    SCHEMA_RELEASES
+        .iter()
+        .find(|&&(version, _)| version >= OLDEST_SCHEMA_VERSION)
+        .map(|&(_, release)| release.to_string())
+        .unwrap_or_else(version)
+}
 
 #[derive(Deserialize)]
 pub struct ProgramOnlySchema {
@@ -87,6 +154,10 @@ pub struct ImportStatic {
 pub struct ImportType {
     pub name: String,
     pub instanceof_shim: String,
+    #[serde(default)]
+    pub vendor_prefixes: Vec<String>,
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -100,6 +171,10 @@ pub struct Export {
     pub is_constructor: bool,
     pub function: Function,
     pub comments: Vec<String>,
+    #[serde(default)]
+    pub start: bool,
+    #[serde(default)]
+    pub split: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -118,6 +193,17 @@ pub struct EnumVariant {
 #[derive(Deserialize, Serialize)]
 pub struct Function {
     pub name: String,
+    #[serde(default)]
+    pub location: Option<SourceLocation>,
+}
+
+/// Where a function was declared in the Rust source, if the compiler told the
+/// macro. Lines are 1-based and columns 0-based.
+#[derive(Deserialize, Serialize)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -148,6 +234,13 @@ pub fn free_function(struct_name: &str) -> String {
     return name;
 }
 
+pub fn unwrap_function(struct_name: &str) -> String {
+    let mut name = format!("__wbg_");
+    name.extend(struct_name.chars().flat_map(|s| s.to_lowercase()));
+    name.push_str("_unwrap");
+    return name;
+}
+
 pub fn free_function_export_name(function_name: &str) -> String {
     function_name.to_string()
 }
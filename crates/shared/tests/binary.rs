@@ -0,0 +1,179 @@
+//! Tests of the binary format programs are embedded in.
+
+extern crate serde_json;
+extern crate wasm_bindgen_shared as shared;
+
+use shared::binary;
+
+/// A program using every kind of item, variant and optional field.
+const PROGRAM: &str = r#"{
+    "exports": [
+        {
+            "class": null,
+            "method": false,
+            "consumed": false,
+            "is_constructor": false,
+            "function": {
+                "name": "greet",
+                "location": { "file": "src/lib.rs", "line": 12, "column": 7 }
+            },
+            "comments": [" Says hello."],
+            "start": false,
+            "split": "greetings"
+        },
+        {
+            "class": "Counter",
+            "method": true,
+            "consumed": true,
+            "is_constructor": true,
+            "function": { "name": "new", "location": null },
+            "comments": [],
+            "start": true,
+            "split": null
+        }
+    ],
+    "enums": [
+        {
+            "name": "Color",
+            "variants": [
+                { "name": "Red", "value": 0 },
+                { "name": "Green", "value": 300 }
+            ],
+            "comments": [" A color."]
+        }
+    ],
+    "imports": [
+        {
+            "module": "./foo.js",
+            "js_namespace": "console",
+            "kind": {
+                "kind": "function",
+                "shim": "__wbg_log",
+                "catch": true,
+                "variadic": true,
+                "method": null,
+                "structural": true,
+                "function": { "name": "log", "location": null }
+            }
+        },
+        {
+            "module": null,
+            "js_namespace": null,
+            "kind": {
+                "kind": "function",
+                "shim": "__wbg_new",
+                "catch": false,
+                "variadic": false,
+                "method": { "class": "Element", "kind": "Constructor" },
+                "structural": false,
+                "function": { "name": "new", "location": null }
+            }
+        }
+    ],
+    "structs": [
+        {
+            "name": "Counter",
+            "fields": [
+                { "name": "count", "readonly": true, "comments": [" The count."] }
+            ],
+            "comments": [" A counter."]
+        }
+    ],
+    "version": "0.2.22 (ae0d8f9c2)",
+    "schema_version": "2"
+}"#;
+
+/// Imports of each operation, static and type.
+const IMPORTS: &[&str] = &[
+    r#"{ "kind": "static", "name": "document", "shim": "__wbg_static" }"#,
+    r#"{ "kind": "type", "name": "Element", "instanceof_shim": "__wbg_instanceof",
+         "vendor_prefixes": ["webkit", "moz"], "fallbacks": ["Node"] }"#,
+    r#"{ "kind": "enum" }"#,
+];
+
+const OPERATIONS: &[&str] = &[
+    r#""Regular""#,
+    r#"{ "Getter": "width" }"#,
+    r#"{ "Setter": "width" }"#,
+    r#""IndexingGetter""#,
+    r#""IndexingSetter""#,
+    r#""IndexingDeleter""#,
+];
+
+fn program() -> shared::Program {
+    let mut program: serde_json::Value = serde_json::from_str(PROGRAM).unwrap();
+    let mut imports = Vec::new();
+    for kind in IMPORTS {
+        imports.push(serde_json::json!({
+            "module": null,
+            "js_namespace": null,
+            "kind": serde_json::from_str::<serde_json::Value>(kind).unwrap(),
+        }));
+    }
+    for (i, kind) in OPERATIONS.iter().enumerate() {
+        let kind = serde_json::from_str::<serde_json::Value>(kind).unwrap();
+        imports.push(serde_json::json!({
+            "module": null,
+            "js_namespace": null,
+            "kind": {
+                "kind": "function",
+                "shim": format!("__wbg_op{}", i),
+                "catch": false,
+                "variadic": false,
+                "method": {
+                    "class": "Element",
+                    "kind": { "Operation": { "is_static": i % 2 == 0, "kind": kind } },
+                },
+                "structural": false,
+                "function": { "name": "op", "location": null },
+            },
+        }));
+    }
+    program["imports"].as_array_mut().unwrap().extend(imports);
+    serde_json::from_value(program).unwrap()
+}
+
+#[test]
+fn round_trip() {
+    let program = program();
+    let encoded = binary::encode(&program);
+    assert!(binary::is_binary(&encoded));
+
+    let versions = binary::decode_versions(&encoded).unwrap();
+    assert_eq!(versions.schema_version, "2");
+    assert_eq!(versions.version, "0.2.22 (ae0d8f9c2)");
+
+    let decoded = binary::decode(&encoded).unwrap();
+    assert_eq!(
+        serde_json::to_value(&decoded).unwrap(),
+        serde_json::to_value(&program).unwrap(),
+    );
+}
+
+#[test]
+fn strings_are_shared() {
+    let program = program();
+    let encoded = binary::encode(&program);
+    // `Element` is the class of many imports but is only stored once.
+    let occurrences = encoded.windows(7).filter(|w| w == b"Element").count();
+    assert_eq!(occurrences, 1);
+}
+
+#[test]
+fn errors() {
+    let encoded = binary::encode(&program());
+    let err = binary::decode(&encoded[..encoded.len() - 1]).err().unwrap();
+    assert_eq!(err.to_string(), "unexpected end of program");
+
+    let mut extra = encoded.clone();
+    extra.push(0);
+    let err = binary::decode(&extra).err().unwrap();
+    assert_eq!(err.to_string(), "1 bytes left over after the program");
+
+    let mut format = encoded.clone();
+    format[1] = binary::FORMAT_VERSION as u8 + 1;
+    let err = binary::decode_versions(&format).err().unwrap();
+    assert!(err.to_string().contains("unknown version"), "{}", err);
+
+    assert!(binary::decode(b"{}").is_err());
+}
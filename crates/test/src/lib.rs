@@ -15,6 +15,9 @@ extern crate wasm_bindgen_test_macro;
 
 pub use wasm_bindgen_test_macro::wasm_bindgen_test;
 
+use js_sys::{Array, Reflect};
+use wasm_bindgen::{JsCast, JsValue};
+
 /// Helper macro which acts like `println!` only routes to `console.log`
 /// instead.
 #[macro_export]
@@ -50,5 +53,70 @@ macro_rules! wasm_bindgen_test_configure {
     () => ()
 }
 
+/// Runs `f` and panics if any `JsValue` it created is still alive once it
+/// returns, listing each leaked value along with the stack trace of where it
+/// was created.
+///
+/// This relies on the JS glue tracking its heap, which it only does when
+/// generated with `wasm-bindgen --debug` and without `--anyref`. The
+/// `wasm-bindgen-test-runner` passes `--debug` unless `WASM_BINDGEN_NO_DEBUG`
+/// is set in the environment. Otherwise `f` is run without checking for
+/// leaks, see `find_leaks` to tell the two apart.
+pub fn assert_no_leaks<F: FnOnce()>(f: F) {
+    let leaks = match find_leaks(f) {
+        Some(leaks) => leaks,
+        None => return,
+    };
+    if !leaks.is_empty() {
+        panic!("{} `JsValue`s leaked:\n\n{}", leaks.len(), leaks.join("\n\n"));
+    }
+}
+
+/// Runs `f` and describes each `JsValue` it created which is still alive
+/// once it returns, like `assert_no_leaks` does.
+///
+/// Returns `None` if the JS glue doesn't track its heap, in which case `f` is
+/// still run.
+pub fn find_leaks<F: FnOnce()>(f: F) -> Option<Vec<String>> {
+    let before = match live_values() {
+        Some(before) => before,
+        None => {
+            f();
+            return None;
+        }
+    };
+    let before = before
+        .iter()
+        .map(|entry| field(entry, "id").as_f64())
+        .collect::<Vec<_>>();
+    f();
+
+    let leaks = live_values()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|entry| !before.contains(&field(entry, "id").as_f64()))
+        .map(|entry| {
+            format!(
+                "{:?} (refcount {}) created at:\n{}",
+                field(&entry, "value"),
+                field(&entry, "refcount").as_f64().unwrap_or_default(),
+                field(&entry, "trace").as_string().unwrap_or_default(),
+            )
+        })
+        .collect();
+    Some(leaks)
+}
+
+fn live_values() -> Option<Vec<JsValue>> {
+    let report: Array = wasm_bindgen::__rt::heap_report()?.unchecked_into();
+    let mut values = Vec::new();
+    report.for_each(&mut |entry, _, _| values.push(entry));
+    Some(values)
+}
+
+fn field(entry: &JsValue, name: &str) -> JsValue {
+    Reflect::get(entry, &JsValue::from_str(name)).unwrap()
+}
+
 #[path = "rt/mod.rs"]
 pub mod __rt;
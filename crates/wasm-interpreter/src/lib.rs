@@ -14,16 +14,38 @@
 //! which gives us a list of `u32` values to then decode.
 //!
 //! The interpreter here is only geared towards this one exact use case, so it's
-//! quite small and likely not extra-efficient.
+//! quite small and likely not extra-efficient. It does however support all of
+//! the control flow and numeric instructions of wasm, as descriptor functions
+//! compiled without optimizations (or mangled by LTO) can contain just about
+//! anything. Instead of panicking on what it can't handle the interpreter
+//! returns an `Error` naming the functions being executed, and it gives up
+//! after a fixed number of instructions or nested calls.
 
 #![deny(missing_docs)]
 
 extern crate parity_wasm;
 
 use std::collections::HashMap;
+use std::error;
+use std::fmt;
 
 use parity_wasm::elements::*;
 
+/// Maximum number of instructions executed to interpret one descriptor.
+const MAX_STEPS: usize = 1_000_000;
+
+/// Maximum depth of nested calls while interpreting a descriptor.
+const MAX_DEPTH: usize = 512;
+
+/// Maximum number of pages of memory written to, beyond those initialized by
+/// data segments.
+const MAX_DIRTY_PAGES: usize = 64;
+
+/// Stack pointer used when the module doesn't initialize one itself.
+const DEFAULT_STACK_POINTER: u64 = 0x100;
+
+const PAGE_SIZE: u32 = 65536;
+
 /// A ready-to-go interpreter of a wasm module.
 ///
 /// An interpreter currently represents effectively cached state. It is reused
@@ -45,6 +67,10 @@ pub struct Interpreter {
     // functions.
     name_map: HashMap<String, u32>,
 
+    // Names of functions used in errors, from exports and the names section
+    // if it's been parsed. Imported functions are named after their import.
+    function_names: HashMap<u32, String>,
+
     // The numerical index of the sections in the wasm module, indexed into
     // the module's list of sections.
     code_idx: Option<usize>,
@@ -52,14 +78,27 @@ pub struct Interpreter {
     functions_idx: Option<usize>,
     elements_idx: Option<usize>,
 
-    // The current stack pointer (global 0) and wasm memory (the stack). Only
-    // used in a limited capacity.
-    sp: i32,
-    mem: Vec<i32>,
+    // The values of all globals, imported ones first. All values here and
+    // below are stored as their bit patterns, which is all wasm's type system
+    // lets us get away with.
+    globals: Vec<u64>,
+
+    // The wasm memory, of which only pages which have been initialized or
+    // written to are allocated. Descriptor functions only really use the
+    // stack, sometimes reading constants from data segments.
+    pages: HashMap<u32, Vec<u8>>,
+    data_pages: usize,
+    memory_size: u64,
 
-    // The wasm stack. Note how it's just `i32` which is intentional, we don't
-    // support other types.
-    stack: Vec<i32>,
+    // Contents of the function table, as initialized by the element section.
+    table: HashMap<u32, u32>,
+
+    // The wasm stack.
+    stack: Vec<u64>,
+
+    // Budget of instructions left to execute and the current depth of calls.
+    steps: usize,
+    depth: usize,
 
     // The descriptor which we're assembling, a list of `u32` entries. This is
     // very specific to wasm-bindgen and is the purpose for the existence of
@@ -79,6 +118,107 @@ struct Sections<'a> {
     elements: &'a ElementSection,
 }
 
+/// An error which occurred while interpreting a descriptor function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    kind: ErrorKind,
+    functions: Vec<String>,
+}
+
+/// The different reasons interpreting a function can fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An instruction the interpreter doesn't support, like atomics.
+    UnsupportedInstruction(String),
+    /// An imported function other than the descriptor intrinsics was called.
+    ImportCalled(String),
+    /// Execution trapped, for example due to `unreachable` or an out of
+    /// bounds memory access.
+    Trap(String),
+    /// More instructions were executed than the interpreter allows for a
+    /// descriptor.
+    StepLimitExceeded,
+    /// Calls were nested deeper than the interpreter allows.
+    CallDepthExceeded,
+    /// The module doesn't look like valid wasm, for example an instruction
+    /// popped more values than there were on the stack.
+    InvalidModule(String),
+}
+
+impl Error {
+    fn new(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            functions: Vec::new(),
+        }
+    }
+
+    fn trap(msg: &str) -> Error {
+        Error::new(ErrorKind::Trap(msg.to_string()))
+    }
+
+    fn invalid(msg: &str) -> Error {
+        Error::new(ErrorKind::InvalidModule(msg.to_string()))
+    }
+
+    /// Returns why interpretation failed.
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Returns the name of the function which was being executed when the
+    /// error occurred.
+    pub fn function(&self) -> &str {
+        self.functions.last().map(|s| &s[..]).unwrap_or("<unknown>")
+    }
+
+    /// Returns the names of all functions being executed when the error
+    /// occurred, starting with the descriptor function which was called.
+    pub fn backtrace(&self) -> &[String] {
+        &self.functions
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::UnsupportedInstruction(ref i) => write!(f, "unsupported instruction `{}`", i),
+            ErrorKind::ImportCalled(ref name) => write!(f, "called the imported function `{}`", name),
+            ErrorKind::Trap(ref msg) => write!(f, "trapped: {}", msg),
+            ErrorKind::StepLimitExceeded => write!(f, "executed more than {} instructions", MAX_STEPS),
+            ErrorKind::CallDepthExceeded => write!(f, "nested calls deeper than {}", MAX_DEPTH),
+            ErrorKind::InvalidModule(ref msg) => write!(f, "invalid wasm: {}", msg),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to interpret `{}`: {}", self.function(), self.kind)?;
+        if self.functions.len() > 1 {
+            write!(f, " (while interpreting `{}`)", self.functions[0])?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        "failed to interpret a wasm-bindgen descriptor function"
+    }
+}
+
+// Where a branch to a label continues execution.
+struct Label {
+    // Height of the stack when the block was entered.
+    height: usize,
+    // Number of values the branch carries over.
+    arity: usize,
+    // Index of the instruction to continue at.
+    cont: usize,
+    is_loop: bool,
+}
+
 impl Interpreter {
     /// Creates a new interpreter from a provided `Module`, precomputing all
     /// information necessary to interpret further.
@@ -88,13 +228,6 @@ impl Interpreter {
     pub fn new(module: &Module) -> Interpreter {
         let mut ret = Interpreter::default();
 
-        // The descriptor functions shouldn't really use all that much memory
-        // (the LLVM call stack, now the wasm stack). To handle that let's give
-        // our selves a little bit of memory and set the stack pointer (global
-        // 0) to the top.
-        ret.mem = vec![0; 0x100];
-        ret.sp = ret.mem.len() as i32;
-
         // Figure out where our code section, if any, is.
         for (i, s) in module.sections().iter().enumerate() {
             match s {
@@ -116,8 +249,20 @@ impl Interpreter {
             for entry in i.entries() {
                 match entry.external() {
                     External::Function(_) => idx += 1,
+                    External::Global(_) => {
+                        ret.globals.push(0);
+                        continue
+                    }
+                    External::Memory(m) => {
+                        ret.memory_size = m.limits().initial() as u64 * PAGE_SIZE as u64;
+                        continue
+                    }
                     _ => continue,
                 }
+                ret.function_names.insert(
+                    idx - 1,
+                    format!("{}::{}", entry.module(), entry.field()),
+                );
                 if entry.module() != "__wbindgen_placeholder__" {
                     continue
                 }
@@ -129,6 +274,12 @@ impl Interpreter {
             }
         }
 
+        if let Some(NameSection::Function(names)) = module.names_section() {
+            for (i, name) in names.names().iter() {
+                ret.function_names.insert(i, name.clone());
+            }
+        }
+
         // Build up the mapping of exported functions to function indices.
         if let Some(e) = module.export_section() {
             for e in e.entries() {
@@ -137,9 +288,56 @@ impl Interpreter {
                     _ => continue,
                 };
                 ret.name_map.insert(e.field().to_string(), *i);
+                ret.function_names
+                    .entry(*i)
+                    .or_insert_with(|| e.field().to_string());
             }
         }
 
+        // Set up globals, memory and the table the way instantiating the
+        // module would.
+        if let Some(s) = module.global_section() {
+            for g in s.entries() {
+                let value = ret.const_expr(g.init_expr()).unwrap_or(0);
+                ret.globals.push(value);
+            }
+        }
+        if let Some(m) = module.memory_section().and_then(|s| s.entries().get(0)) {
+            ret.memory_size = m.limits().initial() as u64 * PAGE_SIZE as u64;
+        }
+        if let Some(s) = module.data_section() {
+            for segment in s.entries() {
+                let offset = match ret.const_expr(segment.offset()) {
+                    Some(offset) => offset as u32,
+                    None => continue,
+                };
+                for (i, byte) in segment.value().iter().enumerate() {
+                    let addr = offset.wrapping_add(i as u32);
+                    ret.page(addr)[(addr % PAGE_SIZE) as usize] = *byte;
+                }
+            }
+        }
+        ret.data_pages = ret.pages.len();
+        if let Some(s) = module.elements_section() {
+            for segment in s.entries() {
+                let offset = match ret.const_expr(segment.offset()) {
+                    Some(offset) => offset as u32,
+                    None => continue,
+                };
+                for (i, f) in segment.members().iter().enumerate() {
+                    ret.table.insert(offset + i as u32, *f);
+                }
+            }
+        }
+
+        // The descriptor functions shouldn't really use all that much memory
+        // (the LLVM call stack, now the wasm stack). If the stack pointer
+        // (global 0) isn't initialized in the module, like when it's imported,
+        // give ourselves a small stack at the bottom of memory.
+        if ret.globals.get(0) == Some(&0) {
+            ret.globals[0] = DEFAULT_STACK_POINTER;
+        }
+
         return ret
     }
 
@@ -149,11 +347,10 @@ impl Interpreter {
     /// the `module` provided here must be the same as the one passed to `new`
     /// when this `Interpreter` was constructed.
     ///
-    /// The `func` must be a wasm-bindgen descriptor function meaning that it
-    /// doesn't do anything like use floats or i64. Instead all it should do is
-    /// call other functions, sometimes some stack pointer manipulation, and
-    /// then call the one imported `__wbindgen_describe` function. Anything else
-    /// will cause this interpreter to panic.
+    /// The `func` must be a wasm-bindgen descriptor function meaning that all
+    /// it should do is run some code and call the one imported
+    /// `__wbindgen_describe` function. Calls to any other imported function
+    /// or instructions like atomics result in an error.
     ///
     /// When the descriptor has finished running the assembled descriptor list
     /// is returned. The descriptor returned can then be re-parsed into an
@@ -161,16 +358,19 @@ impl Interpreter {
     ///
     /// # Return value
     ///
-    /// Returns `Some` if `func` was found in the `module` and `None` if it was
-    /// not found in the `module`.
+    /// Returns `Ok(Some(..))` if `func` was found in the `module` and
+    /// `Ok(None)` if it was not found in the `module`.
     pub fn interpret_descriptor(
         &mut self,
         func: &str,
         module: &Module,
-    ) -> Option<&[u32]> {
-        let idx = *self.name_map.get(func)?;
+    ) -> Result<Option<&[u32]>, Error> {
+        let idx = match self.name_map.get(func) {
+            Some(idx) => *idx,
+            None => return Ok(None),
+        };
         self.with_sections(module, |me, sections| {
-            me.interpret_descriptor_idx(idx, sections)
+            me.interpret_descriptor_idx(idx, sections).map(Some)
         })
     }
 
@@ -178,17 +378,26 @@ impl Interpreter {
         &mut self,
         idx: u32,
         sections: &Sections,
-    ) -> Option<&[u32]> {
+    ) -> Result<&[u32], Error> {
         self.descriptor.truncate(0);
+        self.run(idx, &[], sections)?;
+        Ok(&self.descriptor)
+    }
 
-        // We should have a blank wasm and LLVM stack at both the start and end
-        // of the call.
-        assert_eq!(self.sp, self.mem.len() as i32);
-        assert_eq!(self.stack.len(), 0);
-        self.call(idx, sections);
-        assert_eq!(self.stack.len(), 0);
-        assert_eq!(self.sp, self.mem.len() as i32);
-        Some(&self.descriptor)
+    /// Calls the function `idx` with `args`, restoring the state of the
+    /// interpreter afterwards.
+    fn run(&mut self, idx: u32, args: &[u64], sections: &Sections) -> Result<Vec<u64>, Error> {
+        let globals = self.globals.clone();
+        self.stack.truncate(0);
+        self.stack.extend(args);
+        self.steps = MAX_STEPS;
+        self.depth = 0;
+        let result = self.call(idx, sections);
+        // Descriptor functions are supposed to be free of side effects, and
+        // reset at least the stack pointer themselves.
+        self.globals = globals;
+        result?;
+        Ok(self.stack.drain(..).collect())
     }
 
     /// Interprets a "closure descriptor", figuring out the signature of the
@@ -212,7 +421,7 @@ impl Interpreter {
         code_idx: usize,
         module: &Module,
         entry_removal_list: &mut Vec<(usize, usize)>,
-    ) -> Option<&[u32]> {
+    ) -> Result<&[u32], Error> {
         self.with_sections(module, |me, sections| {
             me._interpret_closure_descriptor(code_idx, sections, entry_removal_list)
         })
@@ -223,7 +432,7 @@ impl Interpreter {
         code_idx: usize,
         sections: &Sections,
         entry_removal_list: &mut Vec<(usize, usize)>,
-    ) -> Option<&[u32]> {
+    ) -> Result<&[u32], Error> {
         // Call the `code_idx` function. This is an internal `#[inline(never)]`
         // whose code is completely controlled by the `wasm-bindgen` crate, so
         // it should take some arguments (the number of arguments depends on the
@@ -231,26 +440,28 @@ impl Interpreter {
         // here). What we're interested in is that while executing this function
         // it'll call `__wbindgen_describe_closure` with an argument that we
         // look for.
-        assert!(self.descriptor_table_idx.is_none());
+        self.descriptor_table_idx = None;
         let closure_descriptor_idx = (code_idx + self.imports) as u32;
-
-        let code_sig = sections.functions.entries()[code_idx].type_ref();
-        let function_ty = match &sections.types.types()[code_sig as usize] {
-            Type::Function(t) => t,
+        let function_ty = self.function_type(closure_descriptor_idx, sections)?;
+        let args = vec![0; function_ty.params().len()];
+        self.run(closure_descriptor_idx, &args, sections)?;
+        let descriptor_table_idx = match self.descriptor_table_idx.take() {
+            Some(idx) => idx,
+            None => {
+                let name = self.function_name(closure_descriptor_idx);
+                return Err(Error {
+                    kind: ErrorKind::InvalidModule(
+                        "`__wbindgen_describe_closure` was never called".to_string(),
+                    ),
+                    functions: vec![name],
+                });
+            }
         };
-        for _ in 0..function_ty.params().len() {
-            self.stack.push(0);
-        }
-
-        self.call(closure_descriptor_idx, sections);
-        assert_eq!(self.stack.len(), 1);
-        self.stack.pop();
-        let descriptor_table_idx = self.descriptor_table_idx.take().unwrap();
 
         // After we've got the table index of the descriptor function we're
         // interested go take a look in the function table to find what the
         // actual index of the function is.
-        let (entry_idx, offset, entry) = sections.elements.entries()
+        let found = sections.elements.entries()
             .iter()
             .enumerate()
             .filter_map(|(i, entry)| {
@@ -269,8 +480,19 @@ impl Interpreter {
             .find(|(_i, offset, entry)| {
                 *offset <= descriptor_table_idx &&
                     descriptor_table_idx < (*offset + entry.members().len() as u32)
-            })
-            .expect("failed to find index in table elements");
+            });
+        let (entry_idx, offset, entry) = match found {
+            Some(found) => found,
+            None => {
+                return Err(Error {
+                    kind: ErrorKind::InvalidModule(format!(
+                        "closure descriptor {} not found in the function table",
+                        descriptor_table_idx,
+                    )),
+                    functions: vec![self.function_name(closure_descriptor_idx)],
+                });
+            }
+        };
         let idx = (descriptor_table_idx - offset) as usize;
         let descriptor_idx = entry.members()[idx];
 
@@ -288,116 +510,619 @@ impl Interpreter {
         self.describe_closure_idx
     }
 
-    fn call(&mut self, idx: u32, sections: &Sections) {
+    fn function_name(&self, idx: u32) -> String {
+        match self.function_names.get(&idx) {
+            Some(name) => name.clone(),
+            None => format!("function #{}", idx),
+        }
+    }
+
+    fn function_type<'a>(&self, idx: u32, sections: &Sections<'a>) -> Result<&'a FunctionType, Error> {
+        let code_idx = (idx as usize)
+            .checked_sub(self.imports)
+            .ok_or_else(|| Error::invalid("expected a defined function"))?;
+        let code_sig = sections
+            .functions
+            .entries()
+            .get(code_idx)
+            .ok_or_else(|| Error::invalid("function index out of bounds"))?
+            .type_ref();
+        self.signature(code_sig, sections)
+    }
+
+    fn signature<'a>(&self, ty: u32, sections: &Sections<'a>) -> Result<&'a FunctionType, Error> {
+        match sections.types.types().get(ty as usize) {
+            Some(Type::Function(t)) => Ok(t),
+            None => Err(Error::invalid("type index out of bounds")),
+        }
+    }
+
+    /// Calls the function `idx`, which may be one of the imported descriptor
+    /// intrinsics, with its arguments on the stack.
+    fn call(&mut self, idx: u32, sections: &Sections) -> Result<(), Error> {
+        // If this function is calling the `__wbindgen_describe` function,
+        // which we've precomputed the index for, then it's telling us about
+        // the next `u32` element in the descriptor to return. We "call" the
+        // imported function here by directly inlining it.
+        if Some(idx) == self.describe_idx {
+            let value = self.pop()?;
+            self.descriptor.push(value as u32);
+            return Ok(());
+        }
+        if Some(idx) == self.describe_closure_idx {
+            self.descriptor_table_idx = Some(self.pop()? as u32);
+            self.pop()?;
+            self.pop()?;
+            self.stack.push(0);
+            return Ok(());
+        }
+        if (idx as usize) < self.imports {
+            return Err(Error::new(ErrorKind::ImportCalled(self.function_name(idx))));
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::new(ErrorKind::CallDepthExceeded));
+        }
+        self.depth += 1;
+        let result = self.call_body(idx, sections);
+        self.depth -= 1;
+        result.map_err(|mut e| {
+            e.functions.insert(0, self.function_name(idx));
+            e
+        })
+    }
+
+    fn call_body(&mut self, idx: u32, sections: &Sections) -> Result<(), Error> {
         use parity_wasm::elements::Instruction::*;
 
-        let idx = idx as usize;
-        assert!(idx >= self.imports); // can't call imported functions
-        let code_idx = idx - self.imports;
-        let body = &sections.code.bodies()[code_idx];
-
-        // Allocate space for our call frame's local variables. All local
-        // variables should be of the `i32` type.
-        assert!(body.locals().len() <= 1, "too many local types");
-        let nlocals = body.locals()
-            .get(0)
-            .map(|i| {
-                assert_eq!(i.value_type(), ValueType::I32);
-                i.count()
-            })
-            .unwrap_or(0);
+        let code_idx = idx as usize - self.imports;
+        let body = sections
+            .code
+            .bodies()
+            .get(code_idx)
+            .ok_or_else(|| Error::invalid("function index out of bounds"))?;
+        let function_ty = self.function_type(idx, sections)?;
 
-        let code_sig = sections.functions.entries()[code_idx].type_ref();
-        let function_ty = match &sections.types.types()[code_sig as usize] {
-            Type::Function(t) => t,
-        };
-        let mut locals = Vec::with_capacity(function_ty.params().len() + nlocals as usize);
-        // Any function parameters we have get popped off the stack and put into
-        // the first few locals ...
-        for param in function_ty.params() {
-            assert_eq!(*param, ValueType::I32);
-            locals.push(self.stack.pop().unwrap());
-        }
-        // ... and the remaining locals all start as zero ...
-        for _ in 0..nlocals {
-            locals.push(0);
-        }
-        // ... and we expect one stack slot at the end if there's a returned
-        // value
-        let before = self.stack.len();
-        let stack_after = match function_ty.return_type() {
-            Some(t) => {
-                assert_eq!(t, ValueType::I32);
-                before + 1
+        // Any function parameters we have get popped off the stack and put
+        // into the first few locals, and the remaining locals all start as
+        // zero.
+        let nparams = function_ty.params().len();
+        if self.stack.len() < nparams {
+            return Err(Error::invalid("not enough arguments on the stack"));
+        }
+        let at = self.stack.len() - nparams;
+        let mut locals = self.stack.split_off(at);
+        for local in body.locals() {
+            for _ in 0..local.count() {
+                locals.push(0);
             }
-            None => before,
-        };
+        }
+
+        // The function body is a block which `return` and branches to its
+        // label exit from.
+        let code = body.code().elements();
+        let (elses, ends) = match_blocks(code)?;
+        let height = self.stack.len();
+        let arity = function_ty.return_type().is_some() as usize;
+        let mut labels = vec![Label {
+            height,
+            arity,
+            cont: code.len(),
+            is_loop: false,
+        }];
+        let mut pc = 0;
+
+        // Actual interpretation loop! Control flow instructions update `pc`
+        // and `continue`, everything else falls through to the next
+        // instruction.
+        while pc < code.len() {
+            if self.steps == 0 {
+                return Err(Error::new(ErrorKind::StepLimitExceeded));
+            }
+            self.steps -= 1;
 
-        // Actual interpretation loop! We keep track of our stack's length to
-        // recover it as part of the `Return` instruction, and otherwise this is
-        // a pretty straightforward interpretation loop.
-        for instr in body.code().elements() {
-            match instr {
-                I32Const(x) => self.stack.push(*x),
-                SetLocal(i) => locals[*i as usize] = self.stack.pop().unwrap(),
-                GetLocal(i) => self.stack.push(locals[*i as usize]),
-                Call(idx) => {
-                    // If this function is calling the `__wbindgen_describe`
-                    // function, which we've precomputed the index for, then
-                    // it's telling us about the next `u32` element in the
-                    // descriptor to return. We "call" the imported function
-                    // here by directly inlining it.
-                    //
-                    // Otherwise this is a normal call so we recurse.
-                    if Some(*idx) == self.describe_idx {
-                        self.descriptor.push(self.stack.pop().unwrap() as u32);
-                    } else if Some(*idx) == self.describe_closure_idx {
-                        self.descriptor_table_idx =
-                            Some(self.stack.pop().unwrap() as u32);
-                        self.stack.pop();
-                        self.stack.pop();
-                        self.stack.push(0);
-                    } else {
-                        self.call(*idx, sections);
+            match code[pc] {
+                Unreachable => return Err(Error::trap("unreachable executed")),
+                Nop => {}
+                Block(ty) => labels.push(Label {
+                    height: self.stack.len(),
+                    arity: block_arity(ty),
+                    cont: ends[&pc] + 1,
+                    is_loop: false,
+                }),
+                Loop(_) => labels.push(Label {
+                    height: self.stack.len(),
+                    arity: 0,
+                    cont: pc + 1,
+                    is_loop: true,
+                }),
+                If(ty) => {
+                    let cond = self.pop()? as u32;
+                    labels.push(Label {
+                        height: self.stack.len(),
+                        arity: block_arity(ty),
+                        cont: ends[&pc] + 1,
+                        is_loop: false,
+                    });
+                    if cond == 0 {
+                        match elses.get(&pc) {
+                            Some(&e) => pc = e + 1,
+                            None => {
+                                labels.pop();
+                                pc = ends[&pc] + 1;
+                            }
+                        }
+                        continue;
                     }
                 }
-                GetGlobal(0) => self.stack.push(self.sp),
-                SetGlobal(0) => self.sp = self.stack.pop().unwrap(),
-                I32Sub => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a - b);
+                // Reaching the `else` means the `then` arm has finished.
+                Else => {
+                    pc = labels.pop().unwrap().cont;
+                    continue;
+                }
+                End => {
+                    if labels.len() == 1 {
+                        break;
+                    }
+                    labels.pop();
                 }
-                I32Add => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push(a + b);
+                Br(depth) => {
+                    pc = self.branch(&mut labels, depth)?;
+                    continue;
                 }
-                I32Store(/* align = */ 2, offset) => {
-                    let val = self.stack.pop().unwrap();
-                    let addr = self.stack.pop().unwrap() as u32;
-                    self.mem[((addr + *offset) as usize) / 4] = val;
+                BrIf(depth) => {
+                    if self.pop()? as u32 != 0 {
+                        pc = self.branch(&mut labels, depth)?;
+                        continue;
+                    }
                 }
-                I32Load(/* align = */ 2, offset) => {
-                    let addr = self.stack.pop().unwrap() as u32;
-                    self.stack.push(self.mem[((addr + *offset) as usize) / 4]);
+                BrTable(ref table, default) => {
+                    let i = self.pop()? as u32 as usize;
+                    let depth = table.get(i).cloned().unwrap_or(default);
+                    pc = self.branch(&mut labels, depth)?;
+                    continue;
                 }
-                Return => self.stack.truncate(stack_after),
-                End => break,
-
-                // All other instructions shouldn't be used by our various
-                // descriptor functions. LLVM optimizations may mean that some
-                // of the above instructions aren't actually needed either, but
-                // the above instructions have empirically been required when
-                // executing our own test suite in wasm-bindgen.
-                //
-                // Note that LLVM may change over time to generate new
-                // instructions in debug mode, and we'll have to react to those
-                // sorts of changes as they arise.
-                s => panic!("unknown instruction {:?}", s),
+                Return => {
+                    let depth = labels.len() as u32 - 1;
+                    pc = self.branch(&mut labels, depth)?;
+                    continue;
+                }
+
+                Call(idx) => self.call(idx, sections)?,
+                CallIndirect(ty, _) => {
+                    let i = self.pop()? as u32;
+                    let f = match self.table.get(&i) {
+                        Some(f) => *f,
+                        None => return Err(Error::trap("undefined table element")),
+                    };
+                    let expected = self.signature(ty, sections)?;
+                    match self.callee_type(f, sections)? {
+                        Some(actual) if actual != expected => {
+                            return Err(Error::trap("indirect call signature mismatch"));
+                        }
+                        _ => {}
+                    }
+                    self.call(f, sections)?;
+                }
+
+                Drop => {
+                    self.pop()?;
+                }
+                Select => {
+                    let cond = self.pop()? as u32;
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.stack.push(if cond != 0 { a } else { b });
+                }
+
+                GetLocal(i) => {
+                    let value = *locals.get(i as usize).ok_or_else(|| Error::invalid("local out of bounds"))?;
+                    self.stack.push(value);
+                }
+                SetLocal(i) => {
+                    let value = self.pop()?;
+                    *locals.get_mut(i as usize).ok_or_else(|| Error::invalid("local out of bounds"))? = value;
+                }
+                TeeLocal(i) => {
+                    let value = self.pop()?;
+                    *locals.get_mut(i as usize).ok_or_else(|| Error::invalid("local out of bounds"))? = value;
+                    self.stack.push(value);
+                }
+                GetGlobal(i) => {
+                    let value = *self.globals.get(i as usize).ok_or_else(|| Error::invalid("global out of bounds"))?;
+                    self.stack.push(value);
+                }
+                SetGlobal(i) => {
+                    let value = self.pop()?;
+                    *self.globals.get_mut(i as usize).ok_or_else(|| Error::invalid("global out of bounds"))? = value;
+                }
+
+                I32Load(_, offset) | F32Load(_, offset) | I64Load32U(_, offset) => self.load(offset, 4, false)?,
+                I64Load(_, offset) | F64Load(_, offset) => self.load(offset, 8, false)?,
+                I32Load8S(_, offset) => self.load_i32(offset, 1, true)?,
+                I32Load8U(_, offset) | I64Load8U(_, offset) => self.load(offset, 1, false)?,
+                I32Load16S(_, offset) => self.load_i32(offset, 2, true)?,
+                I32Load16U(_, offset) | I64Load16U(_, offset) => self.load(offset, 2, false)?,
+                I64Load8S(_, offset) => self.load(offset, 1, true)?,
+                I64Load16S(_, offset) => self.load(offset, 2, true)?,
+                I64Load32S(_, offset) => self.load(offset, 4, true)?,
+                I32Store(_, offset) | F32Store(_, offset) | I64Store32(_, offset) => self.store(offset, 4)?,
+                I64Store(_, offset) | F64Store(_, offset) => self.store(offset, 8)?,
+                I32Store8(_, offset) | I64Store8(_, offset) => self.store(offset, 1)?,
+                I32Store16(_, offset) | I64Store16(_, offset) => self.store(offset, 2)?,
+                CurrentMemory(_) => self.stack.push(self.memory_size / PAGE_SIZE as u64),
+                // Memory never grows while interpreting, the stack lives in
+                // what's already there.
+                GrowMemory(_) => {
+                    self.pop()?;
+                    self.push_i32(-1);
+                }
+
+                I32Const(x) => self.push_i32(x),
+                I64Const(x) => self.stack.push(x as u64),
+                F32Const(x) => self.stack.push(x as u64),
+                F64Const(x) => self.stack.push(x),
+
+                ref instr => {
+                    if !self.numeric(instr)? {
+                        return Err(Error::new(ErrorKind::UnsupportedInstruction(format!("{:?}", instr))));
+                    }
+                }
+            }
+            pc += 1;
+        }
+
+        if self.stack.len() != height + arity {
+            return Err(Error::invalid("unbalanced stack at the end of the function"));
+        }
+        Ok(())
+    }
+
+    /// Type of the function `idx`, either imported or defined.
+    fn callee_type<'a>(&self, idx: u32, sections: &Sections<'a>) -> Result<Option<&'a FunctionType>, Error> {
+        if (idx as usize) < self.imports {
+            // Imports can't be called anyway, except for the intrinsics whose
+            // signature we know.
+            return Ok(None);
+        }
+        self.function_type(idx, sections).map(Some)
+    }
+
+    /// Branches to the label `depth` levels up, returning the instruction to
+    /// continue at.
+    fn branch(&mut self, labels: &mut Vec<Label>, depth: u32) -> Result<usize, Error> {
+        let target = labels
+            .len()
+            .checked_sub(depth as usize + 1)
+            .ok_or_else(|| Error::invalid("branch to nonexistent label"))?;
+        let (height, arity, cont, is_loop) = {
+            let label = &labels[target];
+            (label.height, label.arity, label.cont, label.is_loop)
+        };
+        if self.stack.len() < height + arity {
+            return Err(Error::invalid("not enough values on the stack for a branch"));
+        }
+        let at = self.stack.len() - arity;
+        let values = self.stack.split_off(at);
+        self.stack.truncate(height);
+        self.stack.extend(values);
+        // Branching to a loop continues at its start, anything else exits the
+        // block.
+        labels.truncate(if is_loop { target + 1 } else { target });
+        Ok(cont)
+    }
+
+    /// Interprets a numeric instruction, returning whether it's one.
+    fn numeric(&mut self, instr: &Instruction) -> Result<bool, Error> {
+        use parity_wasm::elements::Instruction::*;
+
+        macro_rules! unop {
+            ($pop:ident, $push:ident, |$a:ident| $e:expr) => {{
+                let $a = self.$pop()?;
+                self.$push($e);
+            }};
+        }
+        macro_rules! binop {
+            ($pop:ident, $push:ident, |$a:ident, $b:ident| $e:expr) => {{
+                let $b = self.$pop()?;
+                let $a = self.$pop()?;
+                self.$push($e);
+            }};
+        }
+
+        match *instr {
+            I32Eqz => unop!(pop_i32, push_bool, |a| a == 0),
+            I32Eq => binop!(pop_i32, push_bool, |a, b| a == b),
+            I32Ne => binop!(pop_i32, push_bool, |a, b| a != b),
+            I32LtS => binop!(pop_i32, push_bool, |a, b| a < b),
+            I32LtU => binop!(pop_i32, push_bool, |a, b| (a as u32) < (b as u32)),
+            I32GtS => binop!(pop_i32, push_bool, |a, b| a > b),
+            I32GtU => binop!(pop_i32, push_bool, |a, b| (a as u32) > (b as u32)),
+            I32LeS => binop!(pop_i32, push_bool, |a, b| a <= b),
+            I32LeU => binop!(pop_i32, push_bool, |a, b| (a as u32) <= (b as u32)),
+            I32GeS => binop!(pop_i32, push_bool, |a, b| a >= b),
+            I32GeU => binop!(pop_i32, push_bool, |a, b| (a as u32) >= (b as u32)),
+
+            I64Eqz => unop!(pop_i64, push_bool, |a| a == 0),
+            I64Eq => binop!(pop_i64, push_bool, |a, b| a == b),
+            I64Ne => binop!(pop_i64, push_bool, |a, b| a != b),
+            I64LtS => binop!(pop_i64, push_bool, |a, b| a < b),
+            I64LtU => binop!(pop_i64, push_bool, |a, b| (a as u64) < (b as u64)),
+            I64GtS => binop!(pop_i64, push_bool, |a, b| a > b),
+            I64GtU => binop!(pop_i64, push_bool, |a, b| (a as u64) > (b as u64)),
+            I64LeS => binop!(pop_i64, push_bool, |a, b| a <= b),
+            I64LeU => binop!(pop_i64, push_bool, |a, b| (a as u64) <= (b as u64)),
+            I64GeS => binop!(pop_i64, push_bool, |a, b| a >= b),
+            I64GeU => binop!(pop_i64, push_bool, |a, b| (a as u64) >= (b as u64)),
+
+            F32Eq => binop!(pop_f32, push_bool, |a, b| a == b),
+            F32Ne => binop!(pop_f32, push_bool, |a, b| a != b),
+            F32Lt => binop!(pop_f32, push_bool, |a, b| a < b),
+            F32Gt => binop!(pop_f32, push_bool, |a, b| a > b),
+            F32Le => binop!(pop_f32, push_bool, |a, b| a <= b),
+            F32Ge => binop!(pop_f32, push_bool, |a, b| a >= b),
+
+            F64Eq => binop!(pop_f64, push_bool, |a, b| a == b),
+            F64Ne => binop!(pop_f64, push_bool, |a, b| a != b),
+            F64Lt => binop!(pop_f64, push_bool, |a, b| a < b),
+            F64Gt => binop!(pop_f64, push_bool, |a, b| a > b),
+            F64Le => binop!(pop_f64, push_bool, |a, b| a <= b),
+            F64Ge => binop!(pop_f64, push_bool, |a, b| a >= b),
+
+            I32Clz => unop!(pop_i32, push_i32, |a| a.leading_zeros() as i32),
+            I32Ctz => unop!(pop_i32, push_i32, |a| a.trailing_zeros() as i32),
+            I32Popcnt => unop!(pop_i32, push_i32, |a| a.count_ones() as i32),
+            I32Add => binop!(pop_i32, push_i32, |a, b| a.wrapping_add(b)),
+            I32Sub => binop!(pop_i32, push_i32, |a, b| a.wrapping_sub(b)),
+            I32Mul => binop!(pop_i32, push_i32, |a, b| a.wrapping_mul(b)),
+            I32DivS => {
+                let b = self.pop_i32()?;
+                let a = self.pop_i32()?;
+                let value = a.checked_div(b).ok_or_else(|| Error::trap("integer divide by zero or overflow"))?;
+                self.push_i32(value);
+            }
+            I32DivU => {
+                let b = self.pop_i32()? as u32;
+                let a = self.pop_i32()? as u32;
+                let value = a.checked_div(b).ok_or_else(|| Error::trap("integer divide by zero"))?;
+                self.push_i32(value as i32);
+            }
+            I32RemS => {
+                let b = self.pop_i32()?;
+                let a = self.pop_i32()?;
+                if b == 0 {
+                    return Err(Error::trap("integer divide by zero"));
+                }
+                self.push_i32(a.wrapping_rem(b));
+            }
+            I32RemU => {
+                let b = self.pop_i32()? as u32;
+                let a = self.pop_i32()? as u32;
+                let value = a.checked_rem(b).ok_or_else(|| Error::trap("integer divide by zero"))?;
+                self.push_i32(value as i32);
+            }
+            I32And => binop!(pop_i32, push_i32, |a, b| a & b),
+            I32Or => binop!(pop_i32, push_i32, |a, b| a | b),
+            I32Xor => binop!(pop_i32, push_i32, |a, b| a ^ b),
+            I32Shl => binop!(pop_i32, push_i32, |a, b| a.wrapping_shl(b as u32)),
+            I32ShrS => binop!(pop_i32, push_i32, |a, b| a.wrapping_shr(b as u32)),
+            I32ShrU => binop!(pop_i32, push_i32, |a, b| (a as u32).wrapping_shr(b as u32) as i32),
+            I32Rotl => binop!(pop_i32, push_i32, |a, b| a.rotate_left(b as u32)),
+            I32Rotr => binop!(pop_i32, push_i32, |a, b| a.rotate_right(b as u32)),
+
+            I64Clz => unop!(pop_i64, push_i64, |a| a.leading_zeros() as i64),
+            I64Ctz => unop!(pop_i64, push_i64, |a| a.trailing_zeros() as i64),
+            I64Popcnt => unop!(pop_i64, push_i64, |a| a.count_ones() as i64),
+            I64Add => binop!(pop_i64, push_i64, |a, b| a.wrapping_add(b)),
+            I64Sub => binop!(pop_i64, push_i64, |a, b| a.wrapping_sub(b)),
+            I64Mul => binop!(pop_i64, push_i64, |a, b| a.wrapping_mul(b)),
+            I64DivS => {
+                let b = self.pop_i64()?;
+                let a = self.pop_i64()?;
+                let value = a.checked_div(b).ok_or_else(|| Error::trap("integer divide by zero or overflow"))?;
+                self.push_i64(value);
+            }
+            I64DivU => {
+                let b = self.pop_i64()? as u64;
+                let a = self.pop_i64()? as u64;
+                let value = a.checked_div(b).ok_or_else(|| Error::trap("integer divide by zero"))?;
+                self.push_i64(value as i64);
+            }
+            I64RemS => {
+                let b = self.pop_i64()?;
+                let a = self.pop_i64()?;
+                if b == 0 {
+                    return Err(Error::trap("integer divide by zero"));
+                }
+                self.push_i64(a.wrapping_rem(b));
+            }
+            I64RemU => {
+                let b = self.pop_i64()? as u64;
+                let a = self.pop_i64()? as u64;
+                let value = a.checked_rem(b).ok_or_else(|| Error::trap("integer divide by zero"))?;
+                self.push_i64(value as i64);
+            }
+            I64And => binop!(pop_i64, push_i64, |a, b| a & b),
+            I64Or => binop!(pop_i64, push_i64, |a, b| a | b),
+            I64Xor => binop!(pop_i64, push_i64, |a, b| a ^ b),
+            I64Shl => binop!(pop_i64, push_i64, |a, b| a.wrapping_shl(b as u32)),
+            I64ShrS => binop!(pop_i64, push_i64, |a, b| a.wrapping_shr(b as u32)),
+            I64ShrU => binop!(pop_i64, push_i64, |a, b| (a as u64).wrapping_shr(b as u32) as i64),
+            I64Rotl => binop!(pop_i64, push_i64, |a, b| a.rotate_left(b as u32)),
+            I64Rotr => binop!(pop_i64, push_i64, |a, b| a.rotate_right(b as u32)),
+
+            F32Abs => unop!(pop_f32, push_f32, |a| a.abs()),
+            F32Neg => unop!(pop_f32, push_f32, |a| -a),
+            F32Ceil => unop!(pop_f32, push_f32, |a| a.ceil()),
+            F32Floor => unop!(pop_f32, push_f32, |a| a.floor()),
+            F32Trunc => unop!(pop_f32, push_f32, |a| a.trunc()),
+            F32Nearest => unop!(pop_f32, push_f32, |a| nearest(a as f64) as f32),
+            F32Sqrt => unop!(pop_f32, push_f32, |a| a.sqrt()),
+            F32Add => binop!(pop_f32, push_f32, |a, b| a + b),
+            F32Sub => binop!(pop_f32, push_f32, |a, b| a - b),
+            F32Mul => binop!(pop_f32, push_f32, |a, b| a * b),
+            F32Div => binop!(pop_f32, push_f32, |a, b| a / b),
+            F32Min => binop!(pop_f32, push_f32, |a, b| a.min(b)),
+            F32Max => binop!(pop_f32, push_f32, |a, b| a.max(b)),
+            F32Copysign => binop!(pop_f32, push_f32, |a, b| copysign(a as f64, b as f64) as f32),
+
+            F64Abs => unop!(pop_f64, push_f64, |a| a.abs()),
+            F64Neg => unop!(pop_f64, push_f64, |a| -a),
+            F64Ceil => unop!(pop_f64, push_f64, |a| a.ceil()),
+            F64Floor => unop!(pop_f64, push_f64, |a| a.floor()),
+            F64Trunc => unop!(pop_f64, push_f64, |a| a.trunc()),
+            F64Nearest => unop!(pop_f64, push_f64, |a| nearest(a)),
+            F64Sqrt => unop!(pop_f64, push_f64, |a| a.sqrt()),
+            F64Add => binop!(pop_f64, push_f64, |a, b| a + b),
+            F64Sub => binop!(pop_f64, push_f64, |a, b| a - b),
+            F64Mul => binop!(pop_f64, push_f64, |a, b| a * b),
+            F64Div => binop!(pop_f64, push_f64, |a, b| a / b),
+            F64Min => binop!(pop_f64, push_f64, |a, b| a.min(b)),
+            F64Max => binop!(pop_f64, push_f64, |a, b| a.max(b)),
+            F64Copysign => binop!(pop_f64, push_f64, |a, b| copysign(a, b)),
+
+            I32WrapI64 => unop!(pop_i64, push_i32, |a| a as i32),
+            I32TruncSF32 => unop!(pop_f32, push_i32, |a| trunc(a as f64, I32_RANGE)? as i32),
+            I32TruncUF32 => unop!(pop_f32, push_i32, |a| trunc(a as f64, U32_RANGE)? as u32 as i32),
+            I32TruncSF64 => unop!(pop_f64, push_i32, |a| trunc(a, I32_RANGE)? as i32),
+            I32TruncUF64 => unop!(pop_f64, push_i32, |a| trunc(a, U32_RANGE)? as u32 as i32),
+            I64ExtendSI32 => unop!(pop_i32, push_i64, |a| a as i64),
+            I64ExtendUI32 => unop!(pop_i32, push_i64, |a| a as u32 as i64),
+            I64TruncSF32 => unop!(pop_f32, push_i64, |a| trunc(a as f64, I64_RANGE)? as i64),
+            I64TruncUF32 => unop!(pop_f32, push_i64, |a| trunc(a as f64, U64_RANGE)? as u64 as i64),
+            I64TruncSF64 => unop!(pop_f64, push_i64, |a| trunc(a, I64_RANGE)? as i64),
+            I64TruncUF64 => unop!(pop_f64, push_i64, |a| trunc(a, U64_RANGE)? as u64 as i64),
+            F32ConvertSI32 => unop!(pop_i32, push_f32, |a| a as f32),
+            F32ConvertUI32 => unop!(pop_i32, push_f32, |a| a as u32 as f32),
+            F32ConvertSI64 => unop!(pop_i64, push_f32, |a| a as f32),
+            F32ConvertUI64 => unop!(pop_i64, push_f32, |a| a as u64 as f32),
+            F32DemoteF64 => unop!(pop_f64, push_f32, |a| a as f32),
+            F64ConvertSI32 => unop!(pop_i32, push_f64, |a| a as f64),
+            F64ConvertUI32 => unop!(pop_i32, push_f64, |a| a as u32 as f64),
+            F64ConvertSI64 => unop!(pop_i64, push_f64, |a| a as f64),
+            F64ConvertUI64 => unop!(pop_i64, push_f64, |a| a as u64 as f64),
+            F64PromoteF32 => unop!(pop_f32, push_f64, |a| a as f64),
+
+            // Values are stored as their bits already.
+            I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64 => {}
+
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn pop(&mut self) -> Result<u64, Error> {
+        self.stack
+            .pop()
+            .ok_or_else(|| Error::invalid("popped a value off an empty stack"))
+    }
+
+    fn pop_i32(&mut self) -> Result<i32, Error> {
+        self.pop().map(|v| v as u32 as i32)
+    }
+
+    fn pop_i64(&mut self) -> Result<i64, Error> {
+        self.pop().map(|v| v as i64)
+    }
+
+    fn pop_f32(&mut self) -> Result<f32, Error> {
+        self.pop().map(|v| f32::from_bits(v as u32))
+    }
+
+    fn pop_f64(&mut self) -> Result<f64, Error> {
+        self.pop().map(f64::from_bits)
+    }
+
+    fn push_i32(&mut self, value: i32) {
+        self.stack.push(value as u32 as u64);
+    }
+
+    fn push_i64(&mut self, value: i64) {
+        self.stack.push(value as u64);
+    }
+
+    fn push_f32(&mut self, value: f32) {
+        self.stack.push(value.to_bits() as u64);
+    }
+
+    fn push_f64(&mut self, value: f64) {
+        self.stack.push(value.to_bits());
+    }
+
+    fn push_bool(&mut self, value: bool) {
+        self.stack.push(value as u64);
+    }
+
+    /// Returns the effective address of an access of `size` bytes at `offset`
+    /// from the address on the stack.
+    fn address(&mut self, offset: u32, size: u32) -> Result<u32, Error> {
+        let addr = self.pop()? as u32 as u64 + offset as u64;
+        if addr + size as u64 > self.memory_size {
+            return Err(Error::trap("out of bounds memory access"));
+        }
+        Ok(addr as u32)
+    }
+
+    fn page(&mut self, addr: u32) -> &mut Vec<u8> {
+        self.pages
+            .entry(addr / PAGE_SIZE)
+            .or_insert_with(|| vec![0; PAGE_SIZE as usize])
+    }
+
+    /// Loads `size` bytes onto the stack, sign extending them to 64 bits if
+    /// `signed`.
+    fn load(&mut self, offset: u32, size: u32, signed: bool) -> Result<(), Error> {
+        let addr = self.address(offset, size)?;
+        let mut value = 0u64;
+        for i in 0..size {
+            let addr = addr + i;
+            let byte = match self.pages.get(&(addr / PAGE_SIZE)) {
+                Some(page) => page[(addr % PAGE_SIZE) as usize],
+                None => 0,
+            };
+            value |= (byte as u64) << (8 * i);
+        }
+        if signed {
+            let shift = 64 - 8 * size;
+            value = (((value << shift) as i64) >> shift) as u64;
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Loads `size` bytes as an `i32`, which unlike the `i64` loads is only
+    /// sign extended to 32 bits.
+    fn load_i32(&mut self, offset: u32, size: u32, signed: bool) -> Result<(), Error> {
+        self.load(offset, size, signed)?;
+        let value = self.pop()?;
+        self.stack.push(value as u32 as u64);
+        Ok(())
+    }
+
+    fn store(&mut self, offset: u32, size: u32) -> Result<(), Error> {
+        let value = self.pop()?;
+        let addr = self.address(offset, size)?;
+        for i in 0..size {
+            let addr = addr + i;
+            if !self.pages.contains_key(&(addr / PAGE_SIZE))
+                && self.pages.len() >= self.data_pages + MAX_DIRTY_PAGES
+            {
+                return Err(Error::trap("wrote to more memory than the interpreter allows"));
             }
+            self.page(addr)[(addr % PAGE_SIZE) as usize] = (value >> (8 * i)) as u8;
+        }
+        Ok(())
+    }
+
+    /// Evaluates a constant initializer expression of a global or segment.
+    fn const_expr(&self, expr: &InitExpr) -> Option<u64> {
+        match expr.code().get(0)? {
+            Instruction::I32Const(x) => Some(*x as u32 as u64),
+            Instruction::I64Const(x) => Some(*x as u64),
+            Instruction::F32Const(x) => Some(*x as u64),
+            Instruction::F64Const(x) => Some(*x),
+            Instruction::GetGlobal(i) => self.globals.get(*i as usize).cloned(),
+            _ => None,
         }
-        assert_eq!(self.stack.len(), stack_after);
     }
 
     fn with_sections<'a, T>(
@@ -431,3 +1156,76 @@ impl Interpreter {
         f(self, &Sections { code, types, functions, elements })
     }
 }
+
+/// Finds the `else` and `end` of every block, loop and `if` in `code`, by the
+/// index of the instruction starting it.
+fn match_blocks(code: &[Instruction]) -> Result<(HashMap<usize, usize>, HashMap<usize, usize>), Error> {
+    let mut elses = HashMap::new();
+    let mut ends = HashMap::new();
+    let mut open = Vec::new();
+    for (i, instr) in code.iter().enumerate() {
+        match *instr {
+            Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => open.push(i),
+            Instruction::Else => {
+                let start = *open.last().ok_or_else(|| Error::invalid("`else` outside of an `if`"))?;
+                elses.insert(start, i);
+            }
+            // The last `end` is the one of the function body.
+            Instruction::End => {
+                if let Some(start) = open.pop() {
+                    ends.insert(start, i);
+                }
+            }
+            _ => {}
+        }
+    }
+    if !open.is_empty() {
+        return Err(Error::invalid("block without an `end`"));
+    }
+    Ok((elses, ends))
+}
+
+fn block_arity(ty: BlockType) -> usize {
+    match ty {
+        BlockType::Value(_) => 1,
+        BlockType::NoResult => 0,
+    }
+}
+
+// The values each integer type can be truncated to from floats, from the
+// smallest one up to but excluding the upper bound.
+const I32_RANGE: (f64, f64) = (-2147483648.0, 2147483648.0);
+const U32_RANGE: (f64, f64) = (0.0, 4294967296.0);
+const I64_RANGE: (f64, f64) = (-9223372036854775808.0, 9223372036854775808.0);
+const U64_RANGE: (f64, f64) = (0.0, 18446744073709551616.0);
+
+/// Truncates `a` towards zero, trapping like wasm does if it's NaN or the
+/// result is outside of `range`.
+fn trunc(a: f64, range: (f64, f64)) -> Result<f64, Error> {
+    if a.is_nan() {
+        return Err(Error::trap("invalid conversion to integer"));
+    }
+    let a = a.trunc();
+    if a < range.0 || a >= range.1 {
+        return Err(Error::trap("integer overflow"));
+    }
+    Ok(a)
+}
+
+/// Rounds to the nearest integer, ties to even.
+fn nearest(a: f64) -> f64 {
+    let rounded = a.round();
+    if (a - a.trunc()).abs() == 0.5 && rounded % 2.0 != 0.0 {
+        rounded - a.signum()
+    } else {
+        rounded
+    }
+}
+
+fn copysign(a: f64, b: f64) -> f64 {
+    if a.is_sign_negative() == b.is_sign_negative() {
+        a
+    } else {
+        -a
+    }
+}
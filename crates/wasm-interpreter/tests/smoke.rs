@@ -5,9 +5,11 @@ extern crate wasm_bindgen_wasm_interpreter;
 use std::fs;
 use std::process::Command;
 
-use wasm_bindgen_wasm_interpreter::Interpreter;
+use parity_wasm::elements::Module;
 
-fn interpret(wat: &str, name: &str, result: Option<&[u32]>) {
+use wasm_bindgen_wasm_interpreter::{Error, ErrorKind, Interpreter};
+
+fn module(wat: &str) -> Module {
     let input = tempfile::NamedTempFile::new().unwrap();
     let output = tempfile::NamedTempFile::new().unwrap();
     fs::write(input.path(), wat).unwrap();
@@ -18,9 +20,19 @@ fn interpret(wat: &str, name: &str, result: Option<&[u32]>) {
         .unwrap();
     println!("status: {}", status);
     assert!(status.success());
-    let module = parity_wasm::deserialize_file(output.path()).unwrap();
+    parity_wasm::deserialize_file(output.path()).unwrap()
+}
+
+fn interpret(wat: &str, name: &str, result: Option<&[u32]>) {
+    let module = module(wat);
+    let mut i = Interpreter::new(&module);
+    assert_eq!(i.interpret_descriptor(name, &module).unwrap(), result);
+}
+
+fn interpret_err(wat: &str, name: &str) -> Error {
+    let module = module(wat);
     let mut i = Interpreter::new(&module);
-    assert_eq!(i.interpret(name, &module), result);
+    i.interpret_descriptor(name, &module).unwrap_err()
 }
 
 #[test]
@@ -230,3 +242,231 @@ fn calling_functions() {
     "#;
     interpret(wat, "foo", Some(&[0]));
 }
+
+#[test]
+fn control_flow() {
+    let wat = r#"
+        (module
+            (import "__wbindgen_placeholder__" "__wbindgen_describe"
+              (func $__wbindgen_describe (param i32)))
+
+            (func $foo
+                (local i32)
+
+                ;; describe 0, 1, 2 in a loop
+                block
+                    loop
+                        get_local 0
+                        i32.const 3
+                        i32.eq
+                        br_if 1
+                        get_local 0
+                        call $__wbindgen_describe
+                        get_local 0
+                        i32.const 1
+                        i32.add
+                        set_local 0
+                        br 0
+                    end
+                end
+
+                ;; if/else with a result
+                i32.const 0
+                if (result i32)
+                    i32.const 10
+                else
+                    i32.const 20
+                end
+                call $__wbindgen_describe
+
+                ;; br_table picking the second block
+                block
+                    block
+                        i32.const 1
+                        br_table 0 1
+                    end
+                    i32.const 30
+                    call $__wbindgen_describe
+                end
+            )
+
+            (export "foo" (func $foo))
+        )
+    "#;
+    interpret(wat, "foo", Some(&[0, 1, 2, 20]));
+}
+
+#[test]
+fn value_types() {
+    let wat = r#"
+        (module
+            (import "__wbindgen_placeholder__" "__wbindgen_describe"
+              (func $__wbindgen_describe (param i32)))
+
+            (memory 1)
+
+            (func $foo
+                (local i64 f64)
+                i64.const 0x100000001
+                set_local 0
+                get_local 0
+                i32.wrap/i64
+                call $__wbindgen_describe
+
+                f64.const 2.5
+                set_local 1
+                get_local 1
+                f64.const 2
+                f64.mul
+                i32.trunc_s/f64
+                call $__wbindgen_describe
+
+                i32.const 8
+                i64.const -1
+                i64.store
+                i32.const 8
+                i32.load8_u
+                call $__wbindgen_describe
+            )
+
+            (export "foo" (func $foo))
+        )
+    "#;
+    interpret(wat, "foo", Some(&[1, 5, 255]));
+}
+
+#[test]
+fn truncation() {
+    let wat = r#"
+        (module
+            (import "__wbindgen_placeholder__" "__wbindgen_describe"
+              (func $__wbindgen_describe (param i32)))
+
+            (func $in_range
+                f64.const -2147483648.9
+                i32.trunc_s/f64
+                call $__wbindgen_describe
+
+                f32.const -0.5
+                i32.trunc_u/f32
+                call $__wbindgen_describe
+
+                f64.const 4294967295.5
+                i64.trunc_u/f64
+                i32.wrap/i64
+                call $__wbindgen_describe
+            )
+
+            (func $nan
+                f32.const nan
+                i32.trunc_s/f32
+                call $__wbindgen_describe
+            )
+
+            (func $overflow
+                f64.const 2147483648
+                i32.trunc_s/f64
+                call $__wbindgen_describe
+            )
+
+            (func $negative
+                f64.const -1
+                i64.trunc_u/f64
+                i32.wrap/i64
+                call $__wbindgen_describe
+            )
+
+            (export "in_range" (func $in_range))
+            (export "nan" (func $nan))
+            (export "overflow" (func $overflow))
+            (export "negative" (func $negative))
+        )
+    "#;
+    interpret(wat, "in_range", Some(&[2147483648, 0, 4294967295]));
+
+    let trap = |name: &str| match *interpret_err(wat, name).kind() {
+        ErrorKind::Trap(ref msg) => msg.clone(),
+        ref kind => panic!("unexpected error: {:?}", kind),
+    };
+    assert_eq!(trap("nan"), "invalid conversion to integer");
+    assert_eq!(trap("overflow"), "integer overflow");
+    assert_eq!(trap("negative"), "integer overflow");
+}
+
+#[test]
+fn params_and_call_indirect() {
+    let wat = r#"
+        (module
+            (import "__wbindgen_placeholder__" "__wbindgen_describe"
+              (func $__wbindgen_describe (param i32)))
+
+            (type $t (func (param i32 i32) (result i32)))
+            (table 1 anyfunc)
+            (elem (i32.const 0) $sub)
+
+            (func $sub (type $t)
+                get_local 0
+                get_local 1
+                i32.sub
+            )
+
+            (func $foo
+                i32.const 5
+                i32.const 3
+                i32.const 0
+                call_indirect (type $t)
+                call $__wbindgen_describe
+            )
+
+            (export "foo" (func $foo))
+        )
+    "#;
+    interpret(wat, "foo", Some(&[2]));
+}
+
+#[test]
+fn errors() {
+    let wat = r#"
+        (module
+            (import "__wbindgen_placeholder__" "__wbindgen_describe"
+              (func $__wbindgen_describe (param i32)))
+            (import "env" "other" (func $other))
+
+            (func $foo
+                call $bar
+            )
+
+            (func $bar
+                call $other
+            )
+
+            (func $baz
+                unreachable
+            )
+
+            (func $forever
+                loop
+                    br 0
+                end
+            )
+
+            (export "foo" (func $foo))
+            (export "bar" (func $bar))
+            (export "baz" (func $baz))
+            (export "forever" (func $forever))
+        )
+    "#;
+    let err = interpret_err(wat, "foo");
+    assert_eq!(*err.kind(), ErrorKind::ImportCalled("env::other".to_string()));
+    assert_eq!(err.backtrace(), &["foo".to_string(), "bar".to_string()]);
+    assert_eq!(err.function(), "bar");
+
+    let err = interpret_err(wat, "baz");
+    match *err.kind() {
+        ErrorKind::Trap(_) => {}
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+
+    let err = interpret_err(wat, "forever");
+    assert_eq!(*err.kind(), ErrorKind::StepLimitExceeded);
+}
@@ -491,6 +491,8 @@ impl<'src> FirstPassRecord<'src> {
             doc_comment: None,
             instanceof_shim: format!("__widl_instanceof_{}", name),
             extends: Vec::new(),
+            vendor_prefixes: Vec::new(),
+            fallbacks: Vec::new(),
         };
         let extra = camel_case_ident(name);
         let extra = &[&extra[..]];
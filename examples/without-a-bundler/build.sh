@@ -0,0 +1,12 @@
+#!/bin/sh
+
+set -ex
+
+cargo +nightly build --target wasm32-unknown-unknown
+
+cargo +nightly run --manifest-path ../../crates/cli/Cargo.toml \
+  --bin wasm-bindgen -- \
+  --target web \
+  ../../target/wasm32-unknown-unknown/debug/without_a_bundler.wasm --out-dir .
+
+python -m SimpleHTTPServer
@@ -1,4 +1,5 @@
 use JsValue;
+use __rt::{Ref, RefMut};
 
 /// A trait for checked and unchecked casting between JS types.
 ///
@@ -117,3 +118,43 @@ where
     /// won't need to call this.
     fn unchecked_from_js_ref(val: &JsValue) -> &Self;
 }
+
+/// A trait for checked casting of JS values into exported Rust types.
+///
+/// Where `JsCast` works with types imported from JS, this trait works with the
+/// Rust types exported to JS as classes. Each instance of such a class in JS
+/// owns a pointer to a value in wasm memory, and the methods here perform an
+/// `instanceof` check against the generated class before handing out that
+/// value, either by-value or behind a borrow.
+///
+/// This trait is automatically implemented for any `struct` annotated with
+/// `#[wasm_bindgen]`.
+pub trait JsClass: Sized {
+    /// Performs a dynamic `instanceof` check to see whether the `JsValue`
+    /// provided is an instance of the JS class generated for this type.
+    ///
+    /// Instances which have already been freed are not considered instances.
+    fn instanceof(val: &JsValue) -> bool;
+
+    /// Performs a dynamic cast (checked at runtime) of `val` into an owned
+    /// instance of this type.
+    ///
+    /// On success ownership of the Rust value is taken away from the JS
+    /// object, which behaves afterwards as if `free` had been called on it.
+    /// If `val` is not an instance of this class then `Err(val)` is returned.
+    fn dyn_from(val: JsValue) -> Result<Self, JsValue>;
+
+    /// Performs a dynamic cast (checked at runtime) of `val` into a shared
+    /// borrow of this type.
+    ///
+    /// Returns `None` if `val` is not an instance of this class or if the
+    /// instance has already been freed.
+    fn dyn_ref(val: &JsValue) -> Option<Ref<Self>>;
+
+    /// Performs a dynamic cast (checked at runtime) of `val` into a mutable
+    /// borrow of this type.
+    ///
+    /// Returns `None` if `val` is not an instance of this class or if the
+    /// instance has already been freed.
+    fn dyn_mut(val: &JsValue) -> Option<RefMut<Self>>;
+}
@@ -2,7 +2,7 @@ use core::char;
 use core::mem::{self, ManuallyDrop};
 
 use convert::{Stack, FromWasmAbi, IntoWasmAbi, RefFromWasmAbi};
-use convert::{OptionIntoWasmAbi, OptionFromWasmAbi, ReturnWasmAbi};
+use convert::{OptionIntoWasmAbi, OptionFromWasmAbi, OptionRefFromWasmAbi, ReturnWasmAbi};
 use convert::traits::WasmAbi;
 use {JsValue, Clamped};
 
@@ -350,6 +350,13 @@ impl RefFromWasmAbi for JsValue {
     }
 }
 
+impl OptionRefFromWasmAbi for JsValue {
+    #[inline]
+    fn is_none(abi: &u32) -> bool {
+        *abi == 0
+    }
+}
+
 impl<T: OptionIntoWasmAbi> IntoWasmAbi for Option<T> {
     type Abi = T::Abi;
 
@@ -90,6 +90,21 @@ pub trait OptionFromWasmAbi: FromWasmAbi {
     fn is_none(abi: &Self::Abi) -> bool;
 }
 
+/// Indicates that this type can be received from JS as `Option<&Self>`.
+///
+/// This trait is used for arguments of type `Option<&T>` in exported
+/// functions.
+pub trait OptionRefFromWasmAbi: RefFromWasmAbi {
+    /// Same as `OptionFromWasmAbi::is_none`
+    fn is_none(abi: &Self::Abi) -> bool;
+}
+
+/// Dual of the `OptionRefFromWasmAbi` trait, except for mutable references.
+pub trait OptionRefMutFromWasmAbi: RefMutFromWasmAbi {
+    /// Same as `OptionFromWasmAbi::is_none`
+    fn is_none(abi: &Self::Abi) -> bool;
+}
+
 pub trait Stack {
     fn push(&mut self, bits: u32);
 }
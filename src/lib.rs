@@ -48,7 +48,7 @@ pub mod convert;
 pub mod describe;
 
 mod cast;
-pub use cast::JsCast;
+pub use cast::{JsCast, JsClass};
 
 if_std! {
     extern crate std;
@@ -56,6 +56,9 @@ if_std! {
     pub mod closure;
 }
 
+#[cfg(all(feature = "std", target_feature = "atomics"))]
+pub mod thread;
+
 /// Representation of an object owned by JS.
 ///
 /// A `JsValue` doesn't actually live in Rust right now but actually in a table
@@ -442,6 +445,10 @@ externs! {
     fn __wbindgen_jsval_eq(a: u32, b: u32) -> u32;
 
     fn __wbindgen_memory() -> u32;
+    fn __wbindgen_heap_snapshot() -> u32;
+
+    fn __wbindgen_anyref_table_grow(delta: usize) -> i32;
+    fn __wbindgen_anyref_table_set_null(idx: usize) -> ();
 }
 
 impl Clone for JsValue {
@@ -643,6 +650,24 @@ pub mod __rt {
         super::throw_str("null pointer passed to rust");
     }
 
+    /// Returns an array describing each `JsValue` currently alive in the JS
+    /// glue, with the `index` of its handle, an `id` unique to the
+    /// allocation, its `value`, its `refcount` and the stack `trace` of where
+    /// it was created.
+    ///
+    /// Returns `None` when the JS glue doesn't track its heap, which it only
+    /// does when generated with `--debug` and without `--anyref`.
+    pub fn heap_report() -> Option<super::JsValue> {
+        let report = unsafe {
+            super::JsValue { idx: super::__wbindgen_heap_snapshot() }
+        };
+        if report.is_undefined() {
+            None
+        } else {
+            Some(report)
+        }
+    }
+
     /// A vendored version of `RefCell` from the standard library.
     ///
     /// Now why, you may ask, would we do that? Surely `RefCell` in libstd is
@@ -714,6 +739,8 @@ pub mod __rt {
         }
     }
 
+    /// A shared borrow of a Rust value owned by a JS object, as handed out
+    /// by `JsClass::dyn_ref`.
     pub struct Ref<'b, T: ?Sized + 'b> {
         value: &'b T,
         borrow: &'b Cell<usize>,
@@ -734,6 +761,8 @@ pub mod __rt {
         }
     }
 
+    /// A mutable borrow of a Rust value owned by a JS object, as handed out
+    /// by `JsClass::dyn_mut`.
     pub struct RefMut<'b, T: ?Sized + 'b> {
         value: &'b mut T,
         borrow: &'b Cell<usize>,
@@ -771,6 +800,7 @@ pub mod __rt {
     if_std! {
         use std::alloc::{System, GlobalAlloc, Layout};
         use std::mem;
+        use std::vec::Vec;
 
         #[no_mangle]
         pub extern fn __wbindgen_malloc(size: usize) -> *mut u8 {
@@ -798,6 +828,75 @@ pub mod __rt {
             let layout = Layout::from_size_align_unchecked(size, align);
             System.dealloc(ptr, layout);
         }
+
+        /// Free list of the slots of the table of `anyref`s which stores
+        /// `JsValue`s when `wasm-bindgen --anyref` is used. Each entry of
+        /// `data` is the next free slot after it, and slot `i` is the index
+        /// `base + i` of the table.
+        ///
+        /// The slab is a plain static, which is only sound without threads.
+        /// It's left out with the `atomics` target feature, where
+        /// `anyref::prepare` in the CLI rejects `--anyref` as well.
+        #[cfg(not(target_feature = "atomics"))]
+        struct AnyrefSlab {
+            data: Vec<usize>,
+            head: usize,
+            base: usize,
+            len: usize,
+        }
+
+        #[cfg(not(target_feature = "atomics"))]
+        static mut ANYREF_SLAB: Option<AnyrefSlab> = None;
+
+        #[cfg(not(target_feature = "atomics"))]
+        unsafe fn anyref_slab() -> &'static mut AnyrefSlab {
+            ANYREF_SLAB.get_or_insert_with(|| AnyrefSlab {
+                data: Vec::new(),
+                head: 0,
+                base: 0,
+                len: 0,
+            })
+        }
+
+        #[cfg(not(target_feature = "atomics"))]
+        #[no_mangle]
+        pub unsafe extern fn __wbindgen_anyref_table_alloc() -> usize {
+            let slab = anyref_slab();
+            if slab.head == slab.data.len() {
+                if slab.data.len() == slab.len {
+                    let extra = if slab.len == 0 { 32 } else { slab.len };
+                    let start = super::__wbindgen_anyref_table_grow(extra);
+                    if start == -1 {
+                        super::throw_str("failed to grow the table of JS objects");
+                    }
+                    // The table starts out with just the reserved values, so
+                    // the first slot is right after them.
+                    if slab.len == 0 {
+                        slab.base = start as usize;
+                    }
+                    slab.len += extra;
+                }
+                let next = slab.data.len() + 1;
+                slab.data.push(next);
+            }
+            let slot = slab.head;
+            slab.head = slab.data[slot];
+            slab.base + slot
+        }
+
+        #[cfg(not(target_feature = "atomics"))]
+        #[no_mangle]
+        pub unsafe extern fn __wbindgen_anyref_table_dealloc(idx: usize) {
+            // Constants like `JsValue::NULL` are never deallocated.
+            if idx < super::JSIDX_RESERVED as usize {
+                return
+            }
+            super::__wbindgen_anyref_table_set_null(idx);
+            let slab = anyref_slab();
+            let slot = idx - slab.base;
+            slab.data[slot] = slab.head;
+            slab.head = slot;
+        }
     }
 
     pub const GLOBAL_STACK_CAP: usize = 16;
@@ -805,15 +904,33 @@ pub mod __rt {
     // Increase the alignment to 8 here because this can be used as a
     // BigUint64Array pointer base which requires alignment 8
     #[repr(align(8))]
+    #[cfg(not(target_feature = "atomics"))]
     struct GlobalData([u32; GLOBAL_STACK_CAP]);
 
+    #[cfg(not(target_feature = "atomics"))]
     static mut GLOBAL_STACK: GlobalData = GlobalData([0; GLOBAL_STACK_CAP]);
 
     #[no_mangle]
+    #[cfg(not(target_feature = "atomics"))]
     pub unsafe extern "C" fn __wbindgen_global_argument_ptr() -> *mut u32 {
         GLOBAL_STACK.0.as_mut_ptr()
     }
 
+    // With a shared memory a single static would be clobbered by concurrent
+    // threads, so instead each thread's JS glue hands out a scratch area of
+    // `GLOBAL_STACK_CAP` words which it allocated just for that thread.
+    #[cfg(target_feature = "atomics")]
+    #[link(wasm_import_module = "__wbindgen_placeholder__")]
+    extern {
+        fn __wbindgen_thread_scratch() -> *mut u32;
+    }
+
+    #[no_mangle]
+    #[cfg(target_feature = "atomics")]
+    pub unsafe extern "C" fn __wbindgen_global_argument_ptr() -> *mut u32 {
+        __wbindgen_thread_scratch()
+    }
+
     /// This is a curious function necessary to get wasm-bindgen working today,
     /// and it's a bit of an unfortunate hack.
     ///
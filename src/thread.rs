@@ -0,0 +1,65 @@
+//! Support for running Rust code on other threads in `wasm-bindgen`
+//!
+//! This module is only available when compiling with the `atomics` target
+//! feature and a shared memory, and the resulting wasm file must be processed
+//! with `wasm-bindgen --threads`. Threads are backed by a pool of web workers
+//! (or `worker_threads` in Node) which each instantiate the same module with
+//! the same memory, created lazily by the generated JS glue.
+//!
+//! Note that JS objects are not shared between threads: each thread has its
+//! own JS heap, so a `JsValue` must only be used on the thread that created
+//! it. This isn't checked by the compiler, `JsValue` is `Send` and `Sync`
+//! regardless of the target.
+
+use std::prelude::v1::*;
+
+#[link(wasm_import_module = "__wbindgen_placeholder__")]
+extern {
+    fn __wbindgen_thread_spawn(task: u32);
+}
+
+/// Runs `f` on a worker from the thread pool of this wasm module.
+///
+/// The closure is queued on one of the pool's workers and this function
+/// returns immediately. Workers execute the closures they're handed one at a
+/// time, in the order they were spawned.
+///
+/// Note that the main thread of a browser can't block, so the result of `f`
+/// needs to be communicated back through shared state like atomics or
+/// channels, polled from an asynchronous context.
+///
+/// # Example
+///
+/// ```ignore
+/// use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+/// use wasm_bindgen::thread;
+///
+/// static DONE: AtomicUsize = ATOMIC_USIZE_INIT;
+///
+/// thread::spawn(|| {
+///     DONE.fetch_add(1, Ordering::SeqCst);
+/// });
+/// ```
+pub fn spawn<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut f = Some(f);
+    let task: Box<FnMut() + Send> = Box::new(move || {
+        if let Some(f) = f.take() {
+            f();
+        }
+    });
+    unsafe {
+        __wbindgen_thread_spawn(Box::into_raw(Box::new(task)) as u32);
+    }
+}
+
+/// Entry point called by the JS glue of a worker to run a task handed to
+/// `__wbindgen_thread_spawn`.
+#[no_mangle]
+#[doc(hidden)]
+pub unsafe extern fn __wbindgen_thread_entry(task: u32) {
+    let mut task = Box::from_raw(task as *mut Box<FnMut() + Send>);
+    task();
+}
@@ -132,3 +132,26 @@ exports.js_js_rename = () => {
     (new wasm.JsRename()).bar();
     wasm.classes_foo();
 };
+
+exports.js_dyn_cast = () => {
+    const a = new wasm.DynCast(3);
+    assert.strictEqual(wasm.dyn_cast_is_instance(a), true);
+    assert.strictEqual(wasm.dyn_cast_is_instance({}), false);
+    assert.strictEqual(wasm.dyn_cast_is_instance(new wasm.ClassesSimple()), false);
+
+    assert.strictEqual(wasm.dyn_cast_bump(a), true);
+    assert.strictEqual(wasm.dyn_cast_bump(1), false);
+    assert.strictEqual(wasm.dyn_cast_peek(a), 4);
+    assert.strictEqual(wasm.dyn_cast_peek(null), undefined);
+    assert.strictEqual(a.contents(), 4);
+
+    assert.strictEqual(wasm.dyn_cast_take(a), 4);
+    assert.strictEqual(wasm.dyn_cast_is_instance(a), false);
+    assert.strictEqual(wasm.dyn_cast_take(a), undefined);
+    // Debug mode checks for moved values in JS, otherwise Rust sees the
+    // null pointer.
+    if (require('process').env.WASM_BINDGEN_NO_DEBUG)
+        assert.throws(() => a.contents(), /null pointer passed to rust/);
+    else
+        assert.throws(() => a.contents(), /Attempt to use a moved value/);
+};
@@ -1,5 +1,6 @@
 use wasm_bindgen_test::*;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsClass;
 
 #[wasm_bindgen(module = "tests/wasm/classes.js")]
 extern {
@@ -17,6 +18,7 @@ extern {
     fn js_readonly_fields();
     fn js_double_consume();
     fn js_js_rename();
+    fn js_dyn_cast();
 }
 
 #[wasm_bindgen_test]
@@ -344,3 +346,51 @@ impl JsRename {
 
 #[wasm_bindgen(js_name = classes_foo)]
 pub fn foo() {}
+
+#[wasm_bindgen_test]
+fn dyn_cast() {
+    js_dyn_cast();
+}
+
+#[wasm_bindgen]
+pub struct DynCast {
+    contents: u32,
+}
+
+#[wasm_bindgen]
+impl DynCast {
+    #[wasm_bindgen(constructor)]
+    pub fn new(contents: u32) -> DynCast {
+        DynCast { contents }
+    }
+
+    pub fn contents(&self) -> u32 {
+        self.contents
+    }
+}
+
+#[wasm_bindgen]
+pub fn dyn_cast_is_instance(val: JsValue) -> bool {
+    DynCast::instanceof(&val)
+}
+
+#[wasm_bindgen]
+pub fn dyn_cast_bump(val: &JsValue) -> bool {
+    match DynCast::dyn_mut(val) {
+        Some(mut cast) => {
+            cast.contents += 1;
+            true
+        }
+        None => false,
+    }
+}
+
+#[wasm_bindgen]
+pub fn dyn_cast_peek(val: &JsValue) -> Option<u32> {
+    DynCast::dyn_ref(val).map(|cast| cast.contents)
+}
+
+#[wasm_bindgen]
+pub fn dyn_cast_take(val: JsValue) -> Option<u32> {
+    DynCast::dyn_from(val).ok().map(|cast| cast.contents)
+}
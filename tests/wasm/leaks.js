@@ -0,0 +1,5 @@
+const process = require('process');
+
+exports.heap_tracked = () => {
+    return !process.env.WASM_BINDGEN_NO_DEBUG && !process.env.WASM_BINDGEN_ANYREF;
+};
@@ -0,0 +1,39 @@
+use std::mem;
+
+use js_sys::Array;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_test::*;
+
+#[wasm_bindgen(module = "tests/wasm/leaks.js")]
+extern "C" {
+    fn heap_tracked() -> bool;
+}
+
+// The heap is only tracked with `--debug`, and not at all with `--anyref`.
+#[wasm_bindgen_test]
+fn heap_tracked_per_mode() {
+    assert_eq!(find_leaks(|| {}).is_some(), heap_tracked());
+}
+
+#[wasm_bindgen_test]
+fn dropped_values_dont_leak() {
+    assert_no_leaks(|| {
+        let array = Array::new();
+        array.push(&JsValue::from_str("dropped"));
+        let clone = array.clone();
+        drop(array);
+        assert_eq!(clone.length(), 1);
+    });
+}
+
+// Only the forgotten value is reported, not the arrays `find_leaks` itself
+// gets from the JS glue to compare the values alive before and after.
+#[wasm_bindgen_test]
+fn forgotten_values_leak() {
+    let leaks = match find_leaks(|| mem::forget(JsValue::from_str("forgotten"))) {
+        Some(leaks) => leaks,
+        None => return,
+    };
+    assert_eq!(leaks.len(), 1, "{:?}", leaks);
+    assert!(leaks[0].starts_with("\"forgotten\" (refcount 1) created at:\n"), "{}", leaks[0]);
+}
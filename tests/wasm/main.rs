@@ -1,8 +1,10 @@
 #![cfg(target_arch = "wasm32")]
 
+extern crate futures;
 extern crate js_sys;
 extern crate wasm_bindgen_test;
 extern crate wasm_bindgen;
+extern crate wasm_bindgen_futures;
 extern crate wasm_bindgen_test_crate_a;
 extern crate wasm_bindgen_test_crate_b;
 
@@ -22,6 +24,7 @@ pub mod import_class;
 pub mod imports;
 pub mod js_objects;
 pub mod jscast;
+pub mod leaks;
 pub mod math;
 pub mod node;
 pub mod option;
@@ -29,7 +32,11 @@ pub mod optional_primitives;
 pub mod rethrow;
 pub mod simple;
 pub mod slice;
+pub mod split;
+pub mod start;
 pub mod structural;
 pub mod u64;
 pub mod validate_prt;
 pub mod variadic;
+pub mod vendor_prefix;
+pub mod weak_refs;
@@ -26,3 +26,47 @@ exports.test_option_values = () => {
     assert.ok(x !== null && x !== undefined);
     assert.ok(x instanceof MyType);
 };
+
+exports.take_some_class = x => {
+    assert.ok(x instanceof wasm.OptionClass);
+    // Borrowing it in Rust leaves it usable, passing it by value consumes it.
+    assert.strictEqual(wasm.rust_take_class_byref(x), 6);
+    assert.notStrictEqual(x.ptr, 0);
+    assert.strictEqual(wasm.rust_take_class_byval(x), 6);
+    assert.strictEqual(x.ptr, 0);
+};
+exports.return_some_class = () => new wasm.OptionClass(3);
+exports.return_moved_class = () => {
+    const c = new wasm.OptionClass(5);
+    wasm.rust_take_class_byval(c);
+    return c;
+};
+
+exports.test_option_classes = () => {
+    assert.strictEqual(wasm.rust_take_ref_byval(undefined), false);
+    assert.strictEqual(wasm.rust_take_ref_byval(new MyType()), true);
+
+    assert.strictEqual(wasm.rust_take_class_byval(undefined), undefined);
+    assert.strictEqual(wasm.rust_take_class_byval(null), undefined);
+    const a = new wasm.OptionClass(1);
+    assert.strictEqual(wasm.rust_take_class_byval(a), 1);
+    assert.strictEqual(a.ptr, 0);
+    assert.throws(() => wasm.rust_take_class_byval(a), /Attempt to use a moved value/);
+
+    const b = new wasm.OptionClass(2);
+    assert.strictEqual(wasm.rust_take_class_byref(undefined), undefined);
+    assert.strictEqual(wasm.rust_take_class_byref(b), 2);
+    assert.notStrictEqual(b.ptr, 0);
+    wasm.rust_bump_class(undefined);
+    wasm.rust_bump_class(b);
+    assert.strictEqual(wasm.rust_take_class_byref(b), 3);
+    b.free();
+    // Moved and freed values aren't mistaken for `None`.
+    assert.throws(() => wasm.rust_take_class_byref(b), /Attempt to use a moved value/);
+    assert.throws(() => wasm.rust_return_moved_class(), /Attempt to use a moved value/);
+
+    assert.strictEqual(wasm.rust_return_class(undefined), undefined);
+    const c = wasm.rust_return_class(4);
+    assert.ok(c instanceof wasm.OptionClass);
+    assert.strictEqual(wasm.rust_take_class_byval(c), 4);
+};
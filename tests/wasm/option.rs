@@ -13,11 +13,20 @@ extern {
     fn return_null_byval() -> Option<MyType>;
     fn return_some_byval() -> Option<MyType>;
     fn test_option_values();
+    fn test_option_classes();
 
     #[wasm_bindgen(js_name = take_none_byval)]
     fn take_none_byref(t: Option<&MyType>);
     #[wasm_bindgen(js_name = take_some_byval)]
     fn take_some_byref(t: Option<&MyType>);
+
+    #[wasm_bindgen(js_name = take_none_byval)]
+    fn take_none_class(t: Option<OptionClass>);
+    fn take_some_class(t: Option<OptionClass>);
+    #[wasm_bindgen(js_name = return_undef_byval)]
+    fn return_none_class() -> Option<OptionClass>;
+    fn return_some_class() -> Option<OptionClass>;
+    fn return_moved_class() -> Option<OptionClass>;
 }
 
 #[wasm_bindgen_test]
@@ -59,3 +68,55 @@ fn import_by_ref() {
     take_none_byref(None);
     take_some_byref(Some(&MyType::new()));
 }
+
+#[wasm_bindgen]
+pub fn rust_take_ref_byval(t: Option<&MyType>) -> bool {
+    t.is_some()
+}
+
+#[wasm_bindgen_test]
+fn classes() {
+    test_option_classes();
+    take_none_class(None);
+    take_some_class(Some(OptionClass(6)));
+    assert!(return_none_class().is_none());
+    assert_eq!(return_some_class().map(|c| c.0), Some(3));
+}
+
+#[wasm_bindgen]
+pub struct OptionClass(u32);
+
+#[wasm_bindgen]
+impl OptionClass {
+    #[wasm_bindgen(constructor)]
+    pub fn new(val: u32) -> OptionClass {
+        OptionClass(val)
+    }
+}
+
+#[wasm_bindgen]
+pub fn rust_take_class_byval(t: Option<OptionClass>) -> Option<u32> {
+    t.map(|t| t.0)
+}
+
+#[wasm_bindgen]
+pub fn rust_take_class_byref(t: Option<&OptionClass>) -> Option<u32> {
+    t.map(|t| t.0)
+}
+
+#[wasm_bindgen]
+pub fn rust_bump_class(t: Option<&mut OptionClass>) {
+    if let Some(t) = t {
+        t.0 += 1;
+    }
+}
+
+#[wasm_bindgen]
+pub fn rust_return_class(val: Option<u32>) -> Option<OptionClass> {
+    val.map(OptionClass)
+}
+
+#[wasm_bindgen]
+pub fn rust_return_moved_class() -> Option<u32> {
+    return_moved_class().map(|c| c.0)
+}
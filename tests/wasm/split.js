@@ -0,0 +1,8 @@
+const wasm = require('wasm-bindgen-test.js');
+const assert = require('assert');
+
+exports.js_call_split_add = (a, b) => {
+    const ret = wasm.split_add(a, b);
+    assert.ok(ret instanceof Promise);
+    return ret;
+};
@@ -0,0 +1,22 @@
+use futures::Future;
+use js_sys::Promise;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use wasm_bindgen_test::*;
+
+#[wasm_bindgen(module = "tests/wasm/split.js")]
+extern {
+    fn js_call_split_add(a: u32, b: u32) -> Promise;
+}
+
+#[wasm_bindgen(split = "split_test")]
+pub fn split_add(a: u32, b: u32) -> u32 {
+    a + b
+}
+
+#[wasm_bindgen_test(async)]
+fn split_exports_return_promises() -> impl Future<Item = (), Error = JsValue> {
+    JsFuture::from(js_call_split_add(1, 2)).map(|n| {
+        assert_eq!(n.as_f64(), Some(3.0));
+    })
+}
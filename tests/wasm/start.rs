@@ -0,0 +1,16 @@
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use wasm_bindgen_test::*;
+use wasm_bindgen::prelude::*;
+
+static STARTED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[wasm_bindgen(start)]
+pub fn start() {
+    STARTED.fetch_add(1, Ordering::SeqCst);
+}
+
+#[wasm_bindgen_test]
+fn start_ran_once() {
+    assert_eq!(STARTED.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,18 @@
+exports.webkitMySpecialApi = class {
+    foo() { return 123; }
+};
+exports.MySpecialApi2 = class {
+    foo() { return 124; }
+};
+exports.webkitMySpecialApi2 = class {
+    foo() { return 125; }
+};
+exports.aMySpecialApi3 = class {
+    foo() { return 126; }
+};
+exports.bMySpecialApi3 = class {
+    foo() { return 127; }
+};
+exports.MyPolyfilledApi = class {
+    foo() { return 128; }
+};
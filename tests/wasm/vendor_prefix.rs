@@ -0,0 +1,94 @@
+use wasm_bindgen_test::*;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+#[wasm_bindgen(module = "tests/wasm/vendor_prefix.js")]
+extern {
+    #[wasm_bindgen(vendor_prefix = webkit)]
+    type MySpecialApi;
+    #[wasm_bindgen(constructor)]
+    fn new() -> MySpecialApi;
+    #[wasm_bindgen(method)]
+    fn foo(this: &MySpecialApi) -> u32;
+
+    #[wasm_bindgen(vendor_prefix = webkit)]
+    type MySpecialApi2;
+    #[wasm_bindgen(constructor)]
+    fn new() -> MySpecialApi2;
+    #[wasm_bindgen(method)]
+    fn foo(this: &MySpecialApi2) -> u32;
+
+    #[wasm_bindgen(vendor_prefix = a, vendor_prefix = b)]
+    type MySpecialApi3;
+    #[wasm_bindgen(constructor)]
+    fn new() -> MySpecialApi3;
+    #[wasm_bindgen(method)]
+    fn foo(this: &MySpecialApi3) -> u32;
+
+    #[wasm_bindgen(vendor_prefix = webkit, fallback = MyPolyfilledApi)]
+    type MyUnprefixedApi;
+    #[wasm_bindgen(constructor)]
+    fn new() -> MyUnprefixedApi;
+    #[wasm_bindgen(method)]
+    fn foo(this: &MyUnprefixedApi) -> u32;
+
+    #[wasm_bindgen(vendor_prefix = webkit)]
+    type MyMissingModuleApi;
+    #[wasm_bindgen(method)]
+    fn foo(this: &MyMissingModuleApi) -> u32;
+}
+
+#[wasm_bindgen]
+extern {
+    #[wasm_bindgen(fallback = Array)]
+    type MyArray;
+    #[wasm_bindgen(constructor)]
+    fn new() -> MyArray;
+    #[wasm_bindgen(method)]
+    fn push(this: &MyArray, value: u32) -> u32;
+
+    #[wasm_bindgen(vendor_prefix = webkit)]
+    type MyMissingApi;
+    #[wasm_bindgen(catch, constructor)]
+    fn new() -> Result<MyMissingApi, JsValue>;
+    #[wasm_bindgen(method)]
+    fn foo(this: &MyMissingApi) -> u32;
+    #[wasm_bindgen(catch, method, js_name = foo)]
+    fn try_foo(this: &MyMissingApi) -> Result<u32, JsValue>;
+}
+
+#[wasm_bindgen_test]
+fn resolves_prefixed_names() {
+    // only `webkitMySpecialApi` exists
+    assert_eq!(MySpecialApi::new().foo(), 123);
+    // both exist, the unprefixed version wins
+    assert_eq!(MySpecialApi2::new().foo(), 124);
+    // prefixes are tried in order
+    assert_eq!(MySpecialApi3::new().foo(), 126);
+}
+
+#[wasm_bindgen_test]
+fn resolves_fallbacks() {
+    // neither `MyUnprefixedApi` nor `webkitMyUnprefixedApi` exist
+    assert_eq!(MyUnprefixedApi::new().foo(), 128);
+    // globals fall back as well
+    assert_eq!(MyArray::new().push(3), 1);
+    assert!(JsValue::from(js_sys::Array::new()).is_instance_of::<MyArray>());
+}
+
+#[wasm_bindgen_test]
+fn missing_types_are_tolerated() {
+    let obj = JsValue::from(js_sys::Object::new());
+    assert!(!obj.is_instance_of::<MyMissingApi>());
+    assert!(!obj.is_instance_of::<MyMissingModuleApi>());
+}
+
+#[wasm_bindgen_test]
+fn missing_types_throw_when_used() {
+    let message = |e: JsValue| String::from(e.unchecked_into::<js_sys::Error>().message());
+    let err = MyMissingApi::new().err().unwrap();
+    assert_eq!(message(err), "wasm-bindgen: MyMissingApi does not exist");
+    let obj = JsValue::from(js_sys::Object::new()).unchecked_into::<MyMissingApi>();
+    let err = obj.try_foo().err().unwrap();
+    assert_eq!(message(err), "wasm-bindgen: MyMissingApi.foo does not exist");
+}
@@ -0,0 +1,29 @@
+const wasm = require('wasm-bindgen-test.js');
+
+function weakRefsEnabled() {
+    return require('process').env.WASM_BINDGEN_WEAKREF !== undefined &&
+        typeof FinalizationRegistry !== 'undefined' &&
+        typeof global.gc === 'function';
+}
+
+exports.weak_refs_enabled = weakRefsEnabled;
+
+exports.js_make_garbage = () => {
+    new wasm.WeakRefsTracked();
+    wasm.weak_refs_make();
+
+    new wasm.WeakRefsTracked().free();
+    new wasm.WeakRefsTracked().consume();
+    wasm.weak_refs_take(wasm.weak_refs_make());
+};
+
+exports.collect_garbage = async () => {
+    if (!weakRefsEnabled())
+        return;
+    // Finalizers are only run in a later task after a collection, so give
+    // them a few turns of the event loop.
+    for (let i = 0; i < 10; i++) {
+        global.gc();
+        await new Promise(resolve => setTimeout(resolve, 10));
+    }
+};
@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use futures::Future;
+use js_sys::Promise;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use wasm_bindgen_test::*;
+
+#[wasm_bindgen(module = "tests/wasm/weak_refs.js")]
+extern {
+    fn weak_refs_enabled() -> bool;
+    fn js_make_garbage();
+    fn collect_garbage() -> Promise;
+}
+
+static DROPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[wasm_bindgen]
+pub struct WeakRefsTracked;
+
+#[wasm_bindgen]
+impl WeakRefsTracked {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WeakRefsTracked {
+        WeakRefsTracked
+    }
+
+    pub fn consume(self) {}
+}
+
+impl Drop for WeakRefsTracked {
+    fn drop(&mut self) {
+        DROPPED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[wasm_bindgen]
+pub fn weak_refs_make() -> WeakRefsTracked {
+    WeakRefsTracked
+}
+
+#[wasm_bindgen]
+pub fn weak_refs_take(_: WeakRefsTracked) {}
+
+#[wasm_bindgen_test(async)]
+fn unreachable_objects_are_freed() -> impl Future<Item = (), Error = JsValue> {
+    let before = DROPPED.load(Ordering::SeqCst);
+
+    // Three of the five objects are freed manually or moved back into Rust,
+    // the other two are only reachable from JS until the collector runs.
+    js_make_garbage();
+    assert_eq!(DROPPED.load(Ordering::SeqCst) - before, 3);
+
+    JsFuture::from(collect_garbage()).map(move |_| {
+        if !weak_refs_enabled() {
+            return;
+        }
+        // Exactly five drops means nothing was freed twice.
+        assert_eq!(DROPPED.load(Ordering::SeqCst) - before, 5);
+    })
+}